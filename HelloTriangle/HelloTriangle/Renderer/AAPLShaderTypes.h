@@ -16,8 +16,38 @@ typedef enum AAPLVertexInputIndex
 {
     AAPLVertexInputIndexVertices     = 0,
     AAPLVertexInputIndexViewportSize = 1,
+    AAPLVertexInputIndexObjectUniforms = 2,
+    AAPLVertexInputIndexInstances    = 3,
+    AAPLVertexInputIndexParticleUniforms = 4,
 } AAPLVertexInputIndex;
 
+// Buffer index values for the compute kernels.
+typedef enum AAPLComputeIndex
+{
+    AAPLComputeIndexParticles        = 0,
+    AAPLComputeIndexParticleUniforms = 1,
+} AAPLComputeIndex;
+
+// Buffer index values for the post-processing fragment shaders.
+typedef enum AAPLFragmentInputIndex
+{
+    AAPLFragmentInputIndexPostProcessUniforms = 0,
+} AAPLFragmentInputIndex;
+
+// Texture and sampler index values shared between shader and C code.
+typedef enum AAPLTextureIndex
+{
+    AAPLTextureIndexBaseColor = 0,
+    AAPLTextureIndexPostProcessSource = 1,
+    AAPLTextureIndexBloom = 2,
+    AAPLTextureIndexColorLut = 3,
+} AAPLTextureIndex;
+
+typedef enum AAPLSamplerIndex
+{
+    AAPLSamplerIndexBaseColor = 0,
+} AAPLSamplerIndex;
+
 //  This structure defines the layout of vertices sent to the vertex
 //  shader. This header is shared between the .metal shader and C code, to guarantee that
 //  the layout of the vertex array in the C code matches the layout that the .metal
@@ -28,4 +58,102 @@ typedef struct
     vector_float4 color;
 } AAPLVertex;
 
+//  A vertex with texture coordinates, for texturedVertexShader.
+//  The texture coordinate comes last so it's laid out the same way in Rust.
+typedef struct
+{
+    vector_float2 position;
+    vector_float4 color;
+    vector_float2 textureCoordinate;
+} AAPLTexturedVertex;
+
+//  Per-object values for sceneVertexShader:
+//  a 2D affine transform (in pixels), a color to multiply the vertex colors by,
+//  and a depth from 0 (nearest) to 1 (farthest) for the depth test.
+typedef struct
+{
+    matrix_float3x3 transform;
+    vector_float4 tint;
+    float depth;
+} AAPLObjectUniforms;
+
+//  Per-instance values for instancedVertexShader: each copy of the mesh is scaled,
+//  rotated (anticlockwise, in radians) and offset (in pixels), and its vertex colors multiplied by the color.
+typedef struct
+{
+    vector_float4 color;
+    vector_float2 offset;
+    vector_float2 scale;
+    float rotation;
+} AAPLInstance;
+
+//  One particle, moved by updateParticles and drawn as a point by particleVertexShader.
+//  A particle whose lifetime is zero hasn't been born yet, and isn't drawn.
+typedef struct
+{
+    vector_float4 color;
+    vector_float2 position;
+    vector_float2 velocity;
+    float age;
+    float lifetime;
+} AAPLParticle;
+
+//  Everything updateParticles needs for one time step, and the size particleVertexShader draws them.
+//  Positions, speeds and sizes are in pixels, and times in seconds.
+typedef struct
+{
+    vector_float4 startColor;
+    vector_float4 endColor;
+    vector_float2 emitterPosition;
+    vector_float2 gravity;
+    vector_float2 bounds;
+    float emitterAngle;
+    float emitterSpread;
+    float minSpeed;
+    float maxSpeed;
+    float minLifetime;
+    float maxLifetime;
+    float restitution;
+    float timeStep;
+    float pointSize;
+    uint32_t particleCount;
+    uint32_t seed;
+} AAPLParticleUniforms;
+
+//  One direction of a separable gaussian blur, for gaussianBlurFragmentShader:
+//  each pixel becomes the average of the pixels up to radius away along direction (a step of one pixel),
+//  weighted by a gaussian whose standard deviation is sigma pixels.
+typedef struct
+{
+    vector_int2 direction;
+    float sigma;
+    uint32_t radius;
+} AAPLBlurUniforms;
+
+//  Bloom: brightPassFragmentShader keeps what's brighter than threshold, easing in over knee,
+//  and bloomCompositeFragmentShader adds the blurred highlights back, scaled by intensity.
+typedef struct
+{
+    float threshold;
+    float knee;
+    float intensity;
+} AAPLBloomUniforms;
+
+//  How much colorGradeFragmentShader moves colors towards the color lookup table's,
+//  and how many entries the table has along each axis.
+typedef struct
+{
+    float amount;
+    uint32_t lutSize;
+} AAPLColorGradeUniforms;
+
+//  vignetteFragmentShader darkens pixels further than radius from the centre (in half-heights of the view),
+//  by up to intensity, easing in over softness.
+typedef struct
+{
+    float intensity;
+    float radius;
+    float softness;
+} AAPLVignetteUniforms;
+
 #endif /* AAPLShaderTypes_h */
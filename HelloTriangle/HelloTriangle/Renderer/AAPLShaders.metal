@@ -56,9 +56,334 @@ vertexShader(uint vertexID [[vertex_id]],
     return out;
 }
 
+// The same as vertexShader, but first moves each vertex by the object's transform
+// and tints its color.
+vertex RasterizerData
+sceneVertexShader(uint vertexID [[vertex_id]],
+                  constant AAPLVertex *vertices [[buffer(AAPLVertexInputIndexVertices)]],
+                  constant vector_uint2 *viewportSizePointer [[buffer(AAPLVertexInputIndexViewportSize)]],
+                  constant AAPLObjectUniforms *objectUniforms [[buffer(AAPLVertexInputIndexObjectUniforms)]])
+{
+    RasterizerData out;
+
+    float3 transformedPosition = objectUniforms->transform * float3(vertices[vertexID].position.xy, 1.0);
+    vector_float2 viewportSize = vector_float2(*viewportSizePointer);
+
+    out.position = vector_float4(0.0, 0.0, objectUniforms->depth, 1.0);
+    out.position.xy = transformedPosition.xy / (viewportSize / 2.0);
+
+    out.color = vertices[vertexID].color * objectUniforms->tint;
+
+    return out;
+}
+
+// The same as sceneVertexShader, but draws a copy of the mesh for each instance,
+// placed and colored by the instance before the object's transform and tint.
+vertex RasterizerData
+instancedVertexShader(uint vertexID [[vertex_id]],
+                      uint instanceID [[instance_id]],
+                      constant AAPLVertex *vertices [[buffer(AAPLVertexInputIndexVertices)]],
+                      constant vector_uint2 *viewportSizePointer [[buffer(AAPLVertexInputIndexViewportSize)]],
+                      constant AAPLObjectUniforms *objectUniforms [[buffer(AAPLVertexInputIndexObjectUniforms)]],
+                      constant AAPLInstance *instances [[buffer(AAPLVertexInputIndexInstances)]])
+{
+    RasterizerData out;
+
+    AAPLInstance instance = instances[instanceID];
+    float2 scaledPosition = vertices[vertexID].position.xy * instance.scale;
+    float cosine;
+    float sine = sincos(instance.rotation, cosine);
+    float2 instancePosition = float2(cosine * scaledPosition.x - sine * scaledPosition.y,
+                                     sine * scaledPosition.x + cosine * scaledPosition.y) + instance.offset;
+    float3 transformedPosition = objectUniforms->transform * float3(instancePosition, 1.0);
+    vector_float2 viewportSize = vector_float2(*viewportSizePointer);
+
+    out.position = vector_float4(0.0, 0.0, objectUniforms->depth, 1.0);
+    out.position.xy = transformedPosition.xy / (viewportSize / 2.0);
+
+    out.color = vertices[vertexID].color * instance.color * objectUniforms->tint;
+
+    return out;
+}
+
 fragment float4 fragmentShader(RasterizerData in [[stage_in]])
 {
     // Return the interpolated color.
     return in.color;
 }
 
+// Vertex shader outputs and fragment shader inputs for textured drawing
+typedef struct
+{
+    float4 position [[position]];
+    float4 color;
+    float2 textureCoordinate;
+} TexturedRasterizerData;
+
+// The same as sceneVertexShader, but also passes on the texture coordinates.
+vertex TexturedRasterizerData
+texturedVertexShader(uint vertexID [[vertex_id]],
+                     constant AAPLTexturedVertex *vertices [[buffer(AAPLVertexInputIndexVertices)]],
+                     constant vector_uint2 *viewportSizePointer [[buffer(AAPLVertexInputIndexViewportSize)]],
+                     constant AAPLObjectUniforms *objectUniforms [[buffer(AAPLVertexInputIndexObjectUniforms)]])
+{
+    TexturedRasterizerData out;
+
+    float3 transformedPosition = objectUniforms->transform * float3(vertices[vertexID].position.xy, 1.0);
+    vector_float2 viewportSize = vector_float2(*viewportSizePointer);
+
+    out.position = vector_float4(0.0, 0.0, objectUniforms->depth, 1.0);
+    out.position.xy = transformedPosition.xy / (viewportSize / 2.0);
+
+    out.color = vertices[vertexID].color * objectUniforms->tint;
+    out.textureCoordinate = vertices[vertexID].textureCoordinate;
+
+    return out;
+}
+
+// Samples the texture and multiplies it by the interpolated color.
+fragment float4 texturedFragmentShader(TexturedRasterizerData in [[stage_in]],
+                                       texture2d<float> baseColorTexture [[texture(AAPLTextureIndexBaseColor)]],
+                                       sampler baseColorSampler [[sampler(AAPLSamplerIndexBaseColor)]])
+{
+    return baseColorTexture.sample(baseColorSampler, in.textureCoordinate) * in.color;
+}
+
+
+// Draws text from a glyph atlas, whose alpha channel holds each texel's distance
+// to the nearest glyph edge (0.5 on the edge, more inside), in the interpolated color.
+fragment float4 textFragmentShader(TexturedRasterizerData in [[stage_in]],
+                                   texture2d<float> glyphAtlas [[texture(AAPLTextureIndexBaseColor)]],
+                                   sampler glyphAtlasSampler [[sampler(AAPLSamplerIndexBaseColor)]])
+{
+    float distance = glyphAtlas.sample(glyphAtlasSampler, in.textureCoordinate).a;
+    // How much the distance changes from one pixel to the next, so edges are a pixel wide at any size.
+    float width = max(fwidth(distance), 1e-4);
+    float coverage = saturate((distance - 0.5) / width + 0.5);
+    return float4(in.color.rgb, in.color.a * coverage);
+}
+
+// A well-mixed hash of a 32-bit number, for random numbers that are the same on the CPU.
+static uint hashParticle(uint x)
+{
+    x ^= x >> 16;
+    x *= 0x7feb352d;
+    x ^= x >> 15;
+    x *= 0x846ca68b;
+    x ^= x >> 16;
+    return x;
+}
+
+// A random number from 0 up to 1, different for each particle, time step and stream.
+static float randomUnit(uint particleID, uint seed, uint stream)
+{
+    return float(hashParticle(hashParticle(seed) ^ (particleID * 4 + stream)) >> 8) / 16777216.0;
+}
+
+// A new particle at the emitter, heading off at a random angle and speed.
+static AAPLParticle spawnParticle(uint particleID, constant AAPLParticleUniforms &uniforms)
+{
+    float angle = uniforms.emitterAngle + (randomUnit(particleID, uniforms.seed, 0) - 0.5) * uniforms.emitterSpread;
+    float speed = mix(uniforms.minSpeed, uniforms.maxSpeed, randomUnit(particleID, uniforms.seed, 1));
+
+    AAPLParticle particle;
+    particle.color = mix(uniforms.startColor, uniforms.endColor, randomUnit(particleID, uniforms.seed, 2));
+    particle.position = uniforms.emitterPosition;
+    particle.velocity = speed * float2(cos(angle), sin(angle));
+    particle.age = 0.0;
+    particle.lifetime = mix(uniforms.minLifetime, uniforms.maxLifetime, randomUnit(particleID, uniforms.seed, 3));
+    return particle;
+}
+
+// Moves each particle on by one time step, bouncing them off the edges of the view
+// and replacing the ones that have lived out their lifetime.
+kernel void
+updateParticles(uint particleID [[thread_position_in_grid]],
+                device AAPLParticle *particles [[buffer(AAPLComputeIndexParticles)]],
+                constant AAPLParticleUniforms &uniforms [[buffer(AAPLComputeIndexParticleUniforms)]])
+{
+    // Whole threadgroups are dispatched, so there can be more threads than particles.
+    if (particleID >= uniforms.particleCount)
+    {
+        return;
+    }
+
+    AAPLParticle particle = particles[particleID];
+    particle.age += uniforms.timeStep;
+    if (particle.age >= particle.lifetime)
+    {
+        particle = spawnParticle(particleID, uniforms);
+    }
+    else if (particle.lifetime > 0.0)
+    {
+        particle.velocity += uniforms.gravity * uniforms.timeStep;
+        particle.position += particle.velocity * uniforms.timeStep;
+        // Bounce off the edges of the view, losing some speed.
+        for (int axis = 0; axis < 2; axis++)
+        {
+            if (abs(particle.position[axis]) > uniforms.bounds[axis])
+            {
+                particle.position[axis] = copysign(uniforms.bounds[axis], particle.position[axis]);
+                particle.velocity[axis] = -particle.velocity[axis] * uniforms.restitution;
+            }
+        }
+    }
+    particles[particleID] = particle;
+}
+
+// Vertex shader outputs and fragment shader inputs for particles
+typedef struct
+{
+    float4 position [[position]];
+    float4 color;
+    float pointSize [[point_size]];
+} ParticleRasterizerData;
+
+// Draws each particle as a point, fading out as it gets older.
+vertex ParticleRasterizerData
+particleVertexShader(uint vertexID [[vertex_id]],
+                     const device AAPLParticle *particles [[buffer(AAPLVertexInputIndexVertices)]],
+                     constant vector_uint2 *viewportSizePointer [[buffer(AAPLVertexInputIndexViewportSize)]],
+                     constant AAPLParticleUniforms *uniforms [[buffer(AAPLVertexInputIndexParticleUniforms)]])
+{
+    ParticleRasterizerData out;
+
+    AAPLParticle particle = particles[vertexID];
+    vector_float2 viewportSize = vector_float2(*viewportSizePointer);
+
+    out.position = vector_float4(0.0, 0.0, 0.0, 1.0);
+    out.position.xy = particle.position / (viewportSize / 2.0);
+
+    // Particles that haven't been born yet have no lifetime, and are drawn fully faded.
+    float life = particle.lifetime > 0.0 ? saturate(particle.age / particle.lifetime) : 1.0;
+    out.color = particle.color;
+    out.color.a *= 1.0 - life;
+    out.pointSize = uniforms->pointSize;
+
+    return out;
+}
+
+// Rounds off the corners of the particle's point.
+fragment float4 particleFragmentShader(ParticleRasterizerData in [[stage_in]],
+                                       float2 pointCoord [[point_coord]])
+{
+    if (length(pointCoord - 0.5) > 0.5)
+    {
+        discard_fragment();
+    }
+    return in.color;
+}
+
+// Vertex shader outputs and fragment shader inputs for full-screen post-processing passes
+typedef struct
+{
+    float4 position [[position]];
+} PostProcessRasterizerData;
+
+// One triangle that covers the whole view: vertices 0, 1 and 2 are at
+// (-1, -1), (3, -1) and (-1, 3) in clip space.
+vertex PostProcessRasterizerData
+postProcessVertexShader(uint vertexID [[vertex_id]])
+{
+    PostProcessRasterizerData out;
+
+    float2 position = float2((vertexID << 1) & 2, vertexID & 2);
+    out.position = float4(position * 2.0 - 1.0, 0.0, 1.0);
+
+    return out;
+}
+
+// The texel under a fragment, or the nearest one inside the texture (like clamp-to-edge addressing).
+static float4 readClamped(texture2d<float> source, int2 pixel)
+{
+    int2 lastPixel = int2(source.get_width(), source.get_height()) - 1;
+    return source.read(uint2(clamp(pixel, int2(0), lastPixel)));
+}
+
+// One direction of a separable gaussian blur.
+fragment float4 gaussianBlurFragmentShader(PostProcessRasterizerData in [[stage_in]],
+                                           texture2d<float> source [[texture(AAPLTextureIndexPostProcessSource)]],
+                                           constant AAPLBlurUniforms &uniforms [[buffer(AAPLFragmentInputIndexPostProcessUniforms)]])
+{
+    int2 pixel = int2(in.position.xy);
+    float sigma = max(uniforms.sigma, 1e-3);
+    int radius = int(uniforms.radius);
+    float4 sum = 0.0;
+    float totalWeight = 0.0;
+    for (int offset = -radius; offset <= radius; offset++)
+    {
+        float weight = exp(-float(offset * offset) / (2.0 * sigma * sigma));
+        sum += readClamped(source, pixel + uniforms.direction * offset) * weight;
+        totalWeight += weight;
+    }
+    return sum / totalWeight;
+}
+
+// Keeps the parts of the image brighter than the threshold, with a soft knee so highlights fade in.
+fragment float4 brightPassFragmentShader(PostProcessRasterizerData in [[stage_in]],
+                                         texture2d<float> source [[texture(AAPLTextureIndexPostProcessSource)]],
+                                         constant AAPLBloomUniforms &uniforms [[buffer(AAPLFragmentInputIndexPostProcessUniforms)]])
+{
+    float4 color = source.read(uint2(in.position.xy));
+    float brightness = max(color.r, max(color.g, color.b));
+    float soft = clamp(brightness - uniforms.threshold + uniforms.knee, 0.0, 2.0 * uniforms.knee);
+    soft = soft * soft / (4.0 * uniforms.knee + 1e-5);
+    float contribution = max(soft, brightness - uniforms.threshold) / max(brightness, 1e-5);
+    return float4(color.rgb * contribution, color.a);
+}
+
+// Adds the blurred highlights back onto the image.
+fragment float4 bloomCompositeFragmentShader(PostProcessRasterizerData in [[stage_in]],
+                                             texture2d<float> source [[texture(AAPLTextureIndexPostProcessSource)]],
+                                             texture2d<float> bloom [[texture(AAPLTextureIndexBloom)]],
+                                             constant AAPLBloomUniforms &uniforms [[buffer(AAPLFragmentInputIndexPostProcessUniforms)]])
+{
+    uint2 pixel = uint2(in.position.xy);
+    float4 color = source.read(pixel);
+    return float4(color.rgb + bloom.read(pixel).rgb * uniforms.intensity, color.a);
+}
+
+// Looks a color up in a color lookup table, interpolating between its entries.
+// The table is stored as its blue slices side by side: red across each slice and green down it.
+static float3 lookUpColor(texture2d<float> lut, uint size, float3 color)
+{
+    float3 scaled = saturate(color) * float(size - 1);
+    uint3 lower = uint3(floor(scaled));
+    uint3 upper = min(lower + 1, uint3(size - 1));
+    float3 fraction = scaled - float3(lower);
+    float3 slices[2];
+    for (uint slice = 0; slice < 2; slice++)
+    {
+        uint blue = slice == 0 ? lower.b : upper.b;
+        float3 c00 = lut.read(uint2(lower.r + blue * size, lower.g)).rgb;
+        float3 c10 = lut.read(uint2(upper.r + blue * size, lower.g)).rgb;
+        float3 c01 = lut.read(uint2(lower.r + blue * size, upper.g)).rgb;
+        float3 c11 = lut.read(uint2(upper.r + blue * size, upper.g)).rgb;
+        slices[slice] = mix(mix(c00, c10, fraction.r), mix(c01, c11, fraction.r), fraction.g);
+    }
+    return mix(slices[0], slices[1], fraction.b);
+}
+
+// Moves each color towards the color lookup table's.
+fragment float4 colorGradeFragmentShader(PostProcessRasterizerData in [[stage_in]],
+                                         texture2d<float> source [[texture(AAPLTextureIndexPostProcessSource)]],
+                                         texture2d<float> lut [[texture(AAPLTextureIndexColorLut)]],
+                                         constant AAPLColorGradeUniforms &uniforms [[buffer(AAPLFragmentInputIndexPostProcessUniforms)]])
+{
+    float4 color = source.read(uint2(in.position.xy));
+    float3 graded = lookUpColor(lut, uniforms.lutSize, color.rgb);
+    return float4(mix(color.rgb, graded, uniforms.amount), color.a);
+}
+
+// Darkens the edges of the view.
+fragment float4 vignetteFragmentShader(PostProcessRasterizerData in [[stage_in]],
+                                       texture2d<float> source [[texture(AAPLTextureIndexPostProcessSource)]],
+                                       constant AAPLVignetteUniforms &uniforms [[buffer(AAPLFragmentInputIndexPostProcessUniforms)]])
+{
+    float4 color = source.read(uint2(in.position.xy));
+    float2 size = float2(source.get_width(), source.get_height());
+    // From the centre, in half-heights of the view.
+    float2 offset = (in.position.xy / size - 0.5) * float2(size.x / size.y, 1.0) * 2.0;
+    float darkening = uniforms.intensity * smoothstep(uniforms.radius, uniforms.radius + uniforms.softness, length(offset));
+    return float4(color.rgb * (1.0 - darkening), color.a);
+}
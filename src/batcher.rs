@@ -0,0 +1,105 @@
+//! An immediate-mode 2D batcher
+//!
+//! Overlays like the debug HUD are described afresh every frame as rectangles, lines and text.
+//! `Batcher2D` collects them into two meshes, one of shapes and one of text,
+//! so however much there is it takes two draws: the shapes, then the text on top.
+//! Positions are in pixels from the top-left corner of the viewport, with y down,
+//! which is how overlays are usually laid out.
+
+use crate::font::Font;
+use crate::glyph_atlas::GlyphAtlas;
+use crate::mesh::Mesh;
+use crate::shader_types::{AAPLVertex, AAPLTexturedVertex};
+use crate::text::{layout_text, append_text_quads, triangle_mesh, LayoutOptions};
+
+/// What a batcher collected, ready to draw.
+pub struct Batch2D {
+    /// Drawn with `fragmentShader` and alpha blending.
+    pub shapes: Option<Mesh<AAPLVertex>>,
+    /// Drawn over the shapes with `textFragmentShader`, from the batcher's glyph atlas.
+    pub text: Option<Mesh<AAPLTexturedVertex>>,
+}
+
+pub struct Batcher2D<'a> {
+    font: &'a Font,
+    atlas: &'a GlyphAtlas,
+    viewport_size: [f32; 2],
+    shape_vertices: Vec<AAPLVertex>,
+    shape_indices: Vec<u32>,
+    text_vertices: Vec<AAPLTexturedVertex>,
+    text_indices: Vec<u32>,
+}
+
+impl<'a> Batcher2D<'a> {
+    /// A batcher for a viewport of the given size, whose text comes from an atlas of the font.
+    pub fn new(font: &'a Font, atlas: &'a GlyphAtlas, viewport_size: [f32; 2]) -> Self {
+        Batcher2D {
+            font,
+            atlas,
+            viewport_size,
+            shape_vertices: Vec::new(),
+            shape_indices: Vec::new(),
+            text_vertices: Vec::new(),
+            text_indices: Vec::new(),
+        }
+    }
+
+    /// From the top-left corner with y down, to the centre of the view with y up.
+    fn to_view(&self, position: [f32; 2]) -> [f32; 2] {
+        [position[0] - self.viewport_size[0] / 2., self.viewport_size[1] / 2. - position[1]]
+    }
+
+    fn quad(&mut self, corners: [[f32; 2]; 4], color: [f32; 4]) {
+        let first = self.shape_vertices.len() as u32;
+        for &corner in &corners {
+            let position = self.to_view(corner);
+            self.shape_vertices.push(AAPLVertex::new(position, color));
+        }
+        self.shape_indices.extend([0, 1, 2, 0, 2, 3].iter().map(|index| first + index));
+    }
+
+    /// Fills a rectangle given its top-left corner and size.
+    pub fn rect(&mut self, position: [f32; 2], size: [f32; 2], color: [f32; 4]) {
+        let [left, top] = position;
+        let (right, bottom) = (left + size[0], top + size[1]);
+        self.quad([[left, top], [right, top], [right, bottom], [left, bottom]], color);
+    }
+
+    /// Draws a line of the given width, with square ends at the two points.
+    pub fn line(&mut self, from: [f32; 2], to: [f32; 2], width: f32, color: [f32; 4]) {
+        let (dx, dy) = (to[0] - from[0], to[1] - from[1]);
+        let length = (dx * dx + dy * dy).sqrt();
+        if length == 0. {
+            return;
+        }
+        // Half the width, at right angles to the line.
+        let (nx, ny) = (-dy / length * width / 2., dx / length * width / 2.);
+        self.quad([
+            [from[0] + nx, from[1] + ny],
+            [to[0] + nx, to[1] + ny],
+            [to[0] - nx, to[1] - ny],
+            [from[0] - nx, from[1] - ny],
+        ], color);
+    }
+
+    /// Draws text with its top-left corner at the given position, and returns its size.
+    pub fn text(&mut self, position: [f32; 2], text: &str, color: [f32; 4]) -> [f32; 2] {
+        let layout = layout_text(self.font, text, &LayoutOptions::default());
+        let origin = self.to_view(position);
+        append_text_quads(self.atlas, &layout, origin, color, &mut self.text_vertices, &mut self.text_indices);
+        [layout.width, layout.height]
+    }
+
+    /// How big the text would be, without drawing it.
+    pub fn text_size(&self, text: &str) -> [f32; 2] {
+        let layout = layout_text(self.font, text, &LayoutOptions::default());
+        [layout.width, layout.height]
+    }
+
+    pub fn finish(self) -> Batch2D {
+        Batch2D {
+            shapes: triangle_mesh(self.shape_vertices, self.shape_indices),
+            text: triangle_mesh(self.text_vertices, self.text_indices),
+        }
+    }
+}
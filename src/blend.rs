@@ -0,0 +1,277 @@
+//! How a fragment's color is combined with what's already been drawn
+//!
+//! Each `BlendMode` preset is described by a `BlendState`,
+//! in the same terms as `MTLRenderPipelineColorAttachmentDescriptor`:
+//!
+//! ```text
+//! rgb   = operation(source.rgb * source_rgb_factor, destination.rgb * destination_rgb_factor)
+//! alpha = operation(source.a * source_alpha_factor, destination.a * destination_alpha_factor)
+//! ```
+//!
+//! The pipeline cache hands the state to Metal,
+//! and the software rasterizer works it out itself with `BlendState::apply`,
+//! so the two agree.
+
+/// A blend preset, chosen per node.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub enum BlendMode {
+    /// The fragment replaces what's there.
+    #[default]
+    Opaque,
+    /// Classic transparency, for colors that aren't premultiplied by their alpha.
+    Alpha,
+    /// Transparency for colors that are already premultiplied by their alpha.
+    Premultiplied,
+    /// Adds the fragment's color (scaled by its alpha) to what's there, for glows.
+    Additive,
+    /// Darkens what's there by the fragment's color, where the fragment is opaque.
+    Multiply,
+}
+
+impl BlendMode {
+    pub fn blend_state(self) -> BlendState {
+        use BlendFactor::*;
+        let (source_rgb_factor, destination_rgb_factor, source_alpha_factor, destination_alpha_factor) = match self {
+            BlendMode::Opaque => return BlendState::disabled(),
+            BlendMode::Alpha => (SourceAlpha, OneMinusSourceAlpha, One, OneMinusSourceAlpha),
+            BlendMode::Premultiplied => (One, OneMinusSourceAlpha, One, OneMinusSourceAlpha),
+            BlendMode::Additive => (SourceAlpha, One, One, One),
+            BlendMode::Multiply => (DestinationColor, OneMinusSourceAlpha, DestinationAlpha, OneMinusSourceAlpha),
+        };
+        BlendState {
+            enabled: true,
+            rgb_operation: BlendOperation::Add,
+            alpha_operation: BlendOperation::Add,
+            source_rgb_factor,
+            destination_rgb_factor,
+            source_alpha_factor,
+            destination_alpha_factor,
+        }
+    }
+
+    /// The name used in scene files.
+    pub fn name(self) -> &'static str {
+        match self {
+            BlendMode::Opaque => "opaque",
+            BlendMode::Alpha => "alpha",
+            BlendMode::Premultiplied => "premultiplied",
+            BlendMode::Additive => "additive",
+            BlendMode::Multiply => "multiply",
+        }
+    }
+
+    /// The blend mode with the given scene file name.
+    pub fn from_name(name: &str) -> Option<Self> {
+        [BlendMode::Opaque, BlendMode::Alpha, BlendMode::Premultiplied, BlendMode::Additive, BlendMode::Multiply]
+            .iter()
+            .copied()
+            .find(|mode| mode.name() == name)
+    }
+}
+
+/// What a color is multiplied by before the two are combined.
+///
+/// These match `MTLBlendFactor`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum BlendFactor {
+    Zero,
+    One,
+    SourceColor,
+    OneMinusSourceColor,
+    SourceAlpha,
+    OneMinusSourceAlpha,
+    DestinationColor,
+    OneMinusDestinationColor,
+    DestinationAlpha,
+    OneMinusDestinationAlpha,
+}
+
+/// How the two scaled colors are combined.
+///
+/// These match `MTLBlendOperation`.
+/// `Min` and `Max` ignore the factors, as Metal does.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum BlendOperation {
+    Add,
+    Subtract,
+    ReverseSubtract,
+    Min,
+    Max,
+}
+
+/// Everything Metal needs to know to blend into a color attachment.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct BlendState {
+    pub enabled: bool,
+    pub rgb_operation: BlendOperation,
+    pub alpha_operation: BlendOperation,
+    pub source_rgb_factor: BlendFactor,
+    pub destination_rgb_factor: BlendFactor,
+    pub source_alpha_factor: BlendFactor,
+    pub destination_alpha_factor: BlendFactor,
+}
+
+impl BlendState {
+    /// No blending: the source replaces the destination.
+    pub fn disabled() -> Self {
+        BlendState {
+            enabled: false,
+            rgb_operation: BlendOperation::Add,
+            alpha_operation: BlendOperation::Add,
+            source_rgb_factor: BlendFactor::One,
+            destination_rgb_factor: BlendFactor::Zero,
+            source_alpha_factor: BlendFactor::One,
+            destination_alpha_factor: BlendFactor::Zero,
+        }
+    }
+
+    /// Blends a source color into a destination color, as the GPU would.
+    ///
+    /// The result isn't clamped; that's up to whatever stores it.
+    pub fn apply(&self, source: [f32; 4], destination: [f32; 4]) -> [f32; 4] {
+        if !self.enabled {
+            return source;
+        }
+        let mut result = [0.; 4];
+        for channel in 0..3 {
+            let source_factor = factor(self.source_rgb_factor, channel, source, destination);
+            let destination_factor = factor(self.destination_rgb_factor, channel, source, destination);
+            result[channel] = combine(self.rgb_operation, source[channel] * source_factor, destination[channel] * destination_factor, source[channel], destination[channel]);
+        }
+        let source_factor = factor(self.source_alpha_factor, 3, source, destination);
+        let destination_factor = factor(self.destination_alpha_factor, 3, source, destination);
+        result[3] = combine(self.alpha_operation, source[3] * source_factor, destination[3] * destination_factor, source[3], destination[3]);
+        result
+    }
+}
+
+/// The value of a blend factor for one channel.
+fn factor(factor: BlendFactor, channel: usize, source: [f32; 4], destination: [f32; 4]) -> f32 {
+    match factor {
+        BlendFactor::Zero => 0.,
+        BlendFactor::One => 1.,
+        BlendFactor::SourceColor => source[channel],
+        BlendFactor::OneMinusSourceColor => 1. - source[channel],
+        BlendFactor::SourceAlpha => source[3],
+        BlendFactor::OneMinusSourceAlpha => 1. - source[3],
+        BlendFactor::DestinationColor => destination[channel],
+        BlendFactor::OneMinusDestinationColor => 1. - destination[channel],
+        BlendFactor::DestinationAlpha => destination[3],
+        BlendFactor::OneMinusDestinationAlpha => 1. - destination[3],
+    }
+}
+
+fn combine(operation: BlendOperation, scaled_source: f32, scaled_destination: f32, source: f32, destination: f32) -> f32 {
+    match operation {
+        BlendOperation::Add => scaled_source + scaled_destination,
+        BlendOperation::Subtract => scaled_source - scaled_destination,
+        BlendOperation::ReverseSubtract => scaled_destination - scaled_source,
+        BlendOperation::Min => source.min(destination),
+        BlendOperation::Max => source.max(destination),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: [f32; 4] = [0.8, 0.4, 0.2, 0.5];
+    const DESTINATION: [f32; 4] = [0.2, 0.6, 1.0, 0.25];
+
+    fn assert_close(actual: [f32; 4], expected: [f32; 4]) {
+        assert!(actual.iter().zip(expected.iter()).all(|(a, e)| (a - e).abs() < 1e-6), "{:?} isn't {:?}", actual, expected);
+    }
+
+    /// Each channel of `source` and `destination` through `blend`.
+    fn per_channel(blend: impl Fn(f32, f32) -> f32, alpha_blend: impl Fn(f32, f32) -> f32) -> [f32; 4] {
+        [
+            blend(SOURCE[0], DESTINATION[0]),
+            blend(SOURCE[1], DESTINATION[1]),
+            blend(SOURCE[2], DESTINATION[2]),
+            alpha_blend(SOURCE[3], DESTINATION[3]),
+        ]
+    }
+
+    #[test]
+    fn opaque_replaces_the_destination() {
+        let state = BlendMode::Opaque.blend_state();
+        assert!(!state.enabled);
+        assert_eq!(state, BlendState::disabled());
+        assert_eq!(state.apply(SOURCE, DESTINATION), SOURCE);
+    }
+
+    #[test]
+    fn alpha_mixes_by_the_source_alpha() {
+        let alpha = SOURCE[3];
+        assert_close(BlendMode::Alpha.blend_state().apply(SOURCE, DESTINATION), per_channel(
+            |source, destination| source * alpha + destination * (1. - alpha),
+            |source, destination| source + destination * (1. - alpha),
+        ));
+    }
+
+    #[test]
+    fn premultiplied_adds_the_source_over_what_it_leaves() {
+        let alpha = SOURCE[3];
+        assert_close(BlendMode::Premultiplied.blend_state().apply(SOURCE, DESTINATION), per_channel(
+            |source, destination| source + destination * (1. - alpha),
+            |source, destination| source + destination * (1. - alpha),
+        ));
+        // A premultiplied color at full alpha is just opaque.
+        let opaque_source = [0.3, 0.2, 0.1, 1.];
+        assert_close(BlendMode::Premultiplied.blend_state().apply(opaque_source, DESTINATION), opaque_source);
+    }
+
+    #[test]
+    fn additive_adds_the_source_scaled_by_its_alpha() {
+        let alpha = SOURCE[3];
+        assert_close(BlendMode::Additive.blend_state().apply(SOURCE, DESTINATION), per_channel(
+            |source, destination| source * alpha + destination,
+            |source, destination| source + destination,
+        ));
+        // Unclamped: it's up to whatever stores the color.
+        assert_eq!(BlendMode::Additive.blend_state().apply([1., 1., 1., 1.], [1., 1., 1., 1.]), [2., 2., 2., 2.]);
+    }
+
+    #[test]
+    fn multiply_darkens_where_the_source_is_opaque() {
+        let alpha = SOURCE[3];
+        assert_close(BlendMode::Multiply.blend_state().apply(SOURCE, DESTINATION), per_channel(
+            |source, destination| source * destination + destination * (1. - alpha),
+            |source, destination| source * destination + destination * (1. - alpha),
+        ));
+        // Nothing at all leaves the destination alone, and so does white at full alpha.
+        assert_close(BlendMode::Multiply.blend_state().apply([0., 0., 0., 0.], DESTINATION), DESTINATION);
+        assert_close(BlendMode::Multiply.blend_state().apply([1., 1., 1., 1.], DESTINATION), DESTINATION);
+    }
+
+    #[test]
+    fn operations_combine_the_scaled_colors() {
+        let state = |operation| BlendState {
+            rgb_operation: operation,
+            alpha_operation: operation,
+            source_rgb_factor: BlendFactor::SourceAlpha,
+            destination_rgb_factor: BlendFactor::One,
+            ..BlendMode::Additive.blend_state()
+        };
+        let alpha = SOURCE[3];
+        assert_close(state(BlendOperation::Subtract).apply(SOURCE, DESTINATION), per_channel(
+            |source, destination| source * alpha - destination,
+            |source, destination| source - destination,
+        ));
+        assert_close(state(BlendOperation::ReverseSubtract).apply(SOURCE, DESTINATION), per_channel(
+            |source, destination| destination - source * alpha,
+            |source, destination| destination - source,
+        ));
+        // Min and max ignore the factors.
+        assert_close(state(BlendOperation::Min).apply(SOURCE, DESTINATION), per_channel(f32::min, f32::min));
+        assert_close(state(BlendOperation::Max).apply(SOURCE, DESTINATION), per_channel(f32::max, f32::max));
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for mode in &[BlendMode::Opaque, BlendMode::Alpha, BlendMode::Premultiplied, BlendMode::Additive, BlendMode::Multiply] {
+            assert_eq!(BlendMode::from_name(mode.name()), Some(*mode));
+        }
+        assert_eq!(BlendMode::from_name("screen"), None);
+    }
+}
@@ -0,0 +1,214 @@
+//! Book-keeping for a ring of per-frame buffers
+//!
+//! The CPU writes this frame's vertex and uniform data into one buffer
+//! while the GPU may still be reading earlier frames from the others.
+//! A semaphore (owned by the renderer) stops the CPU getting more than
+//! `slot_count` frames ahead, so by the time we come back round to a slot
+//! the GPU has finished with it.
+//!
+//! This module only decides which slot to use and where in it each piece of data goes;
+//! the renderer owns the actual `MTLBuffer`s.
+
+/// The number of frames we let the CPU get ahead of the GPU unless told otherwise.
+pub const DEFAULT_FRAMES_IN_FLIGHT: usize = 3;
+/// Metal wants buffer offsets for `constant` data aligned to 256 bytes on macOS.
+pub const BUFFER_OFFSET_ALIGNMENT: usize = 256;
+/// The size we give each slot's buffer before we know what we need.
+pub const DEFAULT_SLOT_CAPACITY: usize = 64 * 1024;
+
+/// The slot to use for the current frame.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct FrameSlot {
+    /// Which buffer in the ring to write to.
+    pub index: usize,
+    /// If set, the slot's buffer is too small and must be
+    /// replaced with one of this many bytes before writing to it.
+    pub grow_to: Option<usize>,
+}
+
+/// Where a piece of this frame's data goes.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RingAllocation {
+    /// Which buffer in the ring.
+    pub slot: usize,
+    /// The offset into that buffer, in bytes.
+    pub offset: usize,
+    /// The length of the data, in bytes.
+    pub length: usize,
+}
+
+/// Hands out space in a ring of buffers, one buffer per frame in flight.
+pub struct BufferRing {
+    slot_capacities: Vec<usize>,
+    current_slot: Option<usize>,
+    offset: usize,
+    frame_count: u64,
+}
+
+impl BufferRing {
+    /// Creates a ring with `slot_count` slots of `initial_capacity` bytes each.
+    pub fn new(slot_count: usize, initial_capacity: usize) -> Self {
+        BufferRing {
+            slot_capacities: vec![initial_capacity; usize::max(1, slot_count)],
+            current_slot: None,
+            offset: 0,
+            frame_count: 0,
+        }
+    }
+
+    /// The number of slots (and so the number of frames that can be in flight).
+    pub fn slot_count(&self) -> usize {
+        self.slot_capacities.len()
+    }
+
+    /// The size of the given slot's buffer, in bytes.
+    pub fn slot_capacity(&self, slot: usize) -> usize {
+        self.slot_capacities[slot]
+    }
+
+    /// Moves on to the next slot, for a frame that needs at most `required_bytes`.
+    ///
+    /// Only call this once the semaphore says the slot is free.
+    pub fn begin_frame(&mut self, required_bytes: usize) -> FrameSlot {
+        let index = (self.frame_count % self.slot_count() as u64) as usize;
+        self.frame_count += 1;
+        self.current_slot = Some(index);
+        self.offset = 0;
+
+        let grow_to = if required_bytes > self.slot_capacities[index] {
+            let new_capacity = required_bytes.next_power_of_two();
+            self.slot_capacities[index] = new_capacity;
+            Some(new_capacity)
+        } else {
+            None
+        };
+        FrameSlot { index, grow_to }
+    }
+
+    /// Finds room in the current slot for `length` bytes
+    /// starting on a multiple of `alignment`.
+    ///
+    /// Returns `None` if there's no current frame,
+    /// or if we asked for more than we said we needed in `begin_frame`.
+    pub fn allocate(&mut self, length: usize, alignment: usize) -> Option<RingAllocation> {
+        let slot = self.current_slot?;
+        let offset = align_up(self.offset, alignment);
+        if offset + length > self.slot_capacities[slot] {
+            return None;
+        }
+        self.offset = offset + length;
+        Some(RingAllocation { slot, offset, length })
+    }
+
+    /// How many bytes a frame needs for the given allocations,
+    /// allowing for the padding `allocate` will add whatever order they're made in.
+    pub fn required_bytes(lengths: &[usize], alignment: usize) -> usize {
+        lengths.iter().map(|&length| align_up(length, alignment)).sum()
+    }
+}
+
+/// Rounds `value` up to the next multiple of `alignment`.
+pub fn align_up(value: usize, alignment: usize) -> usize {
+    if alignment <= 1 {
+        value
+    } else {
+        value.div_ceil(alignment) * alignment
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_take_the_slots_in_turn() {
+        let mut ring = BufferRing::new(3, 1024);
+        let slots: Vec<usize> = (0..7).map(|_| ring.begin_frame(0).index).collect();
+        assert_eq!(slots, vec![0, 1, 2, 0, 1, 2, 0]);
+
+        // There's always at least one slot.
+        let mut ring = BufferRing::new(0, 1024);
+        assert_eq!(ring.slot_count(), 1);
+        assert_eq!(ring.begin_frame(0).index, 0);
+        assert_eq!(ring.begin_frame(0).index, 0);
+    }
+
+    #[test]
+    fn allocations_are_aligned_and_start_again_each_frame() {
+        let mut ring = BufferRing::new(2, 1024);
+        ring.begin_frame(0);
+        assert_eq!(ring.allocate(8, 256), Some(RingAllocation { slot: 0, offset: 0, length: 8 }));
+        assert_eq!(ring.allocate(300, 256), Some(RingAllocation { slot: 0, offset: 256, length: 300 }));
+        assert_eq!(ring.allocate(4, 4), Some(RingAllocation { slot: 0, offset: 556, length: 4 }));
+        assert_eq!(ring.allocate(1, 1), Some(RingAllocation { slot: 0, offset: 560, length: 1 }));
+
+        ring.begin_frame(0);
+        assert_eq!(ring.allocate(8, 256), Some(RingAllocation { slot: 1, offset: 0, length: 8 }));
+    }
+
+    #[test]
+    fn slots_grow_to_the_next_power_of_two() {
+        let mut ring = BufferRing::new(2, 1024);
+        assert_eq!(ring.begin_frame(1024), FrameSlot { index: 0, grow_to: None });
+        assert_eq!(ring.begin_frame(1025), FrameSlot { index: 1, grow_to: Some(2048) });
+        assert_eq!(ring.slot_capacity(0), 1024);
+        assert_eq!(ring.slot_capacity(1), 2048);
+        // Each slot grows on its own, and never shrinks.
+        assert_eq!(ring.begin_frame(5000), FrameSlot { index: 0, grow_to: Some(8192) });
+        assert_eq!(ring.begin_frame(10), FrameSlot { index: 1, grow_to: None });
+        assert_eq!(ring.slot_capacity(1), 2048);
+    }
+
+    #[test]
+    fn allocate_fails_past_the_slot_capacity() {
+        let mut ring = BufferRing::new(1, 1024);
+        assert_eq!(ring.allocate(8, 256), None, "no frame has begun");
+
+        ring.begin_frame(0);
+        assert!(ring.allocate(1000, 256).is_some());
+        // 24 bytes are left, but the next aligned offset is past the end.
+        assert_eq!(ring.allocate(1, 256), None);
+        assert!(ring.allocate(24, 4).is_some());
+        assert_eq!(ring.allocate(1, 1), None);
+    }
+
+    #[test]
+    fn required_bytes_covers_any_order() {
+        let alignment = 256;
+        let lengths = [8, 300, 0, 1, 256, 64, 513];
+        let required_bytes = BufferRing::required_bytes(&lengths, alignment);
+
+        // Every permutation, by Heap's algorithm, must fit in a slot of exactly that size.
+        fn permutations(lengths: &mut Vec<usize>, k: usize, visit: &mut dyn FnMut(&[usize])) {
+            if k <= 1 {
+                visit(lengths);
+                return;
+            }
+            for i in 0..k {
+                permutations(lengths, k - 1, visit);
+                let swap_with = if k.is_multiple_of(2) { i } else { 0 };
+                lengths.swap(swap_with, k - 1);
+            }
+        }
+        let mut permutation_count = 0;
+        permutations(&mut lengths.to_vec(), lengths.len(), &mut |order| {
+            let mut ring = BufferRing::new(1, required_bytes);
+            ring.begin_frame(required_bytes);
+            for &length in order {
+                assert!(ring.allocate(length, alignment).is_some(), "{:?} doesn't fit in {} bytes", order, required_bytes);
+            }
+            permutation_count += 1;
+        });
+        assert_eq!(permutation_count, 5040);
+    }
+
+    #[test]
+    fn align_up_rounds_to_multiples() {
+        assert_eq!(align_up(0, 256), 0);
+        assert_eq!(align_up(1, 256), 256);
+        assert_eq!(align_up(256, 256), 256);
+        assert_eq!(align_up(257, 256), 512);
+        assert_eq!(align_up(7, 1), 7);
+        assert_eq!(align_up(7, 0), 7);
+    }
+}
@@ -0,0 +1,879 @@
+//! Recording the render commands that draw each frame, and replaying them
+//!
+//! A trace is every render pass the renderer encodes, in order: where each pass starts and ends,
+//! the pipelines it binds, its viewport, the bytes it puts in each vertex buffer, its draws,
+//! and where each frame is presented. It's saved as JSON, with the bytes in hex,
+//! so a frame that went wrong on someone's Mac can be replayed anywhere, through any `ReplayBackend`.
+//!
+//! `SoftwareReplayer` replays traces with the software rasterizer, so they can be compared
+//! frame by frame with what the GPU drew. It draws the scene's meshes, instanced meshes and the HUD's shapes.
+//! Textures, particle buffers and post-processing uniforms aren't in the trace,
+//! so it skips the draws that need them, and shows the scene as it was before post-processing.
+//! Compute passes aren't traced.
+
+use std::fmt::{Display, Formatter, Write};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::os::raw::c_uint;
+use crate::json::{self, Position, Spanned, Value, ParseError};
+use crate::mesh::{Mesh, IndexData, DrawRange, PrimitiveTopology};
+use crate::blend::BlendMode;
+use crate::transform::Matrix3;
+use crate::shader_types::{AAPLVertex, AAPLObjectUniforms, AAPLInstance,
+                          AAPLVertexInputIndexVertices, AAPLVertexInputIndexObjectUniforms, AAPLVertexInputIndexInstances};
+use crate::image::{Image, decode_png};
+use crate::software_rasterizer::{Framebuffer, draw_mesh, draw_instanced_mesh};
+use crate::screenshot::save_png;
+use crate::recording::png_sequence_file_name;
+use crate::error_chain::format_error_chain;
+
+/// The version of the trace format we write, and the only one we read.
+pub static TRACE_FORMAT_VERSION: u32 = 2;
+/// How many frames to trace, unless HELLO_TRIANGLE_TRACE_FRAMES says otherwise.
+pub static DEFAULT_TRACE_FRAME_COUNT: usize = 60;
+/// What the `format` member of a trace file says, so it can't be mistaken for a scene file.
+static TRACE_FORMAT_NAME: &str = "hello_triangle command trace";
+/// The widest and tallest attachment a trace can describe, which is as big as Metal's textures get.
+static MAX_ATTACHMENT_SIZE: usize = 16384;
+
+// The shader functions whose draws the software rasterizer can replay, as the renderer names them.
+static SCENE_VERTEX_FUNCTION: &str = "sceneVertexShader";
+static INSTANCED_VERTEX_FUNCTION: &str = "instancedVertexShader";
+static VERTEX_COLOR_FRAGMENT_FUNCTION: &str = "fragmentShader";
+
+/// One render command.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TraceCommand {
+    /// A render command encoder starts, drawing into an attachment of the given size,
+    /// cleared first if there's a clear color.
+    BeginPass { name: String, size: [usize; 2], sample_count: usize, clear_color: Option<[f64; 4]> },
+    EndPass,
+    BindPipeline { vertex_function: String, fragment_function: String, blend_mode: BlendMode },
+    SetViewport { size: [f64; 2] },
+    /// The contents of the buffer bound at a vertex buffer index.
+    SetVertexBytes { index: c_uint, bytes: Vec<u8> },
+    Draw { topology: PrimitiveTopology, vertex_start: usize, vertex_count: usize, instance_count: usize },
+    /// An indexed draw, with just the indices it draws.
+    DrawIndexed { topology: PrimitiveTopology, indices: IndexData, instance_count: usize },
+    /// The frame is finished.
+    Present,
+}
+
+impl TraceCommand {
+    /// The draw `encode_draw` makes for a mesh.
+    pub fn mesh_draw<V>(mesh: &Mesh<V>, instance_count: usize) -> Self {
+        let draw_range = mesh.draw_range();
+        let range = draw_range.start..draw_range.start + draw_range.count;
+        match mesh.indices() {
+            Some(IndexData::U16(indices)) =>
+                TraceCommand::DrawIndexed { topology: mesh.topology(), indices: IndexData::U16(indices[range].to_vec()), instance_count },
+            Some(IndexData::U32(indices)) =>
+                TraceCommand::DrawIndexed { topology: mesh.topology(), indices: IndexData::U32(indices[range].to_vec()), instance_count },
+            None => TraceCommand::Draw { topology: mesh.topology(), vertex_start: draw_range.start, vertex_count: draw_range.count, instance_count },
+        }
+    }
+
+    /// Sets the contents of a vertex buffer to a copy of `data`.
+    pub fn vertex_bytes<T>(index: c_uint, data: &[T]) -> Self {
+        // Our shader types have no padding the compiler adds, so every byte is initialized.
+        let bytes = unsafe { std::slice::from_raw_parts(data.as_ptr() as *const u8, std::mem::size_of_val(data)) };
+        TraceCommand::SetVertexBytes { index, bytes: bytes.to_vec() }
+    }
+}
+
+/// The commands that drew some frames.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CommandTrace {
+    pub commands: Vec<TraceCommand>,
+}
+
+impl CommandTrace {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How many frames were presented.
+    pub fn frame_count(&self) -> usize {
+        self.commands.iter().filter(|command| **command == TraceCommand::Present).count()
+    }
+
+    pub fn load(path: &Path) -> Result<Self, TraceError> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|error| TraceError::Io(path.to_path_buf(), error))?;
+        Self::from_json(&text)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), TraceError> {
+        std::fs::write(path, self.to_json())
+            .map_err(|error| TraceError::Io(path.to_path_buf(), error))
+    }
+
+    /// Parses and checks a trace file's contents.
+    pub fn from_json(text: &str) -> Result<Self, TraceError> {
+        let document = json::parse(text).map_err(TraceError::Syntax)?;
+        match document.get("format") {
+            Some(format) if read_string(format)? == TRACE_FORMAT_NAME => {}
+            _ => return invalid(&document, "not a command trace".to_string()),
+        }
+        let version = match document.get("version") {
+            Some(version) => read_number(version)?,
+            None => return invalid(&document, "missing \"version\"".to_string()),
+        };
+        if version != f64::from(TRACE_FORMAT_VERSION) {
+            return invalid(document.get("version").unwrap(),
+                           format!("unsupported version {} (expected {})", version, TRACE_FORMAT_VERSION));
+        }
+        let commands = match document.get("commands") {
+            Some(Spanned { value: Value::Array(commands), .. }) => commands.iter().map(read_command).collect::<Result<_, _>>()?,
+            Some(commands) => return invalid(commands, format!("expected an array but found {}", commands.type_name())),
+            None => return invalid(&document, "missing \"commands\"".to_string()),
+        };
+        Ok(CommandTrace { commands })
+    }
+
+    /// Writes the trace as JSON that `from_json` reads back unchanged.
+    pub fn to_json(&self) -> String {
+        let document = object(vec![
+            ("format", string(TRACE_FORMAT_NAME)),
+            ("version", number(f64::from(TRACE_FORMAT_VERSION))),
+            ("commands", json::unspanned(Value::Array(self.commands.iter().map(write_command).collect()))),
+        ]);
+        let mut writer = json::Writer::new();
+        writer.write_value(&document.value);
+        writer.finish()
+    }
+}
+
+/// Builds up a trace of the frames the renderer draws, until it has as many as it was asked for.
+pub struct TraceRecorder {
+    output: PathBuf,
+    frame_count: usize,
+    frames_recorded: usize,
+    trace: CommandTrace,
+}
+
+impl TraceRecorder {
+    pub fn new(output: PathBuf, frame_count: usize) -> Self {
+        TraceRecorder { output, frame_count, frames_recorded: 0, trace: CommandTrace::new() }
+    }
+
+    /// The recorder the environment asks for, or `None` if we're not asked to trace.
+    ///
+    /// Set HELLO_TRIANGLE_TRACE to the file to save the trace in,
+    /// and HELLO_TRIANGLE_TRACE_FRAMES to how many frames to trace.
+    pub fn from_environment() -> Option<Self> {
+        let output = std::env::var_os("HELLO_TRIANGLE_TRACE")?;
+        let frame_count = match std::env::var("HELLO_TRIANGLE_TRACE_FRAMES") {
+            Ok(frame_count) => frame_count.parse().unwrap_or_else(|_| {
+                println!("HELLO_TRIANGLE_TRACE_FRAMES should be a number of frames, not {:?}", frame_count);
+                DEFAULT_TRACE_FRAME_COUNT
+            }),
+            Err(_) => DEFAULT_TRACE_FRAME_COUNT,
+        };
+        Some(Self::new(PathBuf::from(output), frame_count))
+    }
+
+    pub fn record(&mut self, command: TraceCommand) {
+        if command == TraceCommand::Present {
+            self.frames_recorded += 1;
+        }
+        self.trace.commands.push(command);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.frames_recorded >= self.frame_count
+    }
+
+    /// Saves the trace, and returns how many frames it has.
+    pub fn finish(self) -> Result<usize, TraceError> {
+        self.trace.save(&self.output)?;
+        Ok(self.frames_recorded)
+    }
+
+    pub fn output(&self) -> &Path {
+        &self.output
+    }
+}
+
+/// Something that can carry out a trace's commands.
+pub trait ReplayBackend {
+    fn execute(&mut self, command: &TraceCommand);
+}
+
+/// Feeds a trace's commands into a backend in order,
+/// stopping at the first that can't be where it is, such as a draw outside a pass.
+pub fn replay(trace: &CommandTrace, backend: &mut impl ReplayBackend) -> Result<(), TraceError> {
+    let mut in_pass = false;
+    for (index, command) in trace.commands.iter().enumerate() {
+        let problem = match command {
+            TraceCommand::BeginPass { .. } if in_pass => Some("a pass begins inside another"),
+            TraceCommand::Present if in_pass => Some("a frame is presented inside a pass"),
+            TraceCommand::BeginPass { .. } | TraceCommand::Present => None,
+            _ if !in_pass => Some("a command is outside a pass"),
+            _ => None,
+        };
+        if let Some(problem) = problem {
+            return Err(TraceError::OutOfOrder { index, message: problem.to_string() });
+        }
+        match command {
+            TraceCommand::BeginPass { .. } => in_pass = true,
+            TraceCommand::EndPass => in_pass = false,
+            _ => {}
+        }
+        backend.execute(command);
+    }
+    Ok(())
+}
+
+/// The pass being replayed.
+struct ReplayPass {
+    size: [usize; 2],
+    sample_count: usize,
+    /// Taken once it's been cleared, which waits until something's drawn,
+    /// so passes we can't draw don't wipe out what's there.
+    clear_color: Option<[f32; 4]>,
+    skipped_draws: bool,
+}
+
+/// Replays a trace with the software rasterizer, keeping an image of each frame presented.
+///
+/// Every pass draws into the same framebuffer, as if the scene, the post-processing passes
+/// and the HUD all drew into the drawable.
+#[derive(Default)]
+pub struct SoftwareReplayer {
+    framebuffer: Option<Framebuffer>,
+    pass: Option<ReplayPass>,
+    pipeline: Option<(String, String, BlendMode)>,
+    vertex_bytes: Vec<(c_uint, Vec<u8>)>,
+    frames: Vec<Image>,
+    /// How many draws it couldn't replay.
+    pub skipped_draws: usize,
+}
+
+impl SoftwareReplayer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// An image of each frame presented so far.
+    pub fn frames(&self) -> &[Image] {
+        &self.frames
+    }
+
+    fn bound_bytes(&self, index: c_uint) -> Option<&[u8]> {
+        self.vertex_bytes.iter().find(|(bound_index, _)| *bound_index == index).map(|(_, bytes)| bytes.as_slice())
+    }
+
+    /// The framebuffer for the current pass, cleared if the pass hasn't been yet.
+    fn pass_framebuffer(&mut self) -> &mut Framebuffer {
+        let pass = self.pass.as_mut().unwrap();
+        let clear_color = pass.clear_color.take();
+        let [width, height] = pass.size;
+        let is_new = match &self.framebuffer {
+            Some(framebuffer) => [framebuffer.width(), framebuffer.height()] != pass.size
+                || (clear_color.is_some() && framebuffer.sample_count() != pass.sample_count),
+            None => true,
+        };
+        if is_new {
+            let clear_color = clear_color.unwrap_or([0.; 4]);
+            self.framebuffer = Some(Framebuffer::new_multisample(width, height, pass.sample_count, clear_color)
+                .unwrap_or_else(|| Framebuffer::new(width, height, clear_color)));
+        } else {
+            let framebuffer = self.framebuffer.as_mut().unwrap();
+            // A single-sampled pass after a multisampled one draws over the resolved pixels.
+            if framebuffer.sample_count() > pass.sample_count {
+                *framebuffer = framebuffer.resolve();
+            }
+            if let Some(clear_color) = clear_color {
+                framebuffer.clear(clear_color);
+            }
+        }
+        self.framebuffer.as_mut().unwrap()
+    }
+
+    fn skip_draw(&mut self) {
+        self.skipped_draws += 1;
+        if let Some(pass) = self.pass.as_mut() {
+            pass.skipped_draws = true;
+        }
+    }
+
+    /// Draws with the bound pipeline and vertex bytes, if they're ones we can draw.
+    fn draw(&mut self, mesh: Mesh<AAPLVertex>, instance_count: usize) {
+        let (vertex_function, blend_mode) = match &self.pipeline {
+            Some((vertex_function, fragment_function, blend_mode)) if fragment_function == VERTEX_COLOR_FRAGMENT_FUNCTION =>
+                (vertex_function.clone(), *blend_mode),
+            _ => return self.skip_draw(),
+        };
+        let uniforms = self.bound_bytes(AAPLVertexInputIndexObjectUniforms)
+            .and_then(|bytes| read_structs::<AAPLObjectUniforms>(bytes).first().copied());
+        let instances = if vertex_function == INSTANCED_VERTEX_FUNCTION {
+            self.bound_bytes(AAPLVertexInputIndexInstances).map(read_structs::<AAPLInstance>)
+        } else if vertex_function == SCENE_VERTEX_FUNCTION {
+            None
+        } else {
+            return self.skip_draw();
+        };
+        let uniforms = match uniforms {
+            Some(uniforms) if mesh.validate().is_ok() => uniforms,
+            _ => return self.skip_draw(),
+        };
+        let transform = Matrix3 {
+            columns: [0, 1, 2].map(|column| {
+                let padded = uniforms.transform[column];
+                [padded[0], padded[1], padded[2]]
+            }),
+        };
+        let tint = [uniforms.tint.x(), uniforms.tint.y(), uniforms.tint.z(), uniforms.tint.w()];
+        let blend_state = blend_mode.blend_state();
+        let framebuffer = self.pass_framebuffer();
+        match instances {
+            Some(instances) => {
+                let instance_count = instance_count.min(instances.len());
+                draw_instanced_mesh(framebuffer, &mesh, &instances[..instance_count], &transform, tint, uniforms.depth, &blend_state);
+            }
+            // Without instance data, each instance is drawn the same.
+            None => for _ in 0..instance_count {
+                draw_mesh(framebuffer, &mesh, &transform, tint, uniforms.depth, &blend_state);
+            },
+        }
+    }
+
+    fn bound_vertices(&self) -> Vec<AAPLVertex> {
+        self.bound_bytes(AAPLVertexInputIndexVertices).map(read_structs).unwrap_or_default()
+    }
+}
+
+impl ReplayBackend for SoftwareReplayer {
+    fn execute(&mut self, command: &TraceCommand) {
+        match command {
+            TraceCommand::BeginPass { size, sample_count, clear_color, .. } => {
+                self.pass = Some(ReplayPass {
+                    size: *size,
+                    sample_count: *sample_count,
+                    clear_color: clear_color.map(|color| color.map(|channel| channel as f32)),
+                    skipped_draws: false,
+                });
+                // Nothing's bound at the start of an encoder.
+                self.pipeline = None;
+                self.vertex_bytes.clear();
+            }
+            TraceCommand::EndPass => {
+                // A pass that drew nothing still clears, unless we skipped what it drew.
+                if self.pass.as_ref().is_some_and(|pass| pass.clear_color.is_some() && !pass.skipped_draws) {
+                    self.pass_framebuffer();
+                }
+                self.pass = None;
+            }
+            TraceCommand::BindPipeline { vertex_function, fragment_function, blend_mode } =>
+                self.pipeline = Some((vertex_function.clone(), fragment_function.clone(), *blend_mode)),
+            // The software rasterizer always draws to the whole framebuffer.
+            TraceCommand::SetViewport { .. } => {}
+            TraceCommand::SetVertexBytes { index, bytes } => {
+                self.vertex_bytes.retain(|(bound_index, _)| bound_index != index);
+                self.vertex_bytes.push((*index, bytes.clone()));
+            }
+            TraceCommand::Draw { topology, vertex_start, vertex_count, instance_count } => {
+                let mesh = Mesh::new(self.bound_vertices(), *topology)
+                    .with_draw_range(DrawRange { start: *vertex_start, count: *vertex_count });
+                self.draw(mesh, *instance_count);
+            }
+            TraceCommand::DrawIndexed { topology, indices, instance_count } => {
+                let mesh = Mesh::new(self.bound_vertices(), *topology).with_indices(indices.clone());
+                self.draw(mesh, *instance_count);
+            }
+            TraceCommand::Present => {
+                // A framebuffer with no pixels makes no image, so there's no frame to keep.
+                if let Some(frame) = self.framebuffer.as_ref()
+                    .and_then(|framebuffer| Image::new(framebuffer.width(), framebuffer.height(), framebuffer.to_rgba8())) {
+                    self.frames.push(frame);
+                }
+            }
+        }
+    }
+}
+
+/// Reads the structs a vertex buffer's bytes hold. Any bytes left over are ignored.
+fn read_structs<T: Copy>(bytes: &[u8]) -> Vec<T> {
+    // Our shader types are all floats, so any bytes make a valid one.
+    bytes.chunks_exact(std::mem::size_of::<T>())
+        .map(|chunk| unsafe { std::ptr::read_unaligned(chunk.as_ptr() as *const T) })
+        .collect()
+}
+
+/// How much one frame differs from another of the same size.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FrameDifference {
+    pub differing_pixels: usize,
+    /// The largest difference in any channel of any pixel.
+    pub max_channel_difference: u8,
+}
+
+/// Compares two frames pixel by pixel, or `None` if they're different sizes.
+pub fn compare_frames(frame: &Image, reference: &Image) -> Option<FrameDifference> {
+    if [frame.width(), frame.height()] != [reference.width(), reference.height()] {
+        return None;
+    }
+    let mut difference = FrameDifference { differing_pixels: 0, max_channel_difference: 0 };
+    for (pixel, reference_pixel) in frame.pixels().chunks_exact(4).zip(reference.pixels().chunks_exact(4)) {
+        let max_channel_difference = pixel.iter().zip(reference_pixel)
+            .map(|(&channel, &reference_channel)| channel.abs_diff(reference_channel))
+            .max()
+            .unwrap_or(0);
+        if max_channel_difference > 0 {
+            difference.differing_pixels += 1;
+            difference.max_channel_difference = difference.max_channel_difference.max(max_channel_difference);
+        }
+    }
+    Some(difference)
+}
+
+/// Replays the trace the environment names with the software rasterizer, without starting the app.
+///
+/// Set HELLO_TRIANGLE_REPLAY to the trace, HELLO_TRIANGLE_REPLAY_OUTPUT to the directory
+/// to save each frame in as a PNG, and HELLO_TRIANGLE_REPLAY_COMPARE to a directory of PNGs,
+/// named the same way, to compare them with.
+pub fn replay_from_environment() {
+    let trace_path = match std::env::var_os("HELLO_TRIANGLE_REPLAY") {
+        Some(trace_path) => PathBuf::from(trace_path),
+        None => return,
+    };
+    let trace = match CommandTrace::load(&trace_path) {
+        Ok(trace) => trace,
+        Err(e) => {
+            println!("Unable to load trace {}: {}", trace_path.display(), format_error_chain(&e));
+            return;
+        }
+    };
+    let mut replayer = SoftwareReplayer::new();
+    if let Err(e) = replay(&trace, &mut replayer) {
+        println!("Replay stopped: {}", format_error_chain(&e));
+    }
+    if replayer.skipped_draws > 0 {
+        println!("Skipped {} draws the software rasterizer can't replay", replayer.skipped_draws);
+    }
+
+    let output = std::env::var_os("HELLO_TRIANGLE_REPLAY_OUTPUT").map_or_else(|| PathBuf::from("replay"), PathBuf::from);
+    if let Err(e) = std::fs::create_dir_all(&output) {
+        println!("Unable to create {}: {}", output.display(), e);
+        return;
+    }
+    for (frame_index, frame) in replayer.frames().iter().enumerate() {
+        if let Err(e) = save_png(frame, &output.join(png_sequence_file_name(frame_index))) {
+            println!("Unable to save frame {}: {}", frame_index, format_error_chain(&e));
+        }
+    }
+    println!("Replayed {} of the trace's {} frames into {}", replayer.frames().len(), trace.frame_count(), output.display());
+
+    if let Some(reference_directory) = std::env::var_os("HELLO_TRIANGLE_REPLAY_COMPARE") {
+        let reference_directory = PathBuf::from(reference_directory);
+        for (frame_index, frame) in replayer.frames().iter().enumerate() {
+            let reference_path = reference_directory.join(png_sequence_file_name(frame_index));
+            let reference = match std::fs::read(&reference_path).map(|bytes| decode_png(&bytes)) {
+                Ok(Ok(reference)) => reference,
+                Ok(Err(e)) => {
+                    println!("Frame {}: unable to decode {}: {}", frame_index, reference_path.display(), format_error_chain(&e));
+                    continue;
+                }
+                Err(e) => {
+                    println!("Frame {}: unable to read {}: {}", frame_index, reference_path.display(), e);
+                    continue;
+                }
+            };
+            match compare_frames(frame, &reference) {
+                Some(difference) if difference.differing_pixels == 0 => println!("Frame {}: the same", frame_index),
+                Some(difference) => println!("Frame {}: {} pixels differ, by up to {}",
+                                             frame_index, difference.differing_pixels, difference.max_channel_difference),
+                None => println!("Frame {}: {}x{}, but the reference is {}x{}",
+                                 frame_index, frame.width(), frame.height(), reference.width(), reference.height()),
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TraceError {
+    /// We couldn't read or write the file.
+    Io(PathBuf, std::io::Error),
+    /// The file isn't valid JSON.
+    Syntax(ParseError),
+    /// The file is valid JSON but not a valid trace.
+    Invalid { message: String, position: Position },
+    /// The command at `index` can't come where it does.
+    OutOfOrder { index: usize, message: String },
+}
+impl Display for TraceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(path, error) => write!(f, "{}: {}", path.display(), error),
+            Self::Syntax(error) => write!(f, "{}", error),
+            Self::Invalid { message, position } => write!(f, "{}: {}", position, message),
+            Self::OutOfOrder { index, message } => write!(f, "command {}: {}", index, message),
+        }
+    }
+}
+impl Error for TraceError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(_, error) => Some(error),
+            Self::Syntax(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+fn invalid<T>(value: &Spanned, message: String) -> Result<T, TraceError> {
+    Err(TraceError::Invalid { message, position: value.position })
+}
+
+// Reading
+
+fn read_command(value: &Spanned) -> Result<TraceCommand, TraceError> {
+    let member = |name: &str| match value.get(name) {
+        Some(member) => Ok(member),
+        None => invalid(value, format!("missing \"{}\"", name)),
+    };
+    let command = match read_string(member("command")?)?.as_str() {
+        "begin_pass" => TraceCommand::BeginPass {
+            name: read_string(member("name")?)?,
+            size: read_sizes(member("size")?)?,
+            sample_count: read_size(member("sample_count")?)?,
+            clear_color: value.get("clear_color").map(read_numbers::<4>).transpose()?,
+        },
+        "end_pass" => TraceCommand::EndPass,
+        "bind_pipeline" => TraceCommand::BindPipeline {
+            vertex_function: read_string(member("vertex_function")?)?,
+            fragment_function: read_string(member("fragment_function")?)?,
+            blend_mode: {
+                let blend_mode = member("blend_mode")?;
+                match BlendMode::from_name(&read_string(blend_mode)?) {
+                    Some(blend_mode) => blend_mode,
+                    None => return invalid(blend_mode, "unknown blend mode".to_string()),
+                }
+            },
+        },
+        "set_viewport" => TraceCommand::SetViewport { size: read_numbers::<2>(member("size")?)? },
+        "set_vertex_bytes" => TraceCommand::SetVertexBytes {
+            index: read_size(member("index")?)? as c_uint,
+            bytes: read_hex(member("bytes")?)?,
+        },
+        "draw" => TraceCommand::Draw {
+            topology: read_topology(member("topology")?)?,
+            vertex_start: read_size(member("vertex_start")?)?,
+            vertex_count: read_size(member("vertex_count")?)?,
+            instance_count: read_size(member("instance_count")?)?,
+        },
+        "draw_indexed" => {
+            let index_bytes = member("indices")?;
+            let bytes = read_hex(index_bytes)?;
+            let indices = match read_string(member("index_type")?)?.as_str() {
+                "uint16" if bytes.len() % 2 == 0 =>
+                    IndexData::U16(bytes.chunks_exact(2).map(|index| u16::from_le_bytes([index[0], index[1]])).collect()),
+                "uint32" if bytes.len() % 4 == 0 =>
+                    IndexData::U32(bytes.chunks_exact(4).map(|index| u32::from_le_bytes([index[0], index[1], index[2], index[3]])).collect()),
+                "uint16" | "uint32" => return invalid(index_bytes, "the indices don't fill a whole number of indices".to_string()),
+                other => return invalid(member("index_type")?, format!("unknown index type \"{}\" (expected uint16 or uint32)", other)),
+            };
+            TraceCommand::DrawIndexed {
+                topology: read_topology(member("topology")?)?,
+                indices,
+                instance_count: read_size(member("instance_count")?)?,
+            }
+        }
+        "present" => TraceCommand::Present,
+        other => return invalid(member("command")?, format!("unknown command \"{}\"", other)),
+    };
+    Ok(command)
+}
+
+fn read_string(value: &Spanned) -> Result<String, TraceError> {
+    match &value.value {
+        Value::String(s) => Ok(s.clone()),
+        _ => invalid(value, format!("expected a string but found {}", value.type_name())),
+    }
+}
+
+fn read_number(value: &Spanned) -> Result<f64, TraceError> {
+    match value.value {
+        Value::Number(n) => Ok(n),
+        _ => invalid(value, format!("expected a number but found {}", value.type_name())),
+    }
+}
+
+fn read_size(value: &Spanned) -> Result<usize, TraceError> {
+    let n = read_number(value)?;
+    if n.fract() != 0. || n < 0. || n > u32::MAX as f64 {
+        return invalid(value, format!("expected a whole number but found {}", n));
+    }
+    Ok(n as usize)
+}
+
+fn read_numbers<const N: usize>(value: &Spanned) -> Result<[f64; N], TraceError> {
+    match &value.value {
+        Value::Array(elements) if elements.len() == N => {
+            let mut numbers = [0.; N];
+            for (number, element) in numbers.iter_mut().zip(elements) {
+                *number = read_number(element)?;
+            }
+            Ok(numbers)
+        }
+        _ => invalid(value, format!("expected an array of {} numbers", N)),
+    }
+}
+
+fn read_sizes(value: &Spanned) -> Result<[usize; 2], TraceError> {
+    match &value.value {
+        Value::Array(elements) if elements.len() == 2 => {
+            let size = [read_size(&elements[0])?, read_size(&elements[1])?];
+            if size.iter().any(|&length| length == 0 || length > MAX_ATTACHMENT_SIZE) {
+                return invalid(value, format!("expected a width and a height from 1 to {} but found {:?}", MAX_ATTACHMENT_SIZE, size));
+            }
+            Ok(size)
+        }
+        _ => invalid(value, "expected a width and a height".to_string()),
+    }
+}
+
+fn read_topology(value: &Spanned) -> Result<PrimitiveTopology, TraceError> {
+    let name = read_string(value)?;
+    match PrimitiveTopology::from_name(&name) {
+        Some(topology) => Ok(topology),
+        None => invalid(value, format!("unknown topology \"{}\"", name)),
+    }
+}
+
+fn read_hex(value: &Spanned) -> Result<Vec<u8>, TraceError> {
+    let hex = read_string(value)?;
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return invalid(value, "expected bytes as pairs of hex digits".to_string());
+    }
+    (0..hex.len()).step_by(2)
+        .map(|start| u8::from_str_radix(&hex[start..start + 2], 16))
+        .collect::<Result<_, _>>()
+        .or_else(|_| invalid(value, "expected bytes as pairs of hex digits".to_string()))
+}
+
+// Writing
+
+fn write_command(command: &TraceCommand) -> Spanned {
+    let command_name = |name| ("command", string(name));
+    match command {
+        TraceCommand::BeginPass { name, size, sample_count, clear_color } => {
+            let mut members = vec![
+                command_name("begin_pass"),
+                ("name", string(name)),
+                ("size", numbers(&[size[0] as f64, size[1] as f64])),
+                ("sample_count", number(*sample_count as f64)),
+            ];
+            if let Some(clear_color) = clear_color {
+                members.push(("clear_color", numbers(clear_color)));
+            }
+            object(members)
+        }
+        TraceCommand::EndPass => object(vec![command_name("end_pass")]),
+        TraceCommand::BindPipeline { vertex_function, fragment_function, blend_mode } => object(vec![
+            command_name("bind_pipeline"),
+            ("vertex_function", string(vertex_function)),
+            ("fragment_function", string(fragment_function)),
+            ("blend_mode", string(blend_mode.name())),
+        ]),
+        TraceCommand::SetViewport { size } => object(vec![command_name("set_viewport"), ("size", numbers(size))]),
+        TraceCommand::SetVertexBytes { index, bytes } => object(vec![
+            command_name("set_vertex_bytes"),
+            ("index", number(f64::from(*index))),
+            ("bytes", string(&hex(bytes))),
+        ]),
+        TraceCommand::Draw { topology, vertex_start, vertex_count, instance_count } => object(vec![
+            command_name("draw"),
+            ("topology", string(topology.name())),
+            ("vertex_start", number(*vertex_start as f64)),
+            ("vertex_count", number(*vertex_count as f64)),
+            ("instance_count", number(*instance_count as f64)),
+        ]),
+        TraceCommand::DrawIndexed { topology, indices, instance_count } => {
+            let (index_type, bytes): (&str, Vec<u8>) = match indices {
+                IndexData::U16(indices) => ("uint16", indices.iter().flat_map(|index| index.to_le_bytes()).collect()),
+                IndexData::U32(indices) => ("uint32", indices.iter().flat_map(|index| index.to_le_bytes()).collect()),
+            };
+            object(vec![
+                command_name("draw_indexed"),
+                ("topology", string(topology.name())),
+                ("index_type", string(index_type)),
+                ("indices", string(&hex(&bytes))),
+                ("instance_count", number(*instance_count as f64)),
+            ])
+        }
+        TraceCommand::Present => object(vec![command_name("present")]),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    hex
+}
+
+fn object(members: Vec<(&str, Spanned)>) -> Spanned {
+    json::unspanned(Value::Object(members.into_iter().map(|(name, value)| (name.to_string(), value)).collect()))
+}
+
+fn number(n: f64) -> Spanned {
+    json::unspanned(Value::Number(n))
+}
+
+fn numbers(ns: &[f64]) -> Spanned {
+    json::unspanned(Value::Array(ns.iter().map(|&n| number(n)).collect()))
+}
+
+fn string(s: &str) -> Spanned {
+    json::unspanned(Value::String(s.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transform::Transform2D;
+    use crate::software_rasterizer::Framebuffer;
+
+    static SIZE: [usize; 2] = [64, 48];
+    static CLEAR_COLOR: [f32; 4] = [0., 0., 0.25, 1.];
+
+    fn transform() -> Matrix3 {
+        Transform2D { scale: [0.1, 0.1], rotation: 0.3, ..Transform2D::identity() }.to_matrix()
+    }
+
+    /// A square of two triangles, drawn with indices.
+    fn indexed_square() -> Mesh<AAPLVertex> {
+        let corners = [[-80., -80.], [80., -80.], [80., 80.], [-80., 80.]];
+        Mesh::new(corners.iter().map(|&corner| AAPLVertex::new(corner, [1., 1., 1., 0.5])).collect(), PrimitiveTopology::Triangle)
+            .with_indices(IndexData::U16(vec![0, 1, 2, 0, 2, 3]))
+    }
+
+    fn instances() -> Vec<AAPLInstance> {
+        vec![
+            AAPLInstance::new([-10., 0.], [1., 1.], 0., [1., 0., 0., 1.]),
+            AAPLInstance::new([12., 5.], [0.5, 1.5], 0.7, [0., 1., 0., 1.]),
+        ]
+    }
+
+    /// Two frames: the triangle multisampled, with a textured draw that can't be replayed,
+    /// then instanced, indexed, translucent squares.
+    fn trace() -> CommandTrace {
+        let triangle = Mesh::hello_triangle();
+        let square = indexed_square();
+        let uniforms = AAPLObjectUniforms::new(&transform(), [1., 0.5, 1., 1.], 0.5);
+        let begin_pass = |sample_count| TraceCommand::BeginPass {
+            name: "Scene".to_string(),
+            size: SIZE,
+            sample_count,
+            clear_color: Some(CLEAR_COLOR.map(f64::from)),
+        };
+        let bind_pipeline = |vertex_function: &str, fragment_function: &str, blend_mode| TraceCommand::BindPipeline {
+            vertex_function: vertex_function.to_string(),
+            fragment_function: fragment_function.to_string(),
+            blend_mode,
+        };
+        CommandTrace {
+            commands: vec![
+                begin_pass(4),
+                bind_pipeline(SCENE_VERTEX_FUNCTION, VERTEX_COLOR_FRAGMENT_FUNCTION, BlendMode::Opaque),
+                TraceCommand::SetViewport { size: [64., 48.] },
+                TraceCommand::vertex_bytes(AAPLVertexInputIndexVertices, triangle.vertices()),
+                TraceCommand::vertex_bytes(AAPLVertexInputIndexObjectUniforms, &[uniforms]),
+                TraceCommand::mesh_draw(&triangle, 1),
+                bind_pipeline("texturedVertexShader", "textureFragmentShader", BlendMode::Alpha),
+                TraceCommand::mesh_draw(&triangle, 1),
+                TraceCommand::EndPass,
+                TraceCommand::Present,
+                begin_pass(1),
+                bind_pipeline(INSTANCED_VERTEX_FUNCTION, VERTEX_COLOR_FRAGMENT_FUNCTION, BlendMode::Alpha),
+                TraceCommand::vertex_bytes(AAPLVertexInputIndexVertices, square.vertices()),
+                TraceCommand::vertex_bytes(AAPLVertexInputIndexObjectUniforms, &[uniforms]),
+                TraceCommand::vertex_bytes(AAPLVertexInputIndexInstances, &instances()),
+                TraceCommand::mesh_draw(&square, 2),
+                TraceCommand::EndPass,
+                TraceCommand::Present,
+            ],
+        }
+    }
+
+    fn image(framebuffer: &Framebuffer) -> Image {
+        Image::new(framebuffer.width(), framebuffer.height(), framebuffer.to_rgba8()).unwrap()
+    }
+
+    #[test]
+    fn traces_round_trip_through_json() {
+        let trace = trace();
+        assert_eq!(trace.frame_count(), 2);
+        assert_eq!(CommandTrace::from_json(&trace.to_json()).unwrap(), trace);
+    }
+
+    #[test]
+    fn replayed_traces_match_drawing_directly() {
+        let trace = CommandTrace::from_json(&trace().to_json()).unwrap();
+        let mut replayer = SoftwareReplayer::new();
+        replay(&trace, &mut replayer).unwrap();
+        assert_eq!(replayer.skipped_draws, 1);
+        assert_eq!(replayer.frames().len(), 2);
+
+        let tint = [1., 0.5, 1., 1.];
+        let mut first = Framebuffer::new_multisample(SIZE[0], SIZE[1], 4, CLEAR_COLOR).unwrap();
+        draw_mesh(&mut first, &Mesh::hello_triangle(), &transform(), tint, 0.5, &BlendMode::Opaque.blend_state());
+        let mut second = Framebuffer::new(SIZE[0], SIZE[1], CLEAR_COLOR);
+        draw_instanced_mesh(&mut second, &indexed_square(), &instances(), &transform(), tint, 0.5, &BlendMode::Alpha.blend_state());
+
+        let cleared = image(&Framebuffer::new(SIZE[0], SIZE[1], CLEAR_COLOR));
+        let no_difference = Some(FrameDifference { differing_pixels: 0, max_channel_difference: 0 });
+        for (replayed, expected) in replayer.frames().iter().zip([image(&first), image(&second)].iter()) {
+            let drawn = compare_frames(expected, &cleared).unwrap().differing_pixels;
+            assert!(drawn > 100, "only {} pixels drawn", drawn);
+            assert_eq!(compare_frames(replayed, expected), no_difference);
+        }
+    }
+
+    #[test]
+    fn frames_that_differ_are_counted() {
+        let frame = Image::new(2, 1, vec![0, 0, 0, 255, 10, 20, 30, 255]).unwrap();
+        let reference = Image::new(2, 1, vec![0, 0, 0, 255, 10, 25, 27, 255]).unwrap();
+        assert_eq!(compare_frames(&frame, &reference), Some(FrameDifference { differing_pixels: 1, max_channel_difference: 5 }));
+        assert_eq!(compare_frames(&frame, &Image::new(1, 1, vec![0; 4]).unwrap()), None);
+    }
+
+    #[test]
+    fn commands_out_of_place_stop_the_replay() {
+        // A draw before its pass begins.
+        let mut no_begin = trace();
+        no_begin.commands.remove(0);
+        assert!(matches!(replay(&no_begin, &mut SoftwareReplayer::new()), Err(TraceError::OutOfOrder { index: 0, .. })));
+        // A frame presented before its pass ends.
+        let mut no_end = trace();
+        no_end.commands.remove(8);
+        assert!(matches!(replay(&no_end, &mut SoftwareReplayer::new()), Err(TraceError::OutOfOrder { index: 8, .. })));
+    }
+
+    #[test]
+    fn attachments_without_pixels_are_invalid() {
+        for size in [[0, 4], [4, 0], [MAX_ATTACHMENT_SIZE + 1, 4]].iter() {
+            let mut trace = trace();
+            if let TraceCommand::BeginPass { size: pass_size, .. } = &mut trace.commands[0] {
+                *pass_size = *size;
+            }
+            assert!(matches!(CommandTrace::from_json(&trace.to_json()), Err(TraceError::Invalid { .. })), "{:?}", size);
+        }
+    }
+
+    #[test]
+    fn frames_without_pixels_are_skipped() {
+        let mut trace = trace();
+        for command in trace.commands.iter_mut() {
+            if let TraceCommand::BeginPass { size, .. } = command {
+                *size = [0, 4];
+            }
+        }
+        let mut replayer = SoftwareReplayer::new();
+        replay(&trace, &mut replayer).unwrap();
+        assert!(replayer.frames().is_empty());
+    }
+}
@@ -0,0 +1,67 @@
+//! Compute command encoders, and dispatching work to them
+//!
+//! `threadgroups` works out how to split the work up.
+
+use objc::msg_send;
+use objc::sel;
+use objc::sel_impl;
+use cocoa::base::{id, nil};
+use cocoa::foundation::{NSString, NSUInteger};
+use crate::threadgroups::{ThreadgroupLimits, threadgroup_count};
+
+// From System/Library/Frameworks/Metal.framework/Versions/A/Headers/MTLTypes.h
+// typedef struct {
+//     NSUInteger width, height, depth;
+// } MTLSize;
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MTLSize {
+    pub width: NSUInteger,
+    pub height: NSUInteger,
+    pub depth: NSUInteger,
+}
+
+impl MTLSize {
+    fn new([width, height]: [usize; 2]) -> Self {
+        MTLSize { width: width as NSUInteger, height: height as NSUInteger, depth: 1 }
+    }
+}
+
+/// The limits of a compute pipeline state.
+pub fn threadgroup_limits(pipeline_state: id) -> ThreadgroupLimits {
+    let thread_execution_width: NSUInteger = unsafe { msg_send![pipeline_state, threadExecutionWidth] };
+    let max_total_threads_per_threadgroup: NSUInteger = unsafe { msg_send![pipeline_state, maxTotalThreadsPerThreadgroup] };
+    ThreadgroupLimits {
+        thread_execution_width: thread_execution_width as usize,
+        max_total_threads_per_threadgroup: max_total_threads_per_threadgroup as usize,
+    }
+}
+
+/// Starts a compute pass in a command buffer, with a label for Xcode's debugger.
+///
+/// The encoder is autoreleased; end it with `endEncoding` before starting another pass.
+pub fn new_compute_encoder(command_buffer: id, label: &str) -> id {
+    let compute_encoder: id = unsafe { msg_send![command_buffer, computeCommandEncoder] };
+    if compute_encoder != nil {
+        let label = unsafe { NSString::alloc(nil).init_str(label) };
+        let _:() = unsafe { msg_send![compute_encoder, setLabel:label] };
+    }
+    compute_encoder
+}
+
+/// Encodes enough threadgroups to run the pipeline state's kernel at least `thread_count` times,
+/// with `[[thread_position_in_grid]]` counting up from zero.
+pub fn dispatch_1d(compute_encoder: id, pipeline_state: id, thread_count: usize) {
+    let threadgroup_size = threadgroup_limits(pipeline_state).threadgroup_size_1d();
+    dispatch(compute_encoder, pipeline_state, [thread_count, 1], [threadgroup_size, 1]);
+}
+
+fn dispatch(compute_encoder: id, pipeline_state: id, grid_size: [usize; 2], threadgroup_size: [usize; 2]) {
+    if grid_size[0] == 0 || grid_size[1] == 0 {
+        return;
+    }
+    let threadgroups = MTLSize::new(threadgroup_count(grid_size, threadgroup_size));
+    let threads_per_threadgroup = MTLSize::new(threadgroup_size);
+    let _:() = unsafe { msg_send![compute_encoder, setComputePipelineState:pipeline_state] };
+    let _:() = unsafe { msg_send![compute_encoder, dispatchThreadgroups:threadgroups threadsPerThreadgroup:threads_per_threadgroup] };
+}
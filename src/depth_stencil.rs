@@ -0,0 +1,192 @@
+//! Depth and stencil testing
+//!
+//! A `DepthStencilDesc` says how fragments are tested against
+//! (and written to) the view's depth and stencil attachments,
+//! in the same terms as `MTLDepthStencilDescriptor`.
+//! Metal keeps these settings in an `MTLDepthStencilState`,
+//! made once and set on the render encoder each frame.
+
+use objc::class;
+use objc::msg_send;
+use objc::sel;
+use objc::sel_impl;
+use cocoa::base::{id, nil};
+use cocoa::foundation::{NSAutoreleasePool, NSString, NSUInteger};
+use objc::runtime::{BOOL, NO, YES};
+
+/// How a new value is compared with the one already stored.
+///
+/// These match `MTLCompareFunction`.
+// They're all here so a `DepthStencilDesc` can describe any test Metal can do,
+// though the app's own only compares with `LessEqual` and `Always`.
+#[allow(unused)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum CompareFunction {
+    Never,
+    Less,
+    Equal,
+    LessEqual,
+    Greater,
+    NotEqual,
+    GreaterEqual,
+    Always,
+}
+
+// From System/Library/Frameworks/Metal.framework/Versions/A/Headers/MTLDepthStencil.h
+// typedef NS_ENUM(NSUInteger, MTLCompareFunction) {
+//     MTLCompareFunctionNever = 0,
+//     MTLCompareFunctionLess = 1,
+//     MTLCompareFunctionEqual = 2,
+//     MTLCompareFunctionLessEqual = 3,
+//     MTLCompareFunctionGreater = 4,
+//     MTLCompareFunctionNotEqual = 5,
+//     MTLCompareFunctionGreaterEqual = 6,
+//     MTLCompareFunctionAlways = 7,
+// } API_AVAILABLE(macos(10.11), ios(8.0));
+fn mtl_compare_function(compare_function: CompareFunction) -> NSUInteger {
+    match compare_function {
+        CompareFunction::Never => 0,
+        CompareFunction::Less => 1,
+        CompareFunction::Equal => 2,
+        CompareFunction::LessEqual => 3,
+        CompareFunction::Greater => 4,
+        CompareFunction::NotEqual => 5,
+        CompareFunction::GreaterEqual => 6,
+        CompareFunction::Always => 7,
+    }
+}
+
+/// What happens to the stored stencil value.
+///
+/// These match `MTLStencilOperation`.
+// As with `CompareFunction`, they're all here, though the app's stencil test only keeps what's there.
+#[allow(unused)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum StencilOperation {
+    Keep,
+    Zero,
+    /// Store the renderer's stencil reference value.
+    Replace,
+    IncrementClamp,
+    DecrementClamp,
+    Invert,
+    IncrementWrap,
+    DecrementWrap,
+}
+
+// From System/Library/Frameworks/Metal.framework/Versions/A/Headers/MTLDepthStencil.h
+// typedef NS_ENUM(NSUInteger, MTLStencilOperation) {
+//     MTLStencilOperationKeep = 0,
+//     MTLStencilOperationZero = 1,
+//     MTLStencilOperationReplace = 2,
+//     MTLStencilOperationIncrementClamp = 3,
+//     MTLStencilOperationDecrementClamp = 4,
+//     MTLStencilOperationInvert = 5,
+//     MTLStencilOperationIncrementWrap = 6,
+//     MTLStencilOperationDecrementWrap = 7,
+// } API_AVAILABLE(macos(10.11), ios(8.0));
+fn mtl_stencil_operation(operation: StencilOperation) -> NSUInteger {
+    match operation {
+        StencilOperation::Keep => 0,
+        StencilOperation::Zero => 1,
+        StencilOperation::Replace => 2,
+        StencilOperation::IncrementClamp => 3,
+        StencilOperation::DecrementClamp => 4,
+        StencilOperation::Invert => 5,
+        StencilOperation::IncrementWrap => 6,
+        StencilOperation::DecrementWrap => 7,
+    }
+}
+
+/// The stencil test, and what it does to the stencil attachment.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct StencilDesc {
+    /// Compares the reference value with the stored one, both masked by `read_mask`.
+    pub compare_function: CompareFunction,
+    pub stencil_failure_operation: StencilOperation,
+    pub depth_failure_operation: StencilOperation,
+    pub depth_stencil_pass_operation: StencilOperation,
+    pub read_mask: u32,
+    pub write_mask: u32,
+}
+
+impl Default for StencilDesc {
+    /// Always passes and leaves the stencil attachment alone, as Metal's default does.
+    fn default() -> Self {
+        StencilDesc {
+            compare_function: CompareFunction::Always,
+            stencil_failure_operation: StencilOperation::Keep,
+            depth_failure_operation: StencilOperation::Keep,
+            depth_stencil_pass_operation: StencilOperation::Keep,
+            read_mask: !0,
+            write_mask: !0,
+        }
+    }
+}
+
+/// Everything that goes into an `MTLDepthStencilState`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct DepthStencilDesc {
+    pub depth_compare_function: CompareFunction,
+    pub depth_write_enabled: bool,
+    /// Used for both front and back facing primitives.
+    pub stencil: StencilDesc,
+}
+
+impl Default for DepthStencilDesc {
+    /// Nearer fragments win, and fragments at the same depth are drawn in order,
+    /// so flat scenes (where everything is at depth 0) look as they would without a depth test.
+    fn default() -> Self {
+        DepthStencilDesc {
+            depth_compare_function: CompareFunction::LessEqual,
+            depth_write_enabled: true,
+            stencil: StencilDesc::default(),
+        }
+    }
+}
+
+/// Creates a depth stencil state.
+///
+/// The caller owns the returned state.
+pub fn new_depth_stencil_state(device: id, desc: &DepthStencilDesc) -> id {
+    let pool = unsafe { NSAutoreleasePool::new(nil) };
+    let depth_stencil_descriptor: id = unsafe { msg_send![class!(MTLDepthStencilDescriptor), new] };
+    let label = unsafe { NSString::alloc(nil).init_str("Depth Stencil State") };
+    let depth_compare_function = mtl_compare_function(desc.depth_compare_function);
+    let depth_write_enabled: BOOL = if desc.depth_write_enabled { YES } else { NO };
+    let stencil_descriptor = new_stencil_descriptor(&desc.stencil);
+    let depth_stencil_state: id = unsafe {
+        let _:() = msg_send![depth_stencil_descriptor, setLabel:label];
+        let _:() = msg_send![depth_stencil_descriptor, setDepthCompareFunction:depth_compare_function];
+        let _:() = msg_send![depth_stencil_descriptor, setDepthWriteEnabled:depth_write_enabled];
+        let _:() = msg_send![depth_stencil_descriptor, setFrontFaceStencil:stencil_descriptor];
+        let _:() = msg_send![depth_stencil_descriptor, setBackFaceStencil:stencil_descriptor];
+        msg_send![device, newDepthStencilStateWithDescriptor:depth_stencil_descriptor]
+    };
+    unsafe {
+        let _:() = msg_send![stencil_descriptor, release];
+        let _:() = msg_send![depth_stencil_descriptor, release];
+        pool.drain();
+    }
+    depth_stencil_state
+}
+
+/// A new `MTLStencilDescriptor`, owned by the caller.
+fn new_stencil_descriptor(desc: &StencilDesc) -> id {
+    let stencil_descriptor: id = unsafe { msg_send![class!(MTLStencilDescriptor), new] };
+    let compare_function = mtl_compare_function(desc.compare_function);
+    let stencil_failure_operation = mtl_stencil_operation(desc.stencil_failure_operation);
+    let depth_failure_operation = mtl_stencil_operation(desc.depth_failure_operation);
+    let depth_stencil_pass_operation = mtl_stencil_operation(desc.depth_stencil_pass_operation);
+    let read_mask = desc.read_mask;
+    let write_mask = desc.write_mask;
+    unsafe {
+        let _:() = msg_send![stencil_descriptor, setStencilCompareFunction:compare_function];
+        let _:() = msg_send![stencil_descriptor, setStencilFailureOperation:stencil_failure_operation];
+        let _:() = msg_send![stencil_descriptor, setDepthFailureOperation:depth_failure_operation];
+        let _:() = msg_send![stencil_descriptor, setDepthStencilPassOperation:depth_stencil_pass_operation];
+        let _:() = msg_send![stencil_descriptor, setReadMask:read_mask];
+        let _:() = msg_send![stencil_descriptor, setWriteMask:write_mask];
+    }
+    stencil_descriptor
+}
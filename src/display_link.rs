@@ -21,6 +21,23 @@ pub type CVOptionFlags = c_ulonglong;
 pub type CVDisplayLinkOutputCallback = *const extern "C" fn(display_link: CVDisplayLinkRef, now: *const CVTimeStamp, output_time: *const CVTimeStamp, flags_in: CVOptionFlags, flags_out: *mut CVOptionFlags, display_link_context: *const c_void) -> CVReturn;
 pub type dispatch_object_t = id;
 
+// From CVBase.h:
+// typedef struct
+// {
+//     int64_t    timeValue;
+//     int32_t    timeScale;
+//     int32_t    flags;
+// } CVTime;
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct CVTime {
+    time_value: i64,
+    time_scale: i32,
+    flags: i32,
+}
+// CVBase.h:    kCVTimeIsIndefinite = 1 << 0
+static kCVTimeIsIndefinite: i32 = 1 << 0;
+
 // CVReturn.h:    kCVReturnSuccess = 0
 static kCVReturnSuccess: c_int = 0;
 
@@ -41,6 +58,8 @@ extern {
     fn CVDisplayLinkStart(display_link: CVDisplayLinkRef) -> CVReturn;
     // CVReturn CVDisplayLinkStop(CVDisplayLinkRef displayLink);
     fn CVDisplayLinkStop(display_link: CVDisplayLinkRef) -> CVReturn;
+    // CVTime CVDisplayLinkGetNominalOutputVideoRefreshPeriod(CVDisplayLinkRef displayLink);
+    fn CVDisplayLinkGetNominalOutputVideoRefreshPeriod(display_link: CVDisplayLinkRef) -> CVTime;
     // oid dispatch_suspend(dispatch_object_t object);
     fn dispatch_suspend(object: dispatch_object_t);
     fn dispatch_source_merge_data(source: dispatch_source_t, data: c_ulong);
@@ -176,6 +195,17 @@ impl DisplayLink {
         }
     }
 
+    /// The rate at which the display refreshes, in Hz,
+    /// or `None` if CoreVideo can't tell us (e.g. for a variable-rate display).
+    pub fn nominal_refresh_rate(&self) -> Option<f64> {
+        let period = unsafe { CVDisplayLinkGetNominalOutputVideoRefreshPeriod(self._display_link) };
+        if period.flags & kCVTimeIsIndefinite != 0 || period.time_value == 0 {
+            None
+        } else {
+            Some(f64::from(period.time_scale) / period.time_value as f64)
+        }
+    }
+
     fn can_enter_state(&self, next_state: DisplayLinkState) -> bool {
         match self.state {
             DisplayLinkState::Running =>
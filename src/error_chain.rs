@@ -0,0 +1,14 @@
+//! Printing an error together with the errors that caused it
+
+use std::error::Error;
+
+/// Writes an error followed by everything that caused it, one per line.
+pub fn format_error_chain(error: &dyn Error) -> String {
+    let mut text = error.to_string();
+    let mut cause = error.source();
+    while let Some(error) = cause {
+        text.push_str(&format!("\n  caused by: {}", error));
+        cause = error.source();
+    }
+    text
+}
@@ -0,0 +1,338 @@
+//! Fonts for drawing text
+//!
+//! A `Font` is either the small bitmap font bundled here,
+//! or a TrueType font read with ab_glyph.
+//! Either way it gives the metrics text layout needs
+//! and the coverage of each glyph at the font's pixel size, for the glyph atlas.
+
+use std::fmt::{Display, Formatter};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use ab_glyph::{Font as _, FontVec, PxScale, ScaleFont};
+
+/// The glyphs of the bundled bitmap font, for `' '` to `'~'`.
+///
+/// Each glyph is five columns, left to right,
+/// and each column is eight rows with the top row in the lowest bit.
+/// The top seven rows are above the baseline and the last is for descenders.
+static BITMAP_FONT_GLYPHS: [[u8; 5]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], // space
+    [0x00, 0x00, 0x5F, 0x00, 0x00], // !
+    [0x00, 0x07, 0x00, 0x07, 0x00], // "
+    [0x14, 0x7F, 0x14, 0x7F, 0x14], // #
+    [0x24, 0x2A, 0x7F, 0x2A, 0x12], // $
+    [0x23, 0x13, 0x08, 0x64, 0x62], // %
+    [0x36, 0x49, 0x55, 0x22, 0x50], // &
+    [0x00, 0x05, 0x03, 0x00, 0x00], // '
+    [0x00, 0x1C, 0x22, 0x41, 0x00], // (
+    [0x00, 0x41, 0x22, 0x1C, 0x00], // )
+    [0x14, 0x08, 0x3E, 0x08, 0x14], // *
+    [0x08, 0x08, 0x3E, 0x08, 0x08], // +
+    [0x00, 0xA0, 0x60, 0x00, 0x00], // ,
+    [0x08, 0x08, 0x08, 0x08, 0x08], // -
+    [0x00, 0x60, 0x60, 0x00, 0x00], // .
+    [0x20, 0x10, 0x08, 0x04, 0x02], // /
+    [0x3E, 0x51, 0x49, 0x45, 0x3E], // 0
+    [0x00, 0x42, 0x7F, 0x40, 0x00], // 1
+    [0x42, 0x61, 0x51, 0x49, 0x46], // 2
+    [0x21, 0x41, 0x45, 0x4B, 0x31], // 3
+    [0x18, 0x14, 0x12, 0x7F, 0x10], // 4
+    [0x27, 0x45, 0x45, 0x45, 0x39], // 5
+    [0x3C, 0x4A, 0x49, 0x49, 0x30], // 6
+    [0x01, 0x71, 0x09, 0x05, 0x03], // 7
+    [0x36, 0x49, 0x49, 0x49, 0x36], // 8
+    [0x06, 0x49, 0x49, 0x29, 0x1E], // 9
+    [0x00, 0x36, 0x36, 0x00, 0x00], // :
+    [0x00, 0x56, 0x36, 0x00, 0x00], // ;
+    [0x08, 0x14, 0x22, 0x41, 0x00], // <
+    [0x14, 0x14, 0x14, 0x14, 0x14], // =
+    [0x00, 0x41, 0x22, 0x14, 0x08], // >
+    [0x02, 0x01, 0x51, 0x09, 0x06], // ?
+    [0x32, 0x49, 0x79, 0x41, 0x3E], // @
+    [0x7E, 0x11, 0x11, 0x11, 0x7E], // A
+    [0x7F, 0x49, 0x49, 0x49, 0x36], // B
+    [0x3E, 0x41, 0x41, 0x41, 0x22], // C
+    [0x7F, 0x41, 0x41, 0x22, 0x1C], // D
+    [0x7F, 0x49, 0x49, 0x49, 0x41], // E
+    [0x7F, 0x09, 0x09, 0x09, 0x01], // F
+    [0x3E, 0x41, 0x49, 0x49, 0x7A], // G
+    [0x7F, 0x08, 0x08, 0x08, 0x7F], // H
+    [0x00, 0x41, 0x7F, 0x41, 0x00], // I
+    [0x20, 0x40, 0x41, 0x3F, 0x01], // J
+    [0x7F, 0x08, 0x14, 0x22, 0x41], // K
+    [0x7F, 0x40, 0x40, 0x40, 0x40], // L
+    [0x7F, 0x02, 0x0C, 0x02, 0x7F], // M
+    [0x7F, 0x04, 0x08, 0x10, 0x7F], // N
+    [0x3E, 0x41, 0x41, 0x41, 0x3E], // O
+    [0x7F, 0x09, 0x09, 0x09, 0x06], // P
+    [0x3E, 0x41, 0x51, 0x21, 0x5E], // Q
+    [0x7F, 0x09, 0x19, 0x29, 0x46], // R
+    [0x46, 0x49, 0x49, 0x49, 0x31], // S
+    [0x01, 0x01, 0x7F, 0x01, 0x01], // T
+    [0x3F, 0x40, 0x40, 0x40, 0x3F], // U
+    [0x1F, 0x20, 0x40, 0x20, 0x1F], // V
+    [0x3F, 0x40, 0x38, 0x40, 0x3F], // W
+    [0x63, 0x14, 0x08, 0x14, 0x63], // X
+    [0x07, 0x08, 0x70, 0x08, 0x07], // Y
+    [0x61, 0x51, 0x49, 0x45, 0x43], // Z
+    [0x00, 0x7F, 0x41, 0x41, 0x00], // [
+    [0x02, 0x04, 0x08, 0x10, 0x20], // backslash
+    [0x00, 0x41, 0x41, 0x7F, 0x00], // ]
+    [0x04, 0x02, 0x01, 0x02, 0x04], // ^
+    [0x80, 0x80, 0x80, 0x80, 0x80], // _
+    [0x00, 0x01, 0x02, 0x04, 0x00], // `
+    [0x20, 0x54, 0x54, 0x54, 0x78], // a
+    [0x7F, 0x48, 0x44, 0x44, 0x38], // b
+    [0x38, 0x44, 0x44, 0x44, 0x20], // c
+    [0x38, 0x44, 0x44, 0x48, 0x7F], // d
+    [0x38, 0x54, 0x54, 0x54, 0x18], // e
+    [0x08, 0x7E, 0x09, 0x01, 0x02], // f
+    [0x18, 0xA4, 0xA4, 0xA4, 0x7C], // g
+    [0x7F, 0x08, 0x04, 0x04, 0x78], // h
+    [0x00, 0x44, 0x7D, 0x40, 0x00], // i
+    [0x40, 0x80, 0x84, 0x7D, 0x00], // j
+    [0x7F, 0x10, 0x28, 0x44, 0x00], // k
+    [0x00, 0x41, 0x7F, 0x40, 0x00], // l
+    [0x7C, 0x04, 0x18, 0x04, 0x78], // m
+    [0x7C, 0x08, 0x04, 0x04, 0x78], // n
+    [0x38, 0x44, 0x44, 0x44, 0x38], // o
+    [0xFC, 0x24, 0x24, 0x24, 0x18], // p
+    [0x18, 0x24, 0x24, 0x24, 0xFC], // q
+    [0x7C, 0x08, 0x04, 0x04, 0x08], // r
+    [0x48, 0x54, 0x54, 0x54, 0x20], // s
+    [0x04, 0x3F, 0x44, 0x40, 0x20], // t
+    [0x3C, 0x40, 0x40, 0x20, 0x7C], // u
+    [0x1C, 0x20, 0x40, 0x20, 0x1C], // v
+    [0x3C, 0x40, 0x30, 0x40, 0x3C], // w
+    [0x44, 0x28, 0x10, 0x28, 0x44], // x
+    [0x1C, 0xA0, 0xA0, 0xA0, 0x7C], // y
+    [0x44, 0x64, 0x54, 0x4C, 0x44], // z
+    [0x00, 0x08, 0x36, 0x41, 0x00], // {
+    [0x00, 0x00, 0x7F, 0x00, 0x00], // |
+    [0x00, 0x41, 0x36, 0x08, 0x00], // }
+    [0x08, 0x04, 0x08, 0x10, 0x08], // ~
+];
+static BITMAP_FONT_FIRST_CHARACTER: char = ' ';
+static BITMAP_FONT_GLYPH_WIDTH: usize = 5;
+static BITMAP_FONT_GLYPH_HEIGHT: usize = 8;
+static BITMAP_FONT_ASCENT: usize = 7;
+/// A column of space between glyphs.
+static BITMAP_FONT_ADVANCE: usize = 6;
+/// A row of space between lines.
+static BITMAP_FONT_LINE_GAP: usize = 1;
+
+/// How lines of text are spaced, in pixels.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct LineMetrics {
+    /// How far the tallest glyphs go above the baseline.
+    pub ascent: f32,
+    /// How far the lowest glyphs go below the baseline (positive).
+    pub descent: f32,
+    /// The extra space between one line's descent and the next line's ascent.
+    pub line_gap: f32,
+}
+
+impl LineMetrics {
+    /// The distance from one baseline to the next.
+    pub fn line_height(&self) -> f32 {
+        self.ascent + self.descent + self.line_gap
+    }
+}
+
+/// The coverage of one glyph, from 0 (outside) to 255 (inside), top row first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GlyphImage {
+    pub width: usize,
+    pub height: usize,
+    pub coverage: Vec<u8>,
+    /// From the pen position on the baseline to the image's top-left corner, with y down.
+    pub offset: [f32; 2],
+}
+
+enum FontSource {
+    /// The bundled font, with each of its pixels drawn as a square this many pixels wide.
+    Bitmap { pixel_scale: usize },
+    TrueType { font: FontVec, scale: PxScale },
+}
+
+/// A font at one pixel size.
+pub struct Font {
+    source: FontSource,
+}
+
+impl Font {
+    /// The bundled 5×8 bitmap font, with each of its pixels `pixel_scale` pixels wide.
+    pub fn bitmap(pixel_scale: usize) -> Self {
+        Font { source: FontSource::Bitmap { pixel_scale: pixel_scale.max(1) } }
+    }
+
+    /// A TrueType (or OpenType) font, where `pixel_height` is the distance from ascent to descent.
+    pub fn from_ttf(bytes: Vec<u8>, pixel_height: f32) -> Option<Self> {
+        let font = FontVec::try_from_vec(bytes).ok()?;
+        Some(Font { source: FontSource::TrueType { font, scale: PxScale::from(pixel_height) } })
+    }
+
+    /// Reads a TrueType font file.
+    pub fn load_ttf(path: &Path, pixel_height: f32) -> Result<Self, FontError> {
+        let bytes = std::fs::read(path).map_err(|e| FontError::Io(path.to_path_buf(), e))?;
+        Font::from_ttf(bytes, pixel_height).ok_or_else(|| FontError::Invalid(path.to_path_buf()))
+    }
+
+    pub fn line_metrics(&self) -> LineMetrics {
+        match &self.source {
+            FontSource::Bitmap { pixel_scale } => LineMetrics {
+                ascent: (BITMAP_FONT_ASCENT * pixel_scale) as f32,
+                descent: ((BITMAP_FONT_GLYPH_HEIGHT - BITMAP_FONT_ASCENT) * pixel_scale) as f32,
+                line_gap: (BITMAP_FONT_LINE_GAP * pixel_scale) as f32,
+            },
+            FontSource::TrueType { font, scale } => {
+                let scaled = font.as_scaled(*scale);
+                LineMetrics { ascent: scaled.ascent(), descent: -scaled.descent(), line_gap: scaled.line_gap() }
+            }
+        }
+    }
+
+    /// Whether the font has a glyph for the character, rather than falling back to a missing glyph.
+    pub fn has_glyph(&self, character: char) -> bool {
+        match &self.source {
+            FontSource::Bitmap { .. } => bitmap_glyph(character).is_some(),
+            FontSource::TrueType { font, .. } => font.glyph_id(character).0 != 0,
+        }
+    }
+
+    /// How far the pen moves after drawing the character.
+    pub fn advance(&self, character: char) -> f32 {
+        match &self.source {
+            FontSource::Bitmap { pixel_scale } => (BITMAP_FONT_ADVANCE * pixel_scale) as f32,
+            FontSource::TrueType { font, scale } => {
+                let scaled = font.as_scaled(*scale);
+                scaled.h_advance(scaled.glyph_id(character))
+            }
+        }
+    }
+
+    /// How much closer (if negative) or further apart the two characters are set than their advance says.
+    pub fn kerning(&self, left: char, right: char) -> f32 {
+        match &self.source {
+            FontSource::Bitmap { .. } => 0.,
+            FontSource::TrueType { font, scale } => {
+                let scaled = font.as_scaled(*scale);
+                scaled.kern(scaled.glyph_id(left), scaled.glyph_id(right))
+            }
+        }
+    }
+
+    /// The glyph's coverage, or `None` if it has nothing to draw (like a space).
+    pub fn rasterize(&self, character: char) -> Option<GlyphImage> {
+        match &self.source {
+            FontSource::Bitmap { pixel_scale } => rasterize_bitmap_glyph(character, *pixel_scale),
+            FontSource::TrueType { font, scale } => {
+                let glyph = font.as_scaled(*scale).scaled_glyph(character);
+                let outlined_glyph = font.outline_glyph(glyph)?;
+                let bounds = outlined_glyph.px_bounds();
+                let width = bounds.width() as usize;
+                let height = bounds.height() as usize;
+                if width == 0 || height == 0 {
+                    return None;
+                }
+                let mut coverage = vec![0; width * height];
+                outlined_glyph.draw(|x, y, value| {
+                    coverage[y as usize * width + x as usize] = (value.min(1.) * 255.).round() as u8;
+                });
+                Some(GlyphImage { width, height, coverage, offset: [bounds.min.x, bounds.min.y] })
+            }
+        }
+    }
+}
+
+/// The columns of a bundled glyph, if the font has one for the character.
+fn bitmap_glyph(character: char) -> Option<&'static [u8; 5]> {
+    let index = (character as u32).checked_sub(BITMAP_FONT_FIRST_CHARACTER as u32)?;
+    BITMAP_FONT_GLYPHS.get(index as usize)
+}
+
+fn rasterize_bitmap_glyph(character: char, pixel_scale: usize) -> Option<GlyphImage> {
+    // Characters the font doesn't have are drawn as question marks.
+    let columns = bitmap_glyph(character).or_else(|| bitmap_glyph('?')).unwrap();
+    if columns.iter().all(|&column| column == 0) {
+        return None;
+    }
+    let width = BITMAP_FONT_GLYPH_WIDTH * pixel_scale;
+    let height = BITMAP_FONT_GLYPH_HEIGHT * pixel_scale;
+    let mut coverage = vec![0; width * height];
+    for y in 0..height {
+        for x in 0..width {
+            if columns[x / pixel_scale] >> (y / pixel_scale) & 1 != 0 {
+                coverage[y * width + x] = 255;
+            }
+        }
+    }
+    let offset = [0., -((BITMAP_FONT_ASCENT * pixel_scale) as f32)];
+    Some(GlyphImage { width, height, coverage, offset })
+}
+
+#[derive(Debug)]
+pub enum FontError {
+    Io(PathBuf, std::io::Error),
+    /// The file isn't a font ab_glyph can read.
+    Invalid(PathBuf),
+}
+impl Display for FontError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(path, _) => write!(f, "Unable to read {}", path.display()),
+            Self::Invalid(path) => write!(f, "{} isn't a TrueType or OpenType font", path.display()),
+        }
+    }
+}
+impl Error for FontError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(_, error) => Some(error),
+            Self::Invalid(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_bitmap_font_scales_by_whole_pixels() {
+        let font = Font::bitmap(2);
+        assert_eq!(font.line_metrics(), LineMetrics { ascent: 14., descent: 2., line_gap: 2. });
+        assert_eq!(font.line_metrics().line_height(), 18.);
+        assert_eq!(font.advance('W'), 12.);
+        assert_eq!(font.kerning('A', 'V'), 0.);
+    }
+
+    #[test]
+    fn bitmap_glyphs_are_drawn_column_by_column() {
+        // 'I' is a serifed vertical bar: the middle column is full, the others only at the top and bottom.
+        let glyph = Font::bitmap(1).rasterize('I').unwrap();
+        assert_eq!((glyph.width, glyph.height, glyph.offset), (5, 8, [0., -7.]));
+        let column = |x: usize| (0..8).map(|y| glyph.coverage[y * 5 + x] / 255).collect::<Vec<_>>();
+        assert_eq!(column(0), [0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(column(1), [1, 0, 0, 0, 0, 0, 1, 0]);
+        assert_eq!(column(2), [1, 1, 1, 1, 1, 1, 1, 0]);
+
+        let scaled = Font::bitmap(3).rasterize('I').unwrap();
+        assert_eq!((scaled.width, scaled.height), (15, 24));
+        assert_eq!(scaled.coverage[3 * 15 + 6], 255);
+    }
+
+    #[test]
+    fn missing_glyphs_are_question_marks() {
+        let font = Font::bitmap(1);
+        assert!(font.has_glyph('~'));
+        assert!(!font.has_glyph('é'));
+        assert_eq!(font.rasterize('é'), font.rasterize('?'));
+        assert_eq!(font.rasterize(' '), None);
+    }
+
+    #[test]
+    fn files_that_arent_fonts_are_rejected() {
+        assert!(Font::from_ttf(b"not a font".to_vec(), 16.).is_none());
+    }
+}
@@ -0,0 +1,460 @@
+//! Frame pacing for the metal view
+//!
+//! The display link fires once per vertical sync of the main display.
+//! We coalesce those events down to the view's preferred frames per second
+//! (like MTKView's `preferredFramesPerSecond`),
+//! and run the simulation on a fixed timestep
+//! that is independent of how often we actually draw.
+//!
+//! Nothing in here talks to Cocoa, so the scheduler can be driven
+//! by a `SimulatedClock` instead of the real one.
+
+use std::cell::Cell;
+use std::time::Instant;
+
+/// A source of time, in seconds.
+pub trait Clock {
+    /// The current time, in seconds since some fixed point.
+    fn now(&self) -> f64;
+}
+
+/// A clock that reads the system's monotonic time.
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        SystemClock { start: Instant::now() }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> f64 {
+        self.start.elapsed().as_secs_f64()
+    }
+}
+
+/// A clock that only moves when we tell it to.
+pub struct SimulatedClock {
+    time: Cell<f64>,
+}
+
+impl SimulatedClock {
+    pub fn new() -> Self {
+        SimulatedClock { time: Cell::new(0.) }
+    }
+    /// Moves the clock forward by the given number of seconds.
+    pub fn advance(&self, seconds: f64) {
+        self.time.set(self.time.get() + seconds)
+    }
+    /// Sets the clock to the given time.
+    pub fn set(&self, time: f64) {
+        self.time.set(time)
+    }
+}
+
+impl Default for SimulatedClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SimulatedClock {
+    fn now(&self) -> f64 {
+        self.time.get()
+    }
+}
+
+/// The rate MTKView uses if you never set `preferredFramesPerSecond`.
+pub const DEFAULT_PREFERRED_FRAMES_PER_SECOND: u32 = 60;
+/// The refresh rate we assume if the display link can't tell us.
+pub const DEFAULT_REFRESH_RATE: f64 = 60.;
+/// The simulation rate we use unless told otherwise.
+pub const DEFAULT_UPDATES_PER_SECOND: f64 = 60.;
+/// The most simulation steps we'll run for a single drawn frame.
+///
+/// If we fall further behind than this, we drop the extra time
+/// rather than spiralling (each frame taking longer to catch up than the last).
+pub const DEFAULT_MAX_UPDATES_PER_FRAME: u32 = 8;
+/// Slack (in seconds) when comparing accumulated time against the time step,
+/// so that rounding errors don't cost us a step now and then.
+const TIME_EPSILON: f64 = 1e-9;
+
+/// Decides which vertical syncs we actually draw on.
+pub struct FramePacer {
+    preferred_frames_per_second: u32,
+    refresh_rate: f64,
+    last_frame_time: Option<f64>,
+    missed_vsyncs: u32,
+}
+
+impl FramePacer {
+    pub fn new(preferred_frames_per_second: u32, refresh_rate: f64) -> Self {
+        let mut pacer = FramePacer {
+            preferred_frames_per_second: DEFAULT_PREFERRED_FRAMES_PER_SECOND,
+            refresh_rate: DEFAULT_REFRESH_RATE,
+            last_frame_time: None,
+            missed_vsyncs: 0,
+        };
+        pacer.set_preferred_frames_per_second(preferred_frames_per_second);
+        pacer.set_refresh_rate(refresh_rate);
+        pacer
+    }
+
+    pub fn preferred_frames_per_second(&self) -> u32 {
+        self.preferred_frames_per_second
+    }
+    /// Sets the rate we'd like to draw at.
+    ///
+    /// As with MTKView, zero means "use the default".
+    /// We can never draw faster than the display refreshes.
+    pub fn set_preferred_frames_per_second(&mut self, preferred_frames_per_second: u32) {
+        self.preferred_frames_per_second = if preferred_frames_per_second == 0 {
+            DEFAULT_PREFERRED_FRAMES_PER_SECOND
+        } else {
+            preferred_frames_per_second
+        };
+    }
+
+    /// The display's refresh rate, in Hz.
+    pub fn refresh_rate(&self) -> f64 {
+        self.refresh_rate
+    }
+    pub fn set_refresh_rate(&mut self, refresh_rate: f64) {
+        self.refresh_rate = if refresh_rate > 0. { refresh_rate } else { DEFAULT_REFRESH_RATE };
+    }
+
+    /// The rate we will actually draw at:
+    /// the display rate divided by a whole number of vsyncs.
+    pub fn frames_per_second(&self) -> f64 {
+        self.refresh_rate / f64::from(self.vsyncs_per_frame())
+    }
+
+    /// How many vertical syncs go by for each frame we draw.
+    pub fn vsyncs_per_frame(&self) -> u32 {
+        let ratio = self.refresh_rate / f64::from(self.preferred_frames_per_second);
+        u32::max(1, ratio.round() as u32)
+    }
+
+    /// Called on every vertical sync.
+    /// Returns true if we should draw a frame on this one.
+    pub fn should_draw_frame(&mut self, now: f64) -> bool {
+        let refresh_period = 1. / self.refresh_rate;
+        let frame_period = refresh_period * f64::from(self.vsyncs_per_frame());
+        match self.last_frame_time {
+            // Allow half a refresh period of jitter in the timestamps
+            // so we don't skip a frame just because a vsync came in a little early.
+            Some(last_frame_time) if now - last_frame_time < frame_period - refresh_period / 2. => false,
+            Some(last_frame_time) => {
+                let elapsed_vsyncs = ((now - last_frame_time) / refresh_period).round() as u32;
+                self.missed_vsyncs = elapsed_vsyncs.saturating_sub(self.vsyncs_per_frame());
+                self.last_frame_time = Some(now);
+                true
+            }
+            None => {
+                self.missed_vsyncs = 0;
+                self.last_frame_time = Some(now);
+                true
+            }
+        }
+    }
+
+    /// How many vertical syncs went by, beyond those we meant to skip,
+    /// between the last two frames we drew.
+    pub fn missed_vsyncs(&self) -> u32 {
+        self.missed_vsyncs
+    }
+
+    /// Forget the last frame time, e.g. after the display link has been paused.
+    pub fn reset(&mut self) {
+        self.last_frame_time = None;
+        self.missed_vsyncs = 0;
+    }
+}
+
+/// Runs the simulation at a steady rate, whatever rate we draw at.
+///
+/// Elapsed time is added to an accumulator
+/// and consumed in whole `time_step`s.
+/// What's left over gives the interpolation alpha
+/// between the previous and current simulation states.
+pub struct FixedTimestep {
+    time_step: f64,
+    max_updates_per_frame: u32,
+    accumulator: f64,
+    last_time: Option<f64>,
+}
+
+impl FixedTimestep {
+    pub fn new(updates_per_second: f64) -> Self {
+        let mut timestep = FixedTimestep {
+            time_step: 1. / DEFAULT_UPDATES_PER_SECOND,
+            max_updates_per_frame: DEFAULT_MAX_UPDATES_PER_FRAME,
+            accumulator: 0.,
+            last_time: None,
+        };
+        timestep.set_updates_per_second(updates_per_second);
+        timestep
+    }
+
+    /// The length of each simulation step, in seconds.
+    pub fn time_step(&self) -> f64 {
+        self.time_step
+    }
+    pub fn updates_per_second(&self) -> f64 {
+        1. / self.time_step
+    }
+    pub fn set_updates_per_second(&mut self, updates_per_second: f64) {
+        let updates_per_second = if updates_per_second > 0. {
+            updates_per_second
+        } else {
+            DEFAULT_UPDATES_PER_SECOND
+        };
+        self.time_step = 1. / updates_per_second;
+    }
+    pub fn set_max_updates_per_frame(&mut self, max_updates_per_frame: u32) {
+        self.max_updates_per_frame = u32::max(1, max_updates_per_frame);
+    }
+
+    /// Adds the time since the last call to the accumulator
+    /// and returns the number of simulation steps to run.
+    ///
+    /// The first call only starts the clock.
+    pub fn advance(&mut self, now: f64) -> u32 {
+        let elapsed = match self.last_time {
+            Some(last_time) => f64::max(0., now - last_time),
+            None => 0.,
+        };
+        self.last_time = Some(now);
+        self.accumulator += elapsed;
+
+        let mut steps = 0;
+        while self.accumulator + TIME_EPSILON >= self.time_step {
+            if steps == self.max_updates_per_frame {
+                // We've fallen too far behind: drop the rest,
+                // including a remainder that's only short of a whole step by rounding.
+                self.accumulator %= self.time_step;
+                if self.accumulator + TIME_EPSILON >= self.time_step {
+                    self.accumulator = 0.;
+                }
+                break;
+            }
+            self.accumulator = f64::max(0., self.accumulator - self.time_step);
+            steps += 1;
+        }
+        steps
+    }
+
+    /// How far we are between the last simulation step and the next one,
+    /// from 0 up to (but not including) 1.
+    pub fn interpolation_alpha(&self) -> f64 {
+        self.accumulator / self.time_step
+    }
+
+    /// Forget the accumulated time, e.g. after the display link has been paused.
+    pub fn reset(&mut self) {
+        self.accumulator = 0.;
+        self.last_time = None;
+    }
+}
+
+/// What to do for a frame the scheduler has decided to draw.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct FrameTick {
+    /// The time of this frame, in seconds.
+    pub time: f64,
+    /// How many fixed simulation steps to run before drawing.
+    pub update_steps: u32,
+    /// The length of each simulation step, in seconds.
+    pub time_step: f64,
+    /// How far between simulation steps we are drawing.
+    pub interpolation_alpha: f64,
+    /// How many vertical syncs we missed since the last frame.
+    pub missed_vsyncs: u32,
+}
+
+/// Combines a `FramePacer` and a `FixedTimestep`
+/// and reads the time from a `Clock`.
+pub struct FrameScheduler<C: Clock> {
+    clock: C,
+    pub pacer: FramePacer,
+    pub timestep: FixedTimestep,
+}
+
+impl<C: Clock> FrameScheduler<C> {
+    pub fn new(clock: C) -> Self {
+        FrameScheduler {
+            clock,
+            pacer: FramePacer::new(DEFAULT_PREFERRED_FRAMES_PER_SECOND, DEFAULT_REFRESH_RATE),
+            timestep: FixedTimestep::new(DEFAULT_UPDATES_PER_SECOND),
+        }
+    }
+
+    /// The clock the scheduler reads the time from.
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    /// Called on every vertical sync.
+    ///
+    /// Returns `None` if this vsync is to be skipped,
+    /// otherwise the updates to run and the alpha to draw with.
+    pub fn on_vsync(&mut self) -> Option<FrameTick> {
+        let now = self.clock.now();
+        if !self.pacer.should_draw_frame(now) {
+            return None;
+        }
+        let update_steps = self.timestep.advance(now);
+        Some(FrameTick {
+            time: now,
+            update_steps,
+            time_step: self.timestep.time_step(),
+            interpolation_alpha: self.timestep.interpolation_alpha(),
+            missed_vsyncs: self.pacer.missed_vsyncs(),
+        })
+    }
+
+    /// Forget all timing history, e.g. after the display link has been paused.
+    pub fn reset(&mut self) {
+        self.pacer.reset();
+        self.timestep.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs the scheduler over `vsyncs` vertical syncs of a `refresh_rate` display,
+    /// returning the ticks it drew.
+    fn run(scheduler: &mut FrameScheduler<SimulatedClock>, refresh_rate: f64, vsyncs: u32) -> Vec<FrameTick> {
+        let start = scheduler.clock().now();
+        (0..vsyncs)
+            .filter_map(|vsync| {
+                scheduler.clock().set(start + f64::from(vsync) / refresh_rate);
+                scheduler.on_vsync()
+            })
+            .collect()
+    }
+
+    fn scheduler(preferred_frames_per_second: u32, refresh_rate: f64) -> FrameScheduler<SimulatedClock> {
+        let mut scheduler = FrameScheduler::new(SimulatedClock::new());
+        scheduler.pacer = FramePacer::new(preferred_frames_per_second, refresh_rate);
+        scheduler
+    }
+
+    #[test]
+    fn updates_at_60_hz_while_drawing_at_30_hz() {
+        let mut scheduler = scheduler(30, 60.);
+        // One second and one vsync, so the last frame lands on the second.
+        let ticks = run(&mut scheduler, 60., 61);
+
+        assert_eq!(ticks.len(), 31);
+        assert_eq!(ticks[0].update_steps, 0);
+        assert!(ticks[1..].iter().all(|tick| tick.update_steps == 2), "{:?}", ticks);
+        assert_eq!(ticks.iter().map(|tick| tick.update_steps).sum::<u32>(), 60);
+        assert!(ticks.iter().all(|tick| tick.missed_vsyncs == 0));
+    }
+
+    #[test]
+    fn updates_at_60_hz_while_drawing_at_120_hz() {
+        let mut scheduler = scheduler(120, 120.);
+        let ticks = run(&mut scheduler, 120., 121);
+
+        assert_eq!(ticks.len(), 121);
+        // Every other frame runs a step; the ones between are drawn half way between steps.
+        assert_eq!(ticks.iter().map(|tick| tick.update_steps).sum::<u32>(), 60);
+        for tick in &ticks[1..] {
+            match tick.update_steps {
+                0 => assert!((tick.interpolation_alpha - 0.5).abs() < 1e-6, "{:?}", tick),
+                1 => assert!(tick.interpolation_alpha < 1e-6, "{:?}", tick),
+                _ => panic!("{:?} ran too many steps", tick),
+            }
+        }
+    }
+
+    #[test]
+    fn pacer_coalesces_vsyncs_down_to_the_preferred_rate() {
+        let mut pacer = FramePacer::new(30, 120.);
+        assert_eq!(pacer.vsyncs_per_frame(), 4);
+        assert_eq!(pacer.frames_per_second(), 30.);
+
+        let drawn: Vec<u32> = (0..12).filter(|&vsync| pacer.should_draw_frame(f64::from(vsync) / 120.)).collect();
+        assert_eq!(drawn, vec![0, 4, 8]);
+
+        // A vsync that comes in a little early still counts.
+        assert!(pacer.should_draw_frame(11.8 / 120.));
+        assert_eq!(pacer.missed_vsyncs(), 0);
+
+        // Skipping two vsyncs' worth of frame reports them as missed.
+        assert!(pacer.should_draw_frame(17.8 / 120.));
+        assert_eq!(pacer.missed_vsyncs(), 2);
+    }
+
+    #[test]
+    fn pacer_never_draws_faster_than_the_display() {
+        let mut pacer = FramePacer::new(240, 60.);
+        assert_eq!(pacer.vsyncs_per_frame(), 1);
+        assert_eq!(pacer.frames_per_second(), 60.);
+        assert!((0..10).all(|vsync| pacer.should_draw_frame(f64::from(vsync) / 60.)));
+
+        pacer.set_preferred_frames_per_second(0);
+        assert_eq!(pacer.preferred_frames_per_second(), DEFAULT_PREFERRED_FRAMES_PER_SECOND);
+        pacer.set_refresh_rate(0.);
+        assert_eq!(pacer.refresh_rate(), DEFAULT_REFRESH_RATE);
+    }
+
+    #[test]
+    fn timestep_clamps_the_updates_after_a_stall() {
+        let clock = SimulatedClock::new();
+        let mut timestep = FixedTimestep::new(60.);
+        assert_eq!(timestep.advance(clock.now()), 0);
+
+        // A whole second goes by: we'd owe 60 steps, but only run the maximum and drop the rest.
+        clock.advance(1.);
+        assert_eq!(timestep.advance(clock.now()), DEFAULT_MAX_UPDATES_PER_FRAME);
+        assert!(timestep.interpolation_alpha() < 1.);
+
+        // After that, we're back to one step a frame.
+        clock.advance(1. / 60.);
+        assert_eq!(timestep.advance(clock.now()), 1);
+
+        timestep.set_max_updates_per_frame(0);
+        clock.advance(0.5);
+        assert_eq!(timestep.advance(clock.now()), 1);
+    }
+
+    #[test]
+    fn interpolation_alpha_stays_below_one() {
+        let clock = SimulatedClock::new();
+        let mut timestep = FixedTimestep::new(60.);
+        // Uneven frame times, some shorter and some longer than a step.
+        let frame_times = [0.003, 1. / 60., 0.021, 0.0001, 0.05, 1. / 120., 0.2, 0.016_66, 0.033_33];
+        for (frame, frame_time) in frame_times.iter().cycle().take(200).enumerate() {
+            clock.advance(*frame_time);
+            timestep.advance(clock.now());
+            let alpha = timestep.interpolation_alpha();
+            assert!((0. ..1.).contains(&alpha), "frame {}: alpha {}", frame, alpha);
+        }
+    }
+
+    #[test]
+    fn reset_forgets_the_time_between() {
+        let mut scheduler = scheduler(60, 60.);
+        assert_eq!(run(&mut scheduler, 60., 2).len(), 2);
+
+        scheduler.clock().advance(10.);
+        scheduler.reset();
+        let tick = scheduler.on_vsync().unwrap();
+        assert_eq!(tick.update_steps, 0);
+        assert_eq!(tick.missed_vsyncs, 0);
+        assert_eq!(tick.interpolation_alpha, 0.);
+    }
+}
@@ -0,0 +1,367 @@
+//! Frame statistics for the render loop
+//!
+//! The metal view tells us when each frame starts (and how many vsyncs
+//! it missed getting there), the renderer tells us how long it spent
+//! encoding on the CPU, and the command buffer's completion handler
+//! tells us when the GPU started and finished.
+//!
+//! We keep the last `window_size` frames and summarise them on demand.
+
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+
+/// The number of frames we summarise unless told otherwise
+/// (two seconds at 60 fps).
+pub const DEFAULT_WINDOW_SIZE: usize = 120;
+
+/// What we know about a single frame.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct FrameSample {
+    /// The frame number, counting from zero.
+    pub frame_index: u64,
+    /// The time the frame started, in seconds.
+    pub time: f64,
+    /// The time since the previous frame started, in seconds.
+    pub frame_interval: Option<f64>,
+    /// The number of vertical syncs we missed before this frame.
+    pub missed_vsyncs: u32,
+    /// How long the CPU spent encoding commands, in seconds.
+    pub cpu_encode_time: Option<f64>,
+    /// How long the GPU spent executing the command buffer, in seconds.
+    pub gpu_time: Option<f64>,
+}
+
+/// A rolling window of frame samples.
+pub struct FrameStatistics {
+    window_size: usize,
+    samples: VecDeque<FrameSample>,
+    next_frame_index: u64,
+    total_missed_vsyncs: u64,
+}
+
+impl FrameStatistics {
+    pub fn new(window_size: usize) -> Self {
+        let window_size = usize::max(1, window_size);
+        FrameStatistics {
+            window_size,
+            samples: VecDeque::with_capacity(window_size),
+            next_frame_index: 0,
+            total_missed_vsyncs: 0,
+        }
+    }
+
+    /// Records the start of a new frame and returns its index,
+    /// to be passed back in with the CPU and GPU timings.
+    pub fn begin_frame(&mut self, time: f64, missed_vsyncs: u32) -> u64 {
+        let frame_index = self.next_frame_index;
+        self.next_frame_index += 1;
+        self.total_missed_vsyncs += u64::from(missed_vsyncs);
+
+        let frame_interval = self.samples.back().map(|previous| time - previous.time);
+        if self.samples.len() == self.window_size {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(FrameSample {
+            frame_index,
+            time,
+            frame_interval,
+            missed_vsyncs,
+            cpu_encode_time: None,
+            gpu_time: None,
+        });
+        frame_index
+    }
+
+    /// Records how long the CPU spent encoding the given frame.
+    ///
+    /// Ignored if the frame has already left the window.
+    pub fn record_cpu_encode_time(&mut self, frame_index: u64, seconds: f64) {
+        if let Some(sample) = self.sample_mut(frame_index) {
+            sample.cpu_encode_time = Some(seconds)
+        }
+    }
+
+    /// Records the command buffer's `GPUStartTime` and `GPUEndTime` for the given frame.
+    ///
+    /// Ignored if the frame has already left the window,
+    /// or if the times are not valid (Metal reports zero for buffers that never ran).
+    pub fn record_gpu_times(&mut self, frame_index: u64, gpu_start_time: f64, gpu_end_time: f64) {
+        if gpu_start_time <= 0. || gpu_end_time < gpu_start_time {
+            return;
+        }
+        if let Some(sample) = self.sample_mut(frame_index) {
+            sample.gpu_time = Some(gpu_end_time - gpu_start_time)
+        }
+    }
+
+    /// The frames currently in the window, oldest first.
+    pub fn samples(&self) -> impl Iterator<Item = &FrameSample> {
+        self.samples.iter()
+    }
+
+    /// Summarises the frames currently in the window.
+    pub fn summary(&self) -> FrameStatsSummary {
+        let frame_times: Vec<f64> = self.samples.iter().filter_map(|sample| sample.frame_interval).collect();
+        let cpu_encode_times: Vec<f64> = self.samples.iter().filter_map(|sample| sample.cpu_encode_time).collect();
+        let gpu_times: Vec<f64> = self.samples.iter().filter_map(|sample| sample.gpu_time).collect();
+
+        let frames_per_second = match (self.samples.front(), self.samples.back()) {
+            (Some(first), Some(last)) if last.time > first.time =>
+                (self.samples.len() - 1) as f64 / (last.time - first.time),
+            _ => 0.,
+        };
+
+        FrameStatsSummary {
+            frame_count: self.next_frame_index,
+            window_frames: self.samples.len(),
+            frames_per_second,
+            frame_time: Percentiles::from_samples(frame_times),
+            cpu_encode_time: Percentiles::from_samples(cpu_encode_times),
+            gpu_time: Percentiles::from_samples(gpu_times),
+            missed_vsyncs: self.samples.iter().map(|sample| u64::from(sample.missed_vsyncs)).sum(),
+            total_missed_vsyncs: self.total_missed_vsyncs,
+        }
+    }
+
+    fn sample_mut(&mut self, frame_index: u64) -> Option<&mut FrameSample> {
+        // Samples are in frame order, so we can work out where it is.
+        let first_index = self.samples.front()?.frame_index;
+        if frame_index < first_index {
+            return None;
+        }
+        self.samples.get_mut((frame_index - first_index) as usize)
+    }
+}
+
+impl Default for FrameStatistics {
+    fn default() -> Self {
+        Self::new(DEFAULT_WINDOW_SIZE)
+    }
+}
+
+/// Order statistics over one measurement, in seconds.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Percentiles {
+    pub min: f64,
+    pub mean: f64,
+    pub p50: f64,
+    pub p95: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+impl Percentiles {
+    /// Returns `None` if there are no samples.
+    pub fn from_samples(mut samples: Vec<f64>) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+        samples.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        let mean = samples.iter().sum::<f64>() / samples.len() as f64;
+        Some(Percentiles {
+            min: samples[0],
+            mean,
+            p50: percentile(&samples, 50.),
+            p95: percentile(&samples, 95.),
+            p99: percentile(&samples, 99.),
+            max: samples[samples.len() - 1],
+        })
+    }
+}
+
+/// The nearest-rank percentile of some sorted samples.
+fn percentile(sorted_samples: &[f64], percent: f64) -> f64 {
+    let rank = (percent / 100. * sorted_samples.len() as f64).ceil() as usize;
+    sorted_samples[usize::min(sorted_samples.len(), usize::max(1, rank)) - 1]
+}
+
+/// A snapshot of the frame statistics.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct FrameStatsSummary {
+    /// The number of frames since we started.
+    pub frame_count: u64,
+    /// The number of frames in the window.
+    pub window_frames: usize,
+    /// The average frame rate over the window.
+    pub frames_per_second: f64,
+    /// Time between the start of one frame and the next.
+    pub frame_time: Option<Percentiles>,
+    /// Time spent encoding commands on the CPU.
+    pub cpu_encode_time: Option<Percentiles>,
+    /// Time spent executing commands on the GPU.
+    pub gpu_time: Option<Percentiles>,
+    /// Vertical syncs missed within the window.
+    pub missed_vsyncs: u64,
+    /// Vertical syncs missed since we started.
+    pub total_missed_vsyncs: u64,
+}
+
+/// Formats the summary as a single `key=value` log line, times in milliseconds.
+impl Display for FrameStatsSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "frame_stats frames={} window={} fps={:.1}",
+               self.frame_count, self.window_frames, self.frames_per_second)?;
+        write_percentiles(f, "frame_ms", &self.frame_time)?;
+        write_percentiles(f, "cpu_ms", &self.cpu_encode_time)?;
+        write_percentiles(f, "gpu_ms", &self.gpu_time)?;
+        write!(f, " missed_vsyncs={} total_missed_vsyncs={}", self.missed_vsyncs, self.total_missed_vsyncs)
+    }
+}
+
+fn write_percentiles(f: &mut Formatter<'_>, name: &str, percentiles: &Option<Percentiles>) -> std::fmt::Result {
+    match percentiles {
+        Some(p) => write!(f, " {0}.mean={1:.3} {0}.p50={2:.3} {0}.p95={3:.3} {0}.p99={4:.3} {0}.max={5:.3}",
+                          name,
+                          p.mean * 1000.,
+                          p.p50 * 1000.,
+                          p.p95 * 1000.,
+                          p.p99 * 1000.,
+                          p.max * 1000.),
+        None => Ok(()),
+    }
+}
+
+/// The seconds between log lines that HELLO_TRIANGLE_FRAME_STATS asks for, if it's set.
+pub fn log_interval_from_environment() -> Option<f64> {
+    let interval = std::env::var("HELLO_TRIANGLE_FRAME_STATS").ok()?;
+    let parsed = parse_log_interval(&interval);
+    if parsed.is_none() {
+        println!("HELLO_TRIANGLE_FRAME_STATS should be a number of seconds more than 0, not {:?}", interval);
+    }
+    parsed
+}
+
+/// A log interval, if it's a number of seconds more than zero.
+fn parse_log_interval(text: &str) -> Option<f64> {
+    text.trim().parse().ok().filter(|&seconds: &f64| seconds > 0. && seconds.is_finite())
+}
+
+/// Decides when it's time to print another log line.
+pub struct FrameStatsLogger {
+    interval: f64,
+    last_log_time: Option<f64>,
+}
+
+impl FrameStatsLogger {
+    /// Creates a logger that prints at most once every `interval` seconds.
+    pub fn new(interval: f64) -> Self {
+        FrameStatsLogger {
+            interval,
+            last_log_time: None,
+        }
+    }
+
+    /// Prints the statistics if at least `interval` seconds have passed since the last time.
+    pub fn log_if_due(&mut self, now: f64, statistics: &FrameStatistics) {
+        if self.is_due(now) {
+            println!("{}", statistics.summary());
+        }
+    }
+
+    /// Whether it's time for another line, and if so, starts waiting for the next one.
+    pub fn is_due(&mut self, now: f64) -> bool {
+        match self.last_log_time {
+            Some(last_log_time) if now - last_log_time < self.interval => false,
+            Some(_) => {
+                self.last_log_time = Some(now);
+                true
+            },
+            // Wait a full interval before the first line, so the window has something in it.
+            None => {
+                self.last_log_time = Some(now);
+                false
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_are_nearest_rank() {
+        let hundred: Vec<f64> = (1..=100).map(f64::from).collect();
+        assert_eq!((percentile(&hundred, 50.), percentile(&hundred, 95.), percentile(&hundred, 99.)), (50., 95., 99.));
+        // With fewer samples than percent steps, ranks round up.
+        let ten: Vec<f64> = (1..=10).map(f64::from).collect();
+        assert_eq!((percentile(&ten, 50.), percentile(&ten, 95.), percentile(&ten, 99.)), (5., 10., 10.));
+        assert_eq!(percentile(&ten, 0.), 1.);
+        assert_eq!(percentile(&ten, 100.), 10.);
+        assert_eq!(percentile(&[7.], 99.), 7.);
+    }
+
+    #[test]
+    fn percentiles_sort_their_samples() {
+        assert_eq!(Percentiles::from_samples(Vec::new()), None);
+        let percentiles = Percentiles::from_samples(vec![3., 1., 2., 6.]).unwrap();
+        assert_eq!(percentiles, Percentiles { min: 1., mean: 3., p50: 2., p95: 6., p99: 6., max: 6. });
+    }
+
+    #[test]
+    fn old_frames_leave_the_window() {
+        let mut statistics = FrameStatistics::new(3);
+        for frame in 0..5 {
+            assert_eq!(statistics.begin_frame(f64::from(frame) * 0.5, frame), u64::from(frame));
+        }
+        let indices: Vec<u64> = statistics.samples().map(|sample| sample.frame_index).collect();
+        assert_eq!(indices, [2, 3, 4]);
+        let summary = statistics.summary();
+        assert_eq!((summary.frame_count, summary.window_frames), (5, 3));
+        assert_eq!((summary.missed_vsyncs, summary.total_missed_vsyncs), (2 + 3 + 4, 1 + 2 + 3 + 4));
+        assert_eq!(summary.frames_per_second, 2.);
+        assert_eq!(summary.frame_time.unwrap().mean, 0.5);
+        // The window always has room for at least one frame.
+        let mut single = FrameStatistics::new(0);
+        single.begin_frame(0., 0);
+        single.begin_frame(1., 0);
+        assert_eq!(single.samples().count(), 1);
+        assert_eq!(single.summary().frames_per_second, 0.);
+    }
+
+    #[test]
+    fn timings_for_frames_outside_the_window_are_ignored() {
+        let mut statistics = FrameStatistics::new(2);
+        for frame in 0..3 {
+            statistics.begin_frame(f64::from(frame), 0);
+        }
+        statistics.record_cpu_encode_time(0, 1.);
+        statistics.record_cpu_encode_time(2, 0.25);
+        statistics.record_cpu_encode_time(7, 1.);
+        let cpu_times: Vec<Option<f64>> = statistics.samples().map(|sample| sample.cpu_encode_time).collect();
+        assert_eq!(cpu_times, [None, Some(0.25)]);
+    }
+
+    #[test]
+    fn gpu_times_need_a_start_and_an_end_after_it() {
+        let mut statistics = FrameStatistics::new(4);
+        for frame in 0..4 {
+            statistics.begin_frame(f64::from(frame), 0);
+        }
+        statistics.record_gpu_times(0, 0., 2.);
+        statistics.record_gpu_times(1, 3., 2.);
+        statistics.record_gpu_times(2, 10., 10.5);
+        statistics.record_gpu_times(3, 10., 10.);
+        let gpu_times: Vec<Option<f64>> = statistics.samples().map(|sample| sample.gpu_time).collect();
+        assert_eq!(gpu_times, [None, None, Some(0.5), Some(0.)]);
+    }
+
+    #[test]
+    fn log_intervals_are_positive_numbers_of_seconds() {
+        assert_eq!(parse_log_interval("2"), Some(2.));
+        assert_eq!(parse_log_interval(" 0.5 "), Some(0.5));
+        for invalid in ["", "0", "-1", "1s", "inf", "NaN"].iter() {
+            assert_eq!(parse_log_interval(invalid), None, "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn the_first_line_waits_a_whole_interval() {
+        let mut logger = FrameStatsLogger::new(2.);
+        assert!(!logger.is_due(10.));
+        assert!(!logger.is_due(11.9));
+        assert!(logger.is_due(12.));
+        assert!(!logger.is_due(13.));
+        assert!(logger.is_due(14.5));
+    }
+}
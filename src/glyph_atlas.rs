@@ -0,0 +1,295 @@
+//! Glyph atlases
+//!
+//! A `GlyphAtlas` packs a font's glyphs into one image as signed distance fields:
+//! each texel's alpha says how far it is from the glyph's edge, with 0.5 on the edge,
+//! so `textFragmentShader` can draw sharp edges however much the text is scaled.
+//! Glyphs are packed onto shelves, tallest first, in a deterministic order,
+//! so the same font and characters always give the same atlas.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::{Display, Formatter};
+use std::error::Error;
+use crate::font::{Font, GlyphImage};
+use crate::image::{Image, TextureData};
+
+/// How many texels the distance field reaches out from each glyph's edge.
+pub static DISTANCE_FIELD_SPREAD: usize = 4;
+/// The character drawn for characters the atlas doesn't have.
+pub static REPLACEMENT_CHARACTER: char = '?';
+/// Empty texels between glyphs, so filtering doesn't pick up a neighbour.
+static GLYPH_PADDING: usize = 1;
+static INITIAL_ATLAS_SIZE: usize = 64;
+static MAX_ATLAS_SIZE: usize = 4096;
+
+/// Where a glyph is in the atlas, and where to draw it.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct AtlasGlyph {
+    /// The top-left texel of the glyph's distance field.
+    pub origin: [usize; 2],
+    pub size: [usize; 2],
+    /// From the pen position on the baseline to the top-left corner of the quad, with y down.
+    pub offset: [f32; 2],
+}
+
+/// Places rectangles on horizontal shelves, each as tall as the first rectangle put on it.
+#[derive(Debug, Clone)]
+pub struct ShelfPacker {
+    width: usize,
+    height: usize,
+    /// The top, height and used width of each shelf.
+    shelves: Vec<(usize, usize, usize)>,
+}
+
+impl ShelfPacker {
+    pub fn new(width: usize, height: usize) -> Self {
+        ShelfPacker { width, height, shelves: Vec::new() }
+    }
+
+    /// The top-left corner of a free space for a rectangle, or `None` if there's no room.
+    pub fn pack(&mut self, width: usize, height: usize) -> Option<[usize; 2]> {
+        if width > self.width {
+            return None;
+        }
+        let packer_width = self.width;
+        if let Some(shelf) = self.shelves.iter_mut()
+            .find(|(_, shelf_height, used)| height <= *shelf_height && used + width <= packer_width) {
+            let origin = [shelf.2, shelf.0];
+            shelf.2 += width;
+            return Some(origin);
+        }
+        let top = self.shelves.last().map_or(0, |(top, shelf_height, _)| top + shelf_height);
+        if top + height > self.height {
+            return None;
+        }
+        self.shelves.push((top, height, width));
+        Some([0, top])
+    }
+}
+
+/// The glyphs for a set of characters, packed into one image.
+#[derive(Debug, Clone)]
+pub struct GlyphAtlas {
+    image: Image,
+    /// `None` for characters with nothing to draw, like spaces.
+    glyphs: HashMap<char, Option<AtlasGlyph>>,
+}
+
+impl GlyphAtlas {
+    /// Rasterizes the characters (and the replacement character) and packs them.
+    pub fn new(font: &Font, characters: &str) -> Result<Self, AtlasError> {
+        let characters: BTreeSet<char> = characters.chars()
+            .filter(|character| !character.is_control())
+            .chain(std::iter::once(REPLACEMENT_CHARACTER))
+            .collect();
+        let mut distance_fields: Vec<(char, GlyphImage)> = Vec::new();
+        let mut glyphs = HashMap::new();
+        for character in characters {
+            match font.rasterize(character) {
+                Some(glyph_image) => distance_fields.push((character, distance_field(&glyph_image, DISTANCE_FIELD_SPREAD))),
+                None => {
+                    glyphs.insert(character, None);
+                }
+            }
+        }
+        // Tallest first, so each shelf is filled with glyphs of about its height.
+        distance_fields.sort_by(|(a, a_image), (b, b_image)| b_image.height.cmp(&a_image.height).then(a.cmp(b)));
+
+        let (mut width, mut height) = (INITIAL_ATLAS_SIZE, INITIAL_ATLAS_SIZE);
+        let origins = loop {
+            if let Some(origins) = pack_glyphs(&distance_fields, width, height) {
+                break origins;
+            }
+            if width == MAX_ATLAS_SIZE && height == MAX_ATLAS_SIZE {
+                return Err(AtlasError::TooLarge { glyph_count: distance_fields.len() });
+            }
+            // Grow one side at a time, to waste as little space as possible.
+            if height < width {
+                height *= 2;
+            } else {
+                width *= 2;
+            }
+        };
+
+        // White, with the distance in the alpha channel, so it multiplies with the text color.
+        let mut pixels: Vec<u8> = [255, 255, 255, 0].iter().copied().cycle().take(width * height * 4).collect();
+        for ((character, glyph_image), origin) in distance_fields.iter().zip(origins) {
+            for y in 0..glyph_image.height {
+                for x in 0..glyph_image.width {
+                    let index = ((origin[1] + y) * width + origin[0] + x) * 4 + 3;
+                    pixels[index] = glyph_image.coverage[y * glyph_image.width + x];
+                }
+            }
+            glyphs.insert(*character, Some(AtlasGlyph {
+                origin,
+                size: [glyph_image.width, glyph_image.height],
+                offset: glyph_image.offset,
+            }));
+        }
+        Ok(GlyphAtlas { image: Image::new(width, height, pixels).unwrap(), glyphs })
+    }
+
+    /// Where to find the character's glyph, falling back to the replacement character's,
+    /// or `None` if there's nothing to draw.
+    pub fn glyph(&self, character: char) -> Option<&AtlasGlyph> {
+        match self.glyphs.get(&character) {
+            Some(glyph) => glyph.as_ref(),
+            None => self.glyphs.get(&REPLACEMENT_CHARACTER).and_then(Option::as_ref),
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.image.width()
+    }
+    pub fn height(&self) -> usize {
+        self.image.height()
+    }
+    pub fn image(&self) -> &Image {
+        &self.image
+    }
+
+    /// The atlas as a texture. Distance fields aren't mipmapped, as averaging distances blurs the edges.
+    pub fn texture_data(&self) -> TextureData {
+        TextureData::without_mipmaps(self.image.clone(), false)
+    }
+}
+
+/// The origin of each image, or `None` if they don't all fit.
+fn pack_glyphs(images: &[(char, GlyphImage)], width: usize, height: usize) -> Option<Vec<[usize; 2]>> {
+    let mut packer = ShelfPacker::new(width, height);
+    images.iter()
+        .map(|(_, image)| packer.pack(image.width + GLYPH_PADDING, image.height + GLYPH_PADDING))
+        .collect()
+}
+
+/// Turns a glyph's coverage into a signed distance field, `spread` texels bigger on every side.
+///
+/// Texels at least half covered are inside. Each texel holds 0.5 plus its distance to the edge
+/// (positive inside, negative outside) over twice the spread, so distances from `-spread` to `spread` fit.
+pub fn distance_field(glyph_image: &GlyphImage, spread: usize) -> GlyphImage {
+    let (width, height) = (glyph_image.width, glyph_image.height);
+    let inside = |x: i64, y: i64| {
+        x >= 0 && y >= 0 && (x as usize) < width && (y as usize) < height
+            && glyph_image.coverage[y as usize * width + x as usize] >= 128
+    };
+    let field_width = width + spread * 2;
+    let field_height = height + spread * 2;
+    let reach = spread as i64 + 1;
+    let mut coverage = Vec::with_capacity(field_width * field_height);
+    for field_y in 0..field_height {
+        for field_x in 0..field_width {
+            let (x, y) = (field_x as i64 - spread as i64, field_y as i64 - spread as i64);
+            let is_inside = inside(x, y);
+            // The nearest texel on the other side of the edge; the edge is half a texel before it.
+            let mut nearest_squared = i64::MAX;
+            for dy in -reach..=reach {
+                for dx in -reach..=reach {
+                    if inside(x + dx, y + dy) != is_inside {
+                        nearest_squared = nearest_squared.min(dx * dx + dy * dy);
+                    }
+                }
+            }
+            let distance = if nearest_squared == i64::MAX {
+                spread as f32
+            } else {
+                ((nearest_squared as f32).sqrt() - 0.5).min(spread as f32)
+            };
+            let signed_distance = if is_inside { distance } else { -distance };
+            let value = 0.5 + signed_distance / (spread as f32 * 2.);
+            coverage.push((value.clamp(0., 1.) * 255.).round() as u8);
+        }
+    }
+    GlyphImage {
+        width: field_width,
+        height: field_height,
+        coverage,
+        offset: [glyph_image.offset[0] - spread as f32, glyph_image.offset[1] - spread as f32],
+    }
+}
+
+#[derive(Debug)]
+pub enum AtlasError {
+    /// The glyphs don't fit in the largest atlas we make.
+    TooLarge { glyph_count: usize },
+}
+impl Display for AtlasError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TooLarge { glyph_count } =>
+                write!(f, "{} glyphs don't fit in a {}×{} atlas", glyph_count, MAX_ATLAS_SIZE, MAX_ATLAS_SIZE),
+        }
+    }
+}
+impl Error for AtlasError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shelves_fill_left_to_right_then_top_to_bottom() {
+        let mut packer = ShelfPacker::new(10, 10);
+        assert_eq!(packer.pack(4, 5), Some([0, 0]));
+        // Shorter rectangles go on a shelf they fit on.
+        assert_eq!(packer.pack(4, 3), Some([4, 0]));
+        // Too wide for what's left of the first shelf, so a new one goes below it.
+        assert_eq!(packer.pack(4, 2), Some([0, 5]));
+        assert_eq!(packer.pack(6, 2), Some([4, 5]));
+        assert_eq!(packer.pack(11, 1), None);
+        // Too tall for either shelf, and for a new one.
+        assert_eq!(packer.pack(3, 4), None);
+        assert_eq!(packer.pack(3, 3), Some([0, 7]));
+    }
+
+    #[test]
+    fn glyphs_are_packed_without_overlapping() {
+        let atlas = GlyphAtlas::new(&Font::bitmap(2), "Hello, world!").unwrap();
+        // Ten glyphs don't fit in 64 by 64, so it grows wider first.
+        assert_eq!((atlas.width(), atlas.height()), (128, 64));
+        let glyphs: Vec<AtlasGlyph> = "Helo,wrd!?".chars().map(|character| *atlas.glyph(character).unwrap()).collect();
+        for (index, a) in glyphs.iter().enumerate() {
+            assert!(a.origin[0] + a.size[0] <= atlas.width() && a.origin[1] + a.size[1] <= atlas.height());
+            // Each glyph's distance field is the bitmap glyph plus the spread on every side.
+            assert_eq!(a.size, [10 + DISTANCE_FIELD_SPREAD * 2, 16 + DISTANCE_FIELD_SPREAD * 2]);
+            for b in &glyphs[index + 1..] {
+                let apart = a.origin[0] + a.size[0] < b.origin[0] || b.origin[0] + b.size[0] < a.origin[0]
+                    || a.origin[1] + a.size[1] < b.origin[1] || b.origin[1] + b.size[1] < a.origin[1];
+                assert!(apart, "{:?} overlaps {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn atlases_grow_until_the_glyphs_fit() {
+        let characters: String = (' '..='~').collect();
+        let atlas = GlyphAtlas::new(&Font::bitmap(2), &characters).unwrap();
+        assert_eq!((atlas.width(), atlas.height()), (256, 256));
+        // The same font and characters always give the same atlas.
+        assert_eq!(GlyphAtlas::new(&Font::bitmap(2), &characters).unwrap().image(), atlas.image());
+    }
+
+    #[test]
+    fn spaces_have_nothing_to_draw_and_unknown_characters_are_replaced() {
+        let atlas = GlyphAtlas::new(&Font::bitmap(1), "a b").unwrap();
+        assert_eq!(atlas.glyph(' '), None);
+        assert_eq!(atlas.glyph('z'), atlas.glyph(REPLACEMENT_CHARACTER));
+        assert!(atlas.glyph('z').is_some());
+    }
+
+    #[test]
+    fn distance_fields_are_half_at_the_edge() {
+        // A three by three square in a five by five image.
+        let mut coverage = vec![0; 25];
+        for y in 1..4 {
+            for x in 1..4 {
+                coverage[y * 5 + x] = 255;
+            }
+        }
+        let glyph = GlyphImage { width: 5, height: 5, coverage, offset: [1., -4.] };
+        let field = distance_field(&glyph, 2);
+        assert_eq!((field.width, field.height, field.offset), (9, 9, [-1., -6.]));
+        let row: Vec<u8> = (0..9).map(|x| field.coverage[4 * 9 + x]).collect();
+        // Clamped to the spread far outside, half a texel either side of the edge,
+        // and 1.5 texels in at the centre.
+        assert_eq!(row, [0, 32, 96, 159, 223, 159, 96, 32, 0]);
+    }
+}
@@ -0,0 +1,142 @@
+//! Recording without a window or a GPU
+//!
+//! With HELLO_TRIANGLE_HEADLESS set as well as HELLO_TRIANGLE_RECORD, the app doesn't start at all:
+//! the software rasterizer draws each frame of the recording instead.
+//! It draws the scene, the particles and the post-processing effects the environment asks for,
+//! as the GPU does, but not the instances, textures, vertex labels or the HUD.
+
+use std::path::PathBuf;
+use crate::scene::Scene;
+use crate::scene_file::SceneDescription;
+use crate::shader_types::{AAPLVertex, AAPLParticle};
+use crate::particles::{ParticleSettings, ParticleSystem, update_particles};
+use crate::post_process::{PostEffect, post_effects_from_environment};
+use crate::blend::BlendMode;
+use crate::image::Image;
+use crate::frame_pacing::DEFAULT_UPDATES_PER_SECOND;
+use crate::recording::{RecordingSettings, RecordingTimeline, RecordingWriter, RecordingError};
+use crate::software_rasterizer::{Framebuffer, draw_scene, draw_particles, apply_post_effects};
+use crate::error_chain::format_error_chain;
+
+/// How big the frames are, unless HELLO_TRIANGLE_RECORD_SIZE says otherwise.
+pub static DEFAULT_HEADLESS_SIZE: [usize; 2] = [800, 600];
+/// As many samples per pixel as the view has, unless HELLO_TRIANGLE_SAMPLE_COUNT says otherwise.
+static DEFAULT_HEADLESS_SAMPLE_COUNT: usize = 4;
+
+/// Everything a headless recording draws, and the particles' state between frames.
+pub struct HeadlessScene {
+    pub scene: Scene<AAPLVertex>,
+    pub clear_color: [f32; 4],
+    pub sample_count: usize,
+    pub post_effects: Vec<PostEffect>,
+    particles: Option<(ParticleSystem, Vec<AAPLParticle>)>,
+}
+
+impl HeadlessScene {
+    /// The scene in a description, with no particles or effects, and no multisampling.
+    pub fn new(description: &SceneDescription) -> Self {
+        let [red, green, blue, alpha] = description.clear_color;
+        HeadlessScene {
+            scene: description.to_scene(),
+            clear_color: [red as f32, green as f32, blue as f32, alpha as f32],
+            sample_count: 1,
+            post_effects: Vec::new(),
+            particles: None,
+        }
+    }
+
+    /// Starts the particles afresh, or stops drawing them.
+    pub fn set_particles(&mut self, settings: Option<ParticleSettings>) {
+        self.particles = settings.map(|settings| {
+            let particles = settings.initial_particles();
+            (ParticleSystem::new(settings), particles)
+        });
+    }
+
+    /// Moves the simulation on by one step of `time_step` seconds, in frames of the given size.
+    pub fn update(&mut self, time_step: f64, size: [usize; 2]) {
+        if let Some((system, particles)) = self.particles.as_mut() {
+            let uniforms = system.next_uniforms(time_step as f32, [size[0] as f32, size[1] as f32]);
+            update_particles(particles, &uniforms);
+        }
+    }
+
+    /// Draws a frame: the scene, then the particles, then the post-processing effects.
+    pub fn draw(&self, size: [usize; 2]) -> Image {
+        let [width, height] = size;
+        let mut framebuffer = Framebuffer::new_multisample(width, height, self.sample_count, self.clear_color)
+            .unwrap_or_else(|| Framebuffer::new(width, height, self.clear_color));
+        draw_scene(&mut framebuffer, &self.scene);
+        if let Some((system, particles)) = &self.particles {
+            draw_particles(&mut framebuffer, particles, system.settings.point_size, &BlendMode::Additive.blend_state());
+        }
+        let framebuffer = apply_post_effects(&framebuffer, &self.post_effects);
+        // The framebuffer is the size we asked for.
+        Image::new(width, height, framebuffer.to_rgba8()).unwrap()
+    }
+}
+
+/// Draws and writes every frame of a recording, and returns how many were written.
+pub fn record(scene: &mut HeadlessScene, size: [usize; 2], settings: &RecordingSettings) -> Result<usize, RecordingError> {
+    let mut timeline = RecordingTimeline::new(settings, DEFAULT_UPDATES_PER_SECOND);
+    let mut writer = RecordingWriter::new(settings);
+    while let Some(update_steps) = timeline.next_frame() {
+        for _ in 0..update_steps {
+            scene.update(timeline.time_step(), size);
+        }
+        writer.write_frame(&scene.draw(size))?;
+    }
+    writer.finish()
+}
+
+/// A frame size written as `WIDTHxHEIGHT`, e.g. `1280x720`.
+pub fn parse_size(size: &str) -> Option<[usize; 2]> {
+    let (width, height) = size.split_once('x')?;
+    let size = [width.trim().parse().ok()?, height.trim().parse().ok()?];
+    if size.contains(&0) {
+        return None;
+    }
+    Some(size)
+}
+
+/// Records what the environment asks for, without starting the app.
+///
+/// Set HELLO_TRIANGLE_RECORD_SIZE to the frame size (e.g. `1280x720`),
+/// and HELLO_TRIANGLE_SCENE, HELLO_TRIANGLE_PARTICLES, HELLO_TRIANGLE_POST_EFFECTS
+/// and HELLO_TRIANGLE_SAMPLE_COUNT as for the app.
+pub fn record_from_environment() {
+    let settings = match RecordingSettings::from_environment() {
+        Some(settings) => settings,
+        None => {
+            println!("Set HELLO_TRIANGLE_RECORD to a .y4m file or a directory to record without a window");
+            return;
+        }
+    };
+    let size = match std::env::var("HELLO_TRIANGLE_RECORD_SIZE") {
+        Ok(size) => parse_size(&size).unwrap_or_else(|| {
+            println!("HELLO_TRIANGLE_RECORD_SIZE should be a size like 1280x720, not {:?}", size);
+            DEFAULT_HEADLESS_SIZE
+        }),
+        Err(_) => DEFAULT_HEADLESS_SIZE,
+    };
+    let description = match std::env::var("HELLO_TRIANGLE_SCENE") {
+        Ok(scene_path) => match SceneDescription::load(&PathBuf::from(&scene_path)) {
+            Ok(description) => description,
+            Err(e) => {
+                println!("Unable to load scene {}: {}", scene_path, e);
+                return;
+            }
+        },
+        Err(_) => SceneDescription::default(),
+    };
+    let mut scene = HeadlessScene::new(&description);
+    scene.sample_count = std::env::var("HELLO_TRIANGLE_SAMPLE_COUNT").ok()
+        .and_then(|sample_count| sample_count.parse().ok())
+        .unwrap_or(DEFAULT_HEADLESS_SAMPLE_COUNT);
+    scene.set_particles(ParticleSettings::from_environment());
+    scene.post_effects = post_effects_from_environment().unwrap_or_default();
+    match record(&mut scene, size, &settings) {
+        Ok(frame_count) => println!("Recorded {} frames to {}", frame_count, settings.output.display()),
+        Err(e) => println!("Recording failed: {}", format_error_chain(&e)),
+    }
+}
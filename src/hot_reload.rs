@@ -0,0 +1,183 @@
+//! Reloading the scene and shaders when their files change
+//!
+//! We poll the files' modification times rather than ask the OS to tell us,
+//! which is plenty for a couple of files checked a few times a second.
+//! A change is only reported once a file has stopped changing,
+//! so we don't read something an editor is halfway through saving.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use crate::shader_library::{LibrarySource, read_shader_source};
+
+/// How often to look at the files, in seconds.
+const POLL_INTERVAL: f64 = 0.5;
+
+/// What we know about a file without reading it.
+/// `None` if it doesn't exist (yet).
+type Fingerprint = Option<(SystemTime, u64)>;
+
+fn fingerprint(path: &Path) -> Fingerprint {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// Notices when any of a set of files changes.
+pub struct FileWatcher {
+    paths: Vec<PathBuf>,
+    last_seen: Vec<Fingerprint>,
+    pending: Option<Vec<Fingerprint>>,
+}
+
+impl FileWatcher {
+    /// Starts watching the files as they are now.
+    pub fn new(paths: Vec<PathBuf>) -> Self {
+        let last_seen = paths.iter().map(|path| fingerprint(path)).collect();
+        FileWatcher {
+            paths,
+            last_seen,
+            pending: None,
+        }
+    }
+
+    /// Watches a different set of files from now on, without reporting a change.
+    pub fn set_paths(&mut self, paths: Vec<PathBuf>) {
+        *self = Self::new(paths);
+    }
+
+    /// Returns `true` once after the files change.
+    ///
+    /// A change is held back until two polls in a row see the same thing,
+    /// and a file that has disappeared (as some editors do while saving) is waited for.
+    pub fn poll(&mut self) -> bool {
+        let current: Vec<Fingerprint> = self.paths.iter().map(|path| fingerprint(path)).collect();
+        if current == self.last_seen || current.iter().any(Option::is_none) {
+            self.pending = None;
+            return false;
+        }
+        if self.pending.as_ref() == Some(&current) {
+            self.last_seen = current;
+            self.pending = None;
+            true
+        } else {
+            self.pending = Some(current);
+            false
+        }
+    }
+}
+
+/// Where to load shaders from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ShaderSource {
+    /// A compiled library, such as `default.metallib`.
+    LibraryFile(PathBuf),
+    /// Metal Shading Language source, compiled when loaded.
+    SourceFile(PathBuf),
+}
+
+impl ShaderSource {
+    /// Decides from the extension: `.metal` files are source, anything else a compiled library.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("metal") => ShaderSource::SourceFile(path.to_path_buf()),
+            _ => ShaderSource::LibraryFile(path.to_path_buf()),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        match self {
+            ShaderSource::LibraryFile(path) | ShaderSource::SourceFile(path) => path,
+        }
+    }
+
+    /// Reads the shaders, ready to make a library from.
+    pub fn load(&self) -> io::Result<LibrarySource> {
+        LibrarySource::from_path(self.path())
+    }
+
+    /// The files the shaders are built from,
+    /// including any headers a source file includes.
+    pub fn dependencies(&self) -> Vec<PathBuf> {
+        match self {
+            ShaderSource::LibraryFile(path) => vec![path.clone()],
+            ShaderSource::SourceFile(path) => match read_shader_source(path) {
+                Ok((_, files)) => files,
+                Err(_) => vec![path.clone()],
+            },
+        }
+    }
+}
+
+/// Something that needs reloading.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reload {
+    Scene(PathBuf),
+    Shaders(ShaderSource),
+}
+
+/// Watches the scene file and shaders, and says when to reload them.
+pub struct HotReloader {
+    scene: Option<(PathBuf, FileWatcher)>,
+    shaders: Option<(ShaderSource, FileWatcher)>,
+    last_poll_time: Option<f64>,
+}
+
+impl HotReloader {
+    pub fn new() -> Self {
+        HotReloader {
+            scene: None,
+            shaders: None,
+            last_poll_time: None,
+        }
+    }
+
+    /// Watches a scene file.
+    pub fn watch_scene(&mut self, path: PathBuf) {
+        let watcher = FileWatcher::new(vec![path.clone()]);
+        self.scene = Some((path, watcher));
+    }
+
+    /// Watches the shaders (and, for source, the headers they include).
+    pub fn watch_shaders(&mut self, source: ShaderSource) {
+        let watcher = FileWatcher::new(source.dependencies());
+        self.shaders = Some((source, watcher));
+    }
+
+    /// Whether there's anything to watch.
+    pub fn is_watching(&self) -> bool {
+        self.scene.is_some() || self.shaders.is_some()
+    }
+
+    /// Looks for changes, if it's time to, and returns what needs reloading.
+    ///
+    /// `time` is in seconds, as in `FrameTick::time`.
+    pub fn poll(&mut self, time: f64) -> Vec<Reload> {
+        let mut reloads = Vec::new();
+        if let Some(last_poll_time) = self.last_poll_time {
+            if time - last_poll_time < POLL_INTERVAL {
+                return reloads;
+            }
+        }
+        self.last_poll_time = Some(time);
+
+        if let Some((path, watcher)) = self.scene.as_mut() {
+            if watcher.poll() {
+                reloads.push(Reload::Scene(path.clone()));
+            }
+        }
+        if let Some((source, watcher)) = self.shaders.as_mut() {
+            if watcher.poll() {
+                // The source may include different headers now.
+                watcher.set_paths(source.dependencies());
+                reloads.push(Reload::Shaders(source.clone()));
+            }
+        }
+        reloads
+    }
+}
+
+impl Default for HotReloader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
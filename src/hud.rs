@@ -0,0 +1,227 @@
+//! The debug overlay
+//!
+//! The HUD shows how fast we're drawing and what we're drawing into:
+//! the frame rate, a graph of recent frame times, the drawable and viewport sizes,
+//! the color pixel format and the display the view is on.
+//! It's laid out afresh every frame with a `Batcher2D`, and drawn over the scene by the renderer
+//! (or by `software_rasterizer::draw_batch`, to check the layout without a GPU).
+
+use crate::batcher::{Batcher2D, Batch2D};
+use crate::font::Font;
+use crate::glyph_atlas::GlyphAtlas;
+
+/// The key that shows and hides the HUD.
+pub static HUD_TOGGLE_KEY: &str = "h";
+
+/// The top-left corner of the panel, in pixels from the top-left corner of the viewport.
+static PANEL_POSITION: [f32; 2] = [8., 8.];
+/// Space between the edge of the panel and what's in it, and between lines.
+static PANEL_PADDING: f32 = 6.;
+static PANEL_COLOR: [f32; 4] = [0., 0., 0., 0.6];
+static TEXT_COLOR: [f32; 4] = [1., 1., 1., 1.];
+
+/// The width of each frame's bar in the graph.
+static GRAPH_BAR_WIDTH: f32 = 2.;
+static GRAPH_HEIGHT: f32 = 40.;
+/// The frame time at the top of the graph; longer frames are cut off.
+static GRAPH_MAX_FRAME_TIME: f64 = 0.05;
+/// A frame at 60 Hz, drawn as a line across the graph.
+static GRAPH_TARGET_FRAME_TIME: f64 = 1. / 60.;
+static GRAPH_BACKGROUND_COLOR: [f32; 4] = [1., 1., 1., 0.1];
+static GRAPH_TARGET_COLOR: [f32; 4] = [1., 1., 1., 0.5];
+static FAST_FRAME_COLOR: [f32; 4] = [0.2, 0.9, 0.2, 1.];
+static SLOW_FRAME_COLOR: [f32; 4] = [0.95, 0.8, 0.1, 1.];
+static MISSED_FRAME_COLOR: [f32; 4] = [0.95, 0.2, 0.2, 1.];
+
+/// What the HUD shows.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HudInfo {
+    pub frames_per_second: f64,
+    /// The time between frames, oldest first, in seconds.
+    pub frame_times: Vec<f64>,
+    /// In pixels.
+    pub drawable_size: [f64; 2],
+    pub viewport_size: [u32; 2],
+    pub pixel_format: String,
+    /// The `CGDirectDisplayID` of the screen the view is on, if it's on one.
+    pub display_id: Option<u32>,
+}
+
+pub struct Hud {
+    font: Font,
+    atlas: GlyphAtlas,
+}
+
+impl Hud {
+    /// A HUD drawn with the built-in bitmap font, with each of its pixels this many pixels wide.
+    pub fn new(pixel_scale: usize) -> Self {
+        let font = Font::bitmap(pixel_scale);
+        let printable_ascii: String = (' '..='~').collect();
+        let atlas = GlyphAtlas::new(&font, &printable_ascii).expect("the bitmap font fits in an atlas");
+        Hud { font, atlas }
+    }
+
+    /// The atlas the HUD's text is drawn from.
+    pub fn atlas(&self) -> &GlyphAtlas {
+        &self.atlas
+    }
+
+    /// The lines of text, from the top.
+    pub fn lines(&self, info: &HudInfo) -> Vec<String> {
+        let frame_time = match info.frame_times.last() {
+            Some(frame_time) => format!("{:.2} ms", frame_time * 1000.),
+            None => "-".to_string(),
+        };
+        let display = match info.display_id {
+            Some(display_id) => display_id.to_string(),
+            None => "-".to_string(),
+        };
+        vec![
+            format!("FPS {:.1}", info.frames_per_second),
+            format!("Frame {}", frame_time),
+            format!("Drawable {} x {}", info.drawable_size[0], info.drawable_size[1]),
+            format!("Viewport {} x {}", info.viewport_size[0], info.viewport_size[1]),
+            format!("Pixel format {}", info.pixel_format),
+            format!("Display {}", display),
+        ]
+    }
+
+    /// Lays the HUD out in the top-left corner of a viewport of the given size, in pixels.
+    pub fn build(&self, info: &HudInfo, viewport_size: [f32; 2]) -> Batch2D {
+        let mut batcher = Batcher2D::new(&self.font, &self.atlas, viewport_size);
+        let lines = self.lines(info);
+        let line_height = self.font.line_metrics().line_height();
+        let text_width = lines.iter().map(|line| batcher.text_size(line)[0]).fold(0., f32::max);
+        let graph_width = frame_graph_width(info.frame_times.len());
+        let content_width = text_width.max(graph_width).ceil();
+        let content_height = (lines.len() as f32 * line_height + PANEL_PADDING + GRAPH_HEIGHT).ceil();
+
+        let [left, top] = PANEL_POSITION;
+        batcher.rect(
+            [left, top],
+            [content_width + PANEL_PADDING * 2., content_height + PANEL_PADDING * 2.],
+            PANEL_COLOR,
+        );
+        let content_left = left + PANEL_PADDING;
+        let mut y = top + PANEL_PADDING;
+        for line in &lines {
+            batcher.text([content_left, y], line, TEXT_COLOR);
+            y += line_height;
+        }
+        draw_frame_graph(&mut batcher, [content_left, y + PANEL_PADDING], content_width, &info.frame_times);
+        batcher.finish()
+    }
+}
+
+fn frame_graph_width(frame_count: usize) -> f32 {
+    frame_count as f32 * GRAPH_BAR_WIDTH
+}
+
+/// A bar for each frame time, newest on the right, with a line at the 60 Hz frame time.
+fn draw_frame_graph(batcher: &mut Batcher2D, position: [f32; 2], width: f32, frame_times: &[f64]) {
+    let [left, top] = position;
+    let bottom = top + GRAPH_HEIGHT;
+    batcher.rect([left, top], [width, GRAPH_HEIGHT], GRAPH_BACKGROUND_COLOR);
+    let right = left + width;
+    let bar_height = |frame_time: f64| (frame_time.min(GRAPH_MAX_FRAME_TIME) / GRAPH_MAX_FRAME_TIME) as f32 * GRAPH_HEIGHT;
+    for (age, &frame_time) in frame_times.iter().rev().enumerate() {
+        let bar_left = right - (age + 1) as f32 * GRAPH_BAR_WIDTH;
+        if bar_left < left {
+            break;
+        }
+        let color = if frame_time <= GRAPH_TARGET_FRAME_TIME * 1.05 {
+            FAST_FRAME_COLOR
+        } else if frame_time <= GRAPH_TARGET_FRAME_TIME * 2.05 {
+            SLOW_FRAME_COLOR
+        } else {
+            MISSED_FRAME_COLOR
+        };
+        let height = bar_height(frame_time);
+        batcher.rect([bar_left, bottom - height], [GRAPH_BAR_WIDTH, height], color);
+    }
+    let target_y = bottom - bar_height(GRAPH_TARGET_FRAME_TIME);
+    batcher.line([left, target_y], [right, target_y], 1., GRAPH_TARGET_COLOR);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shader_types::AAPLVertex;
+
+    fn info(frame_times: Vec<f64>) -> HudInfo {
+        HudInfo {
+            frames_per_second: 59.94,
+            frame_times,
+            drawable_size: [1600., 1200.],
+            viewport_size: [800, 600],
+            pixel_format: "BGRA8Unorm".to_string(),
+            display_id: None,
+        }
+    }
+
+    /// The top-left and bottom-right corners of each quad the batcher made,
+    /// in pixels from the top-left corner of a 400 by 300 view,
+    /// with the quad's color.
+    fn quads(batch: &Batch2D) -> Vec<([f32; 2], [f32; 2], [f32; 4])> {
+        let vertices = batch.shapes.as_ref().unwrap().vertices();
+        vertices.chunks_exact(4)
+            .map(|quad| {
+                let corner = |vertex: &AAPLVertex| [vertex.position.x() + 200., 150. - vertex.position.y()];
+                let color = quad[0].color;
+                (corner(&quad[0]), corner(&quad[2]), [color.x(), color.y(), color.z(), color.w()])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn lines_say_what_we_know() {
+        let hud = Hud::new(1);
+        assert_eq!(hud.lines(&info(vec![0.016, 0.02])), [
+            "FPS 59.9",
+            "Frame 20.00 ms",
+            "Drawable 1600 x 1200",
+            "Viewport 800 x 600",
+            "Pixel format BGRA8Unorm",
+            "Display -",
+        ]);
+        assert_eq!(hud.lines(&HudInfo { display_id: Some(69733378), ..info(Vec::new()) })[1..], [
+            "Frame -",
+            "Drawable 1600 x 1200",
+            "Viewport 800 x 600",
+            "Pixel format BGRA8Unorm",
+            "Display 69733378",
+        ]);
+    }
+
+    #[test]
+    fn the_panel_fits_the_text_and_graph() {
+        let hud = Hud::new(1);
+        let info = info(vec![0.016, 0.03, 0.1]);
+        let batch = hud.build(&info, [400., 300.]);
+        let quads = quads(&batch);
+        // The panel, the graph's background, a bar for each frame and the 60 Hz line.
+        assert_eq!(quads.len(), 6);
+        // "Pixel format BGRA8Unorm" is the widest line at 23 characters of 6 pixels;
+        // six lines of 9 pixels, padding and the graph make the height.
+        assert_eq!(quads[0], ([8., 8.], [8. + 138. + 12., 8. + 54. + 6. + 40. + 12.], PANEL_COLOR));
+        assert_eq!(quads[1], ([14., 74.], [14. + 138., 114.], GRAPH_BACKGROUND_COLOR));
+
+        // Newest on the right, colored by how long the frame took, and cut off at the top of the graph.
+        assert_eq!(quads[2], ([150., 74.], [152., 114.], MISSED_FRAME_COLOR));
+        assert_eq!(quads[3].2, SLOW_FRAME_COLOR);
+        assert_eq!(quads[4], ([146., 114. - 0.016 / 0.05 * 40.], [148., 114.], FAST_FRAME_COLOR));
+        assert_eq!(quads[5].2, GRAPH_TARGET_COLOR);
+
+        // A quad for every character that isn't a space.
+        let character_count = hud.lines(&info).concat().chars().filter(|character| !character.is_whitespace()).count();
+        assert_eq!(batch.text.unwrap().vertices().len(), character_count * 4);
+    }
+
+    #[test]
+    fn the_graph_widens_the_panel_for_many_frames() {
+        let batch = Hud::new(1).build(&info(vec![0.01; 100]), [400., 300.]);
+        let quads = quads(&batch);
+        assert_eq!(quads.len(), 103);
+        assert_eq!(quads[0].1[0], 8. + 200. + 12.);
+    }
+}
@@ -0,0 +1,465 @@
+//! Decoding images for textures
+//!
+//! PNG and JPEG files hold a single image, so we make the smaller mipmap levels ourselves.
+//! KTX (version 1) files can hold the mipmap levels too, and say whether the colors are sRGB.
+//! Either way we end up with `TextureData`: 8-bit RGBA pixels for each level,
+//! which the GPU and the software rasterizer both sample from,
+//! so they see exactly the same texels.
+
+use std::fmt::{Display, Formatter};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use zune_jpeg::JpegDecoder;
+use zune_jpeg::zune_core::colorspace::ColorSpace;
+use zune_jpeg::zune_core::options::DecoderOptions;
+
+/// An image of 8-bit RGBA pixels, top row first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+}
+
+impl Image {
+    /// An image from its pixels, or `None` if there aren't `width * height` of them.
+    pub fn new(width: usize, height: usize, pixels: Vec<u8>) -> Option<Self> {
+        if width == 0 || height == 0 || pixels.len() != width * height * 4 {
+            return None;
+        }
+        Some(Image { width, height, pixels })
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Every pixel's red, green, blue and alpha bytes, top row first.
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    pub fn texel(&self, x: usize, y: usize) -> [u8; 4] {
+        let start = (y * self.width + x) * 4;
+        let mut texel = [0; 4];
+        texel.copy_from_slice(&self.pixels[start..start + 4]);
+        texel
+    }
+
+    /// The next mipmap level down: half the size (rounding down, but at least one pixel),
+    /// with each pixel the average of the two by two block it covers.
+    ///
+    /// sRGB colors are averaged as light, not as encoded values.
+    pub fn downsample(&self, srgb: bool) -> Image {
+        let width = (self.width / 2).max(1);
+        let height = (self.height / 2).max(1);
+        let mut pixels = Vec::with_capacity(width * height * 4);
+        for y in 0..height {
+            for x in 0..width {
+                // At an odd edge there's only one row or column to average.
+                let xs = [(x * 2).min(self.width - 1), (x * 2 + 1).min(self.width - 1)];
+                let ys = [(y * 2).min(self.height - 1), (y * 2 + 1).min(self.height - 1)];
+                let mut sum = [0.; 4];
+                for &sample_y in &ys {
+                    for &sample_x in &xs {
+                        let texel = self.texel(sample_x, sample_y);
+                        for (channel, value) in sum.iter_mut().enumerate() {
+                            *value += decode_channel(texel[channel], srgb && channel < 3);
+                        }
+                    }
+                }
+                for (channel, value) in sum.iter().enumerate() {
+                    pixels.push(encode_channel(value / 4., srgb && channel < 3));
+                }
+            }
+        }
+        Image { width, height, pixels }
+    }
+}
+
+/// An 8-bit channel as a number from 0 to 1, converted from sRGB to linear if need be,
+/// as the GPU does when it reads a texel.
+pub fn decode_channel(value: u8, srgb: bool) -> f32 {
+    let value = f32::from(value) / 255.;
+    if srgb {
+        srgb_to_linear(value)
+    } else {
+        value
+    }
+}
+
+/// A number from 0 to 1 as an 8-bit channel, converted from linear to sRGB if need be.
+pub fn encode_channel(value: f32, srgb: bool) -> u8 {
+    let value = if srgb { linear_to_srgb(value) } else { value };
+    (value.clamp(0., 1.) * 255. + 0.5) as u8
+}
+
+pub fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+pub fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1. / 2.4) - 0.055
+    }
+}
+
+/// How many mipmap levels an image of this size has, down to one pixel.
+pub fn mip_level_count(width: usize, height: usize) -> usize {
+    let mut size = width.max(height).max(1);
+    let mut count = 1;
+    while size > 1 {
+        size /= 2;
+        count += 1;
+    }
+    count
+}
+
+/// Everything that goes into a texture: each mipmap level, largest first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextureData {
+    pub levels: Vec<Image>,
+    /// Whether the colors are sRGB encoded, so the GPU converts them to linear when sampling.
+    pub srgb: bool,
+}
+
+impl TextureData {
+    /// A texture with every mipmap level made from `image`.
+    pub fn with_mipmaps(image: Image, srgb: bool) -> Self {
+        let level_count = mip_level_count(image.width(), image.height());
+        let mut levels = vec![image];
+        while levels.len() < level_count {
+            let next_level = levels.last().unwrap().downsample(srgb);
+            levels.push(next_level);
+        }
+        TextureData { levels, srgb }
+    }
+
+    /// A texture with just the one level.
+    pub fn without_mipmaps(image: Image, srgb: bool) -> Self {
+        TextureData { levels: vec![image], srgb }
+    }
+
+    pub fn width(&self) -> usize {
+        self.levels[0].width()
+    }
+    pub fn height(&self) -> usize {
+        self.levels[0].height()
+    }
+
+    /// Decodes a PNG, JPEG or KTX file, going by its first few bytes.
+    ///
+    /// PNG and JPEG colors are used as they are, as the vertex colors are,
+    /// rather than being treated as sRGB.
+    pub fn decode(bytes: &[u8]) -> Result<Self, ImageError> {
+        if bytes.starts_with(PNG_SIGNATURE) {
+            Ok(TextureData::with_mipmaps(decode_png(bytes)?, false))
+        } else if bytes.starts_with(JPEG_SIGNATURE) {
+            Ok(TextureData::with_mipmaps(decode_jpeg(bytes)?, false))
+        } else if bytes.starts_with(KTX_IDENTIFIER) {
+            decode_ktx(bytes)
+        } else {
+            Err(ImageError::UnknownFormat)
+        }
+    }
+
+    /// Reads and decodes an image file.
+    pub fn load(path: &Path) -> Result<Self, ImageError> {
+        let bytes = std::fs::read(path).map_err(|e| ImageError::Io(path.to_path_buf(), e))?;
+        TextureData::decode(&bytes)
+    }
+}
+
+#[derive(Debug)]
+pub enum ImageError {
+    /// We couldn't read the file.
+    Io(PathBuf, std::io::Error),
+    /// It isn't a PNG, JPEG or KTX file.
+    UnknownFormat,
+    Png(png::DecodingError),
+    Jpeg(String),
+    /// The KTX file is broken, or uses a format we can't read.
+    Ktx(String),
+}
+impl Display for ImageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(path, _) => write!(f, "Unable to read {}", path.display()),
+            Self::UnknownFormat => write!(f, "The image isn't a PNG, JPEG or KTX file"),
+            Self::Png(_) => write!(f, "Unable to decode the PNG image"),
+            Self::Jpeg(message) => write!(f, "Unable to decode the JPEG image: {}", message),
+            Self::Ktx(message) => write!(f, "Unable to read the KTX file: {}", message),
+        }
+    }
+}
+impl Error for ImageError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(_, error) => Some(error),
+            Self::Png(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+static PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+static JPEG_SIGNATURE: &[u8] = b"\xff\xd8\xff";
+static KTX_IDENTIFIER: &[u8] = b"\xabKTX 11\xbb\r\n\x1a\n";
+
+/// Decodes a PNG file of any color type and bit depth to 8-bit RGBA.
+pub fn decode_png(bytes: &[u8]) -> Result<Image, ImageError> {
+    let mut decoder = png::Decoder::new(std::io::Cursor::new(bytes));
+    // Palettes, low bit depths and transparency chunks are expanded, 16-bit channels cut to 8.
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().map_err(ImageError::Png)?;
+    let mut buffer = vec![0; reader.output_buffer_size().unwrap_or(0)];
+    let frame = reader.next_frame(&mut buffer).map_err(ImageError::Png)?;
+    let samples = &buffer[..frame.buffer_size()];
+    let pixel_count = frame.width as usize * frame.height as usize;
+    let mut pixels = Vec::with_capacity(pixel_count * 4);
+    match frame.color_type {
+        png::ColorType::Grayscale => {
+            for &gray in samples {
+                pixels.extend_from_slice(&[gray, gray, gray, 255]);
+            }
+        }
+        png::ColorType::GrayscaleAlpha => {
+            for gray_alpha in samples.chunks_exact(2) {
+                pixels.extend_from_slice(&[gray_alpha[0], gray_alpha[0], gray_alpha[0], gray_alpha[1]]);
+            }
+        }
+        png::ColorType::Rgb => {
+            for rgb in samples.chunks_exact(3) {
+                pixels.extend_from_slice(&[rgb[0], rgb[1], rgb[2], 255]);
+            }
+        }
+        png::ColorType::Rgba => pixels.extend_from_slice(samples),
+        // Expanded to RGB(A) by the transformations above.
+        png::ColorType::Indexed => unreachable!(),
+    }
+    // The decoder has checked the size, and PNGs can't be empty.
+    Ok(Image::new(frame.width as usize, frame.height as usize, pixels).unwrap())
+}
+
+/// Decodes a JPEG file to 8-bit RGBA.
+pub fn decode_jpeg(bytes: &[u8]) -> Result<Image, ImageError> {
+    let options = DecoderOptions::default().jpeg_set_out_colorspace(ColorSpace::RGBA);
+    let mut decoder = JpegDecoder::new_with_options(std::io::Cursor::new(bytes), options);
+    let pixels = decoder.decode().map_err(|e| ImageError::Jpeg(format!("{:?}", e)))?;
+    let info = decoder.info().ok_or_else(|| ImageError::Jpeg("no image header".to_string()))?;
+    Image::new(usize::from(info.width), usize::from(info.height), pixels)
+        .ok_or_else(|| ImageError::Jpeg("the image is the wrong size".to_string()))
+}
+
+// The OpenGL enums a KTX file describes its format with.
+static GL_UNSIGNED_BYTE: u32 = 0x1401;
+static GL_RGB: u32 = 0x1907;
+static GL_RGBA: u32 = 0x1908;
+static GL_RGB8: u32 = 0x8051;
+static GL_RGBA8: u32 = 0x8058;
+static GL_SRGB8: u32 = 0x8C41;
+static GL_SRGB8_ALPHA8: u32 = 0x8C43;
+
+/// Reads a KTX version 1 file of uncompressed 8-bit RGB or RGBA.
+///
+/// If it has no mipmap levels besides the first, we make them.
+pub fn decode_ktx(bytes: &[u8]) -> Result<TextureData, ImageError> {
+    let ktx_error = |message: &str| ImageError::Ktx(message.to_string());
+    if !bytes.starts_with(KTX_IDENTIFIER) {
+        return Err(ktx_error("it doesn't start with the KTX 1.1 identifier"));
+    }
+    let mut reader = KtxReader { bytes, offset: KTX_IDENTIFIER.len(), swap: false };
+    let endianness = reader.read_u32()?;
+    reader.swap = match endianness {
+        0x04030201 => false,
+        0x01020304 => true,
+        _ => return Err(ktx_error("the endianness is neither big nor little")),
+    };
+    let gl_type = reader.read_u32()?;
+    let _gl_type_size = reader.read_u32()?;
+    let gl_format = reader.read_u32()?;
+    let gl_internal_format = reader.read_u32()?;
+    let _gl_base_internal_format = reader.read_u32()?;
+    let width = reader.read_u32()? as usize;
+    let height = (reader.read_u32()? as usize).max(1);
+    let depth = reader.read_u32()?;
+    let array_elements = reader.read_u32()?;
+    let faces = reader.read_u32()?;
+    let mip_levels = (reader.read_u32()? as usize).max(1);
+    let key_value_bytes = reader.read_u32()? as usize;
+
+    let (channels, srgb) = match (gl_type, gl_format, gl_internal_format) {
+        (t, f, i) if t == GL_UNSIGNED_BYTE && f == GL_RGBA && i == GL_RGBA8 => (4, false),
+        (t, f, i) if t == GL_UNSIGNED_BYTE && f == GL_RGBA && i == GL_SRGB8_ALPHA8 => (4, true),
+        (t, f, i) if t == GL_UNSIGNED_BYTE && f == GL_RGB && i == GL_RGB8 => (3, false),
+        (t, f, i) if t == GL_UNSIGNED_BYTE && f == GL_RGB && i == GL_SRGB8 => (3, true),
+        _ => return Err(ImageError::Ktx(format!("the format (internal format {:#x}) isn't 8-bit RGB or RGBA", gl_internal_format))),
+    };
+    if width == 0 || depth > 1 || array_elements > 0 || faces != 1 {
+        return Err(ktx_error("it isn't a 2D texture"));
+    }
+    if mip_levels > mip_level_count(width, height) {
+        return Err(ktx_error("it has more mipmap levels than its size allows"));
+    }
+    reader.skip(key_value_bytes)?;
+
+    let mut levels = Vec::with_capacity(mip_levels);
+    for level in 0..mip_levels {
+        let level_width = (width >> level).max(1);
+        let level_height = (height >> level).max(1);
+        let image_size = reader.read_u32()? as usize;
+        // Rows are padded to four bytes.
+        let row_bytes = level_width * channels;
+        let padded_row_bytes = row_bytes.next_multiple_of(4);
+        if image_size < padded_row_bytes * level_height {
+            return Err(ImageError::Ktx(format!("mipmap level {} is too small", level)));
+        }
+        let data = reader.take(image_size)?;
+        let mut pixels = Vec::with_capacity(level_width * level_height * 4);
+        for row in data.chunks(padded_row_bytes).take(level_height) {
+            for texel in row[..row_bytes].chunks_exact(channels) {
+                pixels.extend_from_slice(texel);
+                if channels == 3 {
+                    pixels.push(255);
+                }
+            }
+        }
+        levels.push(Image::new(level_width, level_height, pixels).unwrap());
+        reader.skip((4 - image_size % 4) % 4)?;
+    }
+
+    if levels.len() == 1 {
+        Ok(TextureData::with_mipmaps(levels.pop().unwrap(), srgb))
+    } else {
+        Ok(TextureData { levels, srgb })
+    }
+}
+
+struct KtxReader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    /// Whether the file was written with the other byte order.
+    swap: bool,
+}
+
+impl<'a> KtxReader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], ImageError> {
+        let end = self.offset.checked_add(length)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| ImageError::Ktx("the file is cut short".to_string()))?;
+        let taken = &self.bytes[self.offset..end];
+        self.offset = end;
+        Ok(taken)
+    }
+
+    fn skip(&mut self, length: usize) -> Result<(), ImageError> {
+        self.take(length).map(|_| ())
+    }
+
+    fn read_u32(&mut self) -> Result<u32, ImageError> {
+        let mut value = [0; 4];
+        value.copy_from_slice(self.take(4)?);
+        let value = u32::from_le_bytes(value);
+        Ok(if self.swap { value.swap_bytes() } else { value })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode_png(width: u32, height: u32, color_type: png::ColorType, data: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut encoder = png::Encoder::new(&mut bytes, width, height);
+        encoder.set_color(color_type);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.write_header().unwrap().write_image_data(data).unwrap();
+        bytes
+    }
+
+    /// A little-endian KTX file of 8-bit RGB with the given mipmap levels, each row padded to four bytes.
+    fn encode_ktx(width: u32, height: u32, internal_format: u32, levels: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = KTX_IDENTIFIER.to_vec();
+        let header = [0x04030201, GL_UNSIGNED_BYTE, 1, GL_RGB, internal_format, GL_RGB, width, height, 0, 0, 1, levels.len() as u32, 0];
+        for value in header {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        for level in levels {
+            bytes.extend_from_slice(&(level.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(level);
+        }
+        bytes
+    }
+
+    #[test]
+    fn pngs_decode_to_rgba() {
+        let rgb = encode_png(2, 1, png::ColorType::Rgb, &[255, 0, 0, 0, 128, 255]);
+        let texture = TextureData::decode(&rgb).unwrap();
+        assert!(!texture.srgb);
+        assert_eq!(texture.levels[0].pixels(), &[255, 0, 0, 255, 0, 128, 255, 255]);
+
+        let gray_alpha = encode_png(1, 1, png::ColorType::GrayscaleAlpha, &[40, 200]);
+        assert_eq!(decode_png(&gray_alpha).unwrap().texel(0, 0), [40, 40, 40, 200]);
+    }
+
+    #[test]
+    fn unknown_and_broken_files_are_errors() {
+        assert!(matches!(TextureData::decode(b"GIF89a"), Err(ImageError::UnknownFormat)));
+        assert!(matches!(TextureData::decode(PNG_SIGNATURE), Err(ImageError::Png(_))));
+        assert!(matches!(TextureData::decode(b"\xff\xd8\xff\xe0"), Err(ImageError::Jpeg(_))));
+    }
+
+    #[test]
+    fn ktx_rows_are_unpadded_and_missing_mipmaps_made() {
+        // Three RGB texels make nine bytes a row, padded to twelve.
+        let level = vec![10, 20, 30, 40, 50, 60, 70, 80, 90, 0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 0, 0];
+        let texture = decode_ktx(&encode_ktx(3, 2, GL_SRGB8, &[level])).unwrap();
+        assert!(texture.srgb);
+        assert_eq!(texture.levels.len(), 2);
+        assert_eq!(texture.levels[0].texel(2, 0), [70, 80, 90, 255]);
+        assert_eq!(texture.levels[0].texel(0, 1), [1, 2, 3, 255]);
+        assert_eq!((texture.levels[1].width(), texture.levels[1].height()), (1, 1));
+    }
+
+    #[test]
+    fn ktx_mipmap_levels_are_read() {
+        let levels = [vec![255, 0, 0, 0, 255, 0, 0, 0], vec![0, 0, 255, 0]];
+        let texture = decode_ktx(&encode_ktx(2, 1, GL_RGB8, &levels)).unwrap();
+        assert_eq!(texture.levels.len(), 2);
+        assert_eq!(texture.levels[1].texel(0, 0), [0, 0, 255, 255]);
+
+        let too_many = [levels[0].clone(), levels[1].clone(), levels[1].clone()];
+        assert!(matches!(decode_ktx(&encode_ktx(2, 1, GL_RGB8, &too_many)), Err(ImageError::Ktx(_))));
+        let mut cut_short = encode_ktx(2, 1, GL_RGB8, &levels);
+        cut_short.truncate(cut_short.len() - 1);
+        assert!(matches!(decode_ktx(&cut_short), Err(ImageError::Ktx(_))));
+    }
+
+    #[test]
+    fn mip_levels_go_down_to_one_pixel() {
+        assert_eq!(mip_level_count(1, 1), 1);
+        assert_eq!(mip_level_count(5, 3), 3);
+        assert_eq!(mip_level_count(1, 256), 9);
+
+        let texture = TextureData::with_mipmaps(Image::new(5, 3, vec![0; 5 * 3 * 4]).unwrap(), false);
+        let sizes: Vec<(usize, usize)> = texture.levels.iter().map(|level| (level.width(), level.height())).collect();
+        assert_eq!(sizes, [(5, 3), (2, 1), (1, 1)]);
+    }
+
+    #[test]
+    fn downsampling_averages_as_light_when_srgb() {
+        let image = Image::new(2, 1, vec![0, 0, 0, 0, 255, 255, 255, 255]).unwrap();
+        assert_eq!(image.downsample(false).texel(0, 0), [128, 128, 128, 128]);
+        // Half as much light is brighter than halfway in sRGB; alpha is always linear.
+        assert_eq!(image.downsample(true).texel(0, 0), [188, 188, 188, 128]);
+    }
+}
@@ -0,0 +1,200 @@
+//! Drawing many copies of a mesh at once
+//!
+//! An `InstancedMesh` is drawn with a single instanced draw call:
+//! `instancedVertexShader` reads each copy's offset, scale, rotation and color
+//! from a buffer of `AAPLInstance`s by its `[[instance_id]]`.
+//! Copies that can't be seen are culled on the CPU before the buffer is packed,
+//! using a circle around the mesh that contains it however it's rotated.
+
+use crate::blend::BlendMode;
+use crate::mesh::Mesh;
+use crate::shader_types::{AAPLVertex, AAPLInstance};
+use crate::transform::Matrix3;
+
+/// Where one copy of a mesh goes, and its color.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Instance {
+    /// Pixels, after scaling and rotating.
+    pub offset: [f32; 2],
+    pub scale: [f32; 2],
+    /// Anticlockwise, in radians.
+    pub rotation: f32,
+    /// Multiplied with the vertex colors.
+    pub color: [f32; 4],
+}
+
+impl Instance {
+    /// An instance at its mesh's own size and orientation, in the mesh's own colors.
+    pub fn at(offset: [f32; 2]) -> Self {
+        Instance { offset, scale: [1., 1.], rotation: 0., color: [1., 1., 1., 1.] }
+    }
+
+    pub fn to_shader_instance(self) -> AAPLInstance {
+        AAPLInstance::new(self.offset, self.scale, self.rotation, self.color)
+    }
+}
+
+/// A mesh drawn once for each instance, on top of the scene.
+pub struct InstancedMesh {
+    pub mesh: Mesh<AAPLVertex>,
+    pub instances: Vec<Instance>,
+    /// Where all the instances are drawn, in pixels from the centre of the view.
+    pub transform: Matrix3,
+    /// Multiplied with the instance and vertex colors.
+    pub tint: [f32; 4],
+    /// From 0 (nearest) to 1 (farthest).
+    pub depth: f32,
+    pub blend_mode: BlendMode,
+}
+
+impl InstancedMesh {
+    pub fn new(mesh: Mesh<AAPLVertex>, instances: Vec<Instance>) -> Self {
+        InstancedMesh {
+            mesh,
+            instances,
+            transform: Matrix3::identity(),
+            tint: [1., 1., 1., 1.],
+            depth: 0.,
+            blend_mode: BlendMode::Opaque,
+        }
+    }
+
+    /// The instances that might be seen in a view of the given size (in pixels), packed for the shader.
+    pub fn visible_instances(&self, viewport_size: [f32; 2]) -> Vec<AAPLInstance> {
+        let radius = bounding_radius(&self.mesh);
+        self.instances.iter()
+            .filter(|instance| is_visible(instance, radius, &self.transform, viewport_size))
+            .map(|instance| instance.to_shader_instance())
+            .collect()
+    }
+}
+
+/// How far the mesh's furthest vertex is from its origin, which instances rotate and scale it around.
+pub fn bounding_radius(mesh: &Mesh<AAPLVertex>) -> f32 {
+    mesh.vertices().iter()
+        .map(|vertex| {
+            let (x, y) = (vertex.position.x(), vertex.position.y());
+            (x * x + y * y).sqrt()
+        })
+        .fold(0., f32::max)
+}
+
+/// Whether any of an instance of a mesh with the given bounding radius might be inside the view,
+/// once it's been moved by `transform`.
+///
+/// Errs on the side of drawing: the circle around a squashed or sheared instance is a little too big.
+pub fn is_visible(instance: &Instance, bounding_radius: f32, transform: &Matrix3, viewport_size: [f32; 2]) -> bool {
+    let center = transform.transform_point(instance.offset);
+    let instance_scale = instance.scale[0].abs().max(instance.scale[1].abs());
+    let radius = bounding_radius * instance_scale * largest_stretch(transform);
+    // How far the centre is outside the view along each axis.
+    let dx = (center[0].abs() - viewport_size[0] / 2.).max(0.);
+    let dy = (center[1].abs() - viewport_size[1] / 2.).max(0.);
+    dx * dx + dy * dy <= radius * radius
+}
+
+/// The most the transform stretches anything by: its largest singular value.
+fn largest_stretch(transform: &Matrix3) -> f32 {
+    let [[a, c, _], [b, d, _], _] = transform.columns;
+    // The square roots of the eigenvalues of the transpose times the transform.
+    let sum_of_squares = a * a + b * b + c * c + d * d;
+    let determinant = a * d - b * c;
+    let difference = (sum_of_squares * sum_of_squares - 4. * determinant * determinant).max(0.).sqrt();
+    ((sum_of_squares + difference) / 2.).sqrt()
+}
+
+/// `count` instances spiralling out from the centre like the seeds of a sunflower,
+/// `spacing` pixels apart, scaled by `scale`, each turned a little more than the last and a different color.
+pub fn sunflower_instances(count: usize, spacing: f32, scale: f32) -> Vec<Instance> {
+    let golden_angle = std::f32::consts::PI * (3. - 5f32.sqrt());
+    (0..count)
+        .map(|index| {
+            let angle = index as f32 * golden_angle;
+            let distance = spacing * (index as f32).sqrt();
+            let hue = (index as f32 * 0.618_034).fract();
+            Instance {
+                offset: [distance * angle.cos(), distance * angle.sin()],
+                scale: [scale, scale],
+                rotation: angle,
+                color: hue_color(hue),
+            }
+        })
+        .collect()
+}
+
+/// A fully saturated color with the given hue, from 0 (red) round to 1.
+fn hue_color(hue: f32) -> [f32; 4] {
+    let channel = |offset: f32| {
+        let distance = ((hue + offset).fract() * 6. - 3.).abs();
+        (distance - 1.).clamp(0., 1.)
+    };
+    [channel(0.), channel(2. / 3.), channel(1. / 3.), 1.]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transform::Transform2D;
+
+    #[test]
+    fn instances_are_packed_as_metal_lays_them_out() {
+        assert_eq!(std::mem::size_of::<AAPLInstance>(), 48);
+        let instance = Instance { offset: [1., 2.], scale: [3., 4.], rotation: 0.5, color: [0.1, 0.2, 0.3, 0.4] };
+        let packed = instance.to_shader_instance();
+        let floats: [f32; 9] = [
+            packed.color.x(), packed.color.y(), packed.color.z(), packed.color.w(),
+            packed.offset[0], packed.offset[1], packed.scale[0], packed.scale[1], packed.rotation,
+        ];
+        assert_eq!(floats, [0.1, 0.2, 0.3, 0.4, 1., 2., 3., 4., 0.5]);
+        // offset, scale and rotation come straight after the color.
+        let base = &packed as *const AAPLInstance as usize;
+        assert_eq!(&packed.offset as *const [f32; 2] as usize - base, 16);
+        assert_eq!(&packed.rotation as *const f32 as usize - base, 32);
+    }
+
+    #[test]
+    fn the_bounding_radius_reaches_the_furthest_vertex() {
+        assert_eq!(bounding_radius(&Mesh::hello_triangle()), (2f32 * 250. * 250.).sqrt());
+    }
+
+    #[test]
+    fn instances_just_outside_the_view_are_culled() {
+        let viewport_size = [800., 600.];
+        let visible = |offset: [f32; 2], transform: &Matrix3| is_visible(&Instance::at(offset), 10., transform, viewport_size);
+        let identity = Matrix3::identity();
+        assert!(visible([0., 0.], &identity));
+        // Past the right edge (at 400), by less and more than the radius.
+        assert!(visible([409., 0.], &identity));
+        assert!(!visible([411., 0.], &identity));
+        assert!(!visible([0., -311.], &identity));
+        // Past the corner: the circle reaches it diagonally or not.
+        assert!(visible([407., 307.], &identity));
+        assert!(!visible([408., 308.], &identity));
+        // Scaled up, instances move out and their circles grow with them.
+        let doubled = Transform2D { scale: [2., 2.], ..Transform2D::identity() }.to_matrix();
+        assert!(visible([209., 0.], &doubled));
+        assert!(!visible([211., 0.], &doubled));
+        let scaled_instance = Instance { scale: [1., -3.], ..Instance::at([425., 0.]) };
+        assert!(is_visible(&scaled_instance, 10., &identity, viewport_size));
+    }
+
+    #[test]
+    fn only_visible_instances_are_packed() {
+        let instances = vec![Instance::at([0., 0.]), Instance::at([1000., 0.]), Instance::at([-50., 50.])];
+        let instanced_mesh = InstancedMesh::new(Mesh::hello_triangle(), instances);
+        let offsets: Vec<[f32; 2]> = instanced_mesh.visible_instances([800., 600.]).iter().map(|instance| instance.offset).collect();
+        assert_eq!(offsets, [[0., 0.], [-50., 50.]]);
+    }
+
+    #[test]
+    fn sunflower_instances_spiral_out() {
+        let instances = sunflower_instances(5, 10., 0.5);
+        assert_eq!(instances.len(), 5);
+        assert_eq!(instances[0], Instance { offset: [0., 0.], scale: [0.5, 0.5], rotation: 0., color: [1., 0., 0., 1.] });
+        for (index, instance) in instances.iter().enumerate() {
+            let distance = (instance.offset[0].powi(2) + instance.offset[1].powi(2)).sqrt();
+            assert!((distance - 10. * (index as f32).sqrt()).abs() < 1e-4);
+        }
+        assert_eq!(hue_color(1. / 3.), [0., 1., 0., 1.]);
+    }
+}
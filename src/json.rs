@@ -0,0 +1,528 @@
+//! Just enough JSON for our data files
+//!
+//! Every value remembers where it started in the source text,
+//! so that whoever interprets the values can say *where* something is wrong,
+//! not just that it is.
+
+use std::fmt::{Display, Formatter, Write};
+use std::error::Error;
+
+/// A place in the source text, counting from 1.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Display for Position {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+/// A JSON value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Spanned>),
+    /// Members are kept in the order they appear.
+    Object(Vec<(String, Spanned)>),
+}
+
+/// A JSON value and where it started.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spanned {
+    pub value: Value,
+    pub position: Position,
+}
+
+impl Spanned {
+    /// Looks up a member of an object.
+    pub fn get(&self, key: &str) -> Option<&Spanned> {
+        match &self.value {
+            Value::Object(members) => members.iter().find(|(name, _)| name == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    /// A short description of the kind of value, for error messages.
+    pub fn type_name(&self) -> &'static str {
+        match self.value {
+            Value::Null => "null",
+            Value::Bool(_) => "a boolean",
+            Value::Number(_) => "a number",
+            Value::String(_) => "a string",
+            Value::Array(_) => "an array",
+            Value::Object(_) => "an object",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ParseError {
+    pub message: String,
+    pub position: Position,
+}
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.position, self.message)
+    }
+}
+impl Error for ParseError{}
+
+/// Parses a complete JSON document.
+pub fn parse(text: &str) -> Result<Spanned, ParseError> {
+    let mut parser = Parser {
+        chars: text.chars().collect(),
+        index: 0,
+        line: 1,
+        column: 1,
+    };
+    parser.skip_whitespace();
+    let value = parser.parse_value()?;
+    parser.skip_whitespace();
+    if parser.peek().is_some() {
+        return Err(parser.error("unexpected text after the end of the document"));
+    }
+    Ok(value)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    index: usize,
+    line: usize,
+    column: usize,
+}
+
+impl Parser {
+    fn position(&self) -> Position {
+        Position { line: self.line, column: self.column }
+    }
+
+    fn error(&self, message: &str) -> ParseError {
+        ParseError { message: message.to_string(), position: self.position() }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.index).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.index += 1;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), ParseError> {
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.next();
+                Ok(())
+            }
+            _ => Err(self.error(&format!("expected '{}'", expected))),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.peek() {
+            if c == ' ' || c == '\t' || c == '\n' || c == '\r' {
+                self.next();
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Spanned, ParseError> {
+        let position = self.position();
+        let value = match self.peek() {
+            Some('{') => self.parse_object()?,
+            Some('[') => self.parse_array()?,
+            Some('"') => Value::String(self.parse_string()?),
+            Some('t') => self.parse_keyword("true", Value::Bool(true))?,
+            Some('f') => self.parse_keyword("false", Value::Bool(false))?,
+            Some('n') => self.parse_keyword("null", Value::Null)?,
+            Some(c) if c == '-' || c.is_ascii_digit() => Value::Number(self.parse_number()?),
+            Some(_) => return Err(self.error("expected a value")),
+            None => return Err(self.error("unexpected end of document")),
+        };
+        Ok(Spanned { value, position })
+    }
+
+    fn parse_keyword(&mut self, keyword: &str, value: Value) -> Result<Value, ParseError> {
+        let position = self.position();
+        for expected in keyword.chars() {
+            if self.next() != Some(expected) {
+                return Err(ParseError { message: format!("expected '{}'", keyword), position });
+            }
+        }
+        Ok(value)
+    }
+
+    /// Reads a number as JSON spells it: no leading zeros, and digits after any `.` or exponent.
+    fn parse_number(&mut self) -> Result<f64, ParseError> {
+        let position = self.position();
+        let mut text = String::new();
+        if self.peek() == Some('-') {
+            text.push('-');
+            self.next();
+        }
+        if self.peek() == Some('0') {
+            text.push('0');
+            self.next();
+            if self.peek().is_some_and(|c| c.is_ascii_digit()) {
+                return Err(self.error("numbers can't have leading zeros"));
+            }
+        } else {
+            self.read_digits(&mut text, "expected a digit")?;
+        }
+        if self.peek() == Some('.') {
+            text.push('.');
+            self.next();
+            self.read_digits(&mut text, "expected a digit after the decimal point")?;
+        }
+        if let Some(exponent @ ('e' | 'E')) = self.peek() {
+            text.push(exponent);
+            self.next();
+            if let Some(sign @ ('+' | '-')) = self.peek() {
+                text.push(sign);
+                self.next();
+            }
+            self.read_digits(&mut text, "expected a digit in the exponent")?;
+        }
+        text.parse::<f64>()
+            .map_err(|_| ParseError { message: format!("'{}' is not a valid number", text), position })
+    }
+
+    /// Adds one or more digits to `text`, or fails with `message` if there aren't any.
+    fn read_digits(&mut self, text: &mut String, message: &str) -> Result<(), ParseError> {
+        if !self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            return Err(self.error(message));
+        }
+        while let Some(digit) = self.peek().filter(char::is_ascii_digit) {
+            text.push(digit);
+            self.next();
+        }
+        Ok(())
+    }
+
+    fn parse_string(&mut self) -> Result<String, ParseError> {
+        self.expect('"')?;
+        let mut string = String::new();
+        loop {
+            let position = self.position();
+            match self.next() {
+                Some('"') => return Ok(string),
+                Some('\\') => {
+                    let escaped = match self.next() {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('/') => '/',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('u') => self.parse_unicode_escape(position)?,
+                        _ => return Err(ParseError { message: "invalid escape sequence".to_string(), position }),
+                    };
+                    string.push(escaped);
+                }
+                Some(c) if (c as u32) < 0x20 => {
+                    return Err(ParseError { message: "control characters must be escaped".to_string(), position })
+                }
+                Some(c) => string.push(c),
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    fn parse_unicode_escape(&mut self, position: Position) -> Result<char, ParseError> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let digit = self.next().and_then(|c| c.to_digit(16))
+                .ok_or(ParseError { message: "invalid unicode escape".to_string(), position })?;
+            code = code * 16 + digit;
+        }
+        // We don't bother pairing up surrogates; our files don't need them.
+        std::char::from_u32(code)
+            .ok_or(ParseError { message: "unsupported unicode escape".to_string(), position })
+    }
+
+    fn parse_array(&mut self) -> Result<Value, ParseError> {
+        self.expect('[')?;
+        let mut elements = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(']') {
+            self.next();
+            return Ok(Value::Array(elements));
+        }
+        loop {
+            self.skip_whitespace();
+            elements.push(self.parse_value()?);
+            self.skip_whitespace();
+            match self.next() {
+                Some(',') => continue,
+                Some(']') => return Ok(Value::Array(elements)),
+                _ => return Err(self.error("expected ',' or ']'")),
+            }
+        }
+    }
+
+    fn parse_object(&mut self) -> Result<Value, ParseError> {
+        self.expect('{')?;
+        let mut members: Vec<(String, Spanned)> = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.next();
+            return Ok(Value::Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            let key_position = self.position();
+            let key = self.parse_string()?;
+            if members.iter().any(|(name, _)| *name == key) {
+                return Err(ParseError { message: format!("duplicate key \"{}\"", key), position: key_position });
+            }
+            self.skip_whitespace();
+            self.expect(':')?;
+            self.skip_whitespace();
+            let value = self.parse_value()?;
+            members.push((key, value));
+            self.skip_whitespace();
+            match self.next() {
+                Some(',') => continue,
+                Some('}') => return Ok(Value::Object(members)),
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+}
+
+/// Builds JSON text, two spaces per level of indentation.
+///
+/// Short arrays of numbers stay on one line, which keeps vertex data readable.
+pub struct Writer {
+    output: String,
+    indent: usize,
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        Writer { output: String::new(), indent: 0 }
+    }
+
+    pub fn finish(mut self) -> String {
+        self.output.push('\n');
+        self.output
+    }
+
+    pub fn write_value(&mut self, value: &Value) {
+        match value {
+            Value::Null => self.output.push_str("null"),
+            Value::Bool(b) => self.output.push_str(if *b { "true" } else { "false" }),
+            Value::Number(n) => write_number(&mut self.output, *n),
+            Value::String(s) => write_string(&mut self.output, s),
+            Value::Array(elements) if elements.iter().all(|e| matches!(e.value, Value::Number(_))) && elements.len() <= 4 => {
+                self.output.push('[');
+                for (index, element) in elements.iter().enumerate() {
+                    if index > 0 {
+                        self.output.push_str(", ");
+                    }
+                    self.write_value(&element.value);
+                }
+                self.output.push(']');
+            }
+            Value::Array(elements) if elements.is_empty() => self.output.push_str("[]"),
+            Value::Array(elements) => {
+                self.output.push('[');
+                self.indent += 1;
+                for (index, element) in elements.iter().enumerate() {
+                    self.output.push_str(if index > 0 { ",\n" } else { "\n" });
+                    self.write_indent();
+                    self.write_value(&element.value);
+                }
+                self.indent -= 1;
+                self.output.push('\n');
+                self.write_indent();
+                self.output.push(']');
+            }
+            Value::Object(members) if members.is_empty() => self.output.push_str("{}"),
+            Value::Object(members) => {
+                self.output.push('{');
+                self.indent += 1;
+                for (index, (name, member)) in members.iter().enumerate() {
+                    self.output.push_str(if index > 0 { ",\n" } else { "\n" });
+                    self.write_indent();
+                    write_string(&mut self.output, name);
+                    self.output.push_str(": ");
+                    self.write_value(&member.value);
+                }
+                self.indent -= 1;
+                self.output.push('\n');
+                self.write_indent();
+                self.output.push('}');
+            }
+        }
+    }
+
+    fn write_indent(&mut self) {
+        for _ in 0..self.indent {
+            self.output.push_str("  ");
+        }
+    }
+}
+
+impl Default for Writer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn write_number(output: &mut String, n: f64) {
+    if n.is_finite() {
+        // Rust prints the shortest text that reads back as the same number.
+        let _ = write!(output, "{}", n);
+    } else {
+        // JSON has no infinities or NaNs.
+        output.push_str("null");
+    }
+}
+
+fn write_string(output: &mut String, s: &str) {
+    output.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\n' => output.push_str("\\n"),
+            '\r' => output.push_str("\\r"),
+            '\t' => output.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(output, "\\u{:04x}", c as u32);
+            }
+            c => output.push(c),
+        }
+    }
+    output.push('"');
+}
+
+/// Wraps a value with a dummy position, for building documents to write out.
+pub fn unspanned(value: Value) -> Spanned {
+    Spanned { value, position: Position { line: 0, column: 0 } }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn number(text: &str) -> Result<f64, ParseError> {
+        match parse(text)?.value {
+            Value::Number(n) => Ok(n),
+            other => panic!("{} parsed as {:?}", text, other),
+        }
+    }
+
+    fn error_at(text: &str) -> (usize, usize, String) {
+        let error = parse(text).unwrap_err();
+        (error.position.line, error.position.column, error.message)
+    }
+
+    #[test]
+    fn numbers_parse_as_json_spells_them() {
+        assert_eq!(number("0"), Ok(0.));
+        assert_eq!(number("-0"), Ok(0.));
+        assert_eq!(number("10"), Ok(10.));
+        assert_eq!(number("-12.5"), Ok(-12.5));
+        assert_eq!(number("0.25"), Ok(0.25));
+        assert_eq!(number("1e3"), Ok(1000.));
+        assert_eq!(number("2.5E-1"), Ok(0.25));
+        assert_eq!(number("-1e+2"), Ok(-100.));
+    }
+
+    #[test]
+    fn malformed_numbers_are_rejected() {
+        assert_eq!(error_at("01"), (1, 2, "numbers can't have leading zeros".to_string()));
+        assert_eq!(error_at("-00"), (1, 3, "numbers can't have leading zeros".to_string()));
+        assert_eq!(error_at("1."), (1, 3, "expected a digit after the decimal point".to_string()));
+        assert_eq!(error_at("1.e5"), (1, 3, "expected a digit after the decimal point".to_string()));
+        assert_eq!(error_at("1e"), (1, 3, "expected a digit in the exponent".to_string()));
+        assert_eq!(error_at("1e+"), (1, 4, "expected a digit in the exponent".to_string()));
+        assert_eq!(error_at("-"), (1, 2, "expected a digit".to_string()));
+        assert_eq!(error_at("-.5"), (1, 2, "expected a digit".to_string()));
+        assert!(parse(".5").is_err());
+        assert!(parse("+1").is_err());
+        assert!(parse("1.5.2").is_err());
+        assert!(parse("[1-2]").is_err());
+    }
+
+    #[test]
+    fn values_remember_their_line_and_column() {
+        let document = parse("{\n  \"a\": [1,\n    true],\n  \"b\": \"x\"\n}").unwrap();
+        assert_eq!(document.position, Position { line: 1, column: 1 });
+        let a = document.get("a").unwrap();
+        assert_eq!(a.position, Position { line: 2, column: 8 });
+        match &a.value {
+            Value::Array(elements) => assert_eq!(elements[1].position, Position { line: 3, column: 5 }),
+            other => panic!("expected an array, not {:?}", other),
+        }
+        assert_eq!(document.get("b").unwrap().position, Position { line: 4, column: 8 });
+    }
+
+    #[test]
+    fn errors_say_where_they_are() {
+        assert_eq!(error_at("{\n  \"a\": 1,\n  \"a\": 2\n}"), (3, 3, "duplicate key \"a\"".to_string()));
+        assert_eq!(error_at("[1,\n 2\n"), (3, 1, "expected ',' or ']'".to_string()));
+        assert_eq!(error_at("{\"a\" 1}"), (1, 6, "expected ':'".to_string()));
+        assert_eq!(error_at("[tru]"), (1, 2, "expected 'true'".to_string()));
+        assert_eq!(error_at(r#""\q""#), (1, 2, "invalid escape sequence".to_string()));
+        assert_eq!(error_at("1 2"), (1, 3, "unexpected text after the end of the document".to_string()));
+        assert_eq!(error_at(""), (1, 1, "unexpected end of document".to_string()));
+        assert_eq!(parse("{\"a\": 1}\n  x").unwrap_err().to_string(), "line 2, column 3: unexpected text after the end of the document");
+    }
+
+    #[test]
+    fn strings_unescape() {
+        assert_eq!(parse(r#""a\"b\\c\/d\né""#).unwrap().value, Value::String("a\"b\\c/d\n\u{e9}".to_string()));
+        assert!(parse("\"a\nb\"").is_err(), "control characters must be escaped");
+        assert!(parse("\"abc").is_err());
+    }
+
+    /// The same value, with every position set to the dummy one `unspanned` uses.
+    fn without_positions(value: &Value) -> Value {
+        match value {
+            Value::Array(elements) => Value::Array(elements.iter().map(|element| unspanned(without_positions(&element.value))).collect()),
+            Value::Object(members) => Value::Object(members.iter()
+                .map(|(name, member)| (name.clone(), unspanned(without_positions(&member.value))))
+                .collect()),
+            other => other.clone(),
+        }
+    }
+
+    #[test]
+    fn written_documents_parse_back_the_same() {
+        let numbers = [0.1, -2e-7, 1e21].iter().map(|&n| unspanned(Value::Number(n))).collect();
+        let document = Value::Object(vec![
+            ("numbers".to_string(), unspanned(Value::Array(numbers))),
+            ("text".to_string(), unspanned(Value::String("tab\tquote\"\u{1}".to_string()))),
+            ("empty".to_string(), unspanned(Value::Object(Vec::new()))),
+            ("nothing".to_string(), unspanned(Value::Null)),
+            ("flags".to_string(), unspanned(Value::Array(vec![unspanned(Value::Bool(true)), unspanned(Value::Bool(false))]))),
+        ]);
+        let mut writer = Writer::new();
+        writer.write_value(&document);
+        let text = writer.finish();
+
+        assert_eq!(without_positions(&parse(&text).unwrap().value), document);
+        assert!(text.contains("[0.1, -0.0000002, 1000000000000000000000]"), "{}", text);
+    }
+}
@@ -0,0 +1,36 @@
+//! The parts of Hello Triangle that don't need Cocoa or Metal,
+//! so they build and test on any platform.
+//!
+//! The app itself, in `main.rs`, adds the modules that talk to Cocoa and Metal.
+
+pub mod pixel_format;
+pub mod frame_pacing;
+pub mod frame_stats;
+pub mod buffer_ring;
+pub mod mesh;
+pub mod scene;
+pub mod scene_file;
+pub mod error_chain;
+pub mod render_graph;
+pub mod image;
+pub mod sampler;
+pub mod font;
+pub mod glyph_atlas;
+pub mod text;
+pub mod batcher;
+pub mod hud;
+pub mod tessellator;
+pub mod instancing;
+pub mod particles;
+pub mod threadgroups;
+pub mod post_process;
+pub mod screenshot;
+pub mod recording;
+pub mod headless;
+pub mod command_trace;
+pub mod blend;
+pub mod software_rasterizer;
+pub mod json;
+pub mod transform;
+pub mod shader_types;
+pub mod vector_types; // our kludge of simd "OpenCL Vector Types".
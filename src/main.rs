@@ -18,21 +18,68 @@
 #![deny(missing_docs)]
 
 // public, so it will get documented
+#[cfg(target_os = "macos")]
 pub use crate::app_delegate::register_app_delegate_class;
+#[cfg(target_os = "macos")]
 use crate::view_controller::register_view_controller_class;
+#[cfg(target_os = "macos")]
 pub use crate::application_main::application_main;
+#[cfg(target_os = "macos")]
 use crate::metal_view::register_metal_view_class;
+use hello_triangle::{command_trace, headless};
 
+// The Cocoa modules find the rest of the app at `crate::`, as they always have.
+#[cfg(target_os = "macos")]
+use hello_triangle::{pixel_format, frame_pacing, frame_stats, buffer_ring, mesh, scene, scene_file, error_chain, render_graph, image, sampler, font, glyph_atlas, text, batcher, hud, tessellator, instancing, particles, threadgroups, post_process, screenshot, recording, blend, transform, shader_types, vector_types};
+
+#[cfg(target_os = "macos")]
 mod application_main;
+#[cfg(target_os = "macos")]
 mod app_delegate;
+#[cfg(target_os = "macos")]
 mod view_controller;
+#[cfg(target_os = "macos")]
 mod metal_view;
+#[cfg(target_os = "macos")]
 mod display_link;
+#[cfg(target_os = "macos")]
 mod renderer;
-mod vector_types; // our kludge of simd "OpenCL Vector Types".
+#[cfg(target_os = "macos")]
+mod hot_reload;
+#[cfg(target_os = "macos")]
+mod shader_library;
+#[cfg(target_os = "macos")]
+mod ns_error;
+#[cfg(target_os = "macos")]
+mod pipeline_cache;
+#[cfg(target_os = "macos")]
+mod compute;
+#[cfg(target_os = "macos")]
+mod render_targets;
+#[cfg(target_os = "macos")]
+mod depth_stencil;
+#[cfg(target_os = "macos")]
+mod texture;
 
 /// Main method
 pub fn main() {
+    // Set HELLO_TRIANGLE_REPLAY to replay a command trace with the software rasterizer, without starting the app.
+    if std::env::var_os("HELLO_TRIANGLE_REPLAY").is_some() {
+        command_trace::replay_from_environment();
+        return;
+    }
+    // Set HELLO_TRIANGLE_HEADLESS to record with the software rasterizer, without starting the app.
+    if std::env::var_os("HELLO_TRIANGLE_HEADLESS").is_some() {
+        headless::record_from_environment();
+        return;
+    }
+
+    run_app();
+}
+
+/// Registers our classes and hands over to Cocoa.
+#[cfg(target_os = "macos")]
+fn run_app() {
     // Register our classes
     // with the Objective C Runtime
     register_app_delegate_class();
@@ -42,3 +89,9 @@ pub fn main() {
     // Pass control to the NSApplicationMain
     application_main(std::env::args());
 }
+
+/// There's no Cocoa to hand over to, so only the software paths above are available.
+#[cfg(not(target_os = "macos"))]
+fn run_app() {
+    println!("The app needs macOS and Metal; set HELLO_TRIANGLE_HEADLESS or HELLO_TRIANGLE_REPLAY to use the software rasterizer.");
+}
@@ -0,0 +1,343 @@
+//! Meshes: vertex data, optional indices, and how to draw them
+
+use std::fmt::Formatter;
+use std::error::Error;
+use crate::shader_types::{AAPLVertex, AAPLTexturedVertex};
+
+/// How the GPU should assemble vertices into primitives.
+///
+/// These match `MTLPrimitiveType`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum PrimitiveTopology {
+    Point,
+    Line,
+    LineStrip,
+    Triangle,
+    TriangleStrip,
+}
+
+impl PrimitiveTopology {
+    /// The fewest vertices (or indices) that make up a primitive.
+    pub fn minimum_count(self) -> usize {
+        match self {
+            PrimitiveTopology::Point => 1,
+            PrimitiveTopology::Line | PrimitiveTopology::LineStrip => 2,
+            PrimitiveTopology::Triangle | PrimitiveTopology::TriangleStrip => 3,
+        }
+    }
+
+    /// The number of whole primitives `count` vertices (or indices) make.
+    pub fn primitive_count(self, count: usize) -> usize {
+        match self {
+            PrimitiveTopology::Point => count,
+            PrimitiveTopology::Line => count / 2,
+            PrimitiveTopology::LineStrip => count.saturating_sub(1),
+            PrimitiveTopology::Triangle => count / 3,
+            PrimitiveTopology::TriangleStrip => count.saturating_sub(2),
+        }
+    }
+
+    /// The name used in scene files.
+    pub fn name(self) -> &'static str {
+        match self {
+            PrimitiveTopology::Point => "point",
+            PrimitiveTopology::Line => "line",
+            PrimitiveTopology::LineStrip => "line_strip",
+            PrimitiveTopology::Triangle => "triangle",
+            PrimitiveTopology::TriangleStrip => "triangle_strip",
+        }
+    }
+
+    /// The topology with the given scene file name.
+    pub fn from_name(name: &str) -> Option<Self> {
+        [PrimitiveTopology::Point, PrimitiveTopology::Line, PrimitiveTopology::LineStrip, PrimitiveTopology::Triangle, PrimitiveTopology::TriangleStrip]
+            .iter()
+            .copied()
+            .find(|topology| topology.name() == name)
+    }
+}
+
+/// Indices into a mesh's vertices.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IndexData {
+    U16(Vec<u16>),
+    U32(Vec<u32>),
+}
+
+impl IndexData {
+    /// Uses 16-bit indices if they'll fit, otherwise 32-bit.
+    pub fn from_u32(indices: Vec<u32>) -> Self {
+        if indices.iter().all(|&index| index <= u32::from(u16::MAX)) {
+            IndexData::U16(indices.into_iter().map(|index| index as u16).collect())
+        } else {
+            IndexData::U32(indices)
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            IndexData::U16(indices) => indices.len(),
+            IndexData::U32(indices) => indices.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The size of a single index, in bytes.
+    pub fn index_size(&self) -> usize {
+        match self {
+            IndexData::U16(_) => std::mem::size_of::<u16>(),
+            IndexData::U32(_) => std::mem::size_of::<u32>(),
+        }
+    }
+
+    /// The largest index, if there are any.
+    pub fn max_index(&self) -> Option<u32> {
+        match self {
+            IndexData::U16(indices) => indices.iter().max().map(|&index| u32::from(index)),
+            IndexData::U32(indices) => indices.iter().max().copied(),
+        }
+    }
+
+    /// Returns the index at the given position.
+    pub fn get(&self, position: usize) -> Option<u32> {
+        match self {
+            IndexData::U16(indices) => indices.get(position).map(|&index| u32::from(index)),
+            IndexData::U32(indices) => indices.get(position).copied(),
+        }
+    }
+}
+
+/// Which part of a mesh to draw,
+/// in indices if the mesh has them, otherwise in vertices.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DrawRange {
+    pub start: usize,
+    pub count: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MeshError {
+    IndexOutOfRange { index: u32, vertex_count: usize },
+    DrawRangeOutOfBounds { range: DrawRange, available: usize },
+    TooFewVertices { count: usize, topology: PrimitiveTopology },
+}
+impl std::fmt::Display for MeshError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IndexOutOfRange { index, vertex_count } =>
+                write!(f, "Index {} is out of range for {} vertices", index, vertex_count),
+            Self::DrawRangeOutOfBounds { range, available } =>
+                write!(f, "Draw range {}..{} is out of bounds (only {} available)", range.start, range.start + range.count, available),
+            Self::TooFewVertices { count, topology } =>
+                write!(f, "{} vertices are too few to draw a {:?}", count, topology),
+        }
+    }
+}
+impl Error for MeshError{}
+
+/// Typed vertex data plus the information needed to draw it.
+#[derive(Clone)]
+pub struct Mesh<V> {
+    vertices: Vec<V>,
+    indices: Option<IndexData>,
+    topology: PrimitiveTopology,
+    draw_range: Option<DrawRange>,
+}
+
+impl<V> Mesh<V> {
+    /// Creates a mesh that draws all the vertices in order.
+    pub fn new(vertices: Vec<V>, topology: PrimitiveTopology) -> Self {
+        Mesh {
+            vertices,
+            indices: None,
+            topology,
+            draw_range: None,
+        }
+    }
+
+    /// Draws the vertices in the order given by `indices` instead.
+    pub fn with_indices(mut self, indices: IndexData) -> Self {
+        self.indices = Some(indices);
+        self
+    }
+
+    /// Draws only part of the mesh.
+    pub fn with_draw_range(mut self, draw_range: DrawRange) -> Self {
+        self.draw_range = Some(draw_range);
+        self
+    }
+
+    pub fn vertices(&self) -> &[V] {
+        &self.vertices
+    }
+    pub fn indices(&self) -> Option<&IndexData> {
+        self.indices.as_ref()
+    }
+    pub fn topology(&self) -> PrimitiveTopology {
+        self.topology
+    }
+
+    /// The part of the mesh to draw: the whole thing unless told otherwise.
+    pub fn draw_range(&self) -> DrawRange {
+        self.draw_range.unwrap_or(DrawRange { start: 0, count: self.element_count() })
+    }
+
+    /// The number of indices, or vertices if there are no indices.
+    pub fn element_count(&self) -> usize {
+        match &self.indices {
+            Some(indices) => indices.len(),
+            None => self.vertices.len(),
+        }
+    }
+
+    /// The size of the vertex data, in bytes.
+    pub fn vertex_bytes_len(&self) -> usize {
+        std::mem::size_of::<V>() * self.vertices.len()
+    }
+
+    /// Checks that the indices and draw range all refer to real vertices.
+    pub fn validate(&self) -> Result<(), MeshError> {
+        if let Some(max_index) = self.indices.as_ref().and_then(IndexData::max_index) {
+            if max_index as usize >= self.vertices.len() {
+                return Err(MeshError::IndexOutOfRange { index: max_index, vertex_count: self.vertices.len() });
+            }
+        }
+        let range = self.draw_range();
+        let available = self.element_count();
+        if range.start + range.count > available {
+            return Err(MeshError::DrawRangeOutOfBounds { range, available });
+        }
+        if range.count < self.topology.minimum_count() {
+            return Err(MeshError::TooFewVertices { count: range.count, topology: self.topology });
+        }
+        Ok(())
+    }
+}
+
+impl Mesh<AAPLVertex> {
+    /// The red, green and blue triangle from Apple's sample.
+    pub fn hello_triangle() -> Self {
+        // static const AAPLVertex triangleVertices[] =
+        // {
+        //     // 2D positions,    RGBA colors
+        //     { {  250,  -250 }, { 1, 0, 0, 1 } },
+        //     { { -250,  -250 }, { 0, 1, 0, 1 } },
+        //     { {    0,   250 }, { 0, 0, 1, 1 } },
+        // };
+        Mesh::new(
+            vec![
+                AAPLVertex::new([250., -250.], [1., 0., 0., 1.]),
+                AAPLVertex::new([-250., -250.], [0., 1., 0., 1.]),
+                AAPLVertex::new([0., 250.], [0., 0., 1., 1.]),
+            ],
+            PrimitiveTopology::Triangle,
+        ).with_indices(IndexData::U16(vec![0, 1, 2]))
+    }
+
+    /// An axis-aligned rectangle in a single color.
+    pub fn rectangle(center: [f32; 2], size: [f32; 2], color: [f32; 4]) -> Self {
+        let (half_width, half_height) = (size[0] / 2., size[1] / 2.);
+        Mesh::new(
+            vec![
+                AAPLVertex::new([center[0] - half_width, center[1] - half_height], color),
+                AAPLVertex::new([center[0] + half_width, center[1] - half_height], color),
+                AAPLVertex::new([center[0] + half_width, center[1] + half_height], color),
+                AAPLVertex::new([center[0] - half_width, center[1] + half_height], color),
+            ],
+            PrimitiveTopology::Triangle,
+        ).with_indices(IndexData::U16(vec![0, 1, 2, 0, 2, 3]))
+    }
+
+    /// A regular polygon with `sides` sides, as a fan of triangles around its centre.
+    pub fn regular_polygon(center: [f32; 2], radius: f32, sides: u32, color: [f32; 4]) -> Self {
+        let sides = u32::max(3, sides);
+        let mut vertices = vec![AAPLVertex::new(center, color)];
+        for side in 0..sides {
+            let angle = std::f32::consts::PI * 2. * side as f32 / sides as f32;
+            vertices.push(AAPLVertex::new([center[0] + radius * angle.cos(), center[1] + radius * angle.sin()], color));
+        }
+        let mut indices = Vec::with_capacity(sides as usize * 3);
+        for side in 0..sides {
+            indices.extend_from_slice(&[0, side + 1, (side + 1) % sides + 1]);
+        }
+        Mesh::new(vertices, PrimitiveTopology::Triangle).with_indices(IndexData::from_u32(indices))
+    }
+}
+
+impl Mesh<AAPLTexturedVertex> {
+    /// An axis-aligned rectangle showing the whole of a texture, the right way up.
+    pub fn textured_quad(center: [f32; 2], size: [f32; 2], color: [f32; 4]) -> Self {
+        let (half_width, half_height) = (size[0] / 2., size[1] / 2.);
+        // Positions have y up, but texture coordinates have y down.
+        Mesh::new(
+            vec![
+                AAPLTexturedVertex::new([center[0] - half_width, center[1] - half_height], color, [0., 1.]),
+                AAPLTexturedVertex::new([center[0] + half_width, center[1] - half_height], color, [1., 1.]),
+                AAPLTexturedVertex::new([center[0] + half_width, center[1] + half_height], color, [1., 0.]),
+                AAPLTexturedVertex::new([center[0] - half_width, center[1] + half_height], color, [0., 0.]),
+            ],
+            PrimitiveTopology::Triangle,
+        ).with_indices(IndexData::U16(vec![0, 1, 2, 0, 2, 3]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn square() -> Mesh<[f32; 2]> {
+        Mesh::new(vec![[0., 0.], [1., 0.], [1., 1.], [0., 1.]], PrimitiveTopology::Triangle)
+            .with_indices(IndexData::U16(vec![0, 1, 2, 0, 2, 3]))
+    }
+
+    #[test]
+    fn indices_must_refer_to_vertices() {
+        assert_eq!(square().validate(), Ok(()));
+        let mesh = square().with_indices(IndexData::U16(vec![0, 1, 4]));
+        assert_eq!(mesh.validate(), Err(MeshError::IndexOutOfRange { index: 4, vertex_count: 4 }));
+    }
+
+    #[test]
+    fn draw_ranges_must_fit_in_the_mesh() {
+        assert_eq!(square().with_draw_range(DrawRange { start: 3, count: 3 }).validate(), Ok(()));
+        let range = DrawRange { start: 4, count: 3 };
+        assert_eq!(square().with_draw_range(range).validate(), Err(MeshError::DrawRangeOutOfBounds { range, available: 6 }));
+        // Without indices, the range counts vertices.
+        let range = DrawRange { start: 0, count: 6 };
+        let unindexed = Mesh::new(square().vertices().to_vec(), PrimitiveTopology::Triangle);
+        assert_eq!(unindexed.with_draw_range(range).validate(), Err(MeshError::DrawRangeOutOfBounds { range, available: 4 }));
+    }
+
+    #[test]
+    fn meshes_need_enough_vertices_for_a_primitive() {
+        let line = Mesh::new(vec![[0., 0.], [1., 0.]], PrimitiveTopology::Triangle);
+        assert_eq!(line.validate(), Err(MeshError::TooFewVertices { count: 2, topology: PrimitiveTopology::Triangle }));
+        assert_eq!(Mesh::new(vec![[0., 0.], [1., 0.]], PrimitiveTopology::Line).validate(), Ok(()));
+        let empty: Mesh<[f32; 2]> = Mesh::new(Vec::new(), PrimitiveTopology::Point);
+        assert_eq!(empty.validate(), Err(MeshError::TooFewVertices { count: 0, topology: PrimitiveTopology::Point }));
+    }
+
+    #[test]
+    fn indices_are_16_bit_when_they_fit() {
+        let largest_u16 = u32::from(u16::MAX);
+        assert_eq!(IndexData::from_u32(vec![0, largest_u16]), IndexData::U16(vec![0, u16::MAX]));
+        assert_eq!(IndexData::from_u32(vec![0, largest_u16 + 1]), IndexData::U32(vec![0, largest_u16 + 1]));
+        assert_eq!(IndexData::from_u32(Vec::new()), IndexData::U16(Vec::new()));
+        assert_eq!(IndexData::from_u32(vec![7]).index_size(), 2);
+    }
+
+    #[test]
+    fn primitives_are_counted_whole() {
+        assert_eq!(PrimitiveTopology::Point.primitive_count(5), 5);
+        assert_eq!(PrimitiveTopology::Line.primitive_count(5), 2);
+        assert_eq!(PrimitiveTopology::LineStrip.primitive_count(5), 4);
+        assert_eq!(PrimitiveTopology::Triangle.primitive_count(7), 2);
+        assert_eq!(PrimitiveTopology::TriangleStrip.primitive_count(7), 5);
+        // Too few for even one primitive.
+        assert_eq!(PrimitiveTopology::LineStrip.primitive_count(0), 0);
+        assert_eq!(PrimitiveTopology::TriangleStrip.primitive_count(1), 0);
+    }
+}
@@ -11,10 +11,13 @@ use objc::{Encode, Encoding};
 use crate::renderer::Renderer;
 use cocoa::base::{id, nil};
 use objc::declare::ClassDecl;
-use objc::runtime::{Object, Sel, BOOL, objc_retain, objc_release};
+use objc::runtime::{Object, Sel, BOOL, NO, YES, objc_retain, objc_release};
 use crate::display_link::{DisplayLink, dispatch_queue_t};
-use cocoa::foundation::{NSUInteger, NSRect, NSSize, NSAutoreleasePool};
+use cocoa::foundation::{NSUInteger, NSInteger, NSRect, NSSize, NSAutoreleasePool, NSString};
 use std::ffi::c_void;
+use crate::frame_pacing::{FrameScheduler, FrameTick, SystemClock, DEFAULT_REFRESH_RATE};
+use crate::ns_error::string_from_ns_string;
+use crate::pixel_format::{MTLPixelFormat, MTLPixelFormatBGRA8Unorm, MTLPixelFormatBGRA8Unorm_sRGB, MTLPixelFormatBGR10A2Unorm, MTLPixelFormatRGBA16Float, MTLPixelFormatInvalid, pixel_format_has_depth, pixel_format_has_stencil};
 
 // From Metal.framework/Versions/A/Headers/MTLRenderPass.h
 // in XCode MacOS.sdk:
@@ -48,6 +51,12 @@ unsafe impl Encode for MTLClearColor {
 pub fn MTLClearColorMake(red: c_double, green: c_double, blue: c_double, alpha: c_double) -> MTLClearColor {
     MTLClearColor {red, green, blue, alpha }
 }
+impl MTLClearColor {
+    /// Red, green, blue and alpha.
+    pub fn components(&self) -> [c_double; 4] {
+        [self.red, self.green, self.blue, self.alpha]
+    }
+}
 
 // From System/Library/Frameworks/CoreGraphics.framework/Versions/A/Headers/CGGeometry.h:
 // struct CGSize {
@@ -70,11 +79,66 @@ pub fn MTLClearColorMake(red: c_double, green: c_double, blue: c_double, alpha:
 // }
 pub type CGSize = NSSize; // technically, it's the other way around
 
-// From System/Library/Frameworks/Metal.framework/Versions/A/Headers/MTLPixelFormat.h:
-// typedef NS_ENUM(NSUInteger, MTLPixelFormat) {...}
-pub type MTLPixelFormat = NSUInteger;
-// MTLPixelFormatBGRA8Unorm      = 80
-static MTLPixelFormatBGRA8Unorm:MTLPixelFormat = 80;
+// From System/Library/Frameworks/CoreGraphics.framework/Versions/A/Headers/CGColorSpace.h
+type CGColorSpaceRef = *mut c_void;
+type CFStringRef = id;
+
+#[link(name="CoreGraphics", kind="framework")]
+extern {
+    // CG_EXTERN const CFStringRef kCGColorSpaceSRGB;
+    static kCGColorSpaceSRGB: CFStringRef;
+    // CG_EXTERN const CFStringRef kCGColorSpaceDisplayP3;
+    static kCGColorSpaceDisplayP3: CFStringRef;
+    // CG_EXTERN const CFStringRef kCGColorSpaceExtendedLinearSRGB;
+    static kCGColorSpaceExtendedLinearSRGB: CFStringRef;
+    // CG_EXTERN CGColorSpaceRef __nullable CGColorSpaceCreateWithName(CFStringRef __nullable name);
+    fn CGColorSpaceCreateWithName(name: CFStringRef) -> CGColorSpaceRef;
+    // CG_EXTERN void CGColorSpaceRelease(CGColorSpaceRef cg_nullable space);
+    fn CGColorSpaceRelease(space: CGColorSpaceRef);
+}
+
+/// How the layer should show a color pixel format:
+/// the name of the color space its values are in (`None` to leave them unmanaged, as by default),
+/// and whether it can show values brighter than white.
+/// `None` if the view can't draw to the format.
+fn layer_color_settings(pixel_format: MTLPixelFormat) -> Option<(Option<CFStringRef>, bool)> {
+    unsafe {
+        if pixel_format == MTLPixelFormatBGRA8Unorm {
+            Some((None, false))
+        } else if pixel_format == MTLPixelFormatBGRA8Unorm_sRGB {
+            Some((Some(kCGColorSpaceSRGB), false))
+        } else if pixel_format == MTLPixelFormatBGR10A2Unorm {
+            // The extra bits go on the wider gamut.
+            Some((Some(kCGColorSpaceDisplayP3), false))
+        } else if pixel_format == MTLPixelFormatRGBA16Float {
+            // Linear, with values outside 0 to 1 for colors outside sRGB and brighter than white.
+            Some((Some(kCGColorSpaceExtendedLinearSRGB), true))
+        } else {
+            None
+        }
+    }
+}
+// From System/Library/Frameworks/Metal.framework/Versions/A/Headers/MTLTexture.h
+// typedef NS_OPTIONS(NSUInteger, MTLTextureUsage) {
+//     MTLTextureUsageRenderTarget = 0x0004,
+//     ...
+// } API_AVAILABLE(macos(10.11), ios(9.0));
+static MTLTextureUsageRenderTarget: NSUInteger = 0x0004;
+
+// From System/Library/Frameworks/Metal.framework/Versions/A/Headers/MTLResource.h
+// typedef NS_ENUM(NSUInteger, MTLStorageMode) {
+//     MTLStorageModePrivate = 2,
+//     ...
+// } API_AVAILABLE(macos(10.11), ios(9.0));
+static MTLStorageModePrivate: NSUInteger = 2;
+
+// From System/Library/Frameworks/Metal.framework/Versions/A/Headers/MTLTexture.h
+// typedef NS_ENUM(NSUInteger, MTLTextureType) {
+//     MTLTextureType2D = 2,
+//     MTLTextureType2DMultisample = 4,
+//     ...
+// } API_AVAILABLE(macos(10.11), ios(8.0));
+static MTLTextureType2DMultisample: NSUInteger = 4;
 
 #[link(name="GlueLib", kind="dylib")]
 extern {
@@ -89,9 +153,37 @@ extern {
 // } API_AVAILABLE(macos(10.11), ios(8.0));
 static MTLLoadActionClear: NSUInteger = 2;
 
+// typedef NS_ENUM(NSUInteger, MTLStoreAction) {
+//     MTLStoreActionDontCare = 0,
+//     MTLStoreActionStore = 1,
+//     MTLStoreActionMultisampleResolve = 2,
+//     ...
+// } API_AVAILABLE(macos(10.11), ios(8.0));
+static MTLStoreActionDontCare: NSUInteger = 0;
+static MTLStoreActionMultisampleResolve: NSUInteger = 2;
+
+/// The depth the depth attachment is cleared to: as far away as possible.
+static DEFAULT_CLEAR_DEPTH: c_double = 1.0;
+
 pub trait MetalViewDelegate: Sized {
     fn metal_view_drawable_size_will_change(&mut self, size: CGSize);
-    fn draw_in_metal_view(&self);
+    /// Called when the view's color or depth stencil pixel format, or its sample count, changes.
+    fn metal_view_pixel_formats_did_change(&mut self) {}
+    /// Called once for each frame we are about to draw,
+    /// before any updates.
+    fn metal_view_will_begin_frame(&mut self, _frame_tick: &FrameTick) {}
+    /// Called zero or more times before each frame is drawn,
+    /// to advance the simulation by one fixed time step (in seconds).
+    fn update_in_metal_view(&mut self, _time_step: f64) {}
+    /// Called to draw a frame.
+    ///
+    /// `interpolation_alpha` is how far we are between the last
+    /// simulation update and the next one, from 0 to 1.
+    fn draw_in_metal_view(&mut self, interpolation_alpha: f64);
+    /// Called when a key is pressed while the view has focus,
+    /// with the characters it types, ignoring modifiers.
+    /// Returns whether the key was handled; if not, it goes on up the responder chain.
+    fn metal_view_key_down(&mut self, _characters: &str) -> bool { false }
 }
 
 /// The Rust portion of the class that handles the view
@@ -104,6 +196,16 @@ pub struct RSMetalView {
     current_render_pass_descriptor: id,
     current_drawable: id,
     drawable_size: CGSize,
+    frame_scheduler: FrameScheduler<SystemClock>,
+    depth_stencil_pixel_format: MTLPixelFormat,
+    clear_depth: c_double,
+    clear_stencil: u32,
+    /// Made when we next draw, to match the drawable size, sample count and depth stencil format.
+    depth_stencil_texture: id,
+    /// How many samples each pixel has; with more than one,
+    /// we draw into `multisample_color_texture` and resolve it into the drawable.
+    sample_count: NSUInteger,
+    multisample_color_texture: id,
 }
 
 impl RSMetalView {
@@ -125,6 +227,78 @@ impl RSMetalView {
             self.current_drawable = unsafe { objc_retain(current_drawable) };
         }
     }
+    /// Lets go of the textures we draw into besides the drawable,
+    /// so new ones are made for the next frame.
+    fn discard_render_targets(&mut self) {
+        if self.depth_stencil_texture != nil {
+            unsafe { objc_release(self.depth_stencil_texture) };
+            self.depth_stencil_texture = nil
+        }
+        if self.multisample_color_texture != nil {
+            unsafe { objc_release(self.multisample_color_texture) };
+            self.multisample_color_texture = nil
+        }
+    }
+    fn can_make_render_targets(&self) -> bool {
+        self.device != nil && self.drawable_size.width >= 1. && self.drawable_size.height >= 1.
+    }
+    /// The depth stencil texture for this frame, made if need be.
+    /// `nil` if the view has no depth stencil format (or device).
+    fn depth_stencil_texture(&mut self) -> id {
+        if self.depth_stencil_texture == nil
+            && self.depth_stencil_pixel_format != MTLPixelFormatInvalid
+            && self.can_make_render_targets() {
+            self.depth_stencil_texture = new_render_target_texture(
+                self.device,
+                self.depth_stencil_pixel_format,
+                self.drawable_size,
+                self.sample_count,
+                "Depth Stencil Texture",
+            );
+        }
+        self.depth_stencil_texture
+    }
+    /// The multisample texture to draw into this frame, made if need be.
+    /// `nil` if the view isn't multisampled (or has no device).
+    fn multisample_color_texture(&mut self, color_pixel_format: MTLPixelFormat) -> id {
+        if self.multisample_color_texture == nil
+            && self.sample_count > 1
+            && self.can_make_render_targets() {
+            self.multisample_color_texture = new_render_target_texture(
+                self.device,
+                color_pixel_format,
+                self.drawable_size,
+                self.sample_count,
+                "Multisample Color Texture",
+            );
+        }
+        self.multisample_color_texture
+    }
+}
+
+/// A texture for the GPU to draw into, owned by the caller.
+fn new_render_target_texture(device: id, pixel_format: MTLPixelFormat, size: CGSize, sample_count: NSUInteger, label: &str) -> id {
+    let width = size.width as NSUInteger;
+    let height = size.height as NSUInteger;
+    unsafe {
+        let texture_descriptor: id = msg_send![class!(MTLTextureDescriptor),
+                                               texture2DDescriptorWithPixelFormat:pixel_format
+                                                                            width:width
+                                                                           height:height
+                                                                        mipmapped:NO];
+        if sample_count > 1 {
+            let _:() = msg_send![texture_descriptor, setTextureType:MTLTextureType2DMultisample];
+            let _:() = msg_send![texture_descriptor, setSampleCount:sample_count];
+        }
+        // Only the GPU uses it, and only while drawing.
+        let _:() = msg_send![texture_descriptor, setUsage:MTLTextureUsageRenderTarget];
+        let _:() = msg_send![texture_descriptor, setStorageMode:MTLStorageModePrivate];
+        let texture: id = msg_send![device, newTextureWithDescriptor:texture_descriptor];
+        let label = NSString::alloc(nil).init_str(label);
+        let _:() = msg_send![texture, setLabel:label];
+        let _:() = msg_send![label, release];
+        texture
+    }
 }
 
 pub fn register_metal_view_class() {
@@ -165,6 +339,10 @@ pub fn register_metal_view_class() {
             sel!(colorPixelFormat),
             get_color_pixel_format as extern "C" fn(&Object, Sel) -> MTLPixelFormat,
         );
+        metal_view_declaration.add_method(
+            sel!(setColorPixelFormat:),
+            set_color_pixel_format_ as extern "C" fn(&mut Object, Sel, MTLPixelFormat),
+        );
         metal_view_declaration.add_method(
             sel!(drawableSize),
             get_drawable_size as extern "C" fn(&Object, Sel) -> CGSize,
@@ -173,22 +351,102 @@ pub fn register_metal_view_class() {
             sel!(setDrawableSize:),
             set_drawable_size_ as extern "C" fn(&mut Object, Sel, CGSize),
         );
+        metal_view_declaration.add_method(
+            sel!(framebufferOnly),
+            get_framebuffer_only as extern "C" fn(&Object, Sel) -> BOOL,
+        );
+        metal_view_declaration.add_method(
+            sel!(setFramebufferOnly:),
+            set_framebuffer_only_ as extern "C" fn(&mut Object, Sel, BOOL),
+        );
         metal_view_declaration.add_method(
             sel!(makeBackingLayer),
             make_backing_layer as extern "C" fn(&Object, Sel) -> id,
         );
         metal_view_declaration.add_method(
             sel!(currentRenderPassDescriptor),
-            get_current_render_pass_descriptor as extern "C" fn(&Object, Sel) -> id,
+            get_current_render_pass_descriptor as extern "C" fn(&mut Object, Sel) -> id,
         );
         metal_view_declaration.add_method(
             sel!(currentDrawable),
             get_current_drawable as extern "C" fn(&Object, Sel) -> id,
         );
+        metal_view_declaration.add_method(
+            sel!(clearColor),
+            get_clear_color as extern "C" fn(&Object, Sel) -> MTLClearColor,
+        );
         metal_view_declaration.add_method(
             sel!(setClearColor:),
             set_clear_color as extern "C" fn(&mut Object, Sel, MTLClearColor),
         );
+        metal_view_declaration.add_method(
+            sel!(preferredFramesPerSecond),
+            get_preferred_frames_per_second as extern "C" fn(&Object, Sel) -> NSInteger,
+        );
+        metal_view_declaration.add_method(
+            sel!(setPreferredFramesPerSecond:),
+            set_preferred_frames_per_second_ as extern "C" fn(&mut Object, Sel, NSInteger),
+        );
+        metal_view_declaration.add_method(
+            sel!(fixedUpdatesPerSecond),
+            get_fixed_updates_per_second as extern "C" fn(&Object, Sel) -> c_double,
+        );
+        metal_view_declaration.add_method(
+            sel!(setFixedUpdatesPerSecond:),
+            set_fixed_updates_per_second_ as extern "C" fn(&mut Object, Sel, c_double),
+        );
+        metal_view_declaration.add_method(
+            sel!(depthStencilPixelFormat),
+            get_depth_stencil_pixel_format as extern "C" fn(&Object, Sel) -> MTLPixelFormat,
+        );
+        metal_view_declaration.add_method(
+            sel!(setDepthStencilPixelFormat:),
+            set_depth_stencil_pixel_format_ as extern "C" fn(&mut Object, Sel, MTLPixelFormat),
+        );
+        metal_view_declaration.add_method(
+            sel!(clearDepth),
+            get_clear_depth as extern "C" fn(&Object, Sel) -> c_double,
+        );
+        metal_view_declaration.add_method(
+            sel!(setClearDepth:),
+            set_clear_depth_ as extern "C" fn(&mut Object, Sel, c_double),
+        );
+        metal_view_declaration.add_method(
+            sel!(clearStencil),
+            get_clear_stencil as extern "C" fn(&Object, Sel) -> u32,
+        );
+        metal_view_declaration.add_method(
+            sel!(setClearStencil:),
+            set_clear_stencil_ as extern "C" fn(&mut Object, Sel, u32),
+        );
+        metal_view_declaration.add_method(
+            sel!(sampleCount),
+            get_sample_count as extern "C" fn(&Object, Sel) -> NSUInteger,
+        );
+        metal_view_declaration.add_method(
+            sel!(setSampleCount:),
+            set_sample_count_ as extern "C" fn(&mut Object, Sel, NSUInteger),
+        );
+        metal_view_declaration.add_method(
+            sel!(multisampleColorTexture),
+            get_multisample_color_texture as extern "C" fn(&mut Object, Sel) -> id,
+        );
+        metal_view_declaration.add_method(
+            sel!(depthStencilTexture),
+            get_depth_stencil_texture as extern "C" fn(&mut Object, Sel) -> id,
+        );
+        metal_view_declaration.add_method(
+            sel!(acceptsFirstResponder),
+            accepts_first_responder as extern "C" fn(&Object, Sel) -> BOOL,
+        );
+        metal_view_declaration.add_method(
+            sel!(viewDidMoveToWindow),
+            view_did_move_to_window as extern "C" fn(&mut Object, Sel),
+        );
+        metal_view_declaration.add_method(
+            sel!(keyDown:),
+            key_down_ as extern "C" fn(&mut Object, Sel, id),
+        );
     }
     metal_view_declaration.register();
 }
@@ -224,6 +482,9 @@ extern "C" fn init_with_coder_(_self: &Object, _sel: Sel, _coder: id) -> id {
 
         let _:() = unsafe { msg_send![_self, setWantsLayer:true] };
 
+        let mut frame_scheduler = FrameScheduler::new(SystemClock::new());
+        frame_scheduler.pacer.set_refresh_rate(timer.nominal_refresh_rate().unwrap_or(DEFAULT_REFRESH_RATE));
+
         let _rust_metal_view = Box::new( RSMetalView {
             timer,
             clear_color,
@@ -233,6 +494,14 @@ extern "C" fn init_with_coder_(_self: &Object, _sel: Sel, _coder: id) -> id {
             current_render_pass_descriptor: nil,
             current_drawable: nil,
             drawable_size,
+            frame_scheduler,
+            // Like MTKView, no depth or stencil unless asked for.
+            depth_stencil_pixel_format: MTLPixelFormatInvalid,
+            clear_depth: DEFAULT_CLEAR_DEPTH,
+            clear_stencil: 0,
+            depth_stencil_texture: nil,
+            sample_count: 1,
+            multisample_color_texture: nil,
         });
         let _raw_ptr = Box::into_raw(_rust_metal_view) as *mut c_void;
         //let _:() = unsafe { msg_send![_self, setRustMetalView:_raw_ptr] };
@@ -246,7 +515,10 @@ extern "C" fn get_device(_self: &Object, _sel: Sel) -> id {
     get_rust_metal_view(_self).device
 }
 extern "C" fn set_device_(_self: &mut Object, _sel: Sel, new_device: id) {
-    get_mut_rust_metal_view(_self).device = new_device;
+    let rust_metal_view = get_mut_rust_metal_view(_self);
+    rust_metal_view.discard_render_targets();
+    rust_metal_view.device = new_device;
+    rust_metal_view.sample_count = supported_sample_count(new_device, rust_metal_view.sample_count);
     if new_device != nil {
         let metal_layer: id = unsafe { msg_send![_self, layer] };
         if metal_layer != nil {
@@ -279,6 +551,7 @@ extern "C" fn set_delegate_(_self: &mut Object, _sel: Sel, new_delegate: *mut c_
     };
 
     if new_renderer.is_some() {
+        rust_metal_view.frame_scheduler.reset();
         rust_metal_view.timer.start()
     } else {
         rust_metal_view.timer.stop()
@@ -310,6 +583,36 @@ extern "C" fn get_color_pixel_format(_self: &Object, _sel: Sel) -> MTLPixelForma
     }
 }
 
+extern "C" fn set_color_pixel_format_(_self: &mut Object, _sel: Sel, new_value: MTLPixelFormat) {
+    let (color_space_name, wants_extended_dynamic_range) = match layer_color_settings(new_value) {
+        Some(settings) => settings,
+        None => {
+            println!("MetalView can't draw to pixel format {}, so keeping the current one", new_value);
+            return;
+        }
+    };
+    let old_value = get_color_pixel_format(_self, _sel);
+    if let Some(metal_layer) = get_metal_layer(_self) {
+        let wants_extended_dynamic_range: BOOL = if wants_extended_dynamic_range { YES } else { NO };
+        unsafe {
+            let color_space = match color_space_name {
+                Some(color_space_name) => CGColorSpaceCreateWithName(color_space_name),
+                None => std::ptr::null_mut(),
+            };
+            let _:() = msg_send![metal_layer, setPixelFormat:new_value];
+            let _:() = msg_send![metal_layer, setColorspace:color_space];
+            let _:() = msg_send![metal_layer, setWantsExtendedDynamicRangeContent:wants_extended_dynamic_range];
+            if !color_space.is_null() {
+                CGColorSpaceRelease(color_space);
+            }
+        }
+    }
+    if new_value != old_value {
+        get_mut_rust_metal_view(_self).discard_render_targets();
+        pixel_formats_did_change(_self);
+    }
+}
+
 extern "C" fn get_drawable_size(_self: &Object, _sel: Sel) -> CGSize {
     get_rust_metal_view(_self).drawable_size
 }
@@ -326,6 +629,9 @@ extern "C" fn set_drawable_size_(_self: &mut Object, _sel: Sel, new_drawable_siz
         //     let _:() = unsafe { msg_send![layer, setDrawableSize:new_drawable_size] };
         // }
 
+        // The depth stencil and multisample textures have to be the same size as the drawable.
+        get_mut_rust_metal_view(_self).discard_render_targets();
+
         if let Some(delegate) = get_mut_rust_metal_view(_self).delegate.as_mut() {
             delegate.metal_view_drawable_size_will_change(new_drawable_size);
         }
@@ -333,17 +639,174 @@ extern "C" fn set_drawable_size_(_self: &mut Object, _sel: Sel, new_drawable_siz
 
     get_mut_rust_metal_view(_self).drawable_size = new_drawable_size
 }
-extern "C" fn get_current_render_pass_descriptor(_self: &Object, _sel: Sel) -> id {
+
+// Drawables can only be drawn into, not read from or copied, unless the layer's told otherwise.
+extern "C" fn get_framebuffer_only(_self: &Object, _sel: Sel) -> BOOL {
+    match get_metal_layer(_self) {
+        Some(metal_layer) => unsafe { msg_send![metal_layer, framebufferOnly] },
+        None => YES,
+    }
+}
+extern "C" fn set_framebuffer_only_(_self: &mut Object, _sel: Sel, new_value: BOOL) {
+    if let Some(metal_layer) = get_metal_layer(_self) {
+        let _:() = unsafe { msg_send![metal_layer, setFramebufferOnly:new_value] };
+    }
+}
+
+// The renderer describes its own passes, so we only make this if someone asks for it.
+extern "C" fn get_current_render_pass_descriptor(_self: &mut Object, _sel: Sel) -> id {
+    if get_rust_metal_view(_self).current_render_pass_descriptor == nil {
+        let render_descriptor: id = unsafe { msg_send![class!(MTLRenderPassDescriptor), renderPassDescriptor] };
+        set_up_render_pass_descriptor(_self, render_descriptor);
+        get_mut_rust_metal_view(_self).set_current_render_pass_descriptor(render_descriptor);
+    }
     get_rust_metal_view(_self).current_render_pass_descriptor
 }
 extern "C" fn get_current_drawable(_self: &Object, _sel: Sel) -> id {
     get_rust_metal_view(_self).current_drawable
 }
 
+extern "C" fn get_clear_color(_self: &Object, _sel: Sel) -> MTLClearColor {
+    get_rust_metal_view(_self).clear_color
+}
 extern "C" fn set_clear_color(_self: &mut Object, _sel: Sel, _color: MTLClearColor) {
     get_mut_rust_metal_view(_self).clear_color = _color
 }
 
+extern "C" fn get_preferred_frames_per_second(_self: &Object, _sel: Sel) -> NSInteger {
+    get_rust_metal_view(_self).frame_scheduler.pacer.preferred_frames_per_second() as NSInteger
+}
+extern "C" fn set_preferred_frames_per_second_(_self: &mut Object, _sel: Sel, new_value: NSInteger) {
+    let preferred_frames_per_second = if new_value > 0 { new_value as u32 } else { 0 };
+    get_mut_rust_metal_view(_self).frame_scheduler.pacer.set_preferred_frames_per_second(preferred_frames_per_second)
+}
+
+extern "C" fn get_fixed_updates_per_second(_self: &Object, _sel: Sel) -> c_double {
+    get_rust_metal_view(_self).frame_scheduler.timestep.updates_per_second()
+}
+extern "C" fn set_fixed_updates_per_second_(_self: &mut Object, _sel: Sel, new_value: c_double) {
+    get_mut_rust_metal_view(_self).frame_scheduler.timestep.set_updates_per_second(new_value)
+}
+
+extern "C" fn get_depth_stencil_pixel_format(_self: &Object, _sel: Sel) -> MTLPixelFormat {
+    get_rust_metal_view(_self).depth_stencil_pixel_format
+}
+extern "C" fn set_depth_stencil_pixel_format_(_self: &mut Object, _sel: Sel, new_value: MTLPixelFormat) {
+    let rust_metal_view = get_mut_rust_metal_view(_self);
+    if rust_metal_view.depth_stencil_pixel_format != new_value {
+        rust_metal_view.discard_render_targets();
+        rust_metal_view.depth_stencil_pixel_format = new_value;
+        pixel_formats_did_change(_self);
+    }
+}
+
+extern "C" fn get_clear_depth(_self: &Object, _sel: Sel) -> c_double {
+    get_rust_metal_view(_self).clear_depth
+}
+extern "C" fn set_clear_depth_(_self: &mut Object, _sel: Sel, new_value: c_double) {
+    get_mut_rust_metal_view(_self).clear_depth = new_value
+}
+
+extern "C" fn get_clear_stencil(_self: &Object, _sel: Sel) -> u32 {
+    get_rust_metal_view(_self).clear_stencil
+}
+extern "C" fn set_clear_stencil_(_self: &mut Object, _sel: Sel, new_value: u32) {
+    get_mut_rust_metal_view(_self).clear_stencil = new_value
+}
+
+extern "C" fn get_depth_stencil_texture(_self: &mut Object, _sel: Sel) -> id {
+    get_mut_rust_metal_view(_self).depth_stencil_texture()
+}
+
+extern "C" fn get_sample_count(_self: &Object, _sel: Sel) -> NSUInteger {
+    get_rust_metal_view(_self).sample_count
+}
+extern "C" fn set_sample_count_(_self: &mut Object, _sel: Sel, new_value: NSUInteger) {
+    let rust_metal_view = get_mut_rust_metal_view(_self);
+    let new_value = supported_sample_count(rust_metal_view.device, new_value.max(1));
+    if rust_metal_view.sample_count != new_value {
+        rust_metal_view.discard_render_targets();
+        rust_metal_view.sample_count = new_value;
+        pixel_formats_did_change(_self);
+    }
+}
+/// The most samples per pixel, up to `sample_count`, the device can draw with.
+/// Any count will do until the view has a device to ask.
+fn supported_sample_count(device: id, sample_count: NSUInteger) -> NSUInteger {
+    if device == nil {
+        return sample_count;
+    }
+    let supported = (1..=sample_count).rev()
+        .find(|&count| {
+            let supported: BOOL = unsafe { msg_send![device, supportsTextureSampleCount:count] };
+            supported != NO
+        })
+        .unwrap_or(1);
+    if supported != sample_count {
+        println!("This device can't draw with {} samples per pixel, so drawing with {}", sample_count, supported);
+    }
+    supported
+}
+extern "C" fn get_multisample_color_texture(_self: &mut Object, _sel: Sel) -> id {
+    let color_pixel_format: MTLPixelFormat = unsafe { msg_send![_self, colorPixelFormat] };
+    get_mut_rust_metal_view(_self).multisample_color_texture(color_pixel_format)
+}
+
+// We take key presses, so the delegate can offer keyboard shortcuts.
+extern "C" fn accepts_first_responder(_self: &Object, _sel: Sel) -> BOOL {
+    YES
+}
+extern "C" fn view_did_move_to_window(_self: &mut Object, _sel: Sel) {
+    let window: id = unsafe { msg_send![_self, window] };
+    if window != nil {
+        let view: id = _self;
+        let _: BOOL = unsafe { msg_send![window, makeFirstResponder:view] };
+    }
+}
+extern "C" fn key_down_(_self: &mut Object, _sel: Sel, event: id) {
+    let characters: id = unsafe { msg_send![event, charactersIgnoringModifiers] };
+    let characters = string_from_ns_string(characters);
+    let handled = match get_mut_rust_metal_view(_self).delegate.as_mut() {
+        Some(delegate) => delegate.metal_view_key_down(&characters),
+        None => false,
+    };
+    if !handled {
+        let ns_view_class = class!(NSView);
+        let _:() = unsafe { msg_send![super(_self, ns_view_class), keyDown:event] };
+    }
+}
+
+// From System/Library/Frameworks/AppKit.framework/Versions/C/Headers/NSScreen.h:
+// The device description of a screen has its CGDirectDisplayID under @"NSScreenNumber".
+/// The ID of the display the view's window is on, or `None` if it isn't on a screen.
+pub fn display_id(view: id) -> Option<u32> {
+    unsafe {
+        let window: id = msg_send![view, window];
+        if window == nil {
+            return None;
+        }
+        let screen: id = msg_send![window, screen];
+        if screen == nil {
+            return None;
+        }
+        let device_description: id = msg_send![screen, deviceDescription];
+        let key = NSString::alloc(nil).init_str("NSScreenNumber");
+        let screen_number: id = msg_send![device_description, objectForKey:key];
+        let _:() = msg_send![key, release];
+        if screen_number == nil {
+            return None;
+        }
+        let display_id: u32 = msg_send![screen_number, unsignedIntValue];
+        Some(display_id)
+    }
+}
+
+/// Tells the delegate, so it can draw with pipelines that match.
+fn pixel_formats_did_change(_self: &mut Object) {
+    if let Some(delegate) = get_mut_rust_metal_view(_self).delegate.as_mut() {
+        delegate.metal_view_pixel_formats_did_change();
+    }
+}
 
 fn get_metal_layer(_self: &Object) -> Option<&Object> {
     let metal_layer:id = unsafe { msg_send![_self, layer] };
@@ -365,6 +828,12 @@ fn get_mut_rust_metal_view(_self: &mut Object) -> &mut RSMetalView {
 }
 
 fn timer_callback(_self: &mut Object) {
+    // Coalesce vsyncs down to our preferred frame rate.
+    let frame_tick = match get_mut_rust_metal_view(_self).frame_scheduler.on_vsync() {
+        Some(frame_tick) => frame_tick,
+        None => return,
+    };
+
     let frame: NSRect = unsafe { msg_send![_self, frame] };
     let _size = frame.size;
     let backing_size:NSSize = unsafe { msg_send![_self, convertSizeToBacking:_size] };
@@ -373,17 +842,18 @@ fn timer_callback(_self: &mut Object) {
 
     set_up_delegate_drawing_state(_self);
 
-    if let Some(renderer) = get_rust_metal_view(_self).delegate.as_ref() {
-        renderer.draw_in_metal_view()
+    if let Some(renderer) = get_mut_rust_metal_view(_self).delegate.as_mut() {
+        renderer.metal_view_will_begin_frame(&frame_tick);
+        for _ in 0..frame_tick.update_steps {
+            renderer.update_in_metal_view(frame_tick.time_step);
+        }
+        renderer.draw_in_metal_view(frame_tick.interpolation_alpha)
     }
 }
 
 fn set_up_delegate_drawing_state(_self: &mut Object) {
-    // Set up our render pass descriptor
-    let render_descriptor_class = class!(MTLRenderPassDescriptor);
-    let render_descriptor: id = unsafe { msg_send![render_descriptor_class, alloc] };
-    let render_descriptor: id = unsafe { msg_send![render_descriptor, init] };
-    get_mut_rust_metal_view(_self).set_current_render_pass_descriptor(render_descriptor);
+    // Last frame's render pass descriptor draws into last frame's drawable.
+    get_mut_rust_metal_view(_self).set_current_render_pass_descriptor(nil);
 
     // Set up our drawable
     if let Some(metal_layer) = get_metal_layer(_self) {
@@ -391,7 +861,10 @@ fn set_up_delegate_drawing_state(_self: &mut Object) {
 
         get_mut_rust_metal_view(_self).set_current_drawable(current_drawable);
     }
+}
 
+/// Points a render pass descriptor at this frame's drawable, multisample and depth stencil textures.
+fn set_up_render_pass_descriptor(_self: &mut Object, render_descriptor: id) {
     let current_drawable = get_rust_metal_view(_self).current_drawable;
     if let Some(current_render_pass_descriptor) = unsafe { render_descriptor.as_mut() } {
         unsafe {
@@ -403,7 +876,15 @@ fn set_up_delegate_drawing_state(_self: &mut Object) {
             if color_attachment_0 != nil {
                 if current_drawable != nil {
                     let _texture: id = msg_send![current_drawable, texture];
-                    let _:() = msg_send![color_attachment_0, setTexture:_texture];
+                    let multisample_color_texture: id = msg_send![_self, multisampleColorTexture];
+                    if multisample_color_texture != nil {
+                        // Draw into the multisample texture, then average each pixel's samples into the drawable.
+                        let _:() = msg_send![color_attachment_0, setTexture:multisample_color_texture];
+                        let _:() = msg_send![color_attachment_0, setResolveTexture:_texture];
+                        let _:() = msg_send![color_attachment_0, setStoreAction:MTLStoreActionMultisampleResolve];
+                    } else {
+                        let _:() = msg_send![color_attachment_0, setTexture:_texture];
+                    }
                 }
                 let _:() = msg_send![color_attachment_0, setLoadAction:MTLLoadActionClear];
 
@@ -413,5 +894,36 @@ fn set_up_delegate_drawing_state(_self: &mut Object) {
             let _:() = msg_send![_render_pass_color_attachment_descriptor_array,
                                  setObject:color_attachment_0 atIndexedSubscript:0];
         }
+        set_up_depth_stencil_attachments(_self, current_render_pass_descriptor);
+    }
+}
+
+/// Attaches the depth stencil texture to the render pass, cleared each frame.
+///
+/// We don't keep the contents after the pass, so the GPU needn't store them.
+fn set_up_depth_stencil_attachments(_self: &mut Object, render_pass_descriptor: id) {
+    let rust_metal_view = get_mut_rust_metal_view(_self);
+    let depth_stencil_texture = rust_metal_view.depth_stencil_texture();
+    if depth_stencil_texture == nil {
+        return;
+    }
+    let pixel_format = rust_metal_view.depth_stencil_pixel_format;
+    let clear_depth = rust_metal_view.clear_depth;
+    let clear_stencil = rust_metal_view.clear_stencil;
+    unsafe {
+        if pixel_format_has_depth(pixel_format) {
+            let depth_attachment: id = msg_send![render_pass_descriptor, depthAttachment];
+            let _:() = msg_send![depth_attachment, setTexture:depth_stencil_texture];
+            let _:() = msg_send![depth_attachment, setLoadAction:MTLLoadActionClear];
+            let _:() = msg_send![depth_attachment, setStoreAction:MTLStoreActionDontCare];
+            let _:() = msg_send![depth_attachment, setClearDepth:clear_depth];
+        }
+        if pixel_format_has_stencil(pixel_format) {
+            let stencil_attachment: id = msg_send![render_pass_descriptor, stencilAttachment];
+            let _:() = msg_send![stencil_attachment, setTexture:depth_stencil_texture];
+            let _:() = msg_send![stencil_attachment, setLoadAction:MTLLoadActionClear];
+            let _:() = msg_send![stencil_attachment, setStoreAction:MTLStoreActionDontCare];
+            let _:() = msg_send![stencil_attachment, setClearStencil:clear_stencil];
+        }
     }
 }
@@ -0,0 +1,88 @@
+//! Rust errors made from Cocoa's `NSError`s
+
+use objc::msg_send;
+use objc::sel;
+use objc::sel_impl;
+use cocoa::base::{id, nil};
+use cocoa::foundation::NSInteger;
+use std::ffi::CStr;
+use std::fmt::{Display, Formatter};
+use std::error::Error;
+use std::os::raw::c_char;
+
+#[link(name="Foundation", kind="framework")]
+extern {
+    // From System/Library/Frameworks/Foundation.framework/Versions/C/Headers/NSError.h:
+    // FOUNDATION_EXPORT NSErrorUserInfoKey const NSUnderlyingErrorKey;
+    static NSUnderlyingErrorKey: id;
+}
+
+/// What an `NSError` had to say, copied out so it can outlive the autorelease pool.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NSErrorDetails {
+    pub domain: String,
+    pub code: NSInteger,
+    pub localized_description: String,
+    /// The error that caused this one, if the `NSError` said.
+    pub underlying: Option<Box<NSErrorDetails>>,
+}
+
+impl NSErrorDetails {
+    /// Copies the details out of an `NSError`, or returns `None` if there isn't one.
+    pub fn from_ns_error(error: id) -> Option<Self> {
+        if error == nil {
+            return None;
+        }
+        unsafe {
+            let domain: id = msg_send![error, domain];
+            let code: NSInteger = msg_send![error, code];
+            let localized_description: id = msg_send![error, localizedDescription];
+            let user_info: id = msg_send![error, userInfo];
+            let underlying: id = if user_info == nil {
+                nil
+            } else {
+                msg_send![user_info, objectForKey:NSUnderlyingErrorKey]
+            };
+            Some(NSErrorDetails {
+                domain: string_from_ns_string(domain),
+                code,
+                localized_description: string_from_ns_string(localized_description),
+                underlying: Self::from_ns_error(underlying).map(Box::new),
+            })
+        }
+    }
+
+    /// Copies the details out of an `NSError`,
+    /// standing in for one when an API failed without giving a reason.
+    pub fn from_ns_error_or_unknown(error: id) -> Self {
+        Self::from_ns_error(error).unwrap_or_else(|| NSErrorDetails {
+            domain: "unknown".to_string(),
+            code: 0,
+            localized_description: "no reason was given".to_string(),
+            underlying: None,
+        })
+    }
+}
+
+impl Display for NSErrorDetails {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({} error {})", self.localized_description, self.domain, self.code)
+    }
+}
+
+impl Error for NSErrorDetails {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.underlying.as_ref().map(|underlying| underlying.as_ref() as &(dyn Error + 'static))
+    }
+}
+
+/// Copies an `NSString` into a Rust `String`.
+pub fn string_from_ns_string(string: id) -> String {
+    if string == nil {
+        return String::new();
+    }
+    unsafe {
+        let utf8: *const c_char = msg_send![string, UTF8String];
+        CStr::from_ptr(utf8).to_string_lossy().into_owned()
+    }
+}
@@ -0,0 +1,315 @@
+//! A particle system, simulated on the GPU
+//!
+//! Particles fly out of an emitter, fall under gravity, bounce off the edges of the view,
+//! and are replaced by new ones once they've lived out their lifetime.
+//! The `updateParticles` kernel moves them all on by one fixed time step at a time,
+//! and `update_particles` does exactly the same on the CPU, so the simulation can be checked without a GPU.
+//! The two agree to within rounding: the GPU's trigonometry is a little less exact.
+//!
+//! Random numbers come from hashing the particle's index and a seed that changes every step,
+//! so they're the same on both.
+
+use crate::shader_types::{AAPLParticle, AAPLParticleUniforms};
+
+/// Where particles come from, how they move and how they look.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParticleSettings {
+    pub count: usize,
+    /// Pixels from the centre of the view.
+    pub emitter_position: [f32; 2],
+    /// Which way particles head off on average, in radians anticlockwise from the x axis.
+    pub emitter_angle: f32,
+    /// How wide the range of directions is, in radians.
+    pub emitter_spread: f32,
+    /// The slowest and fastest particles, in pixels per second.
+    pub speed_range: [f32; 2],
+    /// The shortest and longest lives, in seconds.
+    pub lifetime_range: [f32; 2],
+    /// In pixels per second per second.
+    pub gravity: [f32; 2],
+    /// How much of its speed a particle keeps when it bounces.
+    pub restitution: f32,
+    /// Each particle's color is somewhere between these two.
+    pub colors: [[f32; 4]; 2],
+    /// How many pixels across each particle is drawn.
+    pub point_size: f32,
+}
+
+impl Default for ParticleSettings {
+    /// A fountain of sparks from the middle of the view.
+    fn default() -> Self {
+        ParticleSettings {
+            count: 10_000,
+            emitter_position: [0., 0.],
+            emitter_angle: std::f32::consts::FRAC_PI_2,
+            emitter_spread: 0.6,
+            speed_range: [200., 450.],
+            lifetime_range: [2., 4.],
+            gravity: [0., -300.],
+            restitution: 0.5,
+            colors: [[1., 0.4, 0.1, 1.], [1., 0.9, 0.3, 1.]],
+            point_size: 4.,
+        }
+    }
+}
+
+impl ParticleSettings {
+    /// The particles HELLO_TRIANGLE_PARTICLES asks for, if any.
+    pub fn from_environment() -> Option<Self> {
+        let count = std::env::var("HELLO_TRIANGLE_PARTICLES").ok()?;
+        match count.parse() {
+            Ok(count) => Some(ParticleSettings { count, ..ParticleSettings::default() }),
+            Err(_) => {
+                println!("HELLO_TRIANGLE_PARTICLES should be a number of particles, not {:?}", count);
+                None
+            }
+        }
+    }
+
+    /// The particles before the first step.
+    ///
+    /// None of them have been born yet, and they're due one after another
+    /// over the longest lifetime, so they don't all come out at once.
+    pub fn initial_particles(&self) -> Vec<AAPLParticle> {
+        (0..self.count)
+            .map(|index| AAPLParticle {
+                color: [0., 0., 0., 0.],
+                position: self.emitter_position,
+                velocity: [0., 0.],
+                age: -(index as f32 / self.count as f32) * self.lifetime_range[1],
+                lifetime: 0.,
+                _padding: [0.; 2],
+            })
+            .collect()
+    }
+
+    /// The uniforms for one step of `time_step` seconds in a view of the given size, in pixels.
+    pub fn uniforms(&self, time_step: f32, viewport_size: [f32; 2], seed: u32) -> AAPLParticleUniforms {
+        AAPLParticleUniforms {
+            start_color: self.colors[0],
+            end_color: self.colors[1],
+            emitter_position: self.emitter_position,
+            gravity: self.gravity,
+            bounds: [viewport_size[0] / 2., viewport_size[1] / 2.],
+            emitter_angle: self.emitter_angle,
+            emitter_spread: self.emitter_spread,
+            min_speed: self.speed_range[0],
+            max_speed: self.speed_range[1],
+            min_lifetime: self.lifetime_range[0],
+            max_lifetime: self.lifetime_range[1],
+            restitution: self.restitution,
+            time_step,
+            point_size: self.point_size,
+            particle_count: self.count as u32,
+            seed,
+            _padding: [0; 3],
+        }
+    }
+}
+
+/// Particle settings, and how many steps they've been simulated for,
+/// so each step's new particles are different from the last's.
+pub struct ParticleSystem {
+    pub settings: ParticleSettings,
+    step: u32,
+}
+
+impl ParticleSystem {
+    pub fn new(settings: ParticleSettings) -> Self {
+        ParticleSystem { settings, step: 0 }
+    }
+
+    /// The uniforms for the next step.
+    pub fn next_uniforms(&mut self, time_step: f32, viewport_size: [f32; 2]) -> AAPLParticleUniforms {
+        let uniforms = self.settings.uniforms(time_step, viewport_size, self.step);
+        self.step = self.step.wrapping_add(1);
+        uniforms
+    }
+}
+
+/// Moves each particle on by one time step, as `updateParticles` does.
+pub fn update_particles(particles: &mut [AAPLParticle], uniforms: &AAPLParticleUniforms) {
+    let count = particles.len().min(uniforms.particle_count as usize);
+    for (index, particle) in particles[..count].iter_mut().enumerate() {
+        *particle = update_particle(*particle, index as u32, uniforms);
+    }
+}
+
+/// What `updateParticles` does to one particle.
+pub fn update_particle(mut particle: AAPLParticle, index: u32, uniforms: &AAPLParticleUniforms) -> AAPLParticle {
+    let time_step = uniforms.time_step;
+    particle.age += time_step;
+    if particle.age >= particle.lifetime {
+        return spawn_particle(index, uniforms);
+    }
+    if particle.lifetime > 0. {
+        for axis in 0..2 {
+            particle.velocity[axis] += uniforms.gravity[axis] * time_step;
+            particle.position[axis] += particle.velocity[axis] * time_step;
+            // Bounce off the edges of the view, losing some speed.
+            if particle.position[axis].abs() > uniforms.bounds[axis] {
+                particle.position[axis] = uniforms.bounds[axis].copysign(particle.position[axis]);
+                particle.velocity[axis] = -particle.velocity[axis] * uniforms.restitution;
+            }
+        }
+    }
+    particle
+}
+
+/// A new particle at the emitter, heading off at a random angle and speed, as `spawnParticle` makes them.
+pub fn spawn_particle(index: u32, uniforms: &AAPLParticleUniforms) -> AAPLParticle {
+    let random = |stream| random_unit(index, uniforms.seed, stream);
+    let angle = uniforms.emitter_angle + (random(0) - 0.5) * uniforms.emitter_spread;
+    let speed = mix(uniforms.min_speed, uniforms.max_speed, random(1));
+    let color_mix = random(2);
+    let mut color = [0.; 4];
+    for (channel, (&start, &end)) in color.iter_mut().zip(uniforms.start_color.iter().zip(&uniforms.end_color)) {
+        *channel = mix(start, end, color_mix);
+    }
+    AAPLParticle {
+        color,
+        position: uniforms.emitter_position,
+        velocity: [speed * angle.cos(), speed * angle.sin()],
+        age: 0.,
+        lifetime: mix(uniforms.min_lifetime, uniforms.max_lifetime, random(3)),
+        _padding: [0.; 2],
+    }
+}
+
+/// The color `particleVertexShader` draws a particle in: its own, fading out as it gets older.
+pub fn particle_color(particle: &AAPLParticle) -> [f32; 4] {
+    // Particles that haven't been born yet have no lifetime, and are drawn fully faded.
+    let life = if particle.lifetime > 0. { (particle.age / particle.lifetime).clamp(0., 1.) } else { 1. };
+    let [red, green, blue, alpha] = particle.color;
+    [red, green, blue, alpha * (1. - life)]
+}
+
+/// A random number from 0 up to 1, different for each particle, seed and stream, as `randomUnit` makes them.
+pub fn random_unit(index: u32, seed: u32, stream: u32) -> f32 {
+    let hashed = hash(hash(seed) ^ index.wrapping_mul(4).wrapping_add(stream));
+    (hashed >> 8) as f32 / 16_777_216.
+}
+
+/// What `hashParticle` does: mixes the bits of a number thoroughly.
+fn hash(mut x: u32) -> u32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb_352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846c_a68b);
+    x ^= x >> 16;
+    x
+}
+
+/// Metal's `mix`.
+fn mix(from: f32, to: f32, amount: f32) -> f32 {
+    from + (to - from) * amount
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A particle that's been alive for a while, with a long life ahead of it.
+    fn flying(position: [f32; 2], velocity: [f32; 2]) -> AAPLParticle {
+        AAPLParticle { color: [1.; 4], position, velocity, age: 1., lifetime: 10., _padding: [0.; 2] }
+    }
+
+    fn assert_near(actual: [f32; 2], expected: [f32; 2]) {
+        for axis in 0..2 {
+            assert!((actual[axis] - expected[axis]).abs() < 1e-4, "{:?} isn't {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn particles_are_born_one_after_another() {
+        let settings = ParticleSettings { count: 4, lifetime_range: [2., 4.], ..ParticleSettings::default() };
+        let mut particles = settings.initial_particles();
+        let ages: Vec<f32> = particles.iter().map(|particle| particle.age).collect();
+        assert_eq!(ages, [0., -1., -2., -3.]);
+        assert!(particles.iter().all(|particle| particle_color(particle)[3] == 0.));
+
+        let mut system = ParticleSystem::new(settings);
+        let mut born_after_each_step = Vec::new();
+        for _ in 0..6 {
+            let uniforms = system.next_uniforms(0.5, [800., 600.]);
+            update_particles(&mut particles, &uniforms);
+            born_after_each_step.push(particles.iter().filter(|particle| particle.lifetime > 0.).count());
+            // The ones still waiting stay where they are.
+            for particle in particles.iter().filter(|particle| particle.lifetime == 0.) {
+                assert_eq!((particle.position, particle.velocity), ([0., 0.], [0., 0.]));
+            }
+        }
+        assert_eq!(born_after_each_step, [1, 2, 2, 3, 3, 4]);
+    }
+
+    #[test]
+    fn new_particles_come_out_of_the_emitter_within_the_settings() {
+        let settings = ParticleSettings { emitter_position: [10., -20.], ..ParticleSettings::default() };
+        for seed in 0..20 {
+            let uniforms = settings.uniforms(1. / 60., [800., 600.], seed);
+            let particle = spawn_particle(seed * 7, &uniforms);
+            assert_eq!((particle.position, particle.age), ([10., -20.], 0.));
+            assert!((2. ..=4.).contains(&particle.lifetime));
+            let speed = (particle.velocity[0].powi(2) + particle.velocity[1].powi(2)).sqrt();
+            assert!((200. - 1e-3..=450. + 1e-3).contains(&speed), "speed {}", speed);
+            let angle = particle.velocity[1].atan2(particle.velocity[0]);
+            assert!((angle - settings.emitter_angle).abs() <= settings.emitter_spread / 2. + 1e-5, "angle {}", angle);
+            assert!((0.4..=0.9).contains(&particle.color[1]));
+        }
+        // Each step's new particles are different from the last's.
+        let mut system = ParticleSystem::new(settings);
+        let first = spawn_particle(0, &system.next_uniforms(0.1, [800., 600.]));
+        let second = spawn_particle(0, &system.next_uniforms(0.1, [800., 600.]));
+        assert_ne!(first.velocity, second.velocity);
+    }
+
+    #[test]
+    fn gravity_speeds_particles_up_before_they_move() {
+        let settings = ParticleSettings { gravity: [0., -100.], ..ParticleSettings::default() };
+        let uniforms = settings.uniforms(0.1, [800., 600.], 0);
+        let particle = update_particle(flying([0., 0.], [10., 20.]), 0, &uniforms);
+        assert_near(particle.velocity, [10., 10.]);
+        assert_near(particle.position, [1., 1.]);
+        assert!((particle.age - 1.1).abs() < 1e-6);
+        let particle = update_particle(particle, 0, &uniforms);
+        assert_near(particle.velocity, [10., 0.]);
+        assert_near(particle.position, [2., 1.]);
+    }
+
+    #[test]
+    fn particles_bounce_off_the_edges_losing_speed() {
+        let settings = ParticleSettings { gravity: [0., 0.], restitution: 0.5, ..ParticleSettings::default() };
+        let uniforms = settings.uniforms(0.1, [200., 100.], 0);
+        let right = update_particle(flying([95., 0.], [100., 10.]), 0, &uniforms);
+        assert_near(right.position, [100., 1.]);
+        assert_near(right.velocity, [-50., 10.]);
+        let bottom = update_particle(flying([0., -48.], [0., -40.]), 0, &uniforms);
+        assert_near(bottom.position, [0., -50.]);
+        assert_near(bottom.velocity, [0., 20.]);
+        // Inside the edges they don't bounce.
+        let inside = update_particle(flying([0., 0.], [100., 0.]), 0, &uniforms);
+        assert_near(inside.velocity, [100., 0.]);
+    }
+
+    #[test]
+    fn particles_respawn_when_they_reach_their_lifetime() {
+        let uniforms = ParticleSettings::default().uniforms(0.25, [800., 600.], 3);
+        let old = AAPLParticle { age: 9.75, ..flying([50., 50.], [0., 0.]) };
+        assert_eq!(update_particle(old, 5, &uniforms), spawn_particle(5, &uniforms));
+        let not_yet = AAPLParticle { age: 9.5, ..flying([50., 50.], [0., 0.]) };
+        let still_flying = update_particle(not_yet, 5, &uniforms);
+        assert_eq!((still_flying.age, still_flying.lifetime), (9.75, 10.));
+        // It fades out as it gets older.
+        assert_eq!(particle_color(&AAPLParticle { age: 7.5, ..not_yet }), [1., 1., 1., 0.25]);
+    }
+
+    #[test]
+    fn only_the_uniforms_particle_count_is_updated() {
+        let mut uniforms = ParticleSettings::default().uniforms(0.25, [800., 600.], 0);
+        uniforms.particle_count = 1;
+        let mut particles = vec![flying([0., 0.], [4., 0.]); 2];
+        update_particles(&mut particles, &uniforms);
+        assert_ne!(particles[0], particles[1]);
+        assert_eq!(particles[1], flying([0., 0.], [4., 0.]));
+    }
+}
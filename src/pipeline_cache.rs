@@ -0,0 +1,410 @@
+//! Render and compute pipeline states, built once per description
+//!
+//! Building a pipeline state compiles shaders, so it's slow.
+//! Everything that goes into a render pipeline is described by a `PipelineDesc`,
+//! and the `PipelineCache` builds each distinct description only once;
+//! compute pipelines just need their kernel's name.
+//! It can also keep the compiled render pipelines in an `MTLBinaryArchive` file,
+//! so the next run doesn't compile them again.
+
+use objc::class;
+use objc::msg_send;
+use objc::sel;
+use objc::sel_impl;
+use cocoa::base::{id, nil};
+use cocoa::foundation::{NSAutoreleasePool, NSString, NSUInteger};
+use objc::runtime::{objc_release, BOOL, NO, YES};
+use std::collections::HashMap;
+use std::fmt::Formatter;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use crate::pixel_format::{MTLPixelFormat, MTLPixelFormatInvalid};
+use crate::ns_error::NSErrorDetails;
+use crate::error_chain::format_error_chain;
+use crate::blend::{BlendMode, BlendFactor, BlendOperation, BlendState};
+
+// From System/Library/Frameworks/Metal.framework/Versions/A/Headers/MTLVertexDescriptor.h
+// typedef NS_ENUM(NSUInteger, MTLVertexFormat) {...}
+#[allow(non_camel_case_types)]
+pub type MTLVertexFormat = NSUInteger;
+// MTLVertexFormatFloat2 = 29,
+// MTLVertexFormatFloat3 = 30,
+// MTLVertexFormatFloat4 = 31,
+
+// From System/Library/Frameworks/Metal.framework/Versions/A/Headers/MTLRenderPipeline.h
+// typedef NS_ENUM(NSUInteger, MTLBlendFactor) {
+//     MTLBlendFactorZero = 0,
+//     MTLBlendFactorOne = 1,
+//     MTLBlendFactorSourceColor = 2,
+//     MTLBlendFactorOneMinusSourceColor = 3,
+//     MTLBlendFactorSourceAlpha = 4,
+//     MTLBlendFactorOneMinusSourceAlpha = 5,
+//     MTLBlendFactorDestinationColor = 6,
+//     MTLBlendFactorOneMinusDestinationColor = 7,
+//     MTLBlendFactorDestinationAlpha = 8,
+//     MTLBlendFactorOneMinusDestinationAlpha = 9,
+//     ...
+// }
+fn mtl_blend_factor(factor: BlendFactor) -> NSUInteger {
+    match factor {
+        BlendFactor::Zero => 0,
+        BlendFactor::One => 1,
+        BlendFactor::SourceColor => 2,
+        BlendFactor::OneMinusSourceColor => 3,
+        BlendFactor::SourceAlpha => 4,
+        BlendFactor::OneMinusSourceAlpha => 5,
+        BlendFactor::DestinationColor => 6,
+        BlendFactor::OneMinusDestinationColor => 7,
+        BlendFactor::DestinationAlpha => 8,
+        BlendFactor::OneMinusDestinationAlpha => 9,
+    }
+}
+
+// typedef NS_ENUM(NSUInteger, MTLBlendOperation) {
+//     MTLBlendOperationAdd = 0,
+//     MTLBlendOperationSubtract = 1,
+//     MTLBlendOperationReverseSubtract = 2,
+//     MTLBlendOperationMin = 3,
+//     MTLBlendOperationMax = 4,
+// }
+fn mtl_blend_operation(operation: BlendOperation) -> NSUInteger {
+    match operation {
+        BlendOperation::Add => 0,
+        BlendOperation::Subtract => 1,
+        BlendOperation::ReverseSubtract => 2,
+        BlendOperation::Min => 3,
+        BlendOperation::Max => 4,
+    }
+}
+
+/// One attribute of a vertex, as the vertex shader's `[[attribute(n)]]` sees it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct VertexAttributeDesc {
+    pub format: MTLVertexFormat,
+    /// Where the attribute is within a vertex, in bytes.
+    pub offset: usize,
+}
+
+/// How vertices are laid out in a buffer, for shaders that use `[[stage_in]]`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct VertexLayout {
+    /// In `[[attribute(n)]]` order.
+    pub attributes: Vec<VertexAttributeDesc>,
+    /// The size of a vertex, in bytes.
+    pub stride: usize,
+    /// Which vertex buffer the vertices are bound to.
+    pub buffer_index: usize,
+}
+
+/// Everything that goes into a render pipeline state.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PipelineDesc {
+    pub vertex_function: String,
+    pub fragment_function: String,
+    pub color_pixel_format: MTLPixelFormat,
+    /// `MTLPixelFormatInvalid` if there's no depth attachment.
+    pub depth_pixel_format: MTLPixelFormat,
+    /// `MTLPixelFormatInvalid` if there's no stencil attachment.
+    pub stencil_pixel_format: MTLPixelFormat,
+    pub blend_mode: BlendMode,
+    pub sample_count: usize,
+    /// `None` if the vertex shader reads its buffers itself, as ours do.
+    pub vertex_layout: Option<VertexLayout>,
+}
+
+impl PipelineDesc {
+    /// A single-sampled, opaque pipeline with no depth or stencil
+    /// that draws to `color_pixel_format` with the given functions.
+    pub fn new(vertex_function: &str, fragment_function: &str, color_pixel_format: MTLPixelFormat) -> Self {
+        PipelineDesc {
+            vertex_function: vertex_function.to_string(),
+            fragment_function: fragment_function.to_string(),
+            color_pixel_format,
+            depth_pixel_format: MTLPixelFormatInvalid,
+            stencil_pixel_format: MTLPixelFormatInvalid,
+            blend_mode: BlendMode::Opaque,
+            sample_count: 1,
+            vertex_layout: None,
+        }
+    }
+
+    /// A label for the pipeline state, to tell them apart in Xcode's debugger.
+    fn label(&self) -> String {
+        format!("{} + {} ({:?})", self.vertex_function, self.fragment_function, self.blend_mode)
+    }
+}
+
+/// Why we couldn't make a pipeline state.
+#[derive(Debug)]
+pub enum PipelineError {
+    MissingFunction(String),
+    Compilation(NSErrorDetails),
+    ComputeCompilation(NSErrorDetails),
+}
+impl std::fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingFunction(name) => write!(f, "The shader library has no function called {}", name),
+            Self::Compilation(_) => write!(f, "Unable to compile the render pipeline"),
+            Self::ComputeCompilation(_) => write!(f, "Unable to compile the compute pipeline"),
+        }
+    }
+}
+impl Error for PipelineError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::MissingFunction(_) => None,
+            Self::Compilation(details) | Self::ComputeCompilation(details) => Some(details),
+        }
+    }
+}
+
+/// Builds pipeline states from one shader library, and keeps them.
+pub struct PipelineCache {
+    device: id,
+    library: id,
+    pipeline_states: HashMap<PipelineDesc, id>,
+    /// By kernel name.
+    compute_pipeline_states: HashMap<String, id>,
+    binary_archive: Option<(PathBuf, id)>,
+}
+
+impl PipelineCache {
+    /// Makes pipelines with functions from `library`.
+    ///
+    /// The cache keeps its own hold on the library.
+    pub fn new(device: id, library: id) -> Self {
+        let _: id = unsafe { msg_send![library, retain] };
+        PipelineCache {
+            device,
+            library,
+            pipeline_states: HashMap::new(),
+            compute_pipeline_states: HashMap::new(),
+            binary_archive: None,
+        }
+    }
+
+    /// Looks for compiled pipelines in a binary archive file,
+    /// and adds any new ones to it.
+    ///
+    /// The file is created if it doesn't exist yet.
+    /// Binary archives need macOS 11; before that this does nothing.
+    pub fn use_binary_archive(&mut self, path: &Path) -> Result<(), NSErrorDetails> {
+        let supported: BOOL = unsafe { msg_send![self.device, respondsToSelector:sel!(newBinaryArchiveWithDescriptor:error:)] };
+        if supported == NO {
+            return Ok(());
+        }
+        let pool = unsafe { NSAutoreleasePool::new(nil) };
+        let archive_descriptor: id = unsafe { msg_send![class!(MTLBinaryArchiveDescriptor), new] };
+        if path.exists() {
+            let url = file_url(path);
+            let _:() = unsafe { msg_send![archive_descriptor, setUrl:url] };
+        }
+        let mut error: id = nil;
+        let archive: id = unsafe { msg_send![self.device, newBinaryArchiveWithDescriptor:archive_descriptor error:&mut error] };
+        let result = if archive == nil {
+            Err(NSErrorDetails::from_ns_error_or_unknown(error))
+        } else {
+            if let Some((_, old_archive)) = self.binary_archive.replace((path.to_path_buf(), archive)) {
+                unsafe { objc_release(old_archive) };
+            }
+            Ok(())
+        };
+        unsafe {
+            objc_release(archive_descriptor);
+            pool.drain();
+        }
+        result
+    }
+
+    /// The pipeline state for a description, built the first time it's asked for.
+    pub fn get_or_create(&mut self, desc: &PipelineDesc) -> Result<id, PipelineError> {
+        if let Some(&pipeline_state) = self.pipeline_states.get(desc) {
+            return Ok(pipeline_state);
+        }
+        let pipeline_state = self.new_pipeline_state(desc)?;
+        self.pipeline_states.insert(desc.clone(), pipeline_state);
+        Ok(pipeline_state)
+    }
+
+    /// The compute pipeline state for a kernel, built the first time it's asked for.
+    pub fn get_or_create_compute(&mut self, function_name: &str) -> Result<id, PipelineError> {
+        if let Some(&pipeline_state) = self.compute_pipeline_states.get(function_name) {
+            return Ok(pipeline_state);
+        }
+        let pipeline_state = self.new_compute_pipeline_state(function_name)?;
+        self.compute_pipeline_states.insert(function_name.to_string(), pipeline_state);
+        Ok(pipeline_state)
+    }
+
+    fn new_pipeline_state(&mut self, desc: &PipelineDesc) -> Result<id, PipelineError> {
+        let pool = unsafe { NSAutoreleasePool::new(nil) };
+        let vertex_function = self.new_function(&desc.vertex_function);
+        let fragment_function = self.new_function(&desc.fragment_function);
+        let result = if vertex_function == nil {
+            Err(PipelineError::MissingFunction(desc.vertex_function.clone()))
+        } else if fragment_function == nil {
+            Err(PipelineError::MissingFunction(desc.fragment_function.clone()))
+        } else {
+            // Configure a pipeline descriptor that is used to create a pipeline state.
+            let render_pipeline_descriptor_class = class!(MTLRenderPipelineDescriptor);
+            let pipeline_state_descriptor: id = unsafe { msg_send![render_pipeline_descriptor_class, alloc] };
+            let pipeline_state_descriptor: id = unsafe { msg_send![pipeline_state_descriptor, init] };
+
+            let pipeline_label = unsafe { NSString::alloc(nil).init_str(&desc.label()) };
+            let _:() = unsafe {msg_send![pipeline_state_descriptor, setLabel:pipeline_label] };
+
+            let _:() = unsafe { msg_send![pipeline_state_descriptor, setVertexFunction:vertex_function] };
+            let _:() = unsafe { msg_send![pipeline_state_descriptor, setFragmentFunction:fragment_function] };
+
+            let color_attachment_array: id = unsafe { msg_send![pipeline_state_descriptor, colorAttachments] };
+            let color_attachment_0: id = unsafe { msg_send![color_attachment_array, objectAtIndexedSubscript:0] };
+            let pixel_format = desc.color_pixel_format;
+            let _:() = unsafe { msg_send![color_attachment_0, setPixelFormat:pixel_format] };
+            set_blend_state(color_attachment_0, &desc.blend_mode.blend_state());
+            let _:() = unsafe { msg_send![color_attachment_array, setObject:color_attachment_0 atIndexedSubscript:0] };
+
+            let depth_pixel_format = desc.depth_pixel_format;
+            let _:() = unsafe { msg_send![pipeline_state_descriptor, setDepthAttachmentPixelFormat:depth_pixel_format] };
+            let stencil_pixel_format = desc.stencil_pixel_format;
+            let _:() = unsafe { msg_send![pipeline_state_descriptor, setStencilAttachmentPixelFormat:stencil_pixel_format] };
+            let sample_count = desc.sample_count as NSUInteger;
+            let _:() = unsafe { msg_send![pipeline_state_descriptor, setRasterSampleCount:sample_count] };
+
+            if let Some(vertex_layout) = &desc.vertex_layout {
+                let vertex_descriptor = new_vertex_descriptor(vertex_layout);
+                let _:() = unsafe { msg_send![pipeline_state_descriptor, setVertexDescriptor:vertex_descriptor] };
+            }
+
+            if let Some((_, archive)) = self.binary_archive {
+                let archives: id = unsafe { msg_send![class!(NSArray), arrayWithObject:archive] };
+                let _:() = unsafe { msg_send![pipeline_state_descriptor, setBinaryArchives:archives] };
+            }
+
+            let mut error: id = nil;
+            let pipeline_state: id = unsafe { msg_send![self.device, newRenderPipelineStateWithDescriptor:pipeline_state_descriptor error:&mut error] };
+            let result = if pipeline_state == nil {
+                Err(PipelineError::Compilation(NSErrorDetails::from_ns_error_or_unknown(error)))
+            } else {
+                if let Err(e) = self.add_to_binary_archive(pipeline_state_descriptor) {
+                    // We have the pipeline; it just won't be quicker next time.
+                    println!("Unable to save the pipeline to the binary archive: {}", format_error_chain(&e));
+                }
+                Ok(pipeline_state)
+            };
+            unsafe { objc_release(pipeline_state_descriptor) };
+            result
+        };
+        unsafe {
+            objc_release(vertex_function);
+            objc_release(fragment_function);
+            pool.drain();
+        }
+        result
+    }
+
+    fn new_compute_pipeline_state(&self, function_name: &str) -> Result<id, PipelineError> {
+        let pool = unsafe { NSAutoreleasePool::new(nil) };
+        let function = self.new_function(function_name);
+        let result = if function == nil {
+            Err(PipelineError::MissingFunction(function_name.to_string()))
+        } else {
+            let mut error: id = nil;
+            let pipeline_state: id = unsafe { msg_send![self.device, newComputePipelineStateWithFunction:function error:&mut error] };
+            unsafe { objc_release(function) };
+            if pipeline_state == nil {
+                Err(PipelineError::ComputeCompilation(NSErrorDetails::from_ns_error_or_unknown(error)))
+            } else {
+                Ok(pipeline_state)
+            }
+        };
+        unsafe { pool.drain() };
+        result
+    }
+
+    fn new_function(&self, name: &str) -> id {
+        let function_name = unsafe { NSString::alloc(nil).init_str(name) };
+        unsafe { msg_send![self.library, newFunctionWithName:function_name] }
+    }
+
+    /// Adds a newly built pipeline to the binary archive, if we have one, and writes it out.
+    fn add_to_binary_archive(&self, pipeline_state_descriptor: id) -> Result<(), NSErrorDetails> {
+        let (path, archive) = match &self.binary_archive {
+            Some((path, archive)) => (path, *archive),
+            None => return Ok(()),
+        };
+        let mut error: id = nil;
+        let added: BOOL = unsafe { msg_send![archive, addRenderPipelineFunctionsWithDescriptor:pipeline_state_descriptor error:&mut error] };
+        if added == NO {
+            return Err(NSErrorDetails::from_ns_error_or_unknown(error));
+        }
+        let url = file_url(path);
+        let saved: BOOL = unsafe { msg_send![archive, serializeToURL:url error:&mut error] };
+        if saved == NO {
+            return Err(NSErrorDetails::from_ns_error_or_unknown(error));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PipelineCache {
+    fn drop(&mut self) {
+        // Command buffers still in flight keep their own hold on the pipeline states.
+        for (_, pipeline_state) in self.pipeline_states.drain() {
+            unsafe { objc_release(pipeline_state) };
+        }
+        for (_, pipeline_state) in self.compute_pipeline_states.drain() {
+            unsafe { objc_release(pipeline_state) };
+        }
+        if let Some((_, archive)) = self.binary_archive.take() {
+            unsafe { objc_release(archive) };
+        }
+        unsafe { objc_release(self.library) };
+    }
+}
+
+/// Configures a `MTLRenderPipelineColorAttachmentDescriptor` to blend as `blend_state` says.
+fn set_blend_state(color_attachment: id, blend_state: &BlendState) {
+    let enabled: BOOL = if blend_state.enabled { YES } else { NO };
+    let rgb_operation = mtl_blend_operation(blend_state.rgb_operation);
+    let alpha_operation = mtl_blend_operation(blend_state.alpha_operation);
+    let source_rgb_factor = mtl_blend_factor(blend_state.source_rgb_factor);
+    let destination_rgb_factor = mtl_blend_factor(blend_state.destination_rgb_factor);
+    let source_alpha_factor = mtl_blend_factor(blend_state.source_alpha_factor);
+    let destination_alpha_factor = mtl_blend_factor(blend_state.destination_alpha_factor);
+    unsafe {
+        let _:() = msg_send![color_attachment, setBlendingEnabled:enabled];
+        let _:() = msg_send![color_attachment, setRgbBlendOperation:rgb_operation];
+        let _:() = msg_send![color_attachment, setAlphaBlendOperation:alpha_operation];
+        let _:() = msg_send![color_attachment, setSourceRGBBlendFactor:source_rgb_factor];
+        let _:() = msg_send![color_attachment, setDestinationRGBBlendFactor:destination_rgb_factor];
+        let _:() = msg_send![color_attachment, setSourceAlphaBlendFactor:source_alpha_factor];
+        let _:() = msg_send![color_attachment, setDestinationAlphaBlendFactor:destination_alpha_factor];
+    }
+}
+
+/// An autoreleased `MTLVertexDescriptor` for a vertex layout.
+fn new_vertex_descriptor(vertex_layout: &VertexLayout) -> id {
+    let vertex_descriptor: id = unsafe { msg_send![class!(MTLVertexDescriptor), vertexDescriptor] };
+    let attributes: id = unsafe { msg_send![vertex_descriptor, attributes] };
+    let buffer_index = vertex_layout.buffer_index as NSUInteger;
+    for (index, attribute_desc) in vertex_layout.attributes.iter().enumerate() {
+        let attribute: id = unsafe { msg_send![attributes, objectAtIndexedSubscript:index as NSUInteger] };
+        let format = attribute_desc.format;
+        let offset = attribute_desc.offset as NSUInteger;
+        let _:() = unsafe { msg_send![attribute, setFormat:format] };
+        let _:() = unsafe { msg_send![attribute, setOffset:offset] };
+        let _:() = unsafe { msg_send![attribute, setBufferIndex:buffer_index] };
+    }
+    let layouts: id = unsafe { msg_send![vertex_descriptor, layouts] };
+    let layout: id = unsafe { msg_send![layouts, objectAtIndexedSubscript:buffer_index] };
+    let stride = vertex_layout.stride as NSUInteger;
+    let _:() = unsafe { msg_send![layout, setStride:stride] };
+    vertex_descriptor
+}
+
+/// An autoreleased file `NSURL` for a path.
+fn file_url(path: &Path) -> id {
+    let path_string = unsafe { NSString::alloc(nil).init_str(&path.to_string_lossy()) };
+    unsafe { msg_send![class!(NSURL), fileURLWithPath:path_string] }
+}
@@ -0,0 +1,77 @@
+//! Metal's pixel formats, and what we know about them
+//!
+//! Kept apart from the view so the parts of the app that only need to name formats,
+//! such as the render graph, build without Metal.
+#![allow(non_upper_case_globals)]
+
+use std::os::raw::c_ulong;
+
+// From System/Library/Frameworks/Metal.framework/Versions/A/Headers/MTLPixelFormat.h:
+// typedef NS_ENUM(NSUInteger, MTLPixelFormat) {...}
+// An NSUInteger, which is a `c_ulong` on 64-bit Macs, as in `cocoa::foundation`.
+pub type MTLPixelFormat = c_ulong;
+// MTLPixelFormatBGRA8Unorm      = 80
+// MTLPixelFormatBGRA8Unorm_sRGB = 81,
+// MTLPixelFormatBGR10A2Unorm    = 94,
+// MTLPixelFormatRGBA16Float     = 115,
+pub static MTLPixelFormatBGRA8Unorm:MTLPixelFormat = 80;
+pub static MTLPixelFormatBGRA8Unorm_sRGB:MTLPixelFormat = 81;
+pub static MTLPixelFormatBGR10A2Unorm:MTLPixelFormat = 94;
+pub static MTLPixelFormatRGBA16Float:MTLPixelFormat = 115;
+
+/// The color pixel formats the view can draw to, by name.
+static COLOR_PIXEL_FORMAT_NAMES: [(&str, &MTLPixelFormat); 4] = [
+    ("BGRA8Unorm", &MTLPixelFormatBGRA8Unorm),
+    ("BGRA8Unorm_sRGB", &MTLPixelFormatBGRA8Unorm_sRGB),
+    ("BGR10A2Unorm", &MTLPixelFormatBGR10A2Unorm),
+    ("RGBA16Float", &MTLPixelFormatRGBA16Float),
+];
+
+/// The color pixel format with the given name (as in `MTLPixelFormat`, without the prefix),
+/// if the view can draw to it.
+pub fn color_pixel_format_from_name(name: &str) -> Option<MTLPixelFormat> {
+    COLOR_PIXEL_FORMAT_NAMES.iter()
+        .find(|(format_name, _)| *format_name == name)
+        .map(|(_, &pixel_format)| pixel_format)
+}
+
+/// The name of a color pixel format the view can draw to, as `color_pixel_format_from_name` takes it.
+pub fn color_pixel_format_name(pixel_format: MTLPixelFormat) -> Option<&'static str> {
+    COLOR_PIXEL_FORMAT_NAMES.iter()
+        .find(|(_, &format)| format == pixel_format)
+        .map(|(name, _)| *name)
+}
+
+// MTLPixelFormatInvalid = 0,
+// MTLPixelFormatDepth16Unorm = 250,
+// MTLPixelFormatDepth32Float = 252,
+// MTLPixelFormatStencil8 = 253,
+// MTLPixelFormatDepth24Unorm_Stencil8 = 255,
+// MTLPixelFormatDepth32Float_Stencil8 = 260,
+// MTLPixelFormatX32_Stencil8 = 261,
+// MTLPixelFormatX24_Stencil8 = 262,
+pub static MTLPixelFormatInvalid:MTLPixelFormat = 0;
+static MTLPixelFormatDepth16Unorm:MTLPixelFormat = 250;
+static MTLPixelFormatDepth32Float:MTLPixelFormat = 252;
+static MTLPixelFormatStencil8:MTLPixelFormat = 253;
+static MTLPixelFormatDepth24Unorm_Stencil8:MTLPixelFormat = 255;
+pub static MTLPixelFormatDepth32Float_Stencil8:MTLPixelFormat = 260;
+static MTLPixelFormatX32_Stencil8:MTLPixelFormat = 261;
+static MTLPixelFormatX24_Stencil8:MTLPixelFormat = 262;
+
+/// Whether a pixel format has a depth component.
+pub fn pixel_format_has_depth(pixel_format: MTLPixelFormat) -> bool {
+    pixel_format == MTLPixelFormatDepth16Unorm
+        || pixel_format == MTLPixelFormatDepth32Float
+        || pixel_format == MTLPixelFormatDepth24Unorm_Stencil8
+        || pixel_format == MTLPixelFormatDepth32Float_Stencil8
+}
+
+/// Whether a pixel format has a stencil component.
+pub fn pixel_format_has_stencil(pixel_format: MTLPixelFormat) -> bool {
+    pixel_format == MTLPixelFormatStencil8
+        || pixel_format == MTLPixelFormatDepth24Unorm_Stencil8
+        || pixel_format == MTLPixelFormatDepth32Float_Stencil8
+        || pixel_format == MTLPixelFormatX32_Stencil8
+        || pixel_format == MTLPixelFormatX24_Stencil8
+}
@@ -0,0 +1,442 @@
+//! Full-screen effects applied to the scene before it's shown
+//!
+//! With any effects, the scene is drawn into an offscreen texture instead of the drawable.
+//! Each effect then draws over the whole view in one or more full-screen passes,
+//! each reading what earlier passes drew, and the last pass draws into the drawable.
+//! The textures in between have the view's color pixel format,
+//! so (as with the scene) colors are clamped from 0 to 1 after each pass.
+//!
+//! The shaders read whole texels rather than sampling, and the functions here do exactly
+//! what they do to a pixel, so the software rasterizer can apply the same effects on the CPU.
+
+use crate::image::Image;
+use crate::shader_types::{AAPLBlurUniforms, AAPLBloomUniforms, AAPLColorGradeUniforms, AAPLVignetteUniforms};
+
+/// A blur in both directions, one after the other.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct GaussianBlur {
+    /// How many pixels away on each side are averaged.
+    pub radius: u32,
+    /// The gaussian's standard deviation, in pixels.
+    pub sigma: f32,
+}
+
+impl Default for GaussianBlur {
+    fn default() -> Self {
+        GaussianBlur { radius: 6, sigma: 3. }
+    }
+}
+
+impl GaussianBlur {
+    /// The uniforms to blur along `direction`: `[1, 0]` across, or `[0, 1]` down.
+    pub fn uniforms(&self, direction: [i32; 2]) -> AAPLBlurUniforms {
+        AAPLBlurUniforms { direction, sigma: self.sigma, radius: self.radius }
+    }
+}
+
+/// A glow around the brightest parts of the image.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Bloom {
+    /// How bright (in the brightest channel) a color has to be to glow.
+    pub threshold: f32,
+    /// How far below the threshold the glow starts to fade in.
+    pub knee: f32,
+    /// How strongly the glow is added back.
+    pub intensity: f32,
+    /// How far the glow spreads.
+    pub blur: GaussianBlur,
+}
+
+impl Default for Bloom {
+    fn default() -> Self {
+        Bloom { threshold: 0.6, knee: 0.2, intensity: 1., blur: GaussianBlur { radius: 12, sigma: 6. } }
+    }
+}
+
+impl Bloom {
+    pub fn uniforms(&self) -> AAPLBloomUniforms {
+        AAPLBloomUniforms { threshold: self.threshold, knee: self.knee, intensity: self.intensity }
+    }
+}
+
+/// Colors moved towards the ones a color lookup table gives.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColorGrade {
+    pub lut: ColorLut,
+    /// 0 leaves the colors alone, 1 uses the table's.
+    pub amount: f32,
+}
+
+impl Default for ColorGrade {
+    /// Warmer, more saturated and a little more contrasty.
+    fn default() -> Self {
+        ColorGrade { lut: ColorLut::from_fn(16, warm_grade), amount: 1. }
+    }
+}
+
+impl ColorGrade {
+    pub fn uniforms(&self) -> AAPLColorGradeUniforms {
+        AAPLColorGradeUniforms { amount: self.amount, lut_size: self.lut.size() as u32 }
+    }
+}
+
+/// The edges of the view darkened.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Vignette {
+    /// How dark the corners get, from 0 (not at all) to 1 (black).
+    pub intensity: f32,
+    /// How far from the centre the darkening starts, in half-heights of the view.
+    pub radius: f32,
+    /// How far past the radius the darkening takes to reach its full intensity. Must be more than 0.
+    pub softness: f32,
+}
+
+impl Default for Vignette {
+    fn default() -> Self {
+        Vignette { intensity: 0.6, radius: 0.75, softness: 0.75 }
+    }
+}
+
+impl Vignette {
+    pub fn uniforms(&self) -> AAPLVignetteUniforms {
+        AAPLVignetteUniforms { intensity: self.intensity, radius: self.radius, softness: self.softness }
+    }
+}
+
+/// One effect in the chain.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PostEffect {
+    GaussianBlur(GaussianBlur),
+    Bloom(Bloom),
+    ColorGrade(ColorGrade),
+    Vignette(Vignette),
+}
+
+/// A full-screen shader, and its uniforms.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PostShader {
+    GaussianBlur(AAPLBlurUniforms),
+    BrightPass(AAPLBloomUniforms),
+    /// Also reads the blurred highlights.
+    BloomComposite(AAPLBloomUniforms),
+    /// Also reads the effect's color lookup table.
+    ColorGrade(AAPLColorGradeUniforms),
+    Vignette(AAPLVignetteUniforms),
+}
+
+/// An image a pass reads.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PostInput {
+    /// What the effect is applied to.
+    EffectInput,
+    /// What an earlier pass of the effect drew.
+    Pass(usize),
+}
+
+/// One full-screen pass of an effect.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PostPass {
+    pub shader: PostShader,
+    pub source: PostInput,
+    /// The blurred highlights, for `BloomComposite`.
+    pub bloom: Option<PostInput>,
+}
+
+impl PostPass {
+    fn new(shader: PostShader, source: PostInput) -> Self {
+        PostPass { shader, source, bloom: None }
+    }
+}
+
+impl PostEffect {
+    /// The effect with its default settings, by the name it has in `HELLO_TRIANGLE_POST_EFFECTS`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "blur" => Some(PostEffect::GaussianBlur(GaussianBlur::default())),
+            "bloom" => Some(PostEffect::Bloom(Bloom::default())),
+            "grade" => Some(PostEffect::ColorGrade(ColorGrade::default())),
+            "vignette" => Some(PostEffect::Vignette(Vignette::default())),
+            _ => None,
+        }
+    }
+
+    /// The passes that draw the effect, in order. The last one's output is the effect's.
+    pub fn passes(&self) -> Vec<PostPass> {
+        use PostInput::{EffectInput, Pass};
+        match self {
+            PostEffect::GaussianBlur(blur) => vec![
+                PostPass::new(PostShader::GaussianBlur(blur.uniforms([1, 0])), EffectInput),
+                PostPass::new(PostShader::GaussianBlur(blur.uniforms([0, 1])), Pass(0)),
+            ],
+            PostEffect::Bloom(bloom) => vec![
+                PostPass::new(PostShader::BrightPass(bloom.uniforms()), EffectInput),
+                PostPass::new(PostShader::GaussianBlur(bloom.blur.uniforms([1, 0])), Pass(0)),
+                PostPass::new(PostShader::GaussianBlur(bloom.blur.uniforms([0, 1])), Pass(1)),
+                PostPass { bloom: Some(Pass(2)), ..PostPass::new(PostShader::BloomComposite(bloom.uniforms()), EffectInput) },
+            ],
+            PostEffect::ColorGrade(grade) => vec![PostPass::new(PostShader::ColorGrade(grade.uniforms()), EffectInput)],
+            PostEffect::Vignette(vignette) => vec![PostPass::new(PostShader::Vignette(vignette.uniforms()), EffectInput)],
+        }
+    }
+
+    /// The color lookup table the effect's passes read, if any.
+    pub fn lut(&self) -> Option<&ColorLut> {
+        match self {
+            PostEffect::ColorGrade(grade) => Some(&grade.lut),
+            _ => None,
+        }
+    }
+}
+
+/// The post-processing effects HELLO_TRIANGLE_POST_EFFECTS asks for, if it's set.
+pub fn post_effects_from_environment() -> Option<Vec<PostEffect>> {
+    let names = std::env::var("HELLO_TRIANGLE_POST_EFFECTS").ok()?;
+    let mut effects = Vec::new();
+    for name in names.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+        match PostEffect::from_name(name) {
+            Some(effect) => effects.push(effect),
+            None => println!("Skipping unknown post-processing effect {:?}; try blur, bloom, grade or vignette", name),
+        }
+    }
+    Some(effects)
+}
+
+/// A table of colors to replace colors with, `size` entries along each of red, green and blue.
+/// Colors between entries are interpolated.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColorLut {
+    size: usize,
+    /// Red changes fastest, then green, then blue.
+    colors: Vec<[f32; 3]>,
+}
+
+impl ColorLut {
+    /// A table of what `grade` makes of each entry's color, with at least two entries along each axis.
+    ///
+    /// The colors are rounded to 8 bits, as they're stored in the texture.
+    pub fn from_fn(size: usize, grade: impl Fn([f32; 3]) -> [f32; 3]) -> Self {
+        let size = size.max(2);
+        let scale = 1. / (size - 1) as f32;
+        let mut colors = Vec::with_capacity(size * size * size);
+        for blue in 0..size {
+            for green in 0..size {
+                for red in 0..size {
+                    let graded = grade([red as f32 * scale, green as f32 * scale, blue as f32 * scale]);
+                    colors.push(graded.map(|channel| (channel.clamp(0., 1.) * 255.).round() / 255.));
+                }
+            }
+        }
+        ColorLut { size, colors }
+    }
+
+    /// A table that leaves colors as they are.
+    pub fn identity(size: usize) -> Self {
+        Self::from_fn(size, |color| color)
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn entry(&self, red: usize, green: usize, blue: usize) -> [f32; 3] {
+        self.colors[(blue * self.size + green) * self.size + red]
+    }
+
+    /// The table's color for any color, as `lookUpColor` finds it.
+    pub fn look_up(&self, color: [f32; 3]) -> [f32; 3] {
+        let last = self.size - 1;
+        let scaled = color.map(|channel| channel.clamp(0., 1.) * last as f32);
+        let lower = scaled.map(|channel| channel.floor() as usize);
+        let upper = lower.map(|index| (index + 1).min(last));
+        let fraction = [0, 1, 2].map(|axis| scaled[axis] - lower[axis] as f32);
+        let slices = [lower[2], upper[2]].map(|blue| {
+            let c00 = self.entry(lower[0], lower[1], blue);
+            let c10 = self.entry(upper[0], lower[1], blue);
+            let c01 = self.entry(lower[0], upper[1], blue);
+            let c11 = self.entry(upper[0], upper[1], blue);
+            mix3(mix3(c00, c10, fraction[0]), mix3(c01, c11, fraction[0]), fraction[1])
+        });
+        mix3(slices[0], slices[1], fraction[2])
+    }
+
+    /// The table as the texture `colorGradeFragmentShader` reads:
+    /// its blue slices side by side, with red across each slice and green down it.
+    pub fn to_image(&self) -> Image {
+        let mut pixels = Vec::with_capacity(self.colors.len() * 4);
+        for green in 0..self.size {
+            for blue in 0..self.size {
+                for red in 0..self.size {
+                    let [r, g, b] = self.entry(red, green, blue);
+                    pixels.extend_from_slice(&[r, g, b, 1.].map(|channel| (channel * 255.).round() as u8));
+                }
+            }
+        }
+        Image::new(self.size * self.size, self.size, pixels).unwrap()
+    }
+}
+
+/// The default grade: more saturated and contrasty, with the reds lifted and the blues lowered.
+fn warm_grade(color: [f32; 3]) -> [f32; 3] {
+    let [red, green, blue] = color;
+    let luma = 0.2126 * red + 0.7152 * green + 0.0722 * blue;
+    let saturated = color.map(|channel| luma + (channel - luma) * 1.2);
+    let contrasted = saturated.map(|channel| (channel - 0.5) * 1.1 + 0.5);
+    [contrasted[0] * 1.06, contrasted[1] * 1.01, contrasted[2] * 0.92]
+}
+
+/// What `gaussianBlurFragmentShader` draws at a pixel, reading the image's pixels with `read`.
+pub fn gaussian_blur(read: impl Fn(i32, i32) -> [f32; 4], x: i32, y: i32, uniforms: &AAPLBlurUniforms) -> [f32; 4] {
+    let sigma = uniforms.sigma.max(1e-3);
+    let radius = uniforms.radius as i32;
+    let mut sum = [0.; 4];
+    let mut total_weight = 0.;
+    for offset in -radius..=radius {
+        let weight = (-((offset * offset) as f32) / (2. * sigma * sigma)).exp();
+        let texel = read(x + uniforms.direction[0] * offset, y + uniforms.direction[1] * offset);
+        for (sum_channel, channel) in sum.iter_mut().zip(texel) {
+            *sum_channel += channel * weight;
+        }
+        total_weight += weight;
+    }
+    sum.map(|channel| channel / total_weight)
+}
+
+/// What `brightPassFragmentShader` makes of a color.
+pub fn bright_pass(color: [f32; 4], uniforms: &AAPLBloomUniforms) -> [f32; 4] {
+    let [red, green, blue, alpha] = color;
+    let brightness = red.max(green).max(blue);
+    let soft = (brightness - uniforms.threshold + uniforms.knee).clamp(0., 2. * uniforms.knee);
+    let soft = soft * soft / (4. * uniforms.knee + 1e-5);
+    let contribution = soft.max(brightness - uniforms.threshold) / brightness.max(1e-5);
+    [red * contribution, green * contribution, blue * contribution, alpha]
+}
+
+/// What `bloomCompositeFragmentShader` makes of a color and the blurred highlights there.
+pub fn bloom_composite(color: [f32; 4], bloom: [f32; 4], uniforms: &AAPLBloomUniforms) -> [f32; 4] {
+    [
+        color[0] + bloom[0] * uniforms.intensity,
+        color[1] + bloom[1] * uniforms.intensity,
+        color[2] + bloom[2] * uniforms.intensity,
+        color[3],
+    ]
+}
+
+/// What `colorGradeFragmentShader` makes of a color.
+pub fn color_grade(color: [f32; 4], lut: &ColorLut, uniforms: &AAPLColorGradeUniforms) -> [f32; 4] {
+    let [red, green, blue, alpha] = color;
+    let graded = lut.look_up([red, green, blue]);
+    let [red, green, blue] = mix3([red, green, blue], graded, uniforms.amount);
+    [red, green, blue, alpha]
+}
+
+/// What `vignetteFragmentShader` makes of the color of pixel (x, y) in a view of the given size.
+pub fn vignette(color: [f32; 4], x: usize, y: usize, size: [usize; 2], uniforms: &AAPLVignetteUniforms) -> [f32; 4] {
+    let [width, height] = [size[0] as f32, size[1] as f32];
+    // From the centre, in half-heights of the view.
+    let offset_x = ((x as f32 + 0.5) / width - 0.5) * (width / height) * 2.;
+    let offset_y = ((y as f32 + 0.5) / height - 0.5) * 2.;
+    let distance = (offset_x * offset_x + offset_y * offset_y).sqrt();
+    let darkening = uniforms.intensity * smoothstep(uniforms.radius, uniforms.radius + uniforms.softness, distance);
+    let [red, green, blue, alpha] = color;
+    [red * (1. - darkening), green * (1. - darkening), blue * (1. - darkening), alpha]
+}
+
+/// Metal's `smoothstep`.
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0., 1.);
+    t * t * (3. - 2. * t)
+}
+
+/// Metal's `mix`, for a `float3`.
+fn mix3(from: [f32; 3], to: [f32; 3], amount: f32) -> [f32; 3] {
+    [0, 1, 2].map(|channel| from[channel] + (to[channel] - from[channel]) * amount)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: [f32; 4], expected: [f32; 4]) {
+        assert!(actual.iter().zip(expected).all(|(a, e)| (a - e).abs() < 1e-5), "{:?} isn't {:?}", actual, expected);
+    }
+
+    #[test]
+    fn effects_are_named_as_in_the_environment() {
+        assert_eq!(PostEffect::from_name("vignette"), Some(PostEffect::Vignette(Vignette::default())));
+        assert_eq!(PostEffect::from_name("sepia"), None);
+    }
+
+    #[test]
+    fn bloom_blurs_the_highlights_and_adds_them_back() {
+        use PostInput::{EffectInput, Pass};
+        let passes = PostEffect::Bloom(Bloom::default()).passes();
+        let inputs: Vec<(PostInput, Option<PostInput>)> = passes.iter().map(|pass| (pass.source, pass.bloom)).collect();
+        assert_eq!(inputs, [(EffectInput, None), (Pass(0), None), (Pass(1), None), (EffectInput, Some(Pass(2)))]);
+        assert!(matches!(passes[1].shader, PostShader::GaussianBlur(AAPLBlurUniforms { direction: [1, 0], .. })));
+        assert!(matches!(passes[2].shader, PostShader::GaussianBlur(AAPLBlurUniforms { direction: [0, 1], .. })));
+    }
+
+    #[test]
+    fn blurring_keeps_flat_colors_and_spreads_edges_evenly() {
+        let uniforms = GaussianBlur { radius: 3, sigma: 1.5 }.uniforms([1, 0]);
+        assert_close(gaussian_blur(|_, _| [0.2, 0.4, 0.6, 1.], 5, 5, &uniforms), [0.2, 0.4, 0.6, 1.]);
+        // White from x = 0 on: the two pixels either side of the edge blur to greys that add up to white.
+        let step = |x: i32, _| if x >= 0 { [1.; 4] } else { [0.; 4] };
+        let left = gaussian_blur(step, -1, 0, &uniforms)[0];
+        let right = gaussian_blur(step, 0, 0, &uniforms)[0];
+        assert!((left + right - 1.).abs() < 1e-5 && left < 0.5, "{} {}", left, right);
+        // Only along the blur's direction.
+        assert_eq!(gaussian_blur(step, 0, 0, &GaussianBlur { radius: 3, sigma: 1.5 }.uniforms([0, 1])), [1.; 4]);
+    }
+
+    #[test]
+    fn the_bright_pass_keeps_what_is_over_the_threshold() {
+        let uniforms = Bloom { threshold: 0.6, knee: 0.2, ..Bloom::default() }.uniforms();
+        // Below the knee, nothing.
+        assert_eq!(bright_pass([0.3, 0.4, 0.2, 1.], &uniforms), [0., 0., 0., 1.]);
+        // Well over it, the part over the threshold, in the color's proportions.
+        assert_close(bright_pass([1., 0.5, 0., 1.], &uniforms), [0.4, 0.2, 0., 1.]);
+        // At the threshold, eased in: a quarter of the knee.
+        assert_close(bright_pass([0.6, 0., 0., 0.5], &uniforms), [0.05, 0., 0., 0.5]);
+        assert_eq!(bloom_composite([0.1, 0.2, 0.3, 0.4], [0.5, 0.5, 0.5, 1.], &Bloom { intensity: 0.5, ..Bloom::default() }.uniforms()),
+                   [0.35, 0.45, 0.55, 0.4]);
+    }
+
+    #[test]
+    fn lookup_tables_interpolate_between_entries() {
+        let identity = ColorLut::identity(4);
+        for color in [[0., 0., 0.], [0.25, 0.5, 0.75], [1., 1., 1.], [0.9, 0.1, 0.4]] {
+            let looked_up = identity.look_up(color);
+            assert!(looked_up.iter().zip(color).all(|(a, e)| (a - e).abs() < 1. / 255.), "{:?}", looked_up);
+        }
+        let inverted = ColorLut::from_fn(2, |color| color.map(|channel| 1. - channel));
+        assert_eq!(inverted.look_up([0.25, 0.5, 1.]), [0.75, 0.5, 0.]);
+        let uniforms = ColorGrade { lut: inverted.clone(), amount: 0.5 }.uniforms();
+        assert_eq!(uniforms.lut_size, 2);
+        assert_eq!(color_grade([0., 1., 0.25, 0.3], &inverted, &uniforms), [0.5, 0.5, 0.5, 0.3]);
+    }
+
+    #[test]
+    fn lookup_tables_are_stored_as_blue_slices_side_by_side() {
+        let lut = ColorLut::from_fn(2, |color| color);
+        let image = lut.to_image();
+        assert_eq!((image.width(), image.height()), (4, 2));
+        // Red across each slice, green down it, and blue from one slice to the next.
+        assert_eq!(image.texel(1, 0), [255, 0, 0, 255]);
+        assert_eq!(image.texel(0, 1), [0, 255, 0, 255]);
+        assert_eq!(image.texel(2, 0), [0, 0, 255, 255]);
+        assert_eq!(image.texel(3, 1), [255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn vignettes_darken_the_corners() {
+        let uniforms = Vignette::default().uniforms();
+        let color = [1., 1., 1., 1.];
+        assert_eq!(vignette(color, 50, 50, [100, 100], &uniforms), color);
+        // The corners are about 1.41 half-heights out, part way into the darkening.
+        let corner = vignette(color, 0, 0, [100, 100], &uniforms);
+        assert!(corner[0] < 0.8 && corner[0] > 1. - 0.6 && corner[3] == 1., "{:?}", corner);
+        // Wider views reach further out at the sides.
+        assert!(vignette(color, 0, 50, [200, 100], &uniforms)[0] < vignette(color, 0, 50, [100, 100], &uniforms)[0]);
+    }
+}
@@ -0,0 +1,394 @@
+//! Recording a run of frames to disk, for clips in the documentation
+//!
+//! A recording is a set number of consecutive frames, with the simulation moved on
+//! by exactly one frame's worth of fixed steps before each, however long they actually take to draw,
+//! so a clip plays back smoothly even if drawing it didn't.
+//!
+//! Frames go into a Y4M stream (uncompressed video, which ffmpeg and most players read)
+//! if the output path ends in `.y4m`, and otherwise into a directory of numbered PNGs.
+//! Y4M frames are 4:2:0 YCbCr, converted from sRGB with the BT.601 coefficients in limited range,
+//! as players assume when a stream doesn't say.
+//!
+//! The renderer records what it draws by reading back each drawable, as for a screenshot,
+//! and `headless` draws with the software rasterizer instead, without a window.
+
+use std::fmt::{Display, Formatter};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use crate::frame_pacing::FixedTimestep;
+use crate::image::Image;
+use crate::screenshot::encode_png;
+
+pub static DEFAULT_RECORDING_FRAME_COUNT: usize = 120;
+pub static DEFAULT_RECORDING_FRAMES_PER_SECOND: u32 = 60;
+
+/// What to record, and where to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordingSettings {
+    /// A `.y4m` file, or a directory for PNGs.
+    pub output: PathBuf,
+    pub frame_count: usize,
+    pub frames_per_second: u32,
+}
+
+impl RecordingSettings {
+    pub fn new(output: PathBuf) -> Self {
+        RecordingSettings {
+            output,
+            frame_count: DEFAULT_RECORDING_FRAME_COUNT,
+            frames_per_second: DEFAULT_RECORDING_FRAMES_PER_SECOND,
+        }
+    }
+
+    /// Whether the frames go into a Y4M stream or a PNG sequence, from the output path.
+    pub fn format(&self) -> RecordingFormat {
+        match self.output.extension() {
+            Some(extension) if extension.eq_ignore_ascii_case("y4m") => RecordingFormat::Y4m,
+            _ => RecordingFormat::PngSequence,
+        }
+    }
+
+    /// The settings from the environment, or `None` if we're not asked to record.
+    ///
+    /// Set HELLO_TRIANGLE_RECORD to a `.y4m` file or a directory to record into,
+    /// HELLO_TRIANGLE_RECORD_FRAMES to how many frames to record,
+    /// and HELLO_TRIANGLE_RECORD_FPS to the frame rate it plays back at.
+    pub fn from_environment() -> Option<Self> {
+        let output = std::env::var_os("HELLO_TRIANGLE_RECORD")?;
+        let mut settings = RecordingSettings::new(PathBuf::from(output));
+        if let Ok(frame_count) = std::env::var("HELLO_TRIANGLE_RECORD_FRAMES") {
+            match frame_count.parse() {
+                Ok(frame_count) => settings.frame_count = frame_count,
+                Err(_) => println!("HELLO_TRIANGLE_RECORD_FRAMES should be a number of frames, not {:?}", frame_count),
+            }
+        }
+        if let Ok(frames_per_second) = std::env::var("HELLO_TRIANGLE_RECORD_FPS") {
+            match frames_per_second.parse() {
+                Ok(frames_per_second) if frames_per_second > 0 => settings.frames_per_second = frames_per_second,
+                _ => println!("HELLO_TRIANGLE_RECORD_FPS should be a number of frames per second, not {:?}", frames_per_second),
+            }
+        }
+        Some(settings)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RecordingFormat {
+    Y4m,
+    PngSequence,
+}
+
+/// When each frame of a recording is: how many simulation steps go before it.
+///
+/// Frame `n` is drawn at `n / frames_per_second` seconds of simulation time.
+pub struct RecordingTimeline {
+    frames_per_second: f64,
+    frame_count: usize,
+    frames_started: usize,
+    timestep: FixedTimestep,
+}
+
+impl RecordingTimeline {
+    pub fn new(settings: &RecordingSettings, updates_per_second: f64) -> Self {
+        let mut timestep = FixedTimestep::new(updates_per_second);
+        // Every step has to run, however many there are between frames.
+        timestep.set_max_updates_per_frame(u32::MAX);
+        RecordingTimeline {
+            frames_per_second: f64::from(settings.frames_per_second),
+            frame_count: settings.frame_count,
+            frames_started: 0,
+            timestep,
+        }
+    }
+
+    /// The length of each simulation step, in seconds.
+    pub fn time_step(&self) -> f64 {
+        self.timestep.time_step()
+    }
+
+    /// How many simulation steps to run before drawing the next frame,
+    /// or `None` once every frame has been drawn.
+    pub fn next_frame(&mut self) -> Option<u32> {
+        if self.frames_started == self.frame_count {
+            return None;
+        }
+        let frame_time = self.frames_started as f64 / self.frames_per_second;
+        self.frames_started += 1;
+        Some(self.timestep.advance(frame_time))
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.frames_started == self.frame_count
+    }
+}
+
+/// Writes frames into a Y4M stream or a PNG sequence, as the settings say.
+pub struct RecordingWriter {
+    output: PathBuf,
+    format: RecordingFormat,
+    frames_per_second: u32,
+    /// Made when the first frame comes, as the header says how big they are.
+    y4m: Option<Y4mWriter<BufWriter<File>>>,
+    frames_written: usize,
+}
+
+impl RecordingWriter {
+    pub fn new(settings: &RecordingSettings) -> Self {
+        RecordingWriter {
+            output: settings.output.clone(),
+            format: settings.format(),
+            frames_per_second: settings.frames_per_second,
+            y4m: None,
+            frames_written: 0,
+        }
+    }
+
+    pub fn write_frame(&mut self, image: &Image) -> Result<(), RecordingError> {
+        match self.format {
+            RecordingFormat::Y4m => {
+                if self.y4m.is_none() {
+                    let file = File::create(&self.output).map_err(|e| RecordingError::Io(self.output.clone(), e))?;
+                    let y4m = Y4mWriter::new(BufWriter::new(file), image.width(), image.height(), self.frames_per_second)
+                        .map_err(|e| RecordingError::Io(self.output.clone(), e))?;
+                    self.y4m = Some(y4m);
+                }
+                let y4m = self.y4m.as_mut().unwrap();
+                let size = [image.width(), image.height()];
+                if size != y4m.size() {
+                    return Err(RecordingError::FrameSize { expected: y4m.size(), actual: size });
+                }
+                y4m.write_frame(image).map_err(|e| RecordingError::Io(self.output.clone(), e))?;
+            }
+            RecordingFormat::PngSequence => {
+                if self.frames_written == 0 {
+                    std::fs::create_dir_all(&self.output).map_err(|e| RecordingError::Io(self.output.clone(), e))?;
+                }
+                let path = self.output.join(png_sequence_file_name(self.frames_written));
+                let bytes = encode_png(image).map_err(RecordingError::Png)?;
+                std::fs::write(&path, bytes).map_err(|e| RecordingError::Io(path, e))?;
+            }
+        }
+        self.frames_written += 1;
+        Ok(())
+    }
+
+    /// Finishes writing, and returns how many frames were written.
+    pub fn finish(self) -> Result<usize, RecordingError> {
+        let output = self.output;
+        if let Some(y4m) = self.y4m {
+            y4m.finish().map_err(|e| RecordingError::Io(output, e))?;
+        }
+        Ok(self.frames_written)
+    }
+
+    pub fn output(&self) -> &Path {
+        &self.output
+    }
+}
+
+/// The name of a frame's file in a PNG sequence, numbered from zero.
+pub fn png_sequence_file_name(frame_index: usize) -> String {
+    format!("frame-{:05}.png", frame_index)
+}
+
+/// Writes frames of the same size into a Y4M stream.
+pub struct Y4mWriter<W: Write> {
+    writer: W,
+    width: usize,
+    height: usize,
+}
+
+impl<W: Write> Y4mWriter<W> {
+    /// Starts the stream by writing its header.
+    pub fn new(mut writer: W, width: usize, height: usize, frames_per_second: u32) -> std::io::Result<Self> {
+        // Progressive, square pixels, with chroma sited between the luma samples, as in JPEG.
+        writeln!(writer, "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C420jpeg", width, height, frames_per_second)?;
+        Ok(Y4mWriter { writer, width, height })
+    }
+
+    /// The width and height every frame has to be.
+    pub fn size(&self) -> [usize; 2] {
+        [self.width, self.height]
+    }
+
+    /// Writes a frame, which has to be `size()`.
+    pub fn write_frame(&mut self, image: &Image) -> std::io::Result<()> {
+        let [y, cb, cr] = ycbcr_420_planes(image);
+        self.writer.write_all(b"FRAME\n")?;
+        self.writer.write_all(&y)?;
+        self.writer.write_all(&cb)?;
+        self.writer.write_all(&cr)
+    }
+
+    /// Flushes the stream and hands back what it was written to.
+    pub fn finish(mut self) -> std::io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// An image's luma plane, then its two chroma planes at half the size in each direction (rounding up),
+/// each chroma sample the average of the two by two block it covers.
+pub fn ycbcr_420_planes(image: &Image) -> [Vec<u8>; 3] {
+    let (width, height) = (image.width(), image.height());
+    let ycbcr: Vec<[f32; 3]> = image.pixels().chunks_exact(4)
+        .map(|pixel| rgb_to_ycbcr([pixel[0], pixel[1], pixel[2]]))
+        .collect();
+    let luma = ycbcr.iter().map(|color| round_to_byte(color[0])).collect();
+    let (chroma_width, chroma_height) = (width.div_ceil(2), height.div_ceil(2));
+    let mut chroma = [Vec::with_capacity(chroma_width * chroma_height), Vec::with_capacity(chroma_width * chroma_height)];
+    for y in 0..chroma_height {
+        for x in 0..chroma_width {
+            // At an odd edge there's only one row or column to average.
+            let xs = [x * 2, (x * 2 + 1).min(width - 1)];
+            let ys = [y * 2, (y * 2 + 1).min(height - 1)];
+            for (channel, plane) in chroma.iter_mut().enumerate() {
+                let sum: f32 = ys.iter()
+                    .flat_map(|&sample_y| xs.iter().map(move |&sample_x| (sample_x, sample_y)))
+                    .map(|(sample_x, sample_y)| ycbcr[sample_y * width + sample_x][channel + 1])
+                    .sum();
+                plane.push(round_to_byte(sum / 4.));
+            }
+        }
+    }
+    let [cb, cr] = chroma;
+    [luma, cb, cr]
+}
+
+/// Converts an sRGB color to BT.601 limited-range Y, Cb and Cr, not yet rounded.
+/// Black is Y 16, white Y 235, and grays have Cb and Cr 128.
+pub fn rgb_to_ycbcr([red, green, blue]: [u8; 3]) -> [f32; 3] {
+    let (red, green, blue) = (f32::from(red) / 255., f32::from(green) / 255., f32::from(blue) / 255.);
+    [
+        16. + 65.481 * red + 128.553 * green + 24.966 * blue,
+        128. - 37.797 * red - 74.203 * green + 112. * blue,
+        128. + 112. * red - 93.786 * green - 18.214 * blue,
+    ]
+}
+
+fn round_to_byte(value: f32) -> u8 {
+    (value.clamp(0., 255.) + 0.5) as u8
+}
+
+#[derive(Debug)]
+pub enum RecordingError {
+    Io(PathBuf, std::io::Error),
+    Png(png::EncodingError),
+    /// Every frame of a Y4M stream has to be the size of the first.
+    FrameSize { expected: [usize; 2], actual: [usize; 2] },
+    /// A frame read back from the GPU was the wrong size for its pixels.
+    UnreadableFrame,
+}
+impl Display for RecordingError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(path, _) => write!(f, "Unable to write {}", path.display()),
+            Self::Png(_) => write!(f, "Unable to encode the PNG"),
+            Self::FrameSize { expected, actual } =>
+                write!(f, "The frame is {}x{}, but the recording is {}x{}", actual[0], actual[1], expected[0], expected[1]),
+            Self::UnreadableFrame => write!(f, "The frame read back is the wrong size"),
+        }
+    }
+}
+impl Error for RecordingError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(_, error) => Some(error),
+            Self::Png(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(width: usize, height: usize, colors: &[[u8; 3]]) -> Image {
+        Image::new(width, height, colors.iter().flat_map(|&[red, green, blue]| [red, green, blue, 255]).collect()).unwrap()
+    }
+
+    #[test]
+    fn colors_convert_to_limited_range_ycbcr() {
+        assert_eq!(rgb_to_ycbcr([0, 0, 0]), [16., 128., 128.]);
+        let [y, cb, cr] = rgb_to_ycbcr([255, 255, 255]);
+        assert_eq!([round_to_byte(y), round_to_byte(cb), round_to_byte(cr)], [235, 128, 128]);
+        let [y, cb, cr] = rgb_to_ycbcr([255, 0, 0]);
+        assert_eq!([round_to_byte(y), round_to_byte(cb), round_to_byte(cr)], [81, 90, 240]);
+    }
+
+    #[test]
+    fn chroma_is_averaged_over_two_by_two_blocks_rounding_up_at_odd_edges() {
+        let white = [255, 255, 255];
+        let red = [255, 0, 0];
+        // Three by three: the chroma planes are two by two, and the last row and column average fewer pixels.
+        let planes = ycbcr_420_planes(&image(3, 3, &[
+            white, white, red,
+            [0, 0, 0], [0, 0, 0], red,
+            red, white, red,
+        ]));
+        assert_eq!(planes[0].len(), 9);
+        assert_eq!(planes[0][..3], [235, 235, 81]);
+        assert_eq!(planes[1], [128, 90, 109, 90]);
+        assert_eq!(planes[2], [128, 240, 184, 240]);
+    }
+
+    #[test]
+    fn y4m_streams_have_a_header_then_planar_frames() {
+        let mut writer = Y4mWriter::new(Vec::new(), 3, 1, 30).unwrap();
+        assert_eq!(writer.size(), [3, 1]);
+        writer.write_frame(&image(3, 1, &[[0, 0, 0], [0, 0, 0], [255, 255, 255]])).unwrap();
+        writer.write_frame(&image(3, 1, &[[255, 255, 255]; 3])).unwrap();
+        let bytes = writer.finish().unwrap();
+        let header = b"YUV4MPEG2 W3 H1 F30:1 Ip A1:1 C420jpeg\n";
+        assert_eq!(&bytes[..header.len()], header);
+        let frames = &bytes[header.len()..];
+        // Three luma samples, then two of each chroma.
+        assert_eq!(frames.len(), 2 * (6 + 3 + 2 + 2));
+        assert_eq!(&frames[..13], b"FRAME\n\x10\x10\xeb\x80\x80\x80\x80");
+        assert_eq!(&frames[13..19], b"FRAME\n");
+    }
+
+    #[test]
+    fn the_output_path_picks_the_format() {
+        assert_eq!(RecordingSettings::new(PathBuf::from("clip.Y4M")).format(), RecordingFormat::Y4m);
+        assert_eq!(RecordingSettings::new(PathBuf::from("frames")).format(), RecordingFormat::PngSequence);
+    }
+
+    #[test]
+    fn each_frame_follows_a_frames_worth_of_steps() {
+        let settings = RecordingSettings { frame_count: 3, frames_per_second: 30, ..RecordingSettings::new(PathBuf::from("clip.y4m")) };
+        let mut timeline = RecordingTimeline::new(&settings, 120.);
+        assert_eq!(timeline.time_step(), 1. / 120.);
+        assert_eq!([timeline.next_frame(), timeline.next_frame(), timeline.next_frame()], [Some(0), Some(4), Some(4)]);
+        assert!(timeline.is_finished());
+        assert_eq!(timeline.next_frame(), None);
+    }
+
+    #[test]
+    fn frames_are_written_to_files() {
+        let directory = std::env::temp_dir().join(format!("hello_triangle_recording_{}", std::process::id()));
+        let frame = image(2, 2, &[[255, 0, 0]; 4]);
+
+        let y4m_settings = RecordingSettings::new(directory.join("clip.y4m"));
+        std::fs::create_dir_all(&directory).unwrap();
+        let mut writer = RecordingWriter::new(&y4m_settings);
+        writer.write_frame(&frame).unwrap();
+        assert!(matches!(writer.write_frame(&image(1, 1, &[[0, 0, 0]])),
+                         Err(RecordingError::FrameSize { expected: [2, 2], actual: [1, 1] })));
+        assert_eq!(writer.finish().unwrap(), 1);
+        let header_length = "YUV4MPEG2 W2 H2 F60:1 Ip A1:1 C420jpeg\n".len();
+        assert_eq!(std::fs::metadata(directory.join("clip.y4m")).unwrap().len() as usize, header_length + 6 + 4 + 1 + 1);
+
+        let png_settings = RecordingSettings::new(directory.join("frames"));
+        let mut writer = RecordingWriter::new(&png_settings);
+        writer.write_frame(&frame).unwrap();
+        writer.write_frame(&frame).unwrap();
+        assert_eq!(writer.finish().unwrap(), 2);
+        let written = crate::image::decode_png(&std::fs::read(directory.join("frames").join("frame-00001.png")).unwrap());
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(written.unwrap(), frame);
+    }
+}
@@ -0,0 +1,646 @@
+//! A render graph: passes that say which textures they read and write
+//!
+//! A frame is described as a list of passes. Each pass draws into color attachments
+//! (and maybe a depth stencil attachment), and can sample textures that other passes drew.
+//! Textures are either imported, like the drawable, which the graph doesn't own,
+//! or transient: only needed while the frame is drawn.
+//!
+//! Compiling the graph works out what's fiddly to get right by hand:
+//!
+//! - the order the passes run in: each texture's writers run in the order they were added,
+//!   and all of them run before any pass that samples it;
+//! - which passes can be left out, because nothing they draw is used;
+//! - when each texture is first and last used, so transient textures whose lifetimes don't overlap
+//!   can share (alias) one Metal texture;
+//! - each attachment's load and store actions: only loading what an earlier pass drew,
+//!   and only storing what a later pass uses or what's kept after the frame.
+//!
+//! Since a texture's readers run after all its writers, don't draw over a texture once it's been sampled;
+//! draw into another transient texture instead, which costs nothing if the two can share.
+
+use std::collections::HashSet;
+use std::fmt::Formatter;
+use std::error::Error;
+use crate::pixel_format::MTLPixelFormat;
+
+/// A texture in a render graph.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TextureId(pub usize);
+
+/// A pass in a render graph.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PassId(pub usize);
+
+/// Everything that goes into making a texture to draw into.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct TextureDesc {
+    pub width: usize,
+    pub height: usize,
+    pub pixel_format: MTLPixelFormat,
+    pub sample_count: usize,
+}
+
+/// What an attachment is cleared to at the start of a pass.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ClearValue {
+    Color([f64; 4]),
+    DepthStencil { depth: f64, stencil: u32 },
+}
+
+/// A texture a pass draws into.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Attachment {
+    pub texture: TextureId,
+    /// `None` to draw over what earlier passes drew.
+    pub clear: Option<ClearValue>,
+    /// A single-sample texture to average a multisample attachment's samples into at the end of the pass.
+    pub resolve_texture: Option<TextureId>,
+}
+
+impl Attachment {
+    /// An attachment cleared at the start of the pass.
+    pub fn clear(texture: TextureId, clear_value: ClearValue) -> Self {
+        Attachment { texture, clear: Some(clear_value), resolve_texture: None }
+    }
+
+    /// An attachment drawn over what's already there.
+    pub fn load(texture: TextureId) -> Self {
+        Attachment { texture, clear: None, resolve_texture: None }
+    }
+}
+
+/// What a pass reads and writes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PassDesc {
+    /// Shown in Xcode's debugger.
+    pub name: String,
+    pub color_attachments: Vec<Attachment>,
+    pub depth_stencil_attachment: Option<Attachment>,
+    /// The textures the pass samples.
+    pub reads: Vec<TextureId>,
+    /// Whether the pass does something outside the graph, so it's never left out.
+    pub has_side_effects: bool,
+}
+
+impl PassDesc {
+    /// A pass that doesn't read or write anything yet.
+    pub fn new(name: &str) -> Self {
+        PassDesc {
+            name: name.to_string(),
+            color_attachments: Vec::new(),
+            depth_stencil_attachment: None,
+            reads: Vec::new(),
+            has_side_effects: false,
+        }
+    }
+
+    /// Every attachment, color ones first.
+    fn attachments(&self) -> impl Iterator<Item = &Attachment> {
+        self.color_attachments.iter().chain(&self.depth_stencil_attachment)
+    }
+
+    /// Every texture the pass draws into, including resolve textures.
+    fn writes(&self) -> impl Iterator<Item = TextureId> + '_ {
+        self.attachments().flat_map(|attachment| std::iter::once(attachment.texture).chain(attachment.resolve_texture))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct TextureEntry {
+    name: String,
+    desc: TextureDesc,
+    imported: bool,
+}
+
+/// Why a render graph can't be compiled.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RenderGraphError {
+    /// A pass uses a texture that isn't in the graph.
+    UnknownTexture { pass: String },
+    /// A pass samples a texture it also draws into.
+    ReadsOwnAttachment { pass: String, texture: String },
+    /// A pass's attachments aren't all the same size.
+    MismatchedSizes { pass: String },
+    /// A resolve texture isn't single-sample, or the attachment it resolves isn't multisample.
+    InvalidResolve { pass: String, texture: String },
+    /// The passes each need another to run first.
+    Cycle { passes: Vec<String> },
+}
+impl std::fmt::Display for RenderGraphError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownTexture { pass } => write!(f, "The {} pass uses a texture that isn't in the render graph", pass),
+            Self::ReadsOwnAttachment { pass, texture } => write!(f, "The {} pass samples {}, which it also draws into", pass, texture),
+            Self::MismatchedSizes { pass } => write!(f, "The {} pass's attachments aren't all the same size", pass),
+            Self::InvalidResolve { pass, texture } => write!(f, "The {} pass can't resolve into {}", pass, texture),
+            Self::Cycle { passes } => write!(f, "The {} passes each need another to run first", passes.join(", ")),
+        }
+    }
+}
+impl Error for RenderGraphError {}
+
+/// The passes of a frame, and the textures they use.
+#[derive(Debug, Clone, Default)]
+pub struct RenderGraph {
+    textures: Vec<TextureEntry>,
+    passes: Vec<PassDesc>,
+    /// Textures whose contents are needed after the frame.
+    kept: Vec<TextureId>,
+}
+
+/// How an attachment's contents are set up at the start of a pass.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LoadAction {
+    DontCare,
+    Load,
+    Clear,
+}
+
+/// What's done with an attachment's contents at the end of a pass.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StoreAction {
+    DontCare,
+    Store,
+    MultisampleResolve,
+    StoreAndMultisampleResolve,
+}
+
+/// An attachment, with the load and store actions its pass needs.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CompiledAttachment {
+    pub texture: TextureId,
+    pub resolve_texture: Option<TextureId>,
+    pub load_action: LoadAction,
+    /// Only used when the load action is `Clear`.
+    pub clear_value: Option<ClearValue>,
+    pub store_action: StoreAction,
+}
+
+/// A pass that's going to run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompiledPass {
+    pub id: PassId,
+    pub name: String,
+    pub color_attachments: Vec<CompiledAttachment>,
+    pub depth_stencil_attachment: Option<CompiledAttachment>,
+    pub reads: Vec<TextureId>,
+}
+
+/// When a texture is used: the first and last of the compiled passes that use it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Lifetime {
+    pub first_pass: usize,
+    pub last_pass: usize,
+}
+
+/// A render graph, ready to run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompiledGraph {
+    /// The passes to run, in order.
+    pub passes: Vec<CompiledPass>,
+    /// The passes left out, since nothing they draw is used.
+    pub culled_passes: Vec<PassId>,
+    /// For each of the graph's textures, when it's used, if it's used at all.
+    pub lifetimes: Vec<Option<Lifetime>>,
+    /// The Metal textures to make for the transient textures, which can each share one.
+    pub transient_textures: Vec<TextureDesc>,
+    /// For each of the graph's textures, which of `transient_textures` it uses.
+    /// `None` for imported textures and ones that aren't used.
+    pub transient_texture_indices: Vec<Option<usize>>,
+}
+
+impl RenderGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a texture the graph doesn't own, such as the drawable.
+    /// Its contents before the frame count as drawn.
+    pub fn import_texture(&mut self, name: &str, desc: TextureDesc) -> TextureId {
+        self.add_texture(name, desc, true)
+    }
+
+    /// Adds a texture that's only needed while the frame is drawn.
+    pub fn create_texture(&mut self, name: &str, desc: TextureDesc) -> TextureId {
+        self.add_texture(name, desc, false)
+    }
+
+    fn add_texture(&mut self, name: &str, desc: TextureDesc, imported: bool) -> TextureId {
+        self.textures.push(TextureEntry { name: name.to_string(), desc, imported });
+        TextureId(self.textures.len() - 1)
+    }
+
+    pub fn add_pass(&mut self, pass: PassDesc) -> PassId {
+        self.passes.push(pass);
+        PassId(self.passes.len() - 1)
+    }
+
+    /// Keeps a texture's contents after the frame, like the drawable's, to be presented.
+    pub fn keep(&mut self, texture: TextureId) {
+        self.kept.push(texture);
+    }
+
+    pub fn texture_desc(&self, texture: TextureId) -> &TextureDesc {
+        &self.textures[texture.0].desc
+    }
+
+    pub fn texture_name(&self, texture: TextureId) -> &str {
+        &self.textures[texture.0].name
+    }
+
+    pub fn is_imported(&self, texture: TextureId) -> bool {
+        self.textures[texture.0].imported
+    }
+
+    pub fn pass(&self, pass: PassId) -> &PassDesc {
+        &self.passes[pass.0]
+    }
+
+    /// Works out the order to run the passes in, which to leave out, and how to set up their attachments.
+    pub fn compile(&self) -> Result<CompiledGraph, RenderGraphError> {
+        self.validate()?;
+        let order = self.execution_order()?;
+        let live = self.live_passes();
+        let order: Vec<PassId> = order.into_iter().filter(|pass| live.contains(pass)).collect();
+        let culled_passes = (0..self.passes.len()).map(PassId).filter(|pass| !live.contains(pass)).collect();
+
+        let lifetimes = self.lifetimes(&order);
+        let (transient_textures, transient_texture_indices) = self.share_transient_textures(&lifetimes);
+        let passes = order.iter().enumerate()
+            .map(|(position, &id)| self.compile_pass(id, position, &order))
+            .collect();
+        Ok(CompiledGraph { passes, culled_passes, lifetimes, transient_textures, transient_texture_indices })
+    }
+
+    fn validate(&self) -> Result<(), RenderGraphError> {
+        for pass in &self.passes {
+            let name = || pass.name.clone();
+            let texture_name = |texture: TextureId| self.texture_name(texture).to_string();
+            if pass.writes().chain(pass.reads.iter().copied()).any(|texture| texture.0 >= self.textures.len()) {
+                return Err(RenderGraphError::UnknownTexture { pass: name() });
+            }
+            if let Some(&texture) = pass.reads.iter().find(|&&read| pass.writes().any(|write| write == read)) {
+                return Err(RenderGraphError::ReadsOwnAttachment { pass: name(), texture: texture_name(texture) });
+            }
+            let mut sizes = pass.writes().map(|texture| {
+                let desc = self.texture_desc(texture);
+                (desc.width, desc.height)
+            });
+            if let Some(size) = sizes.next() {
+                if sizes.any(|other| other != size) {
+                    return Err(RenderGraphError::MismatchedSizes { pass: name() });
+                }
+            }
+            for attachment in pass.attachments() {
+                if let Some(resolve_texture) = attachment.resolve_texture {
+                    if self.texture_desc(attachment.texture).sample_count <= 1 || self.texture_desc(resolve_texture).sample_count != 1 {
+                        return Err(RenderGraphError::InvalidResolve { pass: name(), texture: texture_name(resolve_texture) });
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The passes that draw into a texture, in the order they were added.
+    fn writers(&self, texture: TextureId) -> Vec<PassId> {
+        (0..self.passes.len()).map(PassId)
+            .filter(|&pass| self.pass(pass).writes().any(|write| write == texture))
+            .collect()
+    }
+
+    /// All the passes, each after the ones it depends on,
+    /// and otherwise in the order they were added.
+    fn execution_order(&self) -> Result<Vec<PassId>, RenderGraphError> {
+        let pass_count = self.passes.len();
+        let mut dependents = vec![Vec::new(); pass_count];
+        let mut dependency_counts = vec![0; pass_count];
+        let mut add_dependency = |before: PassId, after: PassId| {
+            dependents[before.0].push(after);
+            dependency_counts[after.0] += 1;
+        };
+        for texture in (0..self.textures.len()).map(TextureId) {
+            let writers = self.writers(texture);
+            for pair in writers.windows(2) {
+                add_dependency(pair[0], pair[1]);
+            }
+            for reader in (0..pass_count).map(PassId).filter(|&pass| self.pass(pass).reads.contains(&texture)) {
+                for &writer in &writers {
+                    add_dependency(writer, reader);
+                }
+            }
+        }
+
+        let mut order = Vec::with_capacity(pass_count);
+        let mut ready: Vec<PassId> = (0..pass_count).map(PassId).filter(|pass| dependency_counts[pass.0] == 0).collect();
+        while !ready.is_empty() {
+            // The earliest added of the passes that can run.
+            ready.sort_by(|a, b| b.cmp(a));
+            let pass = ready.pop().unwrap();
+            order.push(pass);
+            for &dependent in &dependents[pass.0] {
+                dependency_counts[dependent.0] -= 1;
+                if dependency_counts[dependent.0] == 0 {
+                    ready.push(dependent);
+                }
+            }
+        }
+        if order.len() < pass_count {
+            let passes = (0..pass_count)
+                .filter(|&pass| dependency_counts[pass] > 0)
+                .map(|pass| self.passes[pass].name.clone())
+                .collect();
+            return Err(RenderGraphError::Cycle { passes });
+        }
+        Ok(order)
+    }
+
+    /// The passes whose drawing is used: by being kept, or by a pass that's used.
+    fn live_passes(&self) -> HashSet<PassId> {
+        let mut live = HashSet::new();
+        let mut to_visit: Vec<PassId> = (0..self.passes.len()).map(PassId)
+            .filter(|&pass| self.pass(pass).has_side_effects)
+            .collect();
+        for &texture in &self.kept {
+            to_visit.extend(self.writers_seen_by(texture, None));
+        }
+        while let Some(pass) = to_visit.pop() {
+            if !live.insert(pass) {
+                continue;
+            }
+            let desc = self.pass(pass);
+            for &texture in &desc.reads {
+                to_visit.extend(self.writers_seen_by(texture, None));
+            }
+            for attachment in desc.attachments().filter(|attachment| attachment.clear.is_none()) {
+                to_visit.extend(self.writers_seen_by(attachment.texture, Some(pass)));
+            }
+        }
+        live
+    }
+
+    /// The passes whose drawing into a texture is still there just before the given writer draws into it
+    /// (or after all of them): the ones since the last that cleared or resolved into it.
+    fn writers_seen_by(&self, texture: TextureId, before_writer: Option<PassId>) -> Vec<PassId> {
+        let writers = self.writers(texture);
+        let end = before_writer.map_or(writers.len(), |writer| writers.iter().position(|&other| other == writer).unwrap());
+        let mut seen = Vec::new();
+        for &writer in writers[..end].iter().rev() {
+            seen.push(writer);
+            let overwrites = self.pass(writer).attachments().any(|attachment| {
+                (attachment.texture == texture && attachment.clear.is_some()) || attachment.resolve_texture == Some(texture)
+            });
+            if overwrites {
+                break;
+            }
+        }
+        seen
+    }
+
+    fn lifetimes(&self, order: &[PassId]) -> Vec<Option<Lifetime>> {
+        let mut lifetimes: Vec<Option<Lifetime>> = vec![None; self.textures.len()];
+        for (position, &pass) in order.iter().enumerate() {
+            let desc = self.pass(pass);
+            for texture in desc.writes().chain(desc.reads.iter().copied()) {
+                let lifetime = lifetimes[texture.0].get_or_insert(Lifetime { first_pass: position, last_pass: position });
+                lifetime.last_pass = position;
+            }
+        }
+        lifetimes
+    }
+
+    /// Gives each used transient texture a Metal texture,
+    /// sharing one between textures with the same description whose lifetimes don't overlap.
+    fn share_transient_textures(&self, lifetimes: &[Option<Lifetime>]) -> (Vec<TextureDesc>, Vec<Option<usize>>) {
+        let mut transient: Vec<(TextureId, Lifetime)> = lifetimes.iter().enumerate()
+            .filter(|&(texture, _)| !self.textures[texture].imported)
+            .filter_map(|(texture, lifetime)| lifetime.map(|lifetime| (TextureId(texture), lifetime)))
+            .collect();
+        transient.sort_by_key(|&(texture, lifetime)| (lifetime.first_pass, texture));
+
+        // Each Metal texture, and the last pass that uses it so far.
+        let mut shared: Vec<(TextureDesc, usize)> = Vec::new();
+        let mut indices = vec![None; self.textures.len()];
+        for (texture, lifetime) in transient {
+            let desc = *self.texture_desc(texture);
+            let free = shared.iter().position(|&(other_desc, last_pass)| other_desc == desc && last_pass < lifetime.first_pass);
+            let index = match free {
+                Some(index) => {
+                    shared[index].1 = lifetime.last_pass;
+                    index
+                }
+                None => {
+                    shared.push((desc, lifetime.last_pass));
+                    shared.len() - 1
+                }
+            };
+            indices[texture.0] = Some(index);
+        }
+        (shared.into_iter().map(|(desc, _)| desc).collect(), indices)
+    }
+
+    fn compile_pass(&self, id: PassId, position: usize, order: &[PassId]) -> CompiledPass {
+        let desc = self.pass(id);
+        let compile_attachment = |attachment: &Attachment| self.compile_attachment(attachment, position, order);
+        CompiledPass {
+            id,
+            name: desc.name.clone(),
+            color_attachments: desc.color_attachments.iter().map(compile_attachment).collect(),
+            depth_stencil_attachment: desc.depth_stencil_attachment.as_ref().map(compile_attachment),
+            reads: desc.reads.clone(),
+        }
+    }
+
+    fn compile_attachment(&self, attachment: &Attachment, position: usize, order: &[PassId]) -> CompiledAttachment {
+        let texture = attachment.texture;
+        let drawn_before = self.is_imported(texture)
+            || order[..position].iter().any(|&earlier| self.pass(earlier).writes().any(|write| write == texture));
+        let load_action = match attachment.clear {
+            Some(_) => LoadAction::Clear,
+            None if drawn_before => LoadAction::Load,
+            None => LoadAction::DontCare,
+        };
+        let used_later = self.kept.contains(&texture) || order[position + 1..].iter().any(|&later| {
+            let desc = self.pass(later);
+            desc.reads.contains(&texture)
+                || desc.attachments().any(|other| other.texture == texture && other.clear.is_none())
+        });
+        let store_action = match (used_later, attachment.resolve_texture.is_some()) {
+            (false, false) => StoreAction::DontCare,
+            (true, false) => StoreAction::Store,
+            (false, true) => StoreAction::MultisampleResolve,
+            (true, true) => StoreAction::StoreAndMultisampleResolve,
+        };
+        CompiledAttachment {
+            texture,
+            resolve_texture: attachment.resolve_texture,
+            load_action,
+            clear_value: attachment.clear,
+            store_action,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pixel_format::{MTLPixelFormatBGRA8Unorm, MTLPixelFormatRGBA16Float};
+
+    static BLACK: ClearValue = ClearValue::Color([0., 0., 0., 1.]);
+
+    fn texture_desc(sample_count: usize) -> TextureDesc {
+        TextureDesc { width: 64, height: 32, pixel_format: MTLPixelFormatBGRA8Unorm, sample_count }
+    }
+
+    /// A pass that clears and draws into `target`, sampling `reads`.
+    fn draw_pass(name: &str, target: TextureId, reads: &[TextureId]) -> PassDesc {
+        PassDesc {
+            color_attachments: vec![Attachment::clear(target, BLACK)],
+            reads: reads.to_vec(),
+            ..PassDesc::new(name)
+        }
+    }
+
+    fn pass_names(compiled: &CompiledGraph) -> Vec<&str> {
+        compiled.passes.iter().map(|pass| pass.name.as_str()).collect()
+    }
+
+    fn actions(attachment: &CompiledAttachment) -> (LoadAction, StoreAction) {
+        (attachment.load_action, attachment.store_action)
+    }
+
+    #[test]
+    fn writers_run_before_readers_and_otherwise_in_the_order_added() {
+        let mut graph = RenderGraph::new();
+        let drawable = graph.import_texture("Drawable", texture_desc(1));
+        let scene = graph.create_texture("Scene", texture_desc(1));
+        let shadow = graph.create_texture("Shadow", texture_desc(1));
+        // Added before the passes it samples.
+        graph.add_pass(draw_pass("Composite", drawable, &[scene, shadow]));
+        graph.add_pass(draw_pass("Scene", scene, &[]));
+        graph.add_pass(draw_pass("Shadow", shadow, &[]));
+        graph.add_pass(PassDesc { color_attachments: vec![Attachment::load(scene)], ..PassDesc::new("Scene Overlay") });
+        graph.keep(drawable);
+        let compiled = graph.compile().unwrap();
+        assert_eq!(pass_names(&compiled), ["Scene", "Shadow", "Scene Overlay", "Composite"]);
+        assert!(compiled.culled_passes.is_empty());
+    }
+
+    #[test]
+    fn passes_that_need_each_other_are_a_cycle() {
+        let mut graph = RenderGraph::new();
+        let drawable = graph.import_texture("Drawable", texture_desc(1));
+        let a = graph.create_texture("A", texture_desc(1));
+        let b = graph.create_texture("B", texture_desc(1));
+        graph.add_pass(draw_pass("Independent", drawable, &[]));
+        graph.add_pass(draw_pass("Draw A", a, &[b]));
+        graph.add_pass(draw_pass("Draw B", b, &[a]));
+        graph.keep(drawable);
+        assert_eq!(graph.compile(), Err(RenderGraphError::Cycle { passes: vec!["Draw A".to_string(), "Draw B".to_string()] }));
+    }
+
+    #[test]
+    fn passes_whose_drawing_is_never_used_are_culled() {
+        let mut graph = RenderGraph::new();
+        let drawable = graph.import_texture("Drawable", texture_desc(1));
+        let unused = graph.create_texture("Unused", texture_desc(1));
+        let overdrawn = graph.create_texture("Overdrawn", texture_desc(1));
+        let unread = graph.add_pass(draw_pass("Unread", unused, &[]));
+        // Cleared over before anything samples it.
+        let cleared_over = graph.add_pass(draw_pass("Cleared Over", overdrawn, &[]));
+        graph.add_pass(draw_pass("Overdraw", overdrawn, &[]));
+        graph.add_pass(draw_pass("Present", drawable, &[overdrawn]));
+        let side_effects = graph.add_pass(PassDesc { has_side_effects: true, ..draw_pass("Capture", unused, &[]) });
+        graph.keep(drawable);
+        let compiled = graph.compile().unwrap();
+        assert_eq!(pass_names(&compiled), ["Overdraw", "Present", "Capture"]);
+        assert_eq!(compiled.culled_passes, [unread, cleared_over]);
+        assert!(!compiled.culled_passes.contains(&side_effects));
+        assert_eq!(compiled.lifetimes[overdrawn.0], Some(Lifetime { first_pass: 0, last_pass: 1 }));
+    }
+
+    #[test]
+    fn equal_transient_textures_share_when_their_lifetimes_dont_overlap() {
+        let mut graph = RenderGraph::new();
+        let drawable = graph.import_texture("Drawable", texture_desc(1));
+        let first = graph.create_texture("First", texture_desc(1));
+        let second = graph.create_texture("Second", texture_desc(1));
+        let third = graph.create_texture("Third", texture_desc(1));
+        let half_float = graph.create_texture("Half Float", TextureDesc { pixel_format: MTLPixelFormatRGBA16Float, ..texture_desc(1) });
+        graph.add_pass(draw_pass("First", first, &[]));
+        // Second is drawn while First is still being read, so they overlap.
+        graph.add_pass(draw_pass("Second", second, &[first]));
+        // Third only starts once First is done with.
+        graph.add_pass(draw_pass("Third", third, &[second]));
+        graph.add_pass(draw_pass("Half Float", half_float, &[third]));
+        graph.add_pass(draw_pass("Present", drawable, &[half_float]));
+        graph.keep(drawable);
+        let compiled = graph.compile().unwrap();
+        assert_eq!(compiled.lifetimes[first.0], Some(Lifetime { first_pass: 0, last_pass: 1 }));
+        assert_eq!(compiled.lifetimes[second.0], Some(Lifetime { first_pass: 1, last_pass: 2 }));
+        assert_eq!(compiled.lifetimes[third.0], Some(Lifetime { first_pass: 2, last_pass: 3 }));
+        assert_eq!(compiled.transient_texture_indices, [None, Some(0), Some(1), Some(0), Some(2)]);
+        assert_eq!(compiled.transient_textures, [
+            texture_desc(1),
+            texture_desc(1),
+            TextureDesc { pixel_format: MTLPixelFormatRGBA16Float, ..texture_desc(1) },
+        ]);
+    }
+
+    #[test]
+    fn attachments_only_load_and_store_what_is_used() {
+        let mut graph = RenderGraph::new();
+        let drawable = graph.import_texture("Drawable", texture_desc(1));
+        let multisample = graph.create_texture("Multisample", texture_desc(4));
+        let resolved = graph.create_texture("Resolved", texture_desc(1));
+        let depth = graph.create_texture("Depth", texture_desc(4));
+        graph.add_pass(PassDesc {
+            color_attachments: vec![Attachment { resolve_texture: Some(resolved), ..Attachment::clear(multisample, BLACK) }],
+            depth_stencil_attachment: Some(Attachment::clear(depth, ClearValue::DepthStencil { depth: 1., stencil: 0 })),
+            ..PassDesc::new("Scene")
+        });
+        // Draws over the scene's samples, without clearing them, and resolves them again.
+        graph.add_pass(PassDesc {
+            color_attachments: vec![Attachment { resolve_texture: Some(resolved), ..Attachment::load(multisample) }],
+            depth_stencil_attachment: Some(Attachment::load(depth)),
+            ..PassDesc::new("Overlay")
+        });
+        graph.add_pass(PassDesc { color_attachments: vec![Attachment::load(drawable)], reads: vec![resolved], ..PassDesc::new("Present") });
+        let scratch = graph.create_texture("Scratch", texture_desc(1));
+        graph.add_pass(PassDesc { color_attachments: vec![Attachment::load(scratch)], has_side_effects: true, ..PassDesc::new("Scratch") });
+        graph.keep(drawable);
+        let compiled = graph.compile().unwrap();
+        assert_eq!(pass_names(&compiled), ["Scene", "Overlay", "Present", "Scratch"]);
+
+        let scene = &compiled.passes[0];
+        assert_eq!(actions(&scene.color_attachments[0]), (LoadAction::Clear, StoreAction::StoreAndMultisampleResolve));
+        assert_eq!(scene.color_attachments[0].clear_value, Some(BLACK));
+        assert_eq!(actions(scene.depth_stencil_attachment.as_ref().unwrap()), (LoadAction::Clear, StoreAction::Store));
+        let overlay = &compiled.passes[1];
+        assert_eq!(actions(&overlay.color_attachments[0]), (LoadAction::Load, StoreAction::MultisampleResolve));
+        assert_eq!(actions(overlay.depth_stencil_attachment.as_ref().unwrap()), (LoadAction::Load, StoreAction::DontCare));
+        // Imported textures' contents count as drawn, and kept ones are stored.
+        assert_eq!(actions(&compiled.passes[2].color_attachments[0]), (LoadAction::Load, StoreAction::Store));
+        // Nothing's drawn into the scratch texture before, or used after.
+        assert_eq!(actions(&compiled.passes[3].color_attachments[0]), (LoadAction::DontCare, StoreAction::DontCare));
+    }
+
+    #[test]
+    fn resolves_need_a_multisample_attachment_and_a_single_sample_texture() {
+        let resolve = |attachment_samples: usize, resolve_samples: usize| {
+            let mut graph = RenderGraph::new();
+            let multisample = graph.create_texture("Multisample", texture_desc(attachment_samples));
+            let resolved = graph.create_texture("Resolved", texture_desc(resolve_samples));
+            graph.add_pass(PassDesc {
+                color_attachments: vec![Attachment { resolve_texture: Some(resolved), ..Attachment::clear(multisample, BLACK) }],
+                ..PassDesc::new("Scene")
+            });
+            graph.keep(resolved);
+            graph.compile().map(|_| ())
+        };
+        assert_eq!(resolve(4, 1), Ok(()));
+        let invalid = Err(RenderGraphError::InvalidResolve { pass: "Scene".to_string(), texture: "Resolved".to_string() });
+        assert_eq!(resolve(1, 1), invalid);
+        assert_eq!(resolve(4, 4), invalid);
+    }
+}
@@ -0,0 +1,218 @@
+//! The Metal textures and render pass descriptors for a compiled render graph
+//!
+//! Transient textures come from a pool that keeps them from one frame to the next,
+//! so a graph that's the same every frame doesn't make any new ones.
+//! Metal tracks which command buffers use them, so reusing one while the GPU is still
+//! drawing an earlier frame into it just waits.
+#![allow(non_upper_case_globals)]
+
+use objc::msg_send;
+use objc::sel;
+use objc::sel_impl;
+use objc::class;
+use cocoa::base::{id, nil, NO};
+use cocoa::foundation::{NSString, NSUInteger};
+use objc::runtime::objc_release;
+use crate::metal_view::MTLClearColorMake;
+use crate::pixel_format::{pixel_format_has_depth, pixel_format_has_stencil};
+use crate::render_graph::{RenderGraph, CompiledGraph, CompiledPass, CompiledAttachment, TextureId, TextureDesc, ClearValue, LoadAction, StoreAction};
+
+// From System/Library/Frameworks/Metal.framework/Versions/A/Headers/MTLTexture.h
+// typedef NS_OPTIONS(NSUInteger, MTLTextureUsage) {
+//     MTLTextureUsageShaderRead = 0x0001,
+//     MTLTextureUsageRenderTarget = 0x0004,
+//     ...
+// } API_AVAILABLE(macos(10.11), ios(9.0));
+static MTLTextureUsageShaderRead: NSUInteger = 0x0001;
+static MTLTextureUsageRenderTarget: NSUInteger = 0x0004;
+
+// From MTLResource.h:
+// typedef NS_ENUM(NSUInteger, MTLStorageMode) {
+//     MTLStorageModePrivate = 2,
+//     ...
+// } API_AVAILABLE(macos(10.11), ios(9.0));
+static MTLStorageModePrivate: NSUInteger = 2;
+
+// From MTLTexture.h:
+// typedef NS_ENUM(NSUInteger, MTLTextureType) {
+//     MTLTextureType2DMultisample = 4,
+//     ...
+// } API_AVAILABLE(macos(10.11), ios(8.0));
+static MTLTextureType2DMultisample: NSUInteger = 4;
+
+// From MTLRenderPass.h:
+// typedef NS_ENUM(NSUInteger, MTLLoadAction) {
+//     MTLLoadActionDontCare = 0,
+//     MTLLoadActionLoad = 1,
+//     MTLLoadActionClear = 2,
+// } API_AVAILABLE(macos(10.11), ios(8.0));
+static MTLLoadActionDontCare: NSUInteger = 0;
+static MTLLoadActionLoad: NSUInteger = 1;
+static MTLLoadActionClear: NSUInteger = 2;
+
+// typedef NS_ENUM(NSUInteger, MTLStoreAction) {
+//     MTLStoreActionDontCare = 0,
+//     MTLStoreActionStore = 1,
+//     MTLStoreActionMultisampleResolve = 2,
+//     MTLStoreActionStoreAndMultisampleResolve API_AVAILABLE(macos(10.12), ios(10.0)) = 3,
+//     ...
+// } API_AVAILABLE(macos(10.11), ios(8.0));
+static MTLStoreActionDontCare: NSUInteger = 0;
+static MTLStoreActionStore: NSUInteger = 1;
+static MTLStoreActionMultisampleResolve: NSUInteger = 2;
+static MTLStoreActionStoreAndMultisampleResolve: NSUInteger = 3;
+
+fn mtl_load_action(load_action: LoadAction) -> NSUInteger {
+    match load_action {
+        LoadAction::DontCare => MTLLoadActionDontCare,
+        LoadAction::Load => MTLLoadActionLoad,
+        LoadAction::Clear => MTLLoadActionClear,
+    }
+}
+
+fn mtl_store_action(store_action: StoreAction) -> NSUInteger {
+    match store_action {
+        StoreAction::DontCare => MTLStoreActionDontCare,
+        StoreAction::Store => MTLStoreActionStore,
+        StoreAction::MultisampleResolve => MTLStoreActionMultisampleResolve,
+        StoreAction::StoreAndMultisampleResolve => MTLStoreActionStoreAndMultisampleResolve,
+    }
+}
+
+/// Textures for render graphs' transient textures, kept for as long as each frame needs them.
+pub struct TransientTexturePool {
+    device: id,
+    textures: Vec<(TextureDesc, id)>,
+}
+
+impl TransientTexturePool {
+    pub fn new(device: id) -> Self {
+        TransientTexturePool { device, textures: Vec::new() }
+    }
+
+    /// A texture for each of a compiled graph's transient textures,
+    /// reusing the last frame's where the descriptions match, and letting go of the rest.
+    pub fn textures_for(&mut self, graph: &CompiledGraph) -> Vec<id> {
+        let mut unused = std::mem::take(&mut self.textures);
+        let textures: Vec<id> = graph.transient_textures.iter()
+            .map(|desc| match unused.iter().position(|(other, _)| other == desc) {
+                Some(index) => unused.swap_remove(index).1,
+                None => new_transient_texture(self.device, desc),
+            })
+            .collect();
+        for (_, texture) in unused {
+            unsafe { objc_release(texture) };
+        }
+        self.textures = graph.transient_textures.iter().copied().zip(textures.iter().copied()).collect();
+        textures
+    }
+}
+
+impl Drop for TransientTexturePool {
+    fn drop(&mut self) {
+        for &(_, texture) in &self.textures {
+            unsafe { objc_release(texture) };
+        }
+    }
+}
+
+/// A texture only the GPU uses, to draw into and maybe sample.
+fn new_transient_texture(device: id, desc: &TextureDesc) -> id {
+    let width = desc.width as NSUInteger;
+    let height = desc.height as NSUInteger;
+    let sample_count = desc.sample_count as NSUInteger;
+    let usage = if desc.sample_count > 1 {
+        MTLTextureUsageRenderTarget
+    } else {
+        MTLTextureUsageRenderTarget | MTLTextureUsageShaderRead
+    };
+    unsafe {
+        let texture_descriptor: id = msg_send![class!(MTLTextureDescriptor),
+                                               texture2DDescriptorWithPixelFormat:desc.pixel_format
+                                                                            width:width
+                                                                           height:height
+                                                                        mipmapped:NO];
+        if sample_count > 1 {
+            let _:() = msg_send![texture_descriptor, setTextureType:MTLTextureType2DMultisample];
+            let _:() = msg_send![texture_descriptor, setSampleCount:sample_count];
+        }
+        let _:() = msg_send![texture_descriptor, setUsage:usage];
+        let _:() = msg_send![texture_descriptor, setStorageMode:MTLStorageModePrivate];
+        let texture: id = msg_send![device, newTextureWithDescriptor:texture_descriptor];
+        let label = NSString::alloc(nil).init_str("Transient Texture");
+        let _:() = msg_send![texture, setLabel:label];
+        let _:() = msg_send![label, release];
+        texture
+    }
+}
+
+/// The Metal texture for each of a render graph's textures this frame.
+pub struct GraphTextures {
+    textures: Vec<id>,
+}
+
+impl GraphTextures {
+    /// Puts together the imported textures and the ones from `TransientTexturePool::textures_for`.
+    pub fn new(graph: &CompiledGraph, imported: &[(TextureId, id)], transient: &[id]) -> Self {
+        let mut textures: Vec<id> = graph.transient_texture_indices.iter()
+            .map(|index| index.map_or(nil, |index| transient[index]))
+            .collect();
+        for &(texture, imported_texture) in imported {
+            textures[texture.0] = imported_texture;
+        }
+        GraphTextures { textures }
+    }
+
+    /// `nil` if the texture isn't used.
+    pub fn get(&self, texture: TextureId) -> id {
+        self.textures[texture.0]
+    }
+}
+
+/// An autoreleased render pass descriptor for a compiled pass.
+pub fn new_render_pass_descriptor(graph: &RenderGraph, pass: &CompiledPass, textures: &GraphTextures) -> id {
+    let render_pass_descriptor: id = unsafe { msg_send![class!(MTLRenderPassDescriptor), renderPassDescriptor] };
+    let color_attachments: id = unsafe { msg_send![render_pass_descriptor, colorAttachments] };
+    for (index, attachment) in pass.color_attachments.iter().enumerate() {
+        let index = index as NSUInteger;
+        let color_attachment: id = unsafe { msg_send![color_attachments, objectAtIndexedSubscript:index] };
+        set_up_attachment(color_attachment, attachment, textures);
+        if let Some(ClearValue::Color([red, green, blue, alpha])) = attachment.clear_value {
+            let clear_color = MTLClearColorMake(red, green, blue, alpha);
+            let _:() = unsafe { msg_send![color_attachment, setClearColor:clear_color] };
+        }
+    }
+    if let Some(attachment) = &pass.depth_stencil_attachment {
+        let pixel_format = graph.texture_desc(attachment.texture).pixel_format;
+        let (clear_depth, clear_stencil) = match attachment.clear_value {
+            Some(ClearValue::DepthStencil { depth, stencil }) => (depth, stencil),
+            _ => (1.0, 0),
+        };
+        if pixel_format_has_depth(pixel_format) {
+            let depth_attachment: id = unsafe { msg_send![render_pass_descriptor, depthAttachment] };
+            set_up_attachment(depth_attachment, attachment, textures);
+            let _:() = unsafe { msg_send![depth_attachment, setClearDepth:clear_depth] };
+        }
+        if pixel_format_has_stencil(pixel_format) {
+            let stencil_attachment: id = unsafe { msg_send![render_pass_descriptor, stencilAttachment] };
+            set_up_attachment(stencil_attachment, attachment, textures);
+            let _:() = unsafe { msg_send![stencil_attachment, setClearStencil:clear_stencil] };
+        }
+    }
+    render_pass_descriptor
+}
+
+fn set_up_attachment(attachment_descriptor: id, attachment: &CompiledAttachment, textures: &GraphTextures) {
+    let texture = textures.get(attachment.texture);
+    let load_action = mtl_load_action(attachment.load_action);
+    let store_action = mtl_store_action(attachment.store_action);
+    unsafe {
+        let _:() = msg_send![attachment_descriptor, setTexture:texture];
+        if let Some(resolve_texture) = attachment.resolve_texture {
+            let resolve_texture = textures.get(resolve_texture);
+            let _:() = msg_send![attachment_descriptor, setResolveTexture:resolve_texture];
+        }
+        let _:() = msg_send![attachment_descriptor, setLoadAction:load_action];
+        let _:() = msg_send![attachment_descriptor, setStoreAction:store_action];
+    }
+}
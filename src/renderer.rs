@@ -1,17 +1,55 @@
 //! A Renderer to draw in our view
 
-use objc::class;
 use objc::msg_send;
 use objc::sel;
 use objc::sel_impl;
-use cocoa::base::{id, nil};
-use crate::metal_view::{CGSize, MetalViewDelegate, MTLPixelFormat};
+use cocoa::base::{id, nil, BOOL, NO, YES};
+use crate::metal_view::{CGSize, MetalViewDelegate, MTLClearColor, MTLClearColorMake, display_id};
+use crate::pixel_format::{MTLPixelFormat, MTLPixelFormatInvalid, pixel_format_has_depth, pixel_format_has_stencil, color_pixel_format_name};
 use std::fmt::Formatter;
 use std::error::Error;
-use crate::vector_types::{vector_uint2, vector_float2, vector_float4};
+use crate::vector_types::vector_uint2;
 use cocoa::foundation::{NSAutoreleasePool, NSString, NSUInteger};
-use objc::runtime::objc_retain;
-use std::os::raw::{c_uint, c_double};
+use objc::runtime::{objc_retain, objc_release};
+use std::os::raw::{c_double, c_long, c_void};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, mpsc};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::cell::Cell;
+use block::ConcreteBlock;
+use crate::frame_pacing::FrameTick;
+use crate::frame_stats::{FrameStatistics, FrameStatsLogger, FrameStatsSummary};
+use crate::buffer_ring::{BufferRing, RingAllocation, DEFAULT_FRAMES_IN_FLIGHT, DEFAULT_SLOT_CAPACITY, BUFFER_OFFSET_ALIGNMENT};
+use crate::mesh::{Mesh, MeshError, IndexData, PrimitiveTopology};
+use crate::scene::{Scene, SceneError, MeshId, DrawItem};
+use crate::scene_file::{SceneDescription, SceneFileError};
+use crate::hot_reload::{HotReloader, Reload, ShaderSource};
+use crate::shader_library::{LibrarySource, LibraryError, new_library};
+use crate::ns_error::NSErrorDetails;
+use crate::error_chain::format_error_chain;
+use crate::pipeline_cache::{PipelineCache, PipelineDesc, PipelineError};
+use crate::depth_stencil::{DepthStencilDesc, new_depth_stencil_state};
+use crate::blend::BlendMode;
+use crate::shader_types::{AAPLVertex, AAPLTexturedVertex, AAPLObjectUniforms, AAPLInstance, AAPLParticle, AAPLParticleUniforms, AAPLVertexInputIndexVertices, AAPLVertexInputIndexViewportSize, AAPLVertexInputIndexObjectUniforms, AAPLVertexInputIndexInstances, AAPLVertexInputIndexParticleUniforms, AAPLComputeIndexParticles, AAPLComputeIndexParticleUniforms, AAPLFragmentInputIndexPostProcessUniforms, AAPLTextureIndexBaseColor, AAPLTextureIndexPostProcessSource, AAPLTextureIndexBloom, AAPLTextureIndexColorLut, AAPLSamplerIndexBaseColor};
+use crate::texture::{Texture, SamplerCache};
+use crate::sampler::SamplerDesc;
+use crate::glyph_atlas::GlyphAtlas;
+use crate::text::{vertex_labels, label_mesh, glyph_atlas_sampler};
+use crate::font::Font;
+use crate::batcher::Batch2D;
+use crate::hud::{Hud, HudInfo, HUD_TOGGLE_KEY};
+use crate::instancing::InstancedMesh;
+use crate::particles::{ParticleSettings, ParticleSystem};
+use crate::compute::{MTLSize, new_compute_encoder, dispatch_1d};
+use crate::render_graph::{RenderGraph, PassDesc, PassId, Attachment, ClearValue, TextureDesc, TextureId, CompiledPass, CompiledAttachment, LoadAction};
+use crate::render_targets::{TransientTexturePool, GraphTextures, new_render_pass_descriptor};
+use crate::post_process::{PostEffect, PostPass, PostShader, PostInput};
+use crate::image::{ImageError, TextureData};
+use crate::screenshot::{CapturePixelFormat, CapturedFrame, ScreenshotError, SCREENSHOT_KEY, save_screenshot, screenshot_file_name};
+use crate::recording::{RecordingSettings, RecordingTimeline, RecordingWriter, RecordingError};
+use crate::command_trace::{TraceCommand, TraceRecorder};
+use crate::transform::Matrix3;
+use std::rc::Rc;
 
 // From System/Library/Frameworks/Metal.framework/Versions/A/Headers/MTLRenderCommandEncoder.h
 // typedef struct {
@@ -28,81 +66,1260 @@ struct MTLViewport {
 }
 
 // From System/Library/Frameworks/Metal.framework/Versions/A/Headers/MTLRenderCommandEncoder.h
+// typedef NS_ENUM(NSUInteger, MTLPrimitiveType) {
+//     MTLPrimitiveTypePoint = 0,
+//     MTLPrimitiveTypeLine = 1,
+//     MTLPrimitiveTypeLineStrip = 2,
+//     MTLPrimitiveTypeTriangle = 3,
+//     MTLPrimitiveTypeTriangleStrip = 4,
+// } API_AVAILABLE(macos(10.11), ios(8.0));
+fn mtl_primitive_type(topology: PrimitiveTopology) -> NSUInteger {
+    match topology {
+        PrimitiveTopology::Point => 0,
+        PrimitiveTopology::Line => 1,
+        PrimitiveTopology::LineStrip => 2,
+        PrimitiveTopology::Triangle => 3,
+        PrimitiveTopology::TriangleStrip => 4,
+    }
+}
+
+// From System/Library/Frameworks/Metal.framework/Versions/A/Headers/MTLArgument.h
+// typedef NS_ENUM(NSUInteger, MTLIndexType) {
+//     MTLIndexTypeUInt16 = 0,
+//     MTLIndexTypeUInt32 = 1,
+// } API_AVAILABLE(macos(10.11), ios(8.0));
+fn mtl_index_type(indices: &IndexData) -> NSUInteger {
+    match indices {
+        IndexData::U16(_) => 0,
+        IndexData::U32(_) => 1,
+    }
+}
+
+// From System/Library/Frameworks/Metal.framework/Versions/A/Headers/MTLResource.h
+// MTLResourceStorageModeShared  = MTLStorageModeShared  << MTLResourceStorageModeShift,
 #[allow(non_upper_case_globals)]
-static MTLPrimitiveTypeTriangle:NSUInteger = 3;
+static MTLResourceStorageModeShared: NSUInteger = 0;
+
+// From usr/include/dispatch/semaphore.h and time.h
+#[allow(non_camel_case_types)]
+type dispatch_semaphore_t = id;
+#[allow(non_camel_case_types)]
+type dispatch_time_t = u64;
+// #define DISPATCH_TIME_FOREVER (~0ull)
+static DISPATCH_TIME_FOREVER: dispatch_time_t = !0;
+
+#[link(name="System", kind="framework")]
+extern {
+    // dispatch_semaphore_t dispatch_semaphore_create(long value);
+    fn dispatch_semaphore_create(value: c_long) -> dispatch_semaphore_t;
+    // long dispatch_semaphore_wait(dispatch_semaphore_t dsema, dispatch_time_t timeout);
+    fn dispatch_semaphore_wait(dsema: dispatch_semaphore_t, timeout: dispatch_time_t) -> c_long;
+    // long dispatch_semaphore_signal(dispatch_semaphore_t dsema);
+    fn dispatch_semaphore_signal(dsema: dispatch_semaphore_t) -> c_long;
+}
 
 
 #[derive(Debug)]
 pub enum RendererInitError {
-    FailedToInit,
-    UnableToSetPipelineState,
+    /// The view has no Metal device.
+    NoDevice,
+    MissingLibrary(LibraryError),
+    MissingFunction(String),
+    PipelineCompilation(NSErrorDetails),
 }
 impl std::fmt::Display for RendererInitError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::FailedToInit => write!(f, "Failed to initialize"),
-            Self::UnableToSetPipelineState => write!(f, "Unable to set pipeline state"),
+            Self::NoDevice => write!(f, "The view has no Metal device"),
+            Self::MissingLibrary(error) => write!(f, "{}", error),
+            Self::MissingFunction(name) => write!(f, "The shader library has no function called {}", name),
+            Self::PipelineCompilation(_) => write!(f, "Unable to compile the render pipeline"),
+        }
+    }
+}
+impl Error for RendererInitError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::MissingLibrary(error) => error.source(),
+            Self::PipelineCompilation(details) => Some(details),
+            _ => None,
+        }
+    }
+}
+impl From<PipelineError> for RendererInitError {
+    fn from(error: PipelineError) -> Self {
+        match error {
+            PipelineError::MissingFunction(name) => Self::MissingFunction(name),
+            // We only build render pipelines before we start.
+            PipelineError::Compilation(details) | PipelineError::ComputeCompilation(details) => Self::PipelineCompilation(details),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ShaderLoadError {
+    Io(PathBuf, std::io::Error),
+    Library(LibraryError),
+    Pipeline(PipelineError),
+}
+impl std::fmt::Display for ShaderLoadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(path, _) => write!(f, "Unable to read {}", path.display()),
+            Self::Library(error) => write!(f, "{}", error),
+            Self::Pipeline(error) => write!(f, "{}", error),
+        }
+    }
+}
+impl Error for ShaderLoadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(_, error) => Some(error),
+            Self::Library(error) => error.source(),
+            Self::Pipeline(error) => error.source(),
+        }
+    }
+}
+
+/// The shader functions we draw with.
+static VERTEX_SHADER_NAME: &str = "sceneVertexShader";
+static FRAGMENT_SHADER_NAME: &str = "fragmentShader";
+static TEXTURED_VERTEX_SHADER_NAME: &str = "texturedVertexShader";
+static TEXTURED_FRAGMENT_SHADER_NAME: &str = "texturedFragmentShader";
+static TEXT_FRAGMENT_SHADER_NAME: &str = "textFragmentShader";
+static INSTANCED_VERTEX_SHADER_NAME: &str = "instancedVertexShader";
+static PARTICLE_VERTEX_SHADER_NAME: &str = "particleVertexShader";
+static PARTICLE_FRAGMENT_SHADER_NAME: &str = "particleFragmentShader";
+static PARTICLE_UPDATE_KERNEL_NAME: &str = "updateParticles";
+static POST_PROCESS_VERTEX_SHADER_NAME: &str = "postProcessVertexShader";
+static GAUSSIAN_BLUR_FRAGMENT_SHADER_NAME: &str = "gaussianBlurFragmentShader";
+static BRIGHT_PASS_FRAGMENT_SHADER_NAME: &str = "brightPassFragmentShader";
+static BLOOM_COMPOSITE_FRAGMENT_SHADER_NAME: &str = "bloomCompositeFragmentShader";
+static COLOR_GRADE_FRAGMENT_SHADER_NAME: &str = "colorGradeFragmentShader";
+static VIGNETTE_FRAGMENT_SHADER_NAME: &str = "vignetteFragmentShader";
+
+fn post_fragment_function(shader: &PostShader) -> &'static str {
+    match shader {
+        PostShader::GaussianBlur(_) => GAUSSIAN_BLUR_FRAGMENT_SHADER_NAME,
+        PostShader::BrightPass(_) => BRIGHT_PASS_FRAGMENT_SHADER_NAME,
+        PostShader::BloomComposite(_) => BLOOM_COMPOSITE_FRAGMENT_SHADER_NAME,
+        PostShader::ColorGrade(_) => COLOR_GRADE_FRAGMENT_SHADER_NAME,
+        PostShader::Vignette(_) => VIGNETTE_FRAGMENT_SHADER_NAME,
+    }
+}
+
+/// How many bytes of uniforms a post-processing shader reads.
+fn post_uniforms_len(shader: &PostShader) -> usize {
+    match shader {
+        PostShader::GaussianBlur(uniforms) => std::mem::size_of_val(uniforms),
+        PostShader::BrightPass(uniforms) | PostShader::BloomComposite(uniforms) => std::mem::size_of_val(uniforms),
+        PostShader::ColorGrade(uniforms) => std::mem::size_of_val(uniforms),
+        PostShader::Vignette(uniforms) => std::mem::size_of_val(uniforms),
+    }
+}
+
+/// How a textured mesh's texture is used.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TexturedShader {
+    /// The texture's colors, multiplied with the vertex colors.
+    Texture,
+    /// The texture is a glyph atlas, and the vertex colors are the text color.
+    Text,
+}
+
+impl TexturedShader {
+    fn fragment_function(self) -> &'static str {
+        match self {
+            TexturedShader::Texture => TEXTURED_FRAGMENT_SHADER_NAME,
+            TexturedShader::Text => TEXT_FRAGMENT_SHADER_NAME,
         }
     }
 }
-impl Error for RendererInitError{}
 
+/// A mesh drawn with a texture, on top of the scene.
+pub struct TexturedMesh {
+    pub mesh: Mesh<AAPLTexturedVertex>,
+    pub texture: Rc<Texture>,
+    pub sampler: SamplerDesc,
+    pub shader: TexturedShader,
+    /// Where the mesh is drawn, in pixels from the centre of the view.
+    pub transform: Matrix3,
+    /// Multiplied with the texture and vertex colors.
+    pub tint: [f32; 4],
+    pub blend_mode: BlendMode,
+}
+
+/// A particle system whose particles live in a buffer of their own, which the GPU updates in place.
+struct GpuParticles {
+    system: ParticleSystem,
+    /// The `AAPLParticle`s.
+    buffer: id,
+    /// The time steps to simulate before the next frame is drawn, in seconds.
+    pending_time_steps: Vec<f32>,
+}
+
+impl Drop for GpuParticles {
+    fn drop(&mut self) {
+        // Command buffers still in flight keep their own hold on the buffer.
+        unsafe { objc_release(self.buffer) };
+    }
+}
+
+/// A post-processing effect, with its color lookup table uploaded if it has one.
+struct GpuPostEffect {
+    effect: PostEffect,
+    lut: Option<Texture>,
+}
+
+/// How big the HUD's bitmap font is drawn: each of its pixels this many pixels wide.
+static HUD_PIXEL_SCALE: usize = 2;
+
+/// The HUD, with its glyph atlas uploaded. Made the first time the HUD is shown.
+struct HudOverlay {
+    hud: Hud,
+    atlas: Rc<Texture>,
+}
+
+/// A label on each vertex of the scene, remade whenever the scene is replaced.
+struct VertexLabels {
+    font: Font,
+    /// `None` if the scene has no vertices, or its labels don't fit in a glyph atlas.
+    textured_mesh: Option<TexturedMesh>,
+}
+
+/// The passes of a frame, and the view's textures they draw into.
+struct FrameGraph {
+    graph: RenderGraph,
+    /// The Metal texture for each of the graph's imported textures.
+    imported_textures: Vec<(TextureId, id)>,
+    scene_pass: PassId,
+    post_passes: Vec<FramePostPass>,
+    hud_pass: Option<PassId>,
+}
+
+/// A pass of a post-processing effect, and the textures it reads.
+struct FramePostPass {
+    pass: PassId,
+    /// Which of the renderer's effects it's part of.
+    effect: usize,
+    post_pass: PostPass,
+    source: TextureId,
+    bloom: Option<TextureId>,
+}
+
+/// What the scene pass draws this frame, worked out before the frame's buffer is laid out.
+struct SceneFrame {
+    draw_list: Vec<DrawItem>,
+    /// Each mesh the draw list uses, once.
+    used_meshes: Vec<MeshId>,
+    /// For each instanced mesh, the instances that might be seen.
+    visible_instances: Vec<Vec<AAPLInstance>>,
+    /// The particle buffer, and the uniforms to draw it with.
+    particles: Option<(id, AAPLParticleUniforms)>,
+}
+
+/// How to set up a renderer.
+#[derive(Debug, Clone)]
+pub struct RendererConfig {
+    /// How many frames the CPU can get ahead of the GPU.
+    pub frames_in_flight: usize,
+    /// Where to get the shaders.
+    pub library: LibrarySource,
+    /// A binary archive file to keep compiled pipelines in between runs, if any.
+    pub pipeline_archive: Option<PathBuf>,
+    /// How to test against the view's depth and stencil attachments, if it has them.
+    pub depth_stencil: DepthStencilDesc,
+    /// The value stencil tests compare against, and `StencilOperation::Replace` stores.
+    pub stencil_reference_value: u32,
+}
+
+impl Default for RendererConfig {
+    fn default() -> Self {
+        RendererConfig {
+            frames_in_flight: DEFAULT_FRAMES_IN_FLIGHT,
+            library: LibrarySource::Default,
+            pipeline_archive: None,
+            depth_stencil: DepthStencilDesc::default(),
+            stencil_reference_value: 0,
+        }
+    }
+}
 
 /// Renderer to draw in our view
 pub struct Renderer {
     view: id,
-    pipeline_state: id,
+    device: id,
+    pipeline_cache: PipelineCache,
+    pipeline_desc: PipelineDesc,
+    pipeline_archive: Option<PathBuf>,
     command_queue: id,
+    depth_stencil_desc: DepthStencilDesc,
+    /// `nil` if the view has no depth or stencil attachment.
+    depth_stencil_state: id,
+    stencil_reference_value: u32,
     viewport_size: vector_uint2,
+    frame_statistics: Arc<Mutex<FrameStatistics>>,
+    frame_stats_logger: Option<FrameStatsLogger>,
+    current_frame_index: u64,
+    in_flight_semaphore: dispatch_semaphore_t,
+    buffer_ring: BufferRing,
+    frame_buffers: Vec<id>,
+    scene: Scene<AAPLVertex>,
+    instanced_meshes: Vec<InstancedMesh>,
+    particles: Option<GpuParticles>,
+    textured_meshes: Vec<TexturedMesh>,
+    vertex_labels: Option<VertexLabels>,
+    post_effects: Vec<GpuPostEffect>,
+    sampler_cache: SamplerCache,
+    hot_reloader: Option<HotReloader>,
+    hud_visible: bool,
+    hud_overlay: Option<HudOverlay>,
+    transient_textures: TransientTexturePool,
+    /// Where to save the next frame, if we've been asked to.
+    pending_screenshot: Option<PathBuf>,
+    screenshot_directory: PathBuf,
+    recording: Option<GpuRecording>,
+    /// The commands of the frames we're tracing, if we are.
+    trace: Option<TraceRecorder>,
 }
 
 impl Renderer {
-    /// Creates a new renderer with the given view
-    pub fn new_with_metal_kit_view(view: id) -> Result<Self, RendererInitError> {
+    /// Creates a new renderer with the given view, set up as `config` says.
+    pub fn new_with_metal_kit_view_and_config(view: id, config: RendererConfig) -> Result<Self, RendererInitError> {
         let pool = unsafe { NSAutoreleasePool::new(nil) };
         let device: id = unsafe { msg_send![view, device] };
-        let default_library: id = unsafe { msg_send![device, newDefaultLibrary] };
-        let vertex_shader_name = unsafe { NSString::alloc(nil).init_str("vertexShader") };
-        let vertex_function: id = unsafe { msg_send![default_library, newFunctionWithName:vertex_shader_name] };
-        let fragment_function_name = unsafe { NSString::alloc(nil).init_str("fragmentShader") };
-        let fragment_function: id = unsafe { msg_send![default_library, newFunctionWithName:fragment_function_name] };
-
-        // Configure a pipeline descriptor that is used to create a pipeline state.
-        let render_pipeline_descriptor_class = class!(MTLRenderPipelineDescriptor);
-        let pipeline_state_descriptor: id = unsafe { msg_send![render_pipeline_descriptor_class, alloc] };
-        let pipeline_state_descriptor: id = unsafe { msg_send![pipeline_state_descriptor, init] };
-
-        let pipeline_label = unsafe { NSString::alloc(nil).init_str("Simple Pipeline") };
-        let _:() = unsafe {msg_send![pipeline_state_descriptor, setLabel:pipeline_label] };
-
-        let _:() = unsafe { msg_send![pipeline_state_descriptor, setVertexFunction:vertex_function] };
-        let _:() = unsafe { msg_send![pipeline_state_descriptor, setFragmentFunction:fragment_function] };
-
-        let color_attachment_array: id = unsafe { msg_send![pipeline_state_descriptor, colorAttachments] };
-        let color_attachment_0: id = unsafe { msg_send![color_attachment_array, objectAtIndexedSubscript:0] };
-        let pixel_format: MTLPixelFormat = unsafe { msg_send![view, colorPixelFormat] };
-        let _:() = unsafe { msg_send![color_attachment_0, setPixelFormat:pixel_format] };
-        let _:() = unsafe { msg_send![color_attachment_array, setObject:color_attachment_0 atIndexedSubscript:0] };
-
-        let error: id = nil;
-        let pipeline_state: id = unsafe { msg_send![device, newRenderPipelineStateWithDescriptor:pipeline_state_descriptor error: &error] };
-        if pipeline_state == nil {
+        if device == nil {
+            unsafe { pool.drain() };
+            return Err(RendererInitError::NoDevice)
+        }
+        let library = match new_library(device, &config.library) {
+            Ok(library) => library,
+            Err(e) => {
+                unsafe { pool.drain() };
+                return Err(RendererInitError::MissingLibrary(e))
+            }
+        };
+        let mut pipeline_cache = new_pipeline_cache(device, library, config.pipeline_archive.as_deref());
+        unsafe { objc_release(library) };
+        let pipeline_desc = pipeline_desc_for_view(view);
+        // Build the pipeline now, so we find out straight away if we can't.
+        if let Err(e) = pipeline_cache.get_or_create(&pipeline_desc) {
             unsafe { pool.drain() };
-            return Err(RendererInitError::UnableToSetPipelineState)
+            return Err(e.into())
         }
 
         let command_queue: id = unsafe { msg_send![device, newCommandQueue] };
+        let depth_stencil_state = if view_has_depth_stencil(view) {
+            new_depth_stencil_state(device, &config.depth_stencil)
+        } else {
+            nil
+        };
 
-        let pipeline_state = unsafe { objc_retain(pipeline_state) };
+        // One buffer for each frame that can be in flight.
+        let buffer_ring = BufferRing::new(config.frames_in_flight, DEFAULT_SLOT_CAPACITY);
+        let frame_buffers = (0..buffer_ring.slot_count())
+            .map(|slot| new_frame_buffer(device, slot, DEFAULT_SLOT_CAPACITY))
+            .collect();
+        let in_flight_semaphore = unsafe { dispatch_semaphore_create(buffer_ring.slot_count() as c_long) };
+
+        let device = unsafe { objc_retain(device) };
         let command_queue = unsafe { objc_retain(command_queue) };
         unsafe { pool.drain() };
         Ok( Renderer {
             view,
-            pipeline_state,
+            device,
+            pipeline_cache,
+            pipeline_desc,
+            pipeline_archive: config.pipeline_archive,
             command_queue,
+            depth_stencil_desc: config.depth_stencil,
+            depth_stencil_state,
+            stencil_reference_value: config.stencil_reference_value,
             viewport_size: vector_uint2::new(0, 0), // will be set by view immediately
+            frame_statistics: Arc::new(Mutex::new(FrameStatistics::default())),
+            frame_stats_logger: None,
+            current_frame_index: 0,
+            in_flight_semaphore,
+            buffer_ring,
+            frame_buffers,
+            scene: Scene::with_single_mesh(Mesh::hello_triangle()),
+            instanced_meshes: Vec::new(),
+            particles: None,
+            textured_meshes: Vec::new(),
+            vertex_labels: None,
+            post_effects: Vec::new(),
+            sampler_cache: SamplerCache::new(device),
+            hot_reloader: None,
+            hud_visible: false,
+            hud_overlay: None,
+            transient_textures: TransientTexturePool::new(device),
+            pending_screenshot: None,
+            screenshot_directory: PathBuf::from("."),
+            recording: None,
+            trace: None,
         })
     }
+
+    /// Replaces the scene we draw.
+    pub fn set_scene(&mut self, scene: Scene<AAPLVertex>) -> Result<(), SceneError> {
+        scene.validate()?;
+        self.scene = scene;
+        self.update_vertex_labels();
+        Ok(())
+    }
+
+    /// The scene we draw, to be changed in place.
+    pub fn scene_mut(&mut self) -> &mut Scene<AAPLVertex> {
+        &mut self.scene
+    }
+
+    /// Replaces the textured meshes we draw on top of the scene.
+    pub fn set_textured_meshes(&mut self, textured_meshes: Vec<TexturedMesh>) -> Result<(), MeshError> {
+        for textured_mesh in &textured_meshes {
+            textured_mesh.mesh.validate()?;
+        }
+        self.textured_meshes = textured_meshes;
+        Ok(())
+    }
+
+    /// Replaces the meshes drawn once per instance, after the scene and before the textured meshes.
+    pub fn set_instanced_meshes(&mut self, instanced_meshes: Vec<InstancedMesh>) -> Result<(), MeshError> {
+        for instanced_mesh in &instanced_meshes {
+            instanced_mesh.mesh.validate()?;
+        }
+        self.instanced_meshes = instanced_meshes;
+        Ok(())
+    }
+
+    /// Starts simulating particles on the GPU and drawing them after the instanced meshes,
+    /// or stops if there aren't any.
+    pub fn set_particles(&mut self, settings: Option<ParticleSettings>) {
+        self.particles = settings
+            .filter(|settings| settings.count > 0)
+            .map(|settings| GpuParticles {
+                buffer: new_particle_buffer(self.device, &settings.initial_particles()),
+                system: ParticleSystem::new(settings),
+                pending_time_steps: Vec::new(),
+            });
+    }
+
+    /// Moves the simulation on by one step. The particles catch up on their steps when the next frame's drawn.
+    fn update_simulation(&mut self, time_step: f64) {
+        if let Some(particles) = self.particles.as_mut() {
+            particles.pending_time_steps.push(time_step as f32);
+        }
+    }
+
+    /// Labels each vertex the scene draws with its position, in the given font,
+    /// on top of the textured meshes. The labels follow the scene when it's replaced or reloaded.
+    pub fn label_vertices(&mut self, font: Font) {
+        self.vertex_labels = Some(VertexLabels { font, textured_mesh: None });
+        self.update_vertex_labels();
+    }
+
+    /// Remakes the vertex labels, if we're drawing them, to match the scene.
+    fn update_vertex_labels(&mut self) {
+        let textured_mesh = match &self.vertex_labels {
+            Some(vertex_labels) => self.vertex_labels_mesh(&vertex_labels.font),
+            None => return,
+        };
+        self.vertex_labels.as_mut().unwrap().textured_mesh = textured_mesh;
+    }
+
+    fn vertex_labels_mesh(&self, font: &Font) -> Option<TexturedMesh> {
+        let labels = vertex_labels(&self.scene);
+        let characters: String = labels.iter().map(|label| label.text.as_str()).collect();
+        let atlas = match GlyphAtlas::new(font, &characters) {
+            Ok(atlas) => atlas,
+            Err(e) => {
+                println!("Unable to make the glyph atlas: {}", format_error_chain(&e));
+                return None;
+            }
+        };
+        // Text meshes are always valid.
+        Some(TexturedMesh {
+            mesh: label_mesh(font, &atlas, &labels, [1., 1., 1., 1.])?,
+            texture: self.load_glyph_atlas(&atlas),
+            sampler: glyph_atlas_sampler(),
+            shader: TexturedShader::Text,
+            transform: Matrix3::identity(),
+            tint: [1., 1., 1., 1.],
+            blend_mode: BlendMode::Alpha,
+        })
+    }
+
+    /// Replaces the post-processing effects applied to the scene, in the order they're applied.
+    /// With none, the scene is drawn straight into the drawable.
+    pub fn set_post_effects(&mut self, effects: Vec<PostEffect>) {
+        let device = self.device;
+        self.post_effects = effects.into_iter()
+            .map(|effect| {
+                let lut = effect.lut().map(|lut| {
+                    Texture::new(device, &TextureData::without_mipmaps(lut.to_image(), false), "Color Lookup Table")
+                });
+                GpuPostEffect { effect, lut }
+            })
+            .collect();
+    }
+
+    /// Reads a PNG, JPEG or KTX file into a texture we can draw with.
+    pub fn load_texture(&self, path: &Path) -> Result<Rc<Texture>, ImageError> {
+        Texture::load(self.device, path).map(Rc::new)
+    }
+
+    /// Uploads a glyph atlas, for drawing text with `TexturedShader::Text`.
+    pub fn load_glyph_atlas(&self, atlas: &GlyphAtlas) -> Rc<Texture> {
+        Rc::new(Texture::new(self.device, &atlas.texture_data(), "Glyph Atlas"))
+    }
+
+    /// Replaces the shaders we draw with from a file.
+    pub fn load_shaders(&mut self, source: &ShaderSource) -> Result<(), ShaderLoadError> {
+        let library_source = source.load().map_err(|e| ShaderLoadError::Io(source.path().to_path_buf(), e))?;
+        self.load_library(&library_source)
+    }
+
+    /// Replaces the shaders we draw with from a library.
+    ///
+    /// If they can't be loaded we carry on with the ones we have.
+    pub fn load_library(&mut self, source: &LibrarySource) -> Result<(), ShaderLoadError> {
+        let library = new_library(self.device, source).map_err(ShaderLoadError::Library)?;
+        let mut pipeline_cache = new_pipeline_cache(self.device, library, self.pipeline_archive.as_deref());
+        unsafe { objc_release(library) };
+        pipeline_cache.get_or_create(&self.pipeline_desc).map_err(ShaderLoadError::Pipeline)?;
+        // Command buffers still in flight keep their own hold on the old pipeline states.
+        self.pipeline_cache = pipeline_cache;
+        Ok(())
+    }
+
+    /// Reloads the scene and shaders whenever the reloader says they've changed.
+    pub fn set_hot_reloader(&mut self, hot_reloader: Option<HotReloader>) {
+        self.hot_reloader = hot_reloader;
+    }
+
+    /// Loads a scene file, including its clear color, and draws it from now on.
+    pub fn load_scene(&mut self, path: &Path) -> Result<(), SceneFileError> {
+        let scene_description = SceneDescription::load(path)?;
+        // The description has already been checked, so this can't fail.
+        self.set_scene(scene_description.to_scene()).unwrap();
+        let [red, green, blue, alpha] = scene_description.clear_color;
+        let clear_color = MTLClearColorMake(red, green, blue, alpha);
+        let _: () = unsafe { msg_send![self.view, setClearColor:clear_color] };
+        Ok(())
+    }
+
+    /// Applies any changes the hot reloader has seen.
+    fn reload_changed_files(&mut self, time: f64) {
+        let reloads = match self.hot_reloader.as_mut() {
+            Some(hot_reloader) => hot_reloader.poll(time),
+            None => return,
+        };
+        for reload in reloads {
+            match reload {
+                Reload::Scene(path) => match self.load_scene(&path) {
+                    Ok(()) => println!("Reloaded scene {}", path.display()),
+                    Err(e) => println!("Keeping the previous scene, {} didn't load: {}", path.display(), format_error_chain(&e)),
+                },
+                Reload::Shaders(source) => match self.load_shaders(&source) {
+                    Ok(()) => println!("Reloaded shaders {}", source.path().display()),
+                    Err(e) => println!("Keeping the previous shaders: {}", format_error_chain(&e)),
+                },
+            }
+        }
+    }
+
+    /// Encodes one instanced draw for each instanced mesh with instances in view,
+    /// given the instances to draw of each.
+    fn encode_instanced_meshes(&mut self, render_encoder: id, frame_buffer: id, visible_instances: &[Vec<AAPLInstance>]) {
+        // Put back once they're drawn; we need `self` to upload them.
+        let instanced_meshes = std::mem::take(&mut self.instanced_meshes);
+        for (index, (instanced_mesh, instances)) in instanced_meshes.iter().zip(visible_instances).enumerate() {
+            if instances.is_empty() {
+                continue;
+            }
+            let pipeline_desc = PipelineDesc {
+                vertex_function: INSTANCED_VERTEX_SHADER_NAME.to_string(),
+                blend_mode: instanced_mesh.blend_mode,
+                ..self.pipeline_desc.clone()
+            };
+            let pipeline_state: id = match self.pipeline_cache.get_or_create(&pipeline_desc) {
+                Ok(pipeline_state) => pipeline_state,
+                Err(e) => {
+                    println!("Skipping instanced mesh {}: {}", index, format_error_chain(&e));
+                    continue;
+                }
+            };
+            self.set_render_pipeline_state(render_encoder, pipeline_state, &pipeline_desc);
+
+            let instances_allocation = match self.upload_to_frame_buffer(instances) {
+                Some(instances_allocation) => instances_allocation,
+                None => continue,
+            };
+            let instances_offset = instances_allocation.offset as NSUInteger;
+            let _:() = unsafe { msg_send![render_encoder, setVertexBuffer:frame_buffer offset:instances_offset atIndex:AAPLVertexInputIndexInstances as NSUInteger] };
+            self.trace(|| TraceCommand::vertex_bytes(AAPLVertexInputIndexInstances, instances));
+            self.encode_mesh(render_encoder, frame_buffer, &instanced_mesh.mesh, &AAPLObjectUniforms::new(&instanced_mesh.transform, instanced_mesh.tint, instanced_mesh.depth), instances.len());
+        }
+        self.instanced_meshes = instanced_meshes;
+    }
+
+    /// Encodes a compute pass that moves the particles on by each time step in turn.
+    fn encode_particle_update(&mut self, command_buffer: id, frame_buffer: id, particles_buffer: id, step_uniforms: &[AAPLParticleUniforms]) {
+        let pipeline_state = match self.pipeline_cache.get_or_create_compute(PARTICLE_UPDATE_KERNEL_NAME) {
+            Ok(pipeline_state) => pipeline_state,
+            Err(e) => {
+                println!("Not moving the particles: {}", format_error_chain(&e));
+                return;
+            }
+        };
+        let compute_encoder = new_compute_encoder(command_buffer, "Particle Update");
+        let _:() = unsafe { msg_send![compute_encoder, setBuffer:particles_buffer offset:0 as NSUInteger atIndex:AAPLComputeIndexParticles as NSUInteger] };
+        // The encoder's dispatches run one after another, so each step sees the last one's particles.
+        for uniforms in step_uniforms {
+            let uniforms_allocation = match self.upload_to_frame_buffer(std::slice::from_ref(uniforms)) {
+                Some(uniforms_allocation) => uniforms_allocation,
+                None => break,
+            };
+            let uniforms_offset = uniforms_allocation.offset as NSUInteger;
+            let _:() = unsafe { msg_send![compute_encoder, setBuffer:frame_buffer offset:uniforms_offset atIndex:AAPLComputeIndexParticleUniforms as NSUInteger] };
+            dispatch_1d(compute_encoder, pipeline_state, uniforms.particle_count as usize);
+        }
+        let _:() = unsafe { msg_send![compute_encoder, endEncoding] };
+    }
+
+    /// Encodes a draw of the particles as points.
+    fn encode_particles(&mut self, render_encoder: id, frame_buffer: id, particles_buffer: id, uniforms: &AAPLParticleUniforms) {
+        let pipeline_desc = PipelineDesc {
+            vertex_function: PARTICLE_VERTEX_SHADER_NAME.to_string(),
+            fragment_function: PARTICLE_FRAGMENT_SHADER_NAME.to_string(),
+            blend_mode: BlendMode::Additive,
+            ..self.pipeline_desc.clone()
+        };
+        let pipeline_state: id = match self.pipeline_cache.get_or_create(&pipeline_desc) {
+            Ok(pipeline_state) => pipeline_state,
+            Err(e) => {
+                println!("Skipping the particles: {}", format_error_chain(&e));
+                return;
+            }
+        };
+        self.set_render_pipeline_state(render_encoder, pipeline_state, &pipeline_desc);
+        let _:() = unsafe { msg_send![render_encoder, setVertexBuffer:particles_buffer offset:0 as NSUInteger atIndex:AAPLVertexInputIndexVertices as NSUInteger] };
+
+        let uniforms_allocation = match self.upload_to_frame_buffer(std::slice::from_ref(uniforms)) {
+            Some(uniforms_allocation) => uniforms_allocation,
+            None => return,
+        };
+        let uniforms_offset = uniforms_allocation.offset as NSUInteger;
+        let _:() = unsafe { msg_send![render_encoder, setVertexBuffer:frame_buffer offset:uniforms_offset atIndex:AAPLVertexInputIndexParticleUniforms as NSUInteger] };
+        // The particles themselves are only on the GPU, so they aren't traced.
+        self.trace(|| TraceCommand::vertex_bytes(AAPLVertexInputIndexParticleUniforms, std::slice::from_ref(uniforms)));
+
+        let primitive_type = mtl_primitive_type(PrimitiveTopology::Point);
+        let particle_count = uniforms.particle_count as NSUInteger;
+        let _:() = unsafe { msg_send![render_encoder, drawPrimitives:primitive_type vertexStart:0 as NSUInteger vertexCount:particle_count] };
+        self.trace(|| TraceCommand::Draw { topology: PrimitiveTopology::Point, vertex_start: 0, vertex_count: particle_count as usize, instance_count: 1 });
+    }
+
+    /// Encodes a draw for each textured mesh, after the scene.
+    fn encode_textured_meshes(&mut self, render_encoder: id, frame_buffer: id) {
+        // Put back once they're drawn; we need `self` to upload them.
+        let textured_meshes = std::mem::take(&mut self.textured_meshes);
+        let vertex_labels = self.vertex_labels.as_mut().and_then(|vertex_labels| vertex_labels.textured_mesh.take());
+        for (index, textured_mesh) in textured_meshes.iter().chain(&vertex_labels).enumerate() {
+            let pipeline_desc = PipelineDesc {
+                vertex_function: TEXTURED_VERTEX_SHADER_NAME.to_string(),
+                fragment_function: textured_mesh.shader.fragment_function().to_string(),
+                blend_mode: textured_mesh.blend_mode,
+                ..self.pipeline_desc.clone()
+            };
+            let pipeline_state: id = match self.pipeline_cache.get_or_create(&pipeline_desc) {
+                Ok(pipeline_state) => pipeline_state,
+                Err(e) => {
+                    println!("Skipping textured mesh {}: {}", index, format_error_chain(&e));
+                    continue;
+                }
+            };
+            self.set_render_pipeline_state(render_encoder, pipeline_state, &pipeline_desc);
+            self.bind_texture(render_encoder, textured_mesh.texture.texture(), &textured_mesh.sampler);
+            self.encode_mesh(render_encoder, frame_buffer, &textured_mesh.mesh, &AAPLObjectUniforms::new(&textured_mesh.transform, textured_mesh.tint, 0.), 1);
+        }
+        self.textured_meshes = textured_meshes;
+        if let Some(labels) = self.vertex_labels.as_mut() {
+            labels.textured_mesh = vertex_labels;
+        }
+    }
+
+    /// Binds the texture and sampler `texturedFragmentShader` and `textFragmentShader` sample.
+    fn bind_texture(&mut self, render_encoder: id, texture: id, sampler: &SamplerDesc) {
+        let sampler_state = self.sampler_cache.get_or_create(sampler);
+        unsafe {
+            let _:() = msg_send![render_encoder, setFragmentTexture:texture atIndex:AAPLTextureIndexBaseColor as NSUInteger];
+            let _:() = msg_send![render_encoder, setFragmentSamplerState:sampler_state atIndex:AAPLSamplerIndexBaseColor as NSUInteger];
+        }
+    }
+
+    /// Uploads a mesh and its object uniforms to this frame's buffer, binds them
+    /// and encodes a draw of `instance_count` copies.
+    fn encode_mesh<V>(&mut self, render_encoder: id, frame_buffer: id, mesh: &Mesh<V>, object_uniforms: &AAPLObjectUniforms, instance_count: usize) {
+        let (vertices_allocation, indices_offset) = match self.upload_mesh(mesh) {
+            Some(offsets) => offsets,
+            None => return,
+        };
+        let vertices_offset = vertices_allocation.offset as NSUInteger;
+        let _:() = unsafe { msg_send![render_encoder, setVertexBuffer:frame_buffer offset:vertices_offset atIndex:AAPLVertexInputIndexVertices as NSUInteger] };
+        self.trace(|| TraceCommand::vertex_bytes(AAPLVertexInputIndexVertices, mesh.vertices()));
+
+        let object_uniforms_allocation = match self.upload_to_frame_buffer(std::slice::from_ref(object_uniforms)) {
+            Some(object_uniforms_allocation) => object_uniforms_allocation,
+            None => return,
+        };
+        let object_uniforms_offset = object_uniforms_allocation.offset as NSUInteger;
+        let _:() = unsafe { msg_send![render_encoder, setVertexBuffer:frame_buffer offset:object_uniforms_offset atIndex:AAPLVertexInputIndexObjectUniforms as NSUInteger] };
+        self.trace(|| TraceCommand::vertex_bytes(AAPLVertexInputIndexObjectUniforms, std::slice::from_ref(object_uniforms)));
+
+        encode_draw(render_encoder, frame_buffer, mesh, indices_offset, instance_count);
+        self.trace(|| TraceCommand::mesh_draw(mesh, instance_count));
+    }
+
+    /// Shows or hides the debug HUD.
+    pub fn set_hud_visible(&mut self, visible: bool) {
+        self.hud_visible = visible;
+    }
+
+    /// Saves the next frame as a PNG, just what's in the view without the window around it.
+    pub fn capture_next_frame(&mut self, path: PathBuf) {
+        self.pending_screenshot = Some(path);
+        self.update_framebuffer_only();
+    }
+
+    /// Saves the next frame in the screenshot directory, named for when it was taken.
+    pub fn take_screenshot(&mut self) {
+        let seconds_since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs());
+        let file_name = screenshot_file_name(seconds_since_epoch, self.current_frame_index);
+        self.capture_next_frame(self.screenshot_directory.join(file_name));
+    }
+
+    /// Sets where `take_screenshot` saves screenshots: by default, the current directory.
+    pub fn set_screenshot_directory(&mut self, directory: PathBuf) {
+        self.screenshot_directory = directory;
+    }
+
+    /// Starts recording the frames we draw, from the next one we can read back.
+    ///
+    /// Until it's done, the simulation moves on by exactly one frame of the recording before each frame,
+    /// however long the last one took, and the frames are written out on a thread of their own.
+    pub fn start_recording(&mut self, settings: RecordingSettings) {
+        let updates_per_second: c_double = unsafe { msg_send![self.view, fixedUpdatesPerSecond] };
+        let timeline = RecordingTimeline::new(&settings, updates_per_second);
+        let (frames, received_frames) = mpsc::channel::<CapturedFrame>();
+        let mut writer = RecordingWriter::new(&settings);
+        std::thread::spawn(move || {
+            let written = received_frames.iter().try_for_each(|frame| {
+                let image = frame.to_image().ok_or(RecordingError::UnreadableFrame)?;
+                writer.write_frame(&image)
+            });
+            let output = writer.output().to_path_buf();
+            match written.and_then(|()| writer.finish()) {
+                Ok(frame_count) => println!("Recorded {} frames to {}", frame_count, output.display()),
+                Err(e) => println!("Recording failed: {}", format_error_chain(&e)),
+            }
+        });
+        self.recording = Some(GpuRecording { timeline, frames });
+        self.update_framebuffer_only();
+    }
+
+    /// Traces the render commands of the next frames, and saves the trace once there are enough.
+    pub fn start_trace(&mut self, recorder: TraceRecorder) {
+        println!("Tracing frames' commands into {}", recorder.output().display());
+        self.trace = Some(recorder);
+    }
+
+    /// Traces a command, if we're tracing.
+    fn trace(&mut self, command: impl FnOnce() -> TraceCommand) {
+        if let Some(trace) = self.trace.as_mut() {
+            trace.record(command());
+        }
+    }
+
+    /// Traces the end of a frame, and saves the trace if that was the last frame it needed.
+    fn trace_present(&mut self) {
+        self.trace(|| TraceCommand::Present);
+        if self.trace.as_ref().is_some_and(TraceRecorder::is_finished) {
+            let trace = self.trace.take().unwrap();
+            let output = trace.output().to_path_buf();
+            match trace.finish() {
+                Ok(frame_count) => println!("Traced {} frames into {}", frame_count, output.display()),
+                Err(e) => println!("Tracing failed: {}", format_error_chain(&e)),
+            }
+        }
+    }
+
+    /// Binds a pipeline state built from `pipeline_desc`.
+    fn set_render_pipeline_state(&mut self, render_encoder: id, pipeline_state: id, pipeline_desc: &PipelineDesc) {
+        let _:() = unsafe { msg_send![render_encoder, setRenderPipelineState:pipeline_state] };
+        self.trace(|| TraceCommand::BindPipeline {
+            vertex_function: pipeline_desc.vertex_function.clone(),
+            fragment_function: pipeline_desc.fragment_function.clone(),
+            blend_mode: pipeline_desc.blend_mode,
+        });
+    }
+
+    /// Drawables can't be copied unless the layer makes them so, which makes them a little slower,
+    /// so it only does while we want to copy them.
+    fn update_framebuffer_only(&self) {
+        let framebuffer_only: BOOL = if self.pending_screenshot.is_some() || self.recording.is_some() { NO } else { YES };
+        let _:() = unsafe { msg_send![self.view, setFramebufferOnly:framebuffer_only] };
+    }
+
+    /// Moves the simulation on to the next frame of the recording, if there's one going
+    /// and this frame's drawable can be copied. Returns whether this frame is to be recorded.
+    fn begin_recording_frame(&mut self) -> bool {
+        let recording = match self.recording.as_mut() {
+            Some(recording) => recording,
+            None => return false,
+        };
+        let drawable: id = unsafe { msg_send![self.view, currentDrawable] };
+        if drawable == nil || !drawable_can_be_copied(drawable) {
+            return false;
+        }
+        let time_step = recording.timeline.time_step();
+        match recording.timeline.next_frame() {
+            Some(update_steps) => {
+                for _ in 0..update_steps {
+                    self.update_simulation(time_step);
+                }
+                true
+            }
+            None => {
+                // There were no frames to record.
+                self.recording = None;
+                self.update_framebuffer_only();
+                false
+            }
+        }
+    }
+
+    /// Encodes a copy of the drawable for the screenshot, if one's been asked for and the drawable can be copied.
+    fn encode_screenshot(&mut self, command_buffer: id, drawable: id) {
+        if self.pending_screenshot.is_none() || !drawable_can_be_copied(drawable) {
+            return;
+        }
+        let path = self.pending_screenshot.take().unwrap();
+        self.update_framebuffer_only();
+        match encode_drawable_capture(self.device, command_buffer, drawable) {
+            Ok(frame_capture) => {
+                // Encoding a big PNG takes a while, so it gets a thread of its own.
+                add_capture_handler(command_buffer, frame_capture, move |captured_frame| {
+                    std::thread::spawn(move || match save_screenshot(&captured_frame, &path) {
+                        Ok(()) => println!("Saved a screenshot to {}", path.display()),
+                        Err(e) => println!("Not saving the screenshot: {}", format_error_chain(&e)),
+                    });
+                });
+            }
+            Err(e) => println!("Not saving the screenshot: {}", format_error_chain(&e)),
+        }
+    }
+
+    /// Encodes a copy of the drawable for the recording, and stops recording if that was the last frame.
+    fn encode_recording_frame(&mut self, command_buffer: id, drawable: id) {
+        let recording = match self.recording.as_ref() {
+            Some(recording) => recording,
+            None => return,
+        };
+        let finished = match encode_drawable_capture(self.device, command_buffer, drawable) {
+            Ok(frame_capture) => {
+                let frames = recording.frames.clone();
+                add_capture_handler(command_buffer, frame_capture, move |captured_frame| {
+                    // If the writer's stopped, it's said why.
+                    let _ = frames.send(captured_frame);
+                });
+                recording.timeline.is_finished()
+            }
+            Err(e) => {
+                println!("Stopping the recording: {}", format_error_chain(&e));
+                true
+            }
+        };
+        if finished {
+            // The writer finishes once the last frame's been read back and sent.
+            self.recording = None;
+            self.update_framebuffer_only();
+        }
+    }
+
+    /// Lays out the HUD for this frame, or `None` if it's hidden.
+    fn hud_batch(&mut self) -> Option<Batch2D> {
+        if !self.hud_visible {
+            return None;
+        }
+        if self.hud_overlay.is_none() {
+            let hud = Hud::new(HUD_PIXEL_SCALE);
+            let atlas = self.load_glyph_atlas(hud.atlas());
+            self.hud_overlay = Some(HudOverlay { hud, atlas });
+        }
+        let frame_statistics = self.frame_statistics.lock().unwrap();
+        let frames_per_second = frame_statistics.summary().frames_per_second;
+        let frame_times = frame_statistics.samples().filter_map(|sample| sample.frame_interval).collect();
+        drop(frame_statistics);
+        let drawable_size: CGSize = unsafe { msg_send![self.view, drawableSize] };
+        let pixel_format: MTLPixelFormat = unsafe { msg_send![self.view, colorPixelFormat] };
+        let info = HudInfo {
+            frames_per_second,
+            frame_times,
+            drawable_size: [drawable_size.width, drawable_size.height],
+            viewport_size: [self.viewport_size.x(), self.viewport_size.y()],
+            pixel_format: color_pixel_format_name(pixel_format).map_or_else(|| pixel_format.to_string(), str::to_string),
+            display_id: display_id(self.view),
+        };
+        let viewport_size = [self.viewport_size.x() as f32, self.viewport_size.y() as f32];
+        Some(self.hud_overlay.as_ref().unwrap().hud.build(&info, viewport_size))
+    }
+
+    /// Draws the scene nodes, then the instanced meshes, particles and textured meshes on top.
+    fn encode_scene_pass(&mut self, command_buffer: id, render_pass_descriptor: id, frame_buffer: id, viewport_size_offset: NSUInteger, scene_frame: &SceneFrame) {
+        let viewport_size = self.viewport_size;
+        let render_encoder:id = unsafe {
+            msg_send![command_buffer,
+                      renderCommandEncoderWithDescriptor:render_pass_descriptor]
+        };
+        let render_encoder_name = unsafe { NSString::alloc(nil).init_str("MyRenderEncoder") };
+        let _:() = unsafe {msg_send![render_encoder, setLabel:render_encoder_name] };
+
+        //+ println!("Width and height of viewport are ({},{})", viewport_size.x(), viewport_size.y());
+        let viewport = MTLViewport {
+            origin_x: 0.0,
+            origin_y: 0.0,
+            width: f64::from(viewport_size.x()),
+            height: f64::from(viewport_size.y()),
+            z_near: 0.0,
+            z_far: 1.0
+        };
+        self.trace(|| TraceCommand::SetViewport { size: [viewport.width, viewport.height] });
+        let _:() = unsafe { msg_send![render_encoder, setViewport:viewport] };
+
+        if self.depth_stencil_state != nil {
+            let stencil_reference_value = self.stencil_reference_value;
+            let _:() = unsafe { msg_send![render_encoder, setDepthStencilState:self.depth_stencil_state] };
+            let _:() = unsafe { msg_send![render_encoder, setStencilReferenceValue:stencil_reference_value] };
+        }
+
+        let _:() = unsafe { msg_send![render_encoder, setVertexBuffer:frame_buffer offset:viewport_size_offset atIndex:AAPLVertexInputIndexViewportSize] };
+        self.trace(|| TraceCommand::vertex_bytes(AAPLVertexInputIndexViewportSize, std::slice::from_ref(&viewport_size)));
+
+        // Upload each mesh once, however many nodes draw it.
+        // Put the scene back once they're uploaded; we need `self` to upload them.
+        let scene = std::mem::take(&mut self.scene);
+        let mut mesh_offsets = Vec::with_capacity(scene_frame.used_meshes.len());
+        for &mesh_id in &scene_frame.used_meshes {
+            // Meshes that don't fit are left out, and the nodes that draw them skipped.
+            if let Some((vertices_allocation, indices_offset)) = scene.mesh(mesh_id).and_then(|mesh| self.upload_mesh(mesh)) {
+                mesh_offsets.push((mesh_id, vertices_allocation.offset, indices_offset));
+            }
+        }
+        self.scene = scene;
+
+        // One draw per node, only changing the pipeline when the blend mode changes
+        // and rebinding the vertices when the mesh changes.
+        let mut bound_blend_mode: Option<BlendMode> = None;
+        let mut bound_mesh: Option<MeshId> = None;
+        for item in &scene_frame.draw_list {
+            if bound_blend_mode != Some(item.blend_mode) {
+                let pipeline_desc = PipelineDesc { blend_mode: item.blend_mode, ..self.pipeline_desc.clone() };
+                let pipeline_state: id = match self.pipeline_cache.get_or_create(&pipeline_desc) {
+                    Ok(pipeline_state) => pipeline_state,
+                    Err(e) => {
+                        println!("Skipping node {}: {}", item.node.0, format_error_chain(&e));
+                        continue;
+                    }
+                };
+                self.set_render_pipeline_state(render_encoder, pipeline_state, &pipeline_desc);
+                bound_blend_mode = Some(item.blend_mode);
+            }
+            let &(_, vertices_offset, indices_offset) = match mesh_offsets.iter().find(|(mesh_id, _, _)| *mesh_id == item.mesh) {
+                Some(mesh_offsets) => mesh_offsets,
+                None => continue,
+            };
+            if bound_mesh != Some(item.mesh) {
+                let vertices_offset = vertices_offset as NSUInteger;
+                let _:() = unsafe { msg_send![render_encoder, setVertexBuffer:frame_buffer offset:vertices_offset atIndex:AAPLVertexInputIndexVertices] };
+                if let (Some(trace), Some(mesh)) = (self.trace.as_mut(), self.scene.mesh(item.mesh)) {
+                    trace.record(TraceCommand::vertex_bytes(AAPLVertexInputIndexVertices, mesh.vertices()));
+                }
+                bound_mesh = Some(item.mesh);
+            }
+
+            let object_uniforms = AAPLObjectUniforms::new(&item.world_transform, item.tint, item.depth);
+            let object_uniforms_allocation = match self.upload_to_frame_buffer(std::slice::from_ref(&object_uniforms)) {
+                Some(object_uniforms_allocation) => object_uniforms_allocation,
+                None => continue,
+            };
+            let object_uniforms_offset = object_uniforms_allocation.offset as NSUInteger;
+            let _:() = unsafe { msg_send![render_encoder, setVertexBuffer:frame_buffer offset:object_uniforms_offset atIndex:AAPLVertexInputIndexObjectUniforms] };
+            self.trace(|| TraceCommand::vertex_bytes(AAPLVertexInputIndexObjectUniforms, std::slice::from_ref(&object_uniforms)));
+
+            let mesh = match self.scene.mesh(item.mesh) {
+                Some(mesh) => mesh,
+                None => continue,
+            };
+            encode_draw(render_encoder, frame_buffer, mesh, indices_offset, 1);
+            if let Some(trace) = self.trace.as_mut() {
+                trace.record(TraceCommand::mesh_draw(mesh, 1));
+            }
+        }
+
+        self.encode_instanced_meshes(render_encoder, frame_buffer, &scene_frame.visible_instances);
+        if let Some((particles_buffer, draw_uniforms)) = &scene_frame.particles {
+            self.encode_particles(render_encoder, frame_buffer, *particles_buffer, draw_uniforms);
+        }
+        self.encode_textured_meshes(render_encoder, frame_buffer);
+
+        let _:() = unsafe { msg_send![render_encoder, endEncoding] };
+    }
+
+    /// The passes that draw a frame into the drawable:
+    /// the scene, cleared to the view's clear color (into the multisample texture if the view has one,
+    /// resolved into the drawable), the post-processing effects' passes, then the HUD, if it's shown.
+    ///
+    /// With post-processing, the scene is drawn into a transient texture instead of the drawable,
+    /// and each pass but the last draws into one too.
+    fn frame_graph(&self, drawable: id, draw_hud: bool) -> FrameGraph {
+        let drawable_size: CGSize = unsafe { msg_send![self.view, drawableSize] };
+        let color_pixel_format: MTLPixelFormat = unsafe { msg_send![self.view, colorPixelFormat] };
+        let depth_stencil_pixel_format: MTLPixelFormat = unsafe { msg_send![self.view, depthStencilPixelFormat] };
+        let sample_count: NSUInteger = unsafe { msg_send![self.view, sampleCount] };
+        let texture_desc = |pixel_format, sample_count| TextureDesc {
+            width: drawable_size.width as usize,
+            height: drawable_size.height as usize,
+            pixel_format,
+            sample_count,
+        };
+        let mut graph = RenderGraph::new();
+        let mut imported_textures = Vec::new();
+
+        let drawable_texture: id = unsafe { msg_send![drawable, texture] };
+        let drawable_id = graph.import_texture("Drawable", texture_desc(color_pixel_format, 1));
+        graph.keep(drawable_id);
+        imported_textures.push((drawable_id, drawable_texture));
+
+        let scene_output = if self.post_effects.is_empty() {
+            drawable_id
+        } else {
+            graph.create_texture("Scene Color", texture_desc(color_pixel_format, 1))
+        };
+        let clear_color: MTLClearColor = unsafe { msg_send![self.view, clearColor] };
+        let mut scene_color = Attachment::clear(scene_output, ClearValue::Color(clear_color.components()));
+        let multisample_color_texture: id = unsafe { msg_send![self.view, multisampleColorTexture] };
+        if multisample_color_texture != nil {
+            let multisample_id = graph.import_texture("Multisample Color", texture_desc(color_pixel_format, sample_count as usize));
+            imported_textures.push((multisample_id, multisample_color_texture));
+            scene_color = Attachment { texture: multisample_id, resolve_texture: Some(scene_output), ..scene_color };
+        }
+        let mut scene_pass = PassDesc::new("Scene");
+        scene_pass.color_attachments.push(scene_color);
+        let depth_stencil_texture: id = unsafe { msg_send![self.view, depthStencilTexture] };
+        if depth_stencil_texture != nil {
+            let depth_stencil_id = graph.import_texture("Depth Stencil", texture_desc(depth_stencil_pixel_format, sample_count as usize));
+            imported_textures.push((depth_stencil_id, depth_stencil_texture));
+            let depth: c_double = unsafe { msg_send![self.view, clearDepth] };
+            let stencil: u32 = unsafe { msg_send![self.view, clearStencil] };
+            scene_pass.depth_stencil_attachment = Some(Attachment::clear(depth_stencil_id, ClearValue::DepthStencil { depth, stencil }));
+        }
+        let scene_pass = graph.add_pass(scene_pass);
+
+        let mut post_passes = Vec::new();
+        let mut effect_input = scene_output;
+        for (effect_index, post_effect) in self.post_effects.iter().enumerate() {
+            let is_last_effect = effect_index + 1 == self.post_effects.len();
+            let passes = post_effect.effect.passes();
+            let mut pass_outputs: Vec<TextureId> = Vec::with_capacity(passes.len());
+            for (pass_index, post_pass) in passes.iter().enumerate() {
+                let texture = |input: PostInput| match input {
+                    PostInput::EffectInput => effect_input,
+                    PostInput::Pass(index) => pass_outputs[index],
+                };
+                let (source, bloom) = (texture(post_pass.source), post_pass.bloom.map(texture));
+                let output = if is_last_effect && pass_index + 1 == passes.len() {
+                    drawable_id
+                } else {
+                    graph.create_texture("Post-processing", texture_desc(color_pixel_format, 1))
+                };
+                let mut pass = PassDesc::new(post_fragment_function(&post_pass.shader));
+                // Every pixel's drawn over, so there's nothing to load,
+                // and clearing tells the graph nothing drawn before shows through.
+                pass.color_attachments.push(Attachment::clear(output, ClearValue::Color([0., 0., 0., 0.])));
+                pass.reads.push(source);
+                pass.reads.extend(bloom);
+                post_passes.push(FramePostPass { pass: graph.add_pass(pass), effect: effect_index, post_pass: *post_pass, source, bloom });
+                pass_outputs.push(output);
+            }
+            effect_input = *pass_outputs.last().unwrap();
+        }
+
+        let hud_pass = if draw_hud {
+            let mut hud_pass = PassDesc::new("HUD");
+            hud_pass.color_attachments.push(Attachment::load(drawable_id));
+            Some(graph.add_pass(hud_pass))
+        } else {
+            None
+        };
+        FrameGraph { graph, imported_textures, scene_pass, post_passes, hud_pass }
+    }
+
+    /// Draws one full-screen pass of a post-processing effect.
+    fn encode_post_pass(&mut self, command_buffer: id, render_pass_descriptor: id, frame_buffer: id, post_pass: &FramePostPass, textures: &GraphTextures) {
+        let shader = post_pass.post_pass.shader;
+        let pipeline_desc = PipelineDesc {
+            vertex_function: POST_PROCESS_VERTEX_SHADER_NAME.to_string(),
+            fragment_function: post_fragment_function(&shader).to_string(),
+            depth_pixel_format: MTLPixelFormatInvalid,
+            stencil_pixel_format: MTLPixelFormatInvalid,
+            blend_mode: BlendMode::Opaque,
+            sample_count: 1,
+            ..self.pipeline_desc.clone()
+        };
+        let pipeline_state = match self.pipeline_cache.get_or_create(&pipeline_desc) {
+            Ok(pipeline_state) => pipeline_state,
+            Err(e) => {
+                println!("Skipping the {} pass: {}", pipeline_desc.fragment_function, format_error_chain(&e));
+                return;
+            }
+        };
+        let uniforms_allocation = match &shader {
+            PostShader::GaussianBlur(uniforms) => self.upload_to_frame_buffer(std::slice::from_ref(uniforms)),
+            PostShader::BrightPass(uniforms) | PostShader::BloomComposite(uniforms) => self.upload_to_frame_buffer(std::slice::from_ref(uniforms)),
+            PostShader::ColorGrade(uniforms) => self.upload_to_frame_buffer(std::slice::from_ref(uniforms)),
+            PostShader::Vignette(uniforms) => self.upload_to_frame_buffer(std::slice::from_ref(uniforms)),
+        };
+        let uniforms_allocation = match uniforms_allocation {
+            Some(uniforms_allocation) => uniforms_allocation,
+            None => return,
+        };
+        let render_encoder: id = unsafe { msg_send![command_buffer, renderCommandEncoderWithDescriptor:render_pass_descriptor] };
+        let render_encoder_name = unsafe { NSString::alloc(nil).init_str(&pipeline_desc.fragment_function) };
+        let _:() = unsafe { msg_send![render_encoder, setLabel:render_encoder_name] };
+        self.set_render_pipeline_state(render_encoder, pipeline_state, &pipeline_desc);
+
+        let source = textures.get(post_pass.source);
+        let _:() = unsafe { msg_send![render_encoder, setFragmentTexture:source atIndex:AAPLTextureIndexPostProcessSource as NSUInteger] };
+        if let Some(bloom) = post_pass.bloom {
+            let bloom = textures.get(bloom);
+            let _:() = unsafe { msg_send![render_encoder, setFragmentTexture:bloom atIndex:AAPLTextureIndexBloom as NSUInteger] };
+        }
+        if let Some(lut) = &self.post_effects[post_pass.effect].lut {
+            let lut = lut.texture();
+            let _:() = unsafe { msg_send![render_encoder, setFragmentTexture:lut atIndex:AAPLTextureIndexColorLut as NSUInteger] };
+        }
+        let uniforms_offset = uniforms_allocation.offset as NSUInteger;
+        let _:() = unsafe { msg_send![render_encoder, setFragmentBuffer:frame_buffer offset:uniforms_offset atIndex:AAPLFragmentInputIndexPostProcessUniforms as NSUInteger] };
+
+        // One triangle that covers the view.
+        let primitive_type = mtl_primitive_type(PrimitiveTopology::Triangle);
+        let _:() = unsafe { msg_send![render_encoder, drawPrimitives:primitive_type vertexStart:0 as NSUInteger vertexCount:3 as NSUInteger] };
+        self.trace(|| TraceCommand::Draw { topology: PrimitiveTopology::Triangle, vertex_start: 0, vertex_count: 3, instance_count: 1 });
+        let _:() = unsafe { msg_send![render_encoder, endEncoding] };
+    }
+
+    /// Draws the HUD over the drawable in a render pass of its own,
+    /// so it's drawn once per pixel, after any multisample resolve, and never depth tested.
+    fn encode_hud(&mut self, command_buffer: id, render_pass_descriptor: id, frame_buffer: id, viewport_size_offset: NSUInteger, batch: &Batch2D) {
+        let render_encoder: id = unsafe { msg_send![command_buffer, renderCommandEncoderWithDescriptor:render_pass_descriptor] };
+        let render_encoder_name = unsafe { NSString::alloc(nil).init_str("HUD") };
+        let _:() = unsafe { msg_send![render_encoder, setLabel:render_encoder_name] };
+        let _:() = unsafe { msg_send![render_encoder, setVertexBuffer:frame_buffer offset:viewport_size_offset atIndex:AAPLVertexInputIndexViewportSize] };
+        let viewport_size = self.viewport_size;
+        self.trace(|| TraceCommand::vertex_bytes(AAPLVertexInputIndexViewportSize, std::slice::from_ref(&viewport_size)));
+
+        let hud_pipeline_desc = PipelineDesc {
+            depth_pixel_format: MTLPixelFormatInvalid,
+            stencil_pixel_format: MTLPixelFormatInvalid,
+            blend_mode: BlendMode::Alpha,
+            sample_count: 1,
+            ..self.pipeline_desc.clone()
+        };
+        let overlay_pipeline_desc = |vertex_function: &str, fragment_function: &str| PipelineDesc {
+            vertex_function: vertex_function.to_string(),
+            fragment_function: fragment_function.to_string(),
+            ..hud_pipeline_desc.clone()
+        };
+        if let Some(shapes) = &batch.shapes {
+            let pipeline_desc = overlay_pipeline_desc(VERTEX_SHADER_NAME, FRAGMENT_SHADER_NAME);
+            match self.pipeline_cache.get_or_create(&pipeline_desc) {
+                Ok(pipeline_state) => {
+                    self.set_render_pipeline_state(render_encoder, pipeline_state, &pipeline_desc);
+                    self.encode_mesh(render_encoder, frame_buffer, shapes, &AAPLObjectUniforms::new(&Matrix3::identity(), [1., 1., 1., 1.], 0.), 1);
+                }
+                Err(e) => println!("Skipping the HUD's shapes: {}", format_error_chain(&e)),
+            }
+        }
+        if let Some(text) = &batch.text {
+            let pipeline_desc = overlay_pipeline_desc(TEXTURED_VERTEX_SHADER_NAME, TEXT_FRAGMENT_SHADER_NAME);
+            match self.pipeline_cache.get_or_create(&pipeline_desc) {
+                Ok(pipeline_state) => {
+                    self.set_render_pipeline_state(render_encoder, pipeline_state, &pipeline_desc);
+                    let atlas = self.hud_overlay.as_ref().unwrap().atlas.clone();
+                    self.bind_texture(render_encoder, atlas.texture(), &glyph_atlas_sampler());
+                    self.encode_mesh(render_encoder, frame_buffer, text, &AAPLObjectUniforms::new(&Matrix3::identity(), [1., 1., 1., 1.], 0.), 1);
+                }
+                Err(e) => println!("Skipping the HUD's text: {}", format_error_chain(&e)),
+            }
+        }
+        let _:() = unsafe { msg_send![render_encoder, endEncoding] };
+    }
+
+    /// Copies `data` into the next free space in this frame's buffer.
+    ///
+    /// Returns `None`, and says so, if the frame has used up all the room it asked for.
+    fn upload_to_frame_buffer<T>(&mut self, data: &[T]) -> Option<RingAllocation> {
+        let length = std::mem::size_of_val(data);
+        match self.buffer_ring.allocate(length, BUFFER_OFFSET_ALIGNMENT) {
+            Some(allocation) => {
+                self.write_to_frame_buffer(allocation, data);
+                Some(allocation)
+            }
+            None => {
+                println!("Skipping a draw: there's no room left in this frame's buffer for {} more bytes", length);
+                None
+            }
+        }
+    }
+
+    /// Uploads a mesh's vertices and indices to this frame's buffer,
+    /// returning where the vertices went and the offset of the indices, if it has any.
+    fn upload_mesh<V>(&mut self, mesh: &Mesh<V>) -> Option<(RingAllocation, Option<usize>)> {
+        let vertices_allocation = self.upload_to_frame_buffer(mesh.vertices())?;
+        let indices_allocation = match mesh.indices() {
+            Some(IndexData::U16(indices)) => Some(self.upload_to_frame_buffer(indices)?),
+            Some(IndexData::U32(indices)) => Some(self.upload_to_frame_buffer(indices)?),
+            None => None,
+        };
+        Some((vertices_allocation, indices_allocation.map(|allocation| allocation.offset)))
+    }
+
+    /// Copies `data` into this frame's buffer at the given allocation.
+    fn write_to_frame_buffer<T>(&self, allocation: RingAllocation, data: &[T]) {
+        let frame_buffer = self.frame_buffers[allocation.slot];
+        unsafe {
+            let contents: *mut c_void = msg_send![frame_buffer, contents];
+            std::ptr::copy_nonoverlapping(
+                data.as_ptr() as *const u8,
+                (contents as *mut u8).add(allocation.offset),
+                allocation.length,
+            );
+        }
+    }
+
+    /// Summarises the most recent frames.
+    // The app itself only logs the statistics and shows them in the HUD,
+    // but this is how code that embeds the renderer gets at them.
+    #[allow(unused)]
+    pub fn frame_statistics(&self) -> FrameStatsSummary {
+        self.frame_statistics.lock().unwrap().summary()
+    }
+
+    /// Prints the frame statistics every `interval` seconds,
+    /// or stops printing them if `None`.
+    pub fn set_frame_stats_log_interval(&mut self, interval: Option<f64>) {
+        self.frame_stats_logger = interval.map(FrameStatsLogger::new);
+    }
 }
 
 impl MetalViewDelegate for Renderer {
@@ -118,9 +1335,121 @@ impl MetalViewDelegate for Renderer {
         self.viewport_size = new_viewport_size;
     }
 
-    fn draw_in_metal_view(&self) {
+    fn metal_view_pixel_formats_did_change(&mut self) {
+        let pipeline_desc = pipeline_desc_for_view(self.view);
+        if pipeline_desc != self.pipeline_desc {
+            // Build the pipeline now, rather than in the middle of drawing.
+            if let Err(e) = self.pipeline_cache.get_or_create(&pipeline_desc) {
+                println!("Unable to build a pipeline for the view's new pixel formats: {}", format_error_chain(&e));
+            }
+            self.pipeline_desc = pipeline_desc;
+        }
+
+        // Only test depth and stencil if the view has somewhere to keep them.
+        let has_depth_stencil = view_has_depth_stencil(self.view);
+        if has_depth_stencil && self.depth_stencil_state == nil {
+            self.depth_stencil_state = new_depth_stencil_state(self.device, &self.depth_stencil_desc);
+        } else if !has_depth_stencil && self.depth_stencil_state != nil {
+            unsafe { objc_release(self.depth_stencil_state) };
+            self.depth_stencil_state = nil;
+        }
+    }
+
+    fn metal_view_will_begin_frame(&mut self, frame_tick: &FrameTick) {
+        let mut frame_statistics = self.frame_statistics.lock().unwrap();
+        self.current_frame_index = frame_statistics.begin_frame(frame_tick.time, frame_tick.missed_vsyncs);
+        if let Some(logger) = self.frame_stats_logger.as_mut() {
+            logger.log_if_due(frame_tick.time, &frame_statistics);
+        }
+        drop(frame_statistics);
+        self.reload_changed_files(frame_tick.time);
+    }
+
+    fn update_in_metal_view(&mut self, time_step: f64) {
+        // While we're recording, the recording says how far to move on each frame.
+        if self.recording.is_none() {
+            self.update_simulation(time_step);
+        }
+    }
+
+    fn draw_in_metal_view(&mut self, _interpolation_alpha: f64) {
         //+ println!("In draw in metal view");
+        // Wait until the GPU has finished with the buffer we're about to reuse.
+        unsafe { dispatch_semaphore_wait(self.in_flight_semaphore, DISPATCH_TIME_FOREVER) };
+
+        let encode_start = Instant::now();
         let pool = unsafe { NSAutoreleasePool::new(nil) };
+        let recording_frame = self.begin_recording_frame();
+
+        // Work out what to draw, and which meshes that needs.
+        let draw_list = self.scene.draw_list();
+        let mut used_meshes: Vec<MeshId> = draw_list.iter().map(|item| item.mesh).collect();
+        used_meshes.sort();
+        used_meshes.dedup();
+
+        // Claim this frame's buffer and lay out our data in it.
+        let viewport_size: vector_uint2 = self.viewport_size;
+        let _viewport_size_size = std::mem::size_of_val(&viewport_size);
+        let object_uniforms_size = std::mem::size_of::<AAPLObjectUniforms>();
+        let mut allocation_lengths = vec![_viewport_size_size];
+        for mesh in used_meshes.iter().filter_map(|&mesh_id| self.scene.mesh(mesh_id)) {
+            allocation_lengths.push(mesh.vertex_bytes_len());
+            allocation_lengths.push(mesh.indices().map_or(0, index_bytes_len));
+        }
+        allocation_lengths.extend(draw_list.iter().map(|_| object_uniforms_size));
+        // Cull the instances now, so we know how much room the ones we draw need.
+        let viewport_size_in_pixels = [viewport_size.x() as f32, viewport_size.y() as f32];
+        let visible_instances: Vec<Vec<AAPLInstance>> = self.instanced_meshes.iter()
+            .map(|instanced_mesh| instanced_mesh.visible_instances(viewport_size_in_pixels))
+            .collect();
+        for (instanced_mesh, instances) in self.instanced_meshes.iter().zip(&visible_instances) {
+            if !instances.is_empty() {
+                allocation_lengths.push(std::mem::size_of_val(instances.as_slice()));
+                allocation_lengths.push(instanced_mesh.mesh.vertex_bytes_len());
+                allocation_lengths.push(instanced_mesh.mesh.indices().map_or(0, index_bytes_len));
+                allocation_lengths.push(object_uniforms_size);
+            }
+        }
+        // The particles' uniforms for each time step since the last frame, and for drawing them.
+        let particle_uniforms = self.particles.as_mut().map(|particles| {
+            let step_uniforms: Vec<AAPLParticleUniforms> = std::mem::take(&mut particles.pending_time_steps).into_iter()
+                .map(|time_step| particles.system.next_uniforms(time_step, viewport_size_in_pixels))
+                .collect();
+            let draw_uniforms = particles.system.settings.uniforms(0., viewport_size_in_pixels, 0);
+            (particles.buffer, step_uniforms, draw_uniforms)
+        });
+        if let Some((_, step_uniforms, _)) = &particle_uniforms {
+            allocation_lengths.extend((0..=step_uniforms.len()).map(|_| std::mem::size_of::<AAPLParticleUniforms>()));
+        }
+        let vertex_labels = self.vertex_labels.iter().filter_map(|vertex_labels| vertex_labels.textured_mesh.as_ref());
+        for textured_mesh in self.textured_meshes.iter().chain(vertex_labels) {
+            allocation_lengths.push(textured_mesh.mesh.vertex_bytes_len());
+            allocation_lengths.push(textured_mesh.mesh.indices().map_or(0, index_bytes_len));
+            allocation_lengths.push(object_uniforms_size);
+        }
+        for post_effect in &self.post_effects {
+            allocation_lengths.extend(post_effect.effect.passes().iter().map(|post_pass| post_uniforms_len(&post_pass.shader)));
+        }
+        let hud_batch = self.hud_batch();
+        if let Some(hud_batch) = &hud_batch {
+            if let Some(shapes) = &hud_batch.shapes {
+                allocation_lengths.push(shapes.vertex_bytes_len());
+                allocation_lengths.push(shapes.indices().map_or(0, index_bytes_len));
+                allocation_lengths.push(object_uniforms_size);
+            }
+            if let Some(text) = &hud_batch.text {
+                allocation_lengths.push(text.vertex_bytes_len());
+                allocation_lengths.push(text.indices().map_or(0, index_bytes_len));
+                allocation_lengths.push(object_uniforms_size);
+            }
+        }
+        let required_bytes = BufferRing::required_bytes(&allocation_lengths, BUFFER_OFFSET_ALIGNMENT);
+        let frame_slot = self.buffer_ring.begin_frame(required_bytes);
+        if let Some(new_capacity) = frame_slot.grow_to {
+            unsafe { objc_release(self.frame_buffers[frame_slot.index]) };
+            self.frame_buffers[frame_slot.index] = new_frame_buffer(self.device, frame_slot.index, new_capacity);
+        }
+        let frame_buffer = self.frame_buffers[frame_slot.index];
 
         // Create a new command buffer for each render pass to the current drawable.
         let command_queue: id = self.command_queue;
@@ -128,109 +1457,339 @@ impl MetalViewDelegate for Renderer {
         let label_name = unsafe { NSString::alloc(nil).init_str("MyCommand") };
         let _:() = unsafe { msg_send![command_buffer, setLabel:label_name] };
 
-        // Obtain a renderPassDescriptor generated from the view's drawable textures.
-        let render_pass_descriptor: id = unsafe { msg_send![self.view, currentRenderPassDescriptor] };
+        // Move the particles on first. Metal makes the render pass that draws them wait for it.
+        if let Some((particles_buffer, step_uniforms, _)) = &particle_uniforms {
+            if !step_uniforms.is_empty() {
+                self.encode_particle_update(command_buffer, frame_buffer, *particles_buffer, step_uniforms);
+            }
+        }
+
+        //+ println!("size of viewport_size is {}", _viewport_size_size);
+        let viewport_size_allocation = self.upload_to_frame_buffer(std::slice::from_ref(&viewport_size));
+        let viewport_size_offset = viewport_size_allocation.map_or(0, |allocation| allocation.offset as NSUInteger);
 
-        if render_pass_descriptor != nil {
-            let render_encoder:id = unsafe {
-                msg_send![command_buffer,
-                          renderCommandEncoderWithDescriptor:render_pass_descriptor]
-            };
-            let render_encoder_name = unsafe { NSString::alloc(nil).init_str("MyRenderEncoder") };
-            let _:() = unsafe {msg_send![render_encoder, setLabel:render_encoder_name] };
-
-            let viewport_size: vector_uint2 = self.viewport_size;
-            //+ println!("Width and height of viewport are ({},{})", viewport_size.x(), viewport_size.y());
-            let viewport = MTLViewport {
-                origin_x: 0.0,
-                origin_y: 0.0,
-                width: f64::from(viewport_size.x()),
-                height: f64::from(viewport_size.y()),
-                z_near: 0.0,
-                z_far: 1.0
+        let scene_frame = SceneFrame {
+            draw_list,
+            used_meshes,
+            visible_instances,
+            particles: particle_uniforms.map(|(particles_buffer, _, draw_uniforms)| (particles_buffer, draw_uniforms)),
+        };
+
+        // Lay out this frame's passes, and draw each of them.
+        // Without the viewport size there's nothing we can draw, but the command buffer
+        // still has to be committed to let the CPU reuse this frame's buffer.
+        let current_drawable: id = match viewport_size_allocation {
+            Some(_) => unsafe { msg_send![self.view, currentDrawable] },
+            None => nil,
+        };
+        if current_drawable != nil {
+            let frame_graph = self.frame_graph(current_drawable, hud_batch.is_some());
+            match frame_graph.graph.compile() {
+                Ok(compiled_graph) => {
+                    let transient_textures = self.transient_textures.textures_for(&compiled_graph);
+                    let textures = GraphTextures::new(&compiled_graph, &frame_graph.imported_textures, &transient_textures);
+                    for pass in &compiled_graph.passes {
+                        let render_pass_descriptor = new_render_pass_descriptor(&frame_graph.graph, pass, &textures);
+                        self.trace(|| begin_pass_command(&frame_graph.graph, pass));
+                        if pass.id == frame_graph.scene_pass {
+                            self.encode_scene_pass(command_buffer, render_pass_descriptor, frame_buffer, viewport_size_offset, &scene_frame);
+                        } else if let Some(post_pass) = frame_graph.post_passes.iter().find(|post_pass| post_pass.pass == pass.id) {
+                            self.encode_post_pass(command_buffer, render_pass_descriptor, frame_buffer, post_pass, &textures);
+                        } else if Some(pass.id) == frame_graph.hud_pass {
+                            if let Some(hud_batch) = &hud_batch {
+                                self.encode_hud(command_buffer, render_pass_descriptor, frame_buffer, viewport_size_offset, hud_batch);
+                            }
+                        }
+                        self.trace(|| TraceCommand::EndPass);
+                    }
+                }
+                Err(e) => println!("Skipping the frame: {}", format_error_chain(&e)),
+            }
+            self.encode_screenshot(command_buffer, current_drawable);
+            if recording_frame {
+                self.encode_recording_frame(command_buffer, current_drawable);
+            }
+            let _:() = unsafe { msg_send![command_buffer, presentDrawable:current_drawable] };
+            self.trace_present();
+        }
+
+        // Pick up the GPU timings once the command buffer has run,
+        // and let the CPU reuse this frame's buffer.
+        let frame_statistics = self.frame_statistics.clone();
+        let frame_index = self.current_frame_index;
+        let in_flight_semaphore = self.in_flight_semaphore;
+        let completed_handler = ConcreteBlock::new(move |completed_buffer: id| {
+            let gpu_start_time: c_double = unsafe { msg_send![completed_buffer, GPUStartTime] };
+            let gpu_end_time: c_double = unsafe { msg_send![completed_buffer, GPUEndTime] };
+            frame_statistics.lock().unwrap().record_gpu_times(frame_index, gpu_start_time, gpu_end_time);
+            unsafe { dispatch_semaphore_signal(in_flight_semaphore) };
+        }).copy();
+        let _:() = unsafe { msg_send![command_buffer, addCompletedHandler:&*completed_handler] };
+
+        let _:() = unsafe { msg_send![command_buffer, commit] };
+        unsafe { pool.drain() };
+
+        let encode_time = encode_start.elapsed().as_secs_f64();
+        self.frame_statistics.lock().unwrap().record_cpu_encode_time(frame_index, encode_time);
+    }
+
+    fn metal_view_key_down(&mut self, characters: &str) -> bool {
+        if characters == HUD_TOGGLE_KEY {
+            self.hud_visible = !self.hud_visible;
+            return true;
+        }
+        if characters == SCREENSHOT_KEY {
+            self.take_screenshot();
+            return true;
+        }
+        false
+    }
+}
+
+impl Drop for Renderer {
+    fn drop(&mut self) {
+        // Take every slot, so we know the GPU has finished with all our frames,
+        // then give them back: libdispatch won't free a semaphore below its starting value.
+        for _ in 0..self.buffer_ring.slot_count() {
+            unsafe { dispatch_semaphore_wait(self.in_flight_semaphore, DISPATCH_TIME_FOREVER) };
+        }
+        for _ in 0..self.buffer_ring.slot_count() {
+            unsafe { dispatch_semaphore_signal(self.in_flight_semaphore) };
+        }
+        for frame_buffer in self.frame_buffers.drain(..) {
+            unsafe { objc_release(frame_buffer) };
+        }
+        if self.depth_stencil_state != nil {
+            unsafe { objc_release(self.depth_stencil_state) };
+        }
+        unsafe {
+            objc_release(self.in_flight_semaphore);
+            objc_release(self.command_queue);
+            objc_release(self.device);
+        }
+        // The pipeline cache, sampler cache and transient textures release their own objects as they're dropped.
+    }
+}
+
+/// The command that traces the start of a pass, from its first color attachment.
+fn begin_pass_command(graph: &RenderGraph, pass: &CompiledPass) -> TraceCommand {
+    let attachment = pass.color_attachments.first();
+    let texture_desc = attachment.map(|attachment| graph.texture_desc(attachment.texture));
+    let clear_color = match attachment {
+        Some(CompiledAttachment { load_action: LoadAction::Clear, clear_value: Some(ClearValue::Color(color)), .. }) => Some(*color),
+        _ => None,
+    };
+    TraceCommand::BeginPass {
+        name: pass.name.clone(),
+        size: texture_desc.map_or([0, 0], |desc| [desc.width, desc.height]),
+        sample_count: texture_desc.map_or(1, |desc| desc.sample_count),
+        clear_color,
+    }
+}
+
+/// The size of some index data, in bytes.
+fn index_bytes_len(indices: &IndexData) -> usize {
+    indices.len() * indices.index_size()
+}
+
+/// Encodes the draw call for `instance_count` copies of a mesh whose vertices are already bound.
+///
+/// `indices_offset` is where the mesh's indices are in `frame_buffer`, if it has any.
+fn encode_draw<V>(render_encoder: id, frame_buffer: id, mesh: &Mesh<V>, indices_offset: Option<usize>, instance_count: usize) {
+    let instance_count = instance_count as NSUInteger;
+    let primitive_type = mtl_primitive_type(mesh.topology());
+    let draw_range = mesh.draw_range();
+    match (mesh.indices(), indices_offset) {
+        (Some(indices), Some(indices_offset)) => {
+            let index_count = draw_range.count as NSUInteger;
+            let index_type = mtl_index_type(indices);
+            let index_buffer_offset = (indices_offset + draw_range.start * indices.index_size()) as NSUInteger;
+            let _:() = unsafe {
+                msg_send![render_encoder, drawIndexedPrimitives:primitive_type
+                                                     indexCount:index_count
+                                                      indexType:index_type
+                                                    indexBuffer:frame_buffer
+                                              indexBufferOffset:index_buffer_offset
+                                                  instanceCount:instance_count]
             };
-            let _:() = unsafe { msg_send![render_encoder, setViewport:viewport] };
+        }
+        _ => {
+            let vertex_start = draw_range.start as NSUInteger;
+            let vertex_count = draw_range.count as NSUInteger;
+            let _:() = unsafe { msg_send![render_encoder, drawPrimitives:primitive_type vertexStart:vertex_start vertexCount:vertex_count instanceCount:instance_count] };
+        }
+    }
+}
+
+/// Creates one of the shared buffers we write each frame's data into.
+fn new_frame_buffer(device: id, slot: usize, length: usize) -> id {
+    let length = length as NSUInteger;
+    let frame_buffer: id = unsafe { msg_send![device, newBufferWithLength:length options:MTLResourceStorageModeShared] };
+    let label = unsafe { NSString::alloc(nil).init_str(&format!("Frame Buffer {}", slot)) };
+    let _:() = unsafe { msg_send![frame_buffer, setLabel:label] };
+    frame_buffer
+}
 
-            let pipeline_state: id = self.pipeline_state;
-            let _:() = unsafe { msg_send![render_encoder, setRenderPipelineState:pipeline_state] };
+// From System/Library/Frameworks/Metal.framework/Versions/A/Headers/MTLTypes.h
+// typedef struct { NSUInteger x, y, z; } MTLOrigin;
+#[repr(C)]
+struct MTLOrigin {
+    x: NSUInteger,
+    y: NSUInteger,
+    z: NSUInteger,
+}
 
-            let triangle_vertices = AAPLVertices::default();
-            let _tv_size = std::mem::size_of_val(&triangle_vertices);
-            //+ println!("size of triangle vertices is {}", _tv_size);
-            let _:() = unsafe { msg_send![render_encoder, setVertexBytes:&triangle_vertices length:_tv_size atIndex:AAPLVertexInputIndexVertices] };
+/// A drawable's texture being copied into a shared buffer, for a screenshot.
+struct FrameCapture {
+    buffer: id,
+    width: usize,
+    height: usize,
+    bytes_per_row: usize,
+    pixel_format: CapturePixelFormat,
+}
+
+impl FrameCapture {
+    /// The copy, once the command buffer that makes it has completed. Lets go of the buffer.
+    fn read_back(self) -> CapturedFrame {
+        let length = self.bytes_per_row * self.height;
+        let contents: *const u8 = unsafe { msg_send![self.buffer, contents] };
+        let bytes = unsafe { std::slice::from_raw_parts(contents, length) }.to_vec();
+        unsafe { objc_release(self.buffer) };
+        CapturedFrame {
+            width: self.width,
+            height: self.height,
+            bytes_per_row: self.bytes_per_row,
+            pixel_format: self.pixel_format,
+            bytes,
+        }
+    }
+}
 
-            let _viewport_size_size = std::mem::size_of_val(&viewport_size);
-            //+ println!("size of viewport_size is {}", _viewport_size_size);
-            let _:() = unsafe { msg_send![render_encoder, setVertexBytes:&viewport_size length:_viewport_size_size atIndex:AAPLVertexInputIndexViewportSize] };
+/// A recording in progress: when its frames are, and where to send them once they're read back.
+struct GpuRecording {
+    timeline: RecordingTimeline,
+    /// To the thread writing them out, which finishes once this and every copy of it have gone.
+    frames: mpsc::Sender<CapturedFrame>,
+}
 
-            let _:() = unsafe { msg_send![render_encoder, drawPrimitives:MTLPrimitiveTypeTriangle vertexStart:0 vertexCount:3] };
+/// Whether the layer made the drawable so it can be copied.
+fn drawable_can_be_copied(drawable: id) -> bool {
+    let texture: id = unsafe { msg_send![drawable, texture] };
+    let framebuffer_only: BOOL = unsafe { msg_send![texture, isFramebufferOnly] };
+    framebuffer_only == NO
+}
 
-            let _:() = unsafe { msg_send![render_encoder, endEncoding] };
+/// Encodes a copy of a drawable into a new shared buffer, if we can read its pixel format back.
+fn encode_drawable_capture(device: id, command_buffer: id, drawable: id) -> Result<FrameCapture, ScreenshotError> {
+    let texture: id = unsafe { msg_send![drawable, texture] };
+    let pixel_format: MTLPixelFormat = unsafe { msg_send![texture, pixelFormat] };
+    match color_pixel_format_name(pixel_format).and_then(CapturePixelFormat::from_name) {
+        Some(capture_pixel_format) => Ok(encode_frame_capture(device, command_buffer, texture, capture_pixel_format)),
+        None => Err(ScreenshotError::UnsupportedPixelFormat(pixel_format.to_string())),
+    }
+}
 
-            let _current_drawable: id = unsafe { msg_send![self.view, currentDrawable] };
-            let _:() = unsafe { msg_send![command_buffer, presentDrawable:_current_drawable] };
+/// Reads a copy back once the command buffer that makes it has completed, and hands it on.
+fn add_capture_handler(command_buffer: id, frame_capture: FrameCapture, receive: impl FnOnce(CapturedFrame) + 'static) {
+    // Metal only calls the handler once, but blocks can be called again, so it takes what it needs.
+    let pending = Cell::new(Some((frame_capture, receive)));
+    let completed_handler = ConcreteBlock::new(move |_completed_buffer: id| {
+        if let Some((frame_capture, receive)) = pending.take() {
+            receive(frame_capture.read_back());
         }
+    }).copy();
+    let _:() = unsafe { msg_send![command_buffer, addCompletedHandler:&*completed_handler] };
+}
 
-        let _:() = unsafe { msg_send![command_buffer, commit] };
-        unsafe { pool.drain() };
+/// Encodes a copy of a texture into a new shared buffer, rows top first with no padding.
+fn encode_frame_capture(device: id, command_buffer: id, texture: id, pixel_format: CapturePixelFormat) -> FrameCapture {
+    let width: NSUInteger = unsafe { msg_send![texture, width] };
+    let height: NSUInteger = unsafe { msg_send![texture, height] };
+    let bytes_per_row = width * pixel_format.bytes_per_pixel() as NSUInteger;
+    let length = bytes_per_row * height;
+    let buffer: id = unsafe { msg_send![device, newBufferWithLength:length options:MTLResourceStorageModeShared] };
+    let origin = MTLOrigin { x: 0, y: 0, z: 0 };
+    let size = MTLSize { width, height, depth: 1 };
+    unsafe {
+        let label = NSString::alloc(nil).init_str("Screenshot");
+        let _:() = msg_send![buffer, setLabel:label];
+        let blit_encoder: id = msg_send![command_buffer, blitCommandEncoder];
+        let _:() = msg_send![blit_encoder, setLabel:label];
+        let _:() = msg_send![label, release];
+        let _:() = msg_send![blit_encoder, copyFromTexture:texture
+                                                 sourceSlice:0 as NSUInteger
+                                                 sourceLevel:0 as NSUInteger
+                                                sourceOrigin:origin
+                                                  sourceSize:size
+                                                    toBuffer:buffer
+                                           destinationOffset:0 as NSUInteger
+                                      destinationBytesPerRow:bytes_per_row
+                                    destinationBytesPerImage:length];
+        let _:() = msg_send![blit_encoder, endEncoding];
+    }
+    FrameCapture {
+        buffer,
+        width: width as usize,
+        height: height as usize,
+        bytes_per_row: bytes_per_row as usize,
+        pixel_format,
     }
 }
 
-// From AAPLShaderTypes.h:
-// Buffer index values shared between shader and C code to ensure Metal shader buffer inputs
-// match Metal API buffer set calls.
-// typedef enum AAPLVertexInputIndex
-// {
-//   AAPLVertexInputIndexVertices     = 0,
-//   AAPLVertexInputIndexViewportSize = 1,
-// } AAPLVertexInputIndex;
-#[allow(non_upper_case_globals)]
-static AAPLVertexInputIndexVertices: c_uint     = 0;
-#[allow(non_upper_case_globals)]
-static AAPLVertexInputIndexViewportSize: c_uint = 1;
-//
-//  This structure defines the layout of vertices sent to the vertex
-//  shader. This header is shared between the .metal shader and C code, to guarantee that
-//  the layout of the vertex array in the C code matches the layout that the .metal
-//  vertex shader expects.
-// typedef struct
-// {
-//   vector_float2 position;
-//   vector_float4 color;
-// } AAPLVertex;
-#[repr(C, packed(4))]
-struct AAPLVertex {
-    position: vector_float2,
-    color: vector_float4,
-}
-//
-// static const AAPLVertex triangleVertices[] =
-// {
-//     // 2D positions,    RGBA colors
-//     { {  250,  -250 }, { 1, 0, 0, 1 } },
-//     { { -250,  -250 }, { 0, 1, 0, 1 } },
-//     { {    0,   250 }, { 0, 0, 1, 1 } },
-// };
-struct AAPLVertices {
-    _vertices: [AAPLVertex; 3],
-}
-impl Default for AAPLVertices {
-    fn default() -> Self {
-        AAPLVertices {
-            _vertices: [
-                AAPLVertex {
-                    position: vector_float2::new(250., -250.),
-                    color: vector_float4::new(1., 0., 0., 1.),
-                },
-                AAPLVertex {
-                    position: vector_float2::new(-250., -250.),
-                    color: vector_float4::new(0., 1., 0., 1.),
-                },
-                AAPLVertex {
-                    position: vector_float2::new(0., 250.),
-                    color: vector_float4::new(0., 0., 1., 1.),
-                },
-            ]
+/// Creates a shared buffer holding the particles, for the GPU to update in place.
+fn new_particle_buffer(device: id, particles: &[AAPLParticle]) -> id {
+    let length = std::mem::size_of_val(particles) as NSUInteger;
+    let buffer: id = unsafe { msg_send![device, newBufferWithBytes:particles.as_ptr() as *const c_void length:length options:MTLResourceStorageModeShared] };
+    let label = unsafe { NSString::alloc(nil).init_str("Particles") };
+    let _:() = unsafe { msg_send![buffer, setLabel:label] };
+    buffer
+}
+
+/// Our pipeline description, with the pixel formats and sample count of the view we draw in.
+fn pipeline_desc_for_view(view: id) -> PipelineDesc {
+    let pixel_format: MTLPixelFormat = unsafe { msg_send![view, colorPixelFormat] };
+    let depth_stencil_pixel_format: MTLPixelFormat = unsafe { msg_send![view, depthStencilPixelFormat] };
+    let sample_count: NSUInteger = unsafe { msg_send![view, sampleCount] };
+    PipelineDesc {
+        depth_pixel_format: depth_attachment_pixel_format(depth_stencil_pixel_format),
+        stencil_pixel_format: stencil_attachment_pixel_format(depth_stencil_pixel_format),
+        sample_count: sample_count as usize,
+        ..PipelineDesc::new(VERTEX_SHADER_NAME, FRAGMENT_SHADER_NAME, pixel_format)
+    }
+}
+
+/// Whether the view has a depth or stencil attachment.
+fn view_has_depth_stencil(view: id) -> bool {
+    let depth_stencil_pixel_format: MTLPixelFormat = unsafe { msg_send![view, depthStencilPixelFormat] };
+    depth_stencil_pixel_format != MTLPixelFormatInvalid
+}
+
+/// The pipeline's depth attachment format for a view's depth stencil format.
+fn depth_attachment_pixel_format(depth_stencil_pixel_format: MTLPixelFormat) -> MTLPixelFormat {
+    if pixel_format_has_depth(depth_stencil_pixel_format) {
+        depth_stencil_pixel_format
+    } else {
+        MTLPixelFormatInvalid
+    }
+}
+
+/// The pipeline's stencil attachment format for a view's depth stencil format.
+fn stencil_attachment_pixel_format(depth_stencil_pixel_format: MTLPixelFormat) -> MTLPixelFormat {
+    if pixel_format_has_stencil(depth_stencil_pixel_format) {
+        depth_stencil_pixel_format
+    } else {
+        MTLPixelFormatInvalid
+    }
+}
+
+/// A pipeline cache for `library`, using the binary archive file if there is one.
+fn new_pipeline_cache(device: id, library: id, pipeline_archive: Option<&Path>) -> PipelineCache {
+    let mut pipeline_cache = PipelineCache::new(device, library);
+    if let Some(path) = pipeline_archive {
+        if let Err(e) = pipeline_cache.use_binary_archive(path) {
+            // We can still build the pipelines, just more slowly.
+            println!("Unable to use the pipeline archive {}: {}", path.display(), format_error_chain(&e));
         }
     }
+    pipeline_cache
 }
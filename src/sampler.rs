@@ -0,0 +1,254 @@
+//! How textures are sampled
+//!
+//! A `SamplerDesc` describes filtering and addressing
+//! in the same terms as `MTLSamplerDescriptor`.
+//! The texture module makes an `MTLSamplerState` from it for the GPU,
+//! and `SamplerDesc::sample` works out the same result on the CPU
+//! for the software rasterizer.
+
+use crate::image::{TextureData, decode_channel};
+
+/// How texels are combined within one mipmap level.
+///
+/// These match `MTLSamplerMinMagFilter`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum MinMagFilter {
+    Nearest,
+    Linear,
+}
+
+/// How mipmap levels are chosen and combined.
+///
+/// These match `MTLSamplerMipFilter`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum MipFilter {
+    /// Always use the first level.
+    NotMipmapped,
+    Nearest,
+    Linear,
+}
+
+/// What happens to texture coordinates outside 0 to 1.
+///
+/// These match `MTLSamplerAddressMode`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum AddressMode {
+    ClampToEdge,
+    MirrorClampToEdge,
+    Repeat,
+    MirrorRepeat,
+    /// Outside the texture is transparent black.
+    ClampToZero,
+}
+
+/// Everything that goes into an `MTLSamplerState`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct SamplerDesc {
+    /// Used when the texture is drawn smaller than it is.
+    pub min_filter: MinMagFilter,
+    /// Used when the texture is drawn larger than it is.
+    pub mag_filter: MinMagFilter,
+    pub mip_filter: MipFilter,
+    pub address_mode_u: AddressMode,
+    pub address_mode_v: AddressMode,
+}
+
+impl Default for SamplerDesc {
+    /// Trilinear filtering, clamped to the edges.
+    fn default() -> Self {
+        SamplerDesc {
+            min_filter: MinMagFilter::Linear,
+            mag_filter: MinMagFilter::Linear,
+            mip_filter: MipFilter::Linear,
+            address_mode_u: AddressMode::ClampToEdge,
+            address_mode_v: AddressMode::ClampToEdge,
+        }
+    }
+}
+
+impl SamplerDesc {
+    /// Texel for texel, for drawing a texture at its own size.
+    pub fn nearest() -> Self {
+        SamplerDesc {
+            min_filter: MinMagFilter::Nearest,
+            mag_filter: MinMagFilter::Nearest,
+            mip_filter: MipFilter::NotMipmapped,
+            ..SamplerDesc::default()
+        }
+    }
+
+    /// Samples a texture at the given texture coordinates (from its top-left corner)
+    /// and level of detail (log2 of how many texels a pixel covers), as the GPU would.
+    pub fn sample(&self, texture: &TextureData, texture_coordinate: [f32; 2], level_of_detail: f32) -> [f32; 4] {
+        let filter = if level_of_detail > 0. { self.min_filter } else { self.mag_filter };
+        let last_level = (texture.levels.len() - 1) as f32;
+        let level_of_detail = level_of_detail.max(0.).min(last_level);
+        match self.mip_filter {
+            MipFilter::NotMipmapped => self.sample_level(texture, 0, filter, texture_coordinate),
+            MipFilter::Nearest => {
+                let level = (level_of_detail + 0.5).floor() as usize;
+                self.sample_level(texture, level, filter, texture_coordinate)
+            }
+            MipFilter::Linear => {
+                let level = level_of_detail.floor();
+                let fraction = level_of_detail - level;
+                let level = level as usize;
+                let lower = self.sample_level(texture, level, filter, texture_coordinate);
+                if fraction == 0. {
+                    return lower;
+                }
+                let upper = self.sample_level(texture, level + 1, filter, texture_coordinate);
+                mix(lower, upper, fraction)
+            }
+        }
+    }
+
+    fn sample_level(&self, texture: &TextureData, level: usize, filter: MinMagFilter, texture_coordinate: [f32; 2]) -> [f32; 4] {
+        let image = &texture.levels[level];
+        let (width, height) = (image.width() as f32, image.height() as f32);
+        let [u, v] = texture_coordinate;
+        match filter {
+            MinMagFilter::Nearest => {
+                let x = (u * width).floor() as i64;
+                let y = (v * height).floor() as i64;
+                self.texel(texture, level, x, y)
+            }
+            MinMagFilter::Linear => {
+                // The four texels whose centres surround the point.
+                let x = u * width - 0.5;
+                let y = v * height - 0.5;
+                let (x0, y0) = (x.floor(), y.floor());
+                let (fraction_x, fraction_y) = (x - x0, y - y0);
+                let (x0, y0) = (x0 as i64, y0 as i64);
+                let top = mix(self.texel(texture, level, x0, y0), self.texel(texture, level, x0 + 1, y0), fraction_x);
+                let bottom = mix(self.texel(texture, level, x0, y0 + 1), self.texel(texture, level, x0 + 1, y0 + 1), fraction_x);
+                mix(top, bottom, fraction_y)
+            }
+        }
+    }
+
+    /// The texel at a position that might be outside the level, after addressing.
+    fn texel(&self, texture: &TextureData, level: usize, x: i64, y: i64) -> [f32; 4] {
+        let image = &texture.levels[level];
+        match (address(self.address_mode_u, x, image.width()), address(self.address_mode_v, y, image.height())) {
+            (Some(x), Some(y)) => texel_fetch(texture, level, x, y),
+            _ => [0.; 4],
+        }
+    }
+}
+
+/// Where a texel position ends up inside a level of the given size,
+/// or `None` if it's outside and the address mode says to use transparent black.
+fn address(address_mode: AddressMode, position: i64, size: usize) -> Option<usize> {
+    let size = size as i64;
+    let position = match address_mode {
+        AddressMode::ClampToEdge => position.clamp(0, size - 1),
+        AddressMode::MirrorClampToEdge => {
+            let mirrored = if position < 0 { -position - 1 } else { position };
+            mirrored.min(size - 1)
+        }
+        AddressMode::Repeat => position.rem_euclid(size),
+        AddressMode::MirrorRepeat => {
+            let position = position.rem_euclid(size * 2);
+            if position < size { position } else { size * 2 - 1 - position }
+        }
+        AddressMode::ClampToZero => {
+            if position < 0 || position >= size {
+                return None;
+            }
+            position
+        }
+    };
+    Some(position as usize)
+}
+
+/// Reads one texel as RGBA from 0 to 1, converted to linear if the texture is sRGB,
+/// like `texture2d::read` in a shader.
+pub fn texel_fetch(texture: &TextureData, level: usize, x: usize, y: usize) -> [f32; 4] {
+    let texel = texture.levels[level].texel(x, y);
+    let mut color = [0.; 4];
+    for (channel, value) in color.iter_mut().enumerate() {
+        *value = decode_channel(texel[channel], texture.srgb && channel < 3);
+    }
+    color
+}
+
+fn mix(a: [f32; 4], b: [f32; 4], t: f32) -> [f32; 4] {
+    let mut result = [0.; 4];
+    for (channel, value) in result.iter_mut().enumerate() {
+        *value = a[channel] + (b[channel] - a[channel]) * t;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::Image;
+
+    /// A two by one texture: black on the left, white on the right, with a one-pixel grey mipmap.
+    fn black_and_white() -> TextureData {
+        TextureData::with_mipmaps(Image::new(2, 1, vec![0, 0, 0, 255, 255, 255, 255, 255]).unwrap(), false)
+    }
+
+    #[test]
+    fn positions_are_addressed_as_metal_does() {
+        let addressed = |address_mode| (-3..7).map(|position| address(address_mode, position, 4)).collect::<Vec<_>>();
+        let some = |positions: [usize; 10]| positions.iter().map(|&position| Some(position)).collect::<Vec<_>>();
+        assert_eq!(addressed(AddressMode::ClampToEdge), some([0, 0, 0, 0, 1, 2, 3, 3, 3, 3]));
+        assert_eq!(addressed(AddressMode::MirrorClampToEdge), some([2, 1, 0, 0, 1, 2, 3, 3, 3, 3]));
+        assert_eq!(addressed(AddressMode::Repeat), some([1, 2, 3, 0, 1, 2, 3, 0, 1, 2]));
+        assert_eq!(addressed(AddressMode::MirrorRepeat), some([2, 1, 0, 0, 1, 2, 3, 3, 2, 1]));
+        assert_eq!(addressed(AddressMode::ClampToZero),
+                   [None, None, None, Some(0), Some(1), Some(2), Some(3), None, None, None]);
+    }
+
+    #[test]
+    fn nearest_sampling_picks_the_texel_the_point_is_in() {
+        let texture = black_and_white();
+        let sampler = SamplerDesc::nearest();
+        assert_eq!(sampler.sample(&texture, [0.49, 0.5], 0.), [0., 0., 0., 1.]);
+        assert_eq!(sampler.sample(&texture, [0.51, 0.5], 0.), [1., 1., 1., 1.]);
+        // Not mipmapped, so however small it's drawn.
+        assert_eq!(sampler.sample(&texture, [0.51, 0.5], 3.), [1., 1., 1., 1.]);
+        let repeating = SamplerDesc { address_mode_u: AddressMode::Repeat, ..sampler };
+        assert_eq!(repeating.sample(&texture, [1.25, 0.5], 0.), [0., 0., 0., 1.]);
+    }
+
+    #[test]
+    fn linear_sampling_blends_between_texel_centres() {
+        let texture = black_and_white();
+        let sampler = SamplerDesc::default();
+        assert_eq!(sampler.sample(&texture, [0.5, 0.5], 0.), [0.5, 0.5, 0.5, 1.]);
+        assert_eq!(sampler.sample(&texture, [0.375, 0.5], 0.), [0.25, 0.25, 0.25, 1.]);
+        // Clamped to the edge texel beyond the outer centres.
+        assert_eq!(sampler.sample(&texture, [0.1, 0.5], 0.), [0., 0., 0., 1.]);
+        // Or blended with transparent black.
+        let clamped_to_zero = SamplerDesc { address_mode_u: AddressMode::ClampToZero, ..sampler };
+        assert_eq!(clamped_to_zero.sample(&texture, [1., 0.5], 0.), [0.5, 0.5, 0.5, 0.5]);
+    }
+
+    #[test]
+    fn mip_filters_choose_and_blend_levels() {
+        let texture = black_and_white();
+        let at_right_texel = [0.75, 0.5];
+        let sampler = SamplerDesc { min_filter: MinMagFilter::Nearest, mip_filter: MipFilter::Nearest, ..SamplerDesc::default() };
+        assert_eq!(sampler.sample(&texture, at_right_texel, 0.4), [1., 1., 1., 1.]);
+        assert_eq!(sampler.sample(&texture, at_right_texel, 0.6)[0], 128. / 255.);
+        let trilinear = SamplerDesc { mip_filter: MipFilter::Linear, ..sampler };
+        assert_eq!(trilinear.sample(&texture, at_right_texel, 0.5)[0], (1. + 128. / 255.) / 2.);
+        // Levels of detail past the smallest level stay on it.
+        assert_eq!(trilinear.sample(&texture, at_right_texel, 9.)[0], 128. / 255.);
+    }
+
+    #[test]
+    fn srgb_texels_are_fetched_as_linear() {
+        let image = Image::new(1, 1, vec![188, 188, 188, 188]).unwrap();
+        let srgb = TextureData { levels: vec![image.clone()], srgb: true };
+        let linear = TextureData { levels: vec![image], srgb: false };
+        let fetched = texel_fetch(&srgb, 0, 0, 0);
+        assert!((fetched[0] - 0.5).abs() < 0.01, "{:?}", fetched);
+        assert_eq!(fetched[3], 188. / 255.);
+        assert_eq!(texel_fetch(&linear, 0, 0, 0)[0], 188. / 255.);
+    }
+}
@@ -0,0 +1,400 @@
+//! A scene of things to draw
+//!
+//! The scene owns a set of meshes and a tree of nodes.
+//! Each node can draw one of the meshes, with its own transform and color tint.
+//! A node's transform is relative to its parent,
+//! and hiding a node hides everything below it.
+//!
+//! The renderer asks the scene for a `draw_list` each frame
+//! and issues one draw per item.
+
+use std::fmt::Formatter;
+use std::error::Error;
+use crate::mesh::{Mesh, MeshError};
+use crate::transform::{Matrix3, Transform2D};
+use crate::blend::BlendMode;
+
+/// Refers to a mesh in a scene.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MeshId(pub usize);
+
+/// Refers to a node in a scene.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeId(pub usize);
+
+/// Something placed in the scene.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    /// The mesh to draw, if any (a node without one can still group its children).
+    pub mesh: Option<MeshId>,
+    /// Where the node sits relative to its parent.
+    pub transform: Transform2D,
+    /// Multiplied with the vertex colors.
+    pub tint: [f32; 4],
+    /// Whether the node (and everything below it) is drawn.
+    pub visible: bool,
+    /// Nodes with a higher z-order are drawn on top of those with a lower one.
+    pub z_order: i32,
+    /// From 0 (nearest) to 1 (farthest): where the depth test puts the node,
+    /// whatever order it's drawn in.
+    pub depth: f32,
+    /// How the node's colors combine with what's drawn behind it.
+    pub blend_mode: BlendMode,
+    parent: Option<NodeId>,
+    children: Vec<NodeId>,
+}
+
+impl Node {
+    /// A visible node with no mesh, no tint and an identity transform.
+    pub fn new() -> Self {
+        Node {
+            mesh: None,
+            transform: Transform2D::identity(),
+            tint: [1., 1., 1., 1.],
+            visible: true,
+            z_order: 0,
+            depth: 0.,
+            blend_mode: BlendMode::Opaque,
+            parent: None,
+            children: Vec::new(),
+        }
+    }
+
+    pub fn with_mesh(mesh: MeshId) -> Self {
+        Node { mesh: Some(mesh), ..Self::new() }
+    }
+
+    pub fn parent(&self) -> Option<NodeId> {
+        self.parent
+    }
+    pub fn children(&self) -> &[NodeId] {
+        &self.children
+    }
+}
+
+impl Default for Node {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SceneError {
+    UnknownNode(NodeId),
+    UnknownMesh(MeshId),
+    InvalidMesh(MeshId, MeshError),
+    WouldCreateCycle(NodeId),
+}
+impl std::fmt::Display for SceneError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownNode(node) => write!(f, "There is no node {}", node.0),
+            Self::UnknownMesh(mesh) => write!(f, "There is no mesh {}", mesh.0),
+            Self::InvalidMesh(mesh, error) => write!(f, "Mesh {} is invalid: {}", mesh.0, error),
+            Self::WouldCreateCycle(node) => write!(f, "Node {} can't be its own ancestor", node.0),
+        }
+    }
+}
+impl Error for SceneError{}
+
+/// One draw call's worth of work.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DrawItem {
+    pub node: NodeId,
+    pub mesh: MeshId,
+    /// The node's transform combined with all its ancestors'.
+    pub world_transform: Matrix3,
+    pub tint: [f32; 4],
+    pub z_order: i32,
+    pub depth: f32,
+    pub blend_mode: BlendMode,
+}
+
+/// Meshes and the tree of nodes that draw them.
+pub struct Scene<V> {
+    meshes: Vec<Mesh<V>>,
+    nodes: Vec<Node>,
+}
+
+impl<V> Scene<V> {
+    pub fn new() -> Self {
+        Scene {
+            meshes: Vec::new(),
+            nodes: Vec::new(),
+        }
+    }
+
+    /// A scene with a single node that draws the given mesh.
+    pub fn with_single_mesh(mesh: Mesh<V>) -> Self {
+        let mut scene = Self::new();
+        let mesh_id = scene.add_mesh(mesh);
+        scene.add_node(None, Node::with_mesh(mesh_id)).unwrap();
+        scene
+    }
+
+    pub fn add_mesh(&mut self, mesh: Mesh<V>) -> MeshId {
+        self.meshes.push(mesh);
+        MeshId(self.meshes.len() - 1)
+    }
+
+    pub fn mesh(&self, mesh: MeshId) -> Option<&Mesh<V>> {
+        self.meshes.get(mesh.0)
+    }
+    pub fn mesh_mut(&mut self, mesh: MeshId) -> Option<&mut Mesh<V>> {
+        self.meshes.get_mut(mesh.0)
+    }
+    /// All the meshes, in `MeshId` order.
+    pub fn meshes(&self) -> &[Mesh<V>] {
+        &self.meshes
+    }
+
+    /// Adds a node under the given parent, or at the top level if `None`.
+    pub fn add_node(&mut self, parent: Option<NodeId>, mut node: Node) -> Result<NodeId, SceneError> {
+        if let Some(mesh) = node.mesh {
+            self.mesh(mesh).ok_or(SceneError::UnknownMesh(mesh))?;
+        }
+        if let Some(parent) = parent {
+            self.node(parent).ok_or(SceneError::UnknownNode(parent))?;
+        }
+        let id = NodeId(self.nodes.len());
+        node.parent = parent;
+        node.children = Vec::new();
+        self.nodes.push(node);
+        if let Some(parent) = parent {
+            self.nodes[parent.0].children.push(id);
+        }
+        Ok(id)
+    }
+
+    pub fn node(&self, node: NodeId) -> Option<&Node> {
+        self.nodes.get(node.0)
+    }
+    /// Gives access to a node's public fields (its parent can only be changed with `set_parent`).
+    pub fn node_mut(&mut self, node: NodeId) -> Option<&mut Node> {
+        self.nodes.get_mut(node.0)
+    }
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// The nodes that have no parent, in the order they were added.
+    pub fn roots(&self) -> impl Iterator<Item = NodeId> + '_ {
+        self.nodes.iter().enumerate()
+            .filter(|(_, node)| node.parent.is_none())
+            .map(|(index, _)| NodeId(index))
+    }
+
+    /// Moves a node (and everything below it) under a new parent,
+    /// or to the top level if `None`.
+    pub fn set_parent(&mut self, node: NodeId, parent: Option<NodeId>) -> Result<(), SceneError> {
+        self.node(node).ok_or(SceneError::UnknownNode(node))?;
+        if let Some(parent) = parent {
+            self.node(parent).ok_or(SceneError::UnknownNode(parent))?;
+            // Walk up from the new parent: if we meet the node, it would become its own ancestor.
+            let mut ancestor = Some(parent);
+            while let Some(current) = ancestor {
+                if current == node {
+                    return Err(SceneError::WouldCreateCycle(node));
+                }
+                ancestor = self.nodes[current.0].parent;
+            }
+        }
+        if let Some(old_parent) = self.nodes[node.0].parent {
+            self.nodes[old_parent.0].children.retain(|&child| child != node);
+        }
+        self.nodes[node.0].parent = parent;
+        if let Some(parent) = parent {
+            self.nodes[parent.0].children.push(node);
+        }
+        Ok(())
+    }
+
+    /// The node's transform combined with all its ancestors'.
+    pub fn world_transform(&self, node: NodeId) -> Option<Matrix3> {
+        let mut transform = self.node(node)?.transform.to_matrix();
+        let mut ancestor = self.nodes[node.0].parent;
+        while let Some(current) = ancestor {
+            transform = self.nodes[current.0].transform.to_matrix() * transform;
+            ancestor = self.nodes[current.0].parent;
+        }
+        Some(transform)
+    }
+
+    /// Checks every mesh is drawable, and every node draws a mesh the scene has.
+    pub fn validate(&self) -> Result<(), SceneError> {
+        for (index, mesh) in self.meshes.iter().enumerate() {
+            mesh.validate().map_err(|error| SceneError::InvalidMesh(MeshId(index), error))?;
+        }
+        for mesh in self.nodes.iter().filter_map(|node| node.mesh) {
+            self.mesh(mesh).ok_or(SceneError::UnknownMesh(mesh))?;
+        }
+        Ok(())
+    }
+
+    /// Everything to draw, in the order to draw it.
+    ///
+    /// Items are sorted back-to-front by z-order.
+    /// Within the same z-order the opaque items come first, grouped by mesh
+    /// so the renderer changes buffers as little as possible.
+    /// The blended items follow in tree order (parents before children),
+    /// since what they look like depends on what's drawn before them.
+    pub fn draw_list(&self) -> Vec<DrawItem> {
+        let mut draw_list = Vec::new();
+        let mut stack: Vec<(NodeId, Matrix3)> = self.roots()
+            .map(|root| (root, Matrix3::identity()))
+            .collect();
+        stack.reverse();
+        while let Some((node_id, parent_transform)) = stack.pop() {
+            let node = &self.nodes[node_id.0];
+            if !node.visible {
+                continue;
+            }
+            let world_transform = parent_transform * node.transform.to_matrix();
+            if let Some(mesh) = node.mesh {
+                draw_list.push(DrawItem {
+                    node: node_id,
+                    mesh,
+                    world_transform,
+                    tint: node.tint,
+                    z_order: node.z_order,
+                    depth: node.depth,
+                    blend_mode: node.blend_mode,
+                });
+            }
+            // Push in reverse, so the first child comes off the stack first.
+            for &child in node.children.iter().rev() {
+                stack.push((child, world_transform));
+            }
+        }
+        // A stable sort, so equal keys stay in tree order.
+        // Blended items all have the same key within a z-order, so none of them move past another.
+        draw_list.sort_by_key(|item| match item.blend_mode {
+            BlendMode::Opaque => (item.z_order, false, Some(item.mesh)),
+            _ => (item.z_order, true, None),
+        });
+        draw_list
+    }
+}
+
+impl<V> Default for Scene<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::PrimitiveTopology;
+
+    fn triangle() -> Mesh<[f32; 2]> {
+        Mesh::new(vec![[0., 0.], [1., 0.], [0., 1.]], PrimitiveTopology::Triangle)
+    }
+
+    fn nodes(draw_list: &[DrawItem]) -> Vec<usize> {
+        draw_list.iter().map(|item| item.node.0).collect()
+    }
+
+    #[test]
+    fn draw_list_is_in_tree_order_within_a_z_order() {
+        let mut scene = Scene::new();
+        let mesh = scene.add_mesh(triangle());
+        let root = scene.add_node(None, Node::with_mesh(mesh)).unwrap();
+        let child = scene.add_node(Some(root), Node::with_mesh(mesh)).unwrap();
+        let second_root = scene.add_node(None, Node::with_mesh(mesh)).unwrap();
+        let grandchild = scene.add_node(Some(child), Node::with_mesh(mesh)).unwrap();
+        let second_child = scene.add_node(Some(root), Node::with_mesh(mesh)).unwrap();
+
+        // Depth first, parents before children, siblings in the order they were added.
+        assert_eq!(nodes(&scene.draw_list()), vec![root.0, child.0, grandchild.0, second_child.0, second_root.0]);
+    }
+
+    #[test]
+    fn draw_list_puts_higher_z_orders_last() {
+        let mut scene = Scene::new();
+        let mesh = scene.add_mesh(triangle());
+        let top = scene.add_node(None, Node { z_order: 2, ..Node::with_mesh(mesh) }).unwrap();
+        let bottom = scene.add_node(None, Node { z_order: -1, ..Node::with_mesh(mesh) }).unwrap();
+        let middle = scene.add_node(None, Node::with_mesh(mesh)).unwrap();
+
+        assert_eq!(nodes(&scene.draw_list()), vec![bottom.0, middle.0, top.0]);
+    }
+
+    #[test]
+    fn draw_list_groups_opaque_items_by_mesh_and_keeps_blended_ones_in_tree_order() {
+        let mut scene = Scene::new();
+        let first_mesh = scene.add_mesh(triangle());
+        let second_mesh = scene.add_mesh(triangle());
+        let blended = |mesh, blend_mode| Node { blend_mode, ..Node::with_mesh(mesh) };
+        let additive = scene.add_node(None, blended(second_mesh, BlendMode::Additive)).unwrap();
+        let opaque_second = scene.add_node(None, Node::with_mesh(second_mesh)).unwrap();
+        let alpha = scene.add_node(None, blended(first_mesh, BlendMode::Alpha)).unwrap();
+        let opaque_first = scene.add_node(None, Node::with_mesh(first_mesh)).unwrap();
+        let multiply = scene.add_node(Some(opaque_first), blended(second_mesh, BlendMode::Multiply)).unwrap();
+        let opaque_child = scene.add_node(Some(opaque_first), Node::with_mesh(second_mesh)).unwrap();
+        let on_top = scene.add_node(None, Node { z_order: 1, ..Node::with_mesh(first_mesh) }).unwrap();
+
+        assert_eq!(nodes(&scene.draw_list()), vec![
+            opaque_first.0, opaque_second.0, opaque_child.0,
+            additive.0, alpha.0, multiply.0,
+            on_top.0,
+        ]);
+    }
+
+    #[test]
+    fn draw_list_skips_hidden_subtrees() {
+        let mut scene = Scene::new();
+        let mesh = scene.add_mesh(triangle());
+        let hidden = scene.add_node(None, Node { visible: false, ..Node::with_mesh(mesh) }).unwrap();
+        let child = scene.add_node(Some(hidden), Node::with_mesh(mesh)).unwrap();
+        scene.add_node(Some(child), Node::with_mesh(mesh)).unwrap();
+        let shown = scene.add_node(None, Node::with_mesh(mesh)).unwrap();
+        // A node without a mesh draws nothing, but its children still draw.
+        let group = scene.add_node(None, Node::new()).unwrap();
+        let grouped = scene.add_node(Some(group), Node::with_mesh(mesh)).unwrap();
+
+        assert_eq!(nodes(&scene.draw_list()), vec![shown.0, grouped.0]);
+
+        scene.node_mut(hidden).unwrap().visible = true;
+        scene.node_mut(child).unwrap().visible = false;
+        assert_eq!(nodes(&scene.draw_list()), vec![hidden.0, shown.0, grouped.0]);
+    }
+
+    #[test]
+    fn draw_list_composes_world_transforms() {
+        let mut scene = Scene::new();
+        let mesh = scene.add_mesh(triangle());
+        let parent_transform = Transform2D { translation: [10., 0.], rotation: std::f32::consts::FRAC_PI_2, scale: [2., 2.] };
+        let parent = scene.add_node(None, Node { transform: parent_transform, ..Node::with_mesh(mesh) }).unwrap();
+        let child = scene.add_node(Some(parent), Node { transform: Transform2D::from_translation(1., 0.), ..Node::with_mesh(mesh) }).unwrap();
+
+        let draw_list = scene.draw_list();
+        assert_eq!(draw_list[1].node, child);
+        let world_transform = draw_list[1].world_transform;
+        assert_eq!(Some(world_transform), scene.world_transform(child));
+
+        // The child's origin is 1 along the parent's x axis: scaled to 2, turned to point up, then moved 10 right.
+        let origin = world_transform.transform_point([0., 0.]);
+        assert!((origin[0] - 10.).abs() < 1e-5 && (origin[1] - 2.).abs() < 1e-5, "{:?}", origin);
+    }
+
+    #[test]
+    fn set_parent_refuses_cycles() {
+        let mut scene: Scene<[f32; 2]> = Scene::new();
+        let parent = scene.add_node(None, Node::new()).unwrap();
+        let child = scene.add_node(Some(parent), Node::new()).unwrap();
+
+        assert_eq!(scene.set_parent(parent, Some(child)), Err(SceneError::WouldCreateCycle(parent)));
+        assert_eq!(scene.set_parent(child, None), Ok(()));
+        assert_eq!(scene.roots().collect::<Vec<_>>(), vec![parent, child]);
+        assert!(scene.node(parent).unwrap().children().is_empty());
+    }
+
+    #[test]
+    fn validate_finds_nodes_drawing_missing_meshes() {
+        let mut scene = Scene::with_single_mesh(triangle());
+        assert_eq!(scene.validate(), Ok(()));
+        scene.node_mut(NodeId(0)).unwrap().mesh = Some(MeshId(7));
+        assert_eq!(scene.validate(), Err(SceneError::UnknownMesh(MeshId(7))));
+    }
+}
@@ -0,0 +1,623 @@
+//! Scene description files
+//!
+//! A scene file is JSON describing the clear color, the meshes and the nodes that draw them,
+//! so we can change what's drawn without recompiling.
+//! See `scenes/scene.schema.json` for the format and `scenes/hello_triangle.json` for an example.
+//!
+//! Parsing gives a `SceneDescription`, which is plain data and can be written back out;
+//! `to_scene` turns it into the `Scene` the renderer draws.
+
+use std::fmt::{Display, Formatter};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use crate::json::{self, Position, Spanned, Value, ParseError};
+use crate::mesh::{Mesh, IndexData, PrimitiveTopology};
+use crate::scene::{Scene, Node, MeshId, NodeId};
+use crate::shader_types::AAPLVertex;
+use crate::transform::Transform2D;
+use crate::blend::BlendMode;
+
+/// The version of the format we read and write.
+pub const SCENE_FILE_VERSION: u32 = 1;
+
+/// Everything a scene file describes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SceneDescription {
+    /// The color the view is cleared to before drawing, as RGBA.
+    pub clear_color: [f64; 4],
+    pub meshes: Vec<MeshDescription>,
+    /// The top-level nodes, each with their children.
+    pub nodes: Vec<NodeDescription>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct VertexDescription {
+    /// Pixels from the centre of the view.
+    pub position: [f32; 2],
+    /// RGBA.
+    pub color: [f32; 4],
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MeshDescription {
+    /// Nodes refer to meshes by name.
+    pub name: String,
+    pub topology: PrimitiveTopology,
+    pub vertices: Vec<VertexDescription>,
+    pub indices: Option<Vec<u32>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeDescription {
+    pub name: Option<String>,
+    /// The name of the mesh to draw, if any.
+    pub mesh: Option<String>,
+    /// Pixels, relative to the parent.
+    pub translation: [f32; 2],
+    /// Degrees anticlockwise.
+    pub rotation: f32,
+    pub scale: [f32; 2],
+    pub tint: [f32; 4],
+    pub visible: bool,
+    pub z_order: i32,
+    /// From 0 (nearest) to 1 (farthest).
+    pub depth: f32,
+    pub blend_mode: BlendMode,
+    pub children: Vec<NodeDescription>,
+}
+
+impl Default for NodeDescription {
+    fn default() -> Self {
+        NodeDescription {
+            name: None,
+            mesh: None,
+            translation: [0., 0.],
+            rotation: 0.,
+            scale: [1., 1.],
+            tint: [1., 1., 1., 1.],
+            visible: true,
+            z_order: 0,
+            depth: 0.,
+            blend_mode: BlendMode::Opaque,
+            children: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SceneFileError {
+    /// We couldn't read or write the file.
+    Io(PathBuf, std::io::Error),
+    /// The file isn't valid JSON.
+    Syntax(ParseError),
+    /// The file is valid JSON but not a valid scene.
+    Invalid { message: String, position: Position },
+}
+impl Display for SceneFileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(path, error) => write!(f, "{}: {}", path.display(), error),
+            Self::Syntax(error) => write!(f, "{}", error),
+            Self::Invalid { message, position } => write!(f, "{}: {}", position, message),
+        }
+    }
+}
+impl Error for SceneFileError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(_, error) => Some(error),
+            Self::Syntax(error) => Some(error),
+            Self::Invalid { .. } => None,
+        }
+    }
+}
+
+fn invalid<T>(value: &Spanned, message: String) -> Result<T, SceneFileError> {
+    Err(SceneFileError::Invalid { message, position: value.position })
+}
+
+impl Default for SceneDescription {
+    /// Apple's triangle on the blue background from `view_did_load`.
+    fn default() -> Self {
+        SceneDescription {
+            clear_color: [0.0, 0.5, 1.0, 1.0],
+            meshes: vec![
+                MeshDescription {
+                    name: "triangle".to_string(),
+                    topology: PrimitiveTopology::Triangle,
+                    vertices: vec![
+                        VertexDescription { position: [250., -250.], color: [1., 0., 0., 1.] },
+                        VertexDescription { position: [-250., -250.], color: [0., 1., 0., 1.] },
+                        VertexDescription { position: [0., 250.], color: [0., 0., 1., 1.] },
+                    ],
+                    indices: Some(vec![0, 1, 2]),
+                },
+            ],
+            nodes: vec![
+                NodeDescription { mesh: Some("triangle".to_string()), ..NodeDescription::default() },
+            ],
+        }
+    }
+}
+
+impl SceneDescription {
+    /// Reads and parses a scene file.
+    pub fn load(path: &Path) -> Result<Self, SceneFileError> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|error| SceneFileError::Io(path.to_path_buf(), error))?;
+        Self::from_json(&text)
+    }
+
+    /// Writes the description out as a scene file.
+    pub fn save(&self, path: &Path) -> Result<(), SceneFileError> {
+        std::fs::write(path, self.to_json())
+            .map_err(|error| SceneFileError::Io(path.to_path_buf(), error))
+    }
+
+    /// Parses and checks a scene file's contents.
+    pub fn from_json(text: &str) -> Result<Self, SceneFileError> {
+        let document = json::parse(text).map_err(SceneFileError::Syntax)?;
+        // "$schema" lets editors find scenes/scene.schema.json; we don't need it.
+        check_keys(&document, &["$schema", "version", "clear_color", "meshes", "nodes"])?;
+
+        let version = match document.get("version") {
+            Some(version) => read_number(version)?,
+            None => return invalid(&document, "missing \"version\"".to_string()),
+        };
+        if version != f64::from(SCENE_FILE_VERSION) {
+            return invalid(document.get("version").unwrap(),
+                           format!("unsupported version {} (expected {})", version, SCENE_FILE_VERSION));
+        }
+
+        let clear_color = match document.get("clear_color") {
+            Some(clear_color) => read_numbers::<4>(clear_color)?,
+            None => SceneDescription::default().clear_color,
+        };
+
+        let mut meshes: Vec<MeshDescription> = Vec::new();
+        for mesh_value in read_array(document.get("meshes"))? {
+            let mesh = read_mesh(mesh_value)?;
+            if meshes.iter().any(|existing| existing.name == mesh.name) {
+                return invalid(mesh_value, format!("there is already a mesh called \"{}\"", mesh.name));
+            }
+            meshes.push(mesh);
+        }
+
+        let nodes = read_array(document.get("nodes"))?
+            .iter()
+            .map(|node_value| read_node(node_value, &meshes))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(SceneDescription { clear_color, meshes, nodes })
+    }
+
+    /// Writes the description as JSON that `from_json` reads back unchanged.
+    pub fn to_json(&self) -> String {
+        let document = object(vec![
+            ("version", number(f64::from(SCENE_FILE_VERSION))),
+            ("clear_color", numbers(&self.clear_color)),
+            ("meshes", array(self.meshes.iter().map(write_mesh).collect())),
+            ("nodes", array(self.nodes.iter().map(write_node).collect())),
+        ]);
+        let mut writer = json::Writer::new();
+        writer.write_value(&document.value);
+        writer.finish()
+    }
+
+    /// Builds the scene the renderer draws.
+    ///
+    /// Only call this on a description that came from `from_json`
+    /// or that has otherwise been checked.
+    pub fn to_scene(&self) -> Scene<AAPLVertex> {
+        let mut scene = Scene::new();
+        let mesh_ids: Vec<(&str, MeshId)> = self.meshes.iter()
+            .map(|mesh| (mesh.name.as_str(), scene.add_mesh(build_mesh(mesh))))
+            .collect();
+        for node in &self.nodes {
+            add_node(&mut scene, None, node, &mesh_ids);
+        }
+        scene
+    }
+}
+
+fn build_mesh(description: &MeshDescription) -> Mesh<AAPLVertex> {
+    let vertices = description.vertices.iter()
+        .map(|vertex| AAPLVertex::new(vertex.position, vertex.color))
+        .collect();
+    let mesh = Mesh::new(vertices, description.topology);
+    match &description.indices {
+        Some(indices) => mesh.with_indices(IndexData::from_u32(indices.clone())),
+        None => mesh,
+    }
+}
+
+fn add_node(scene: &mut Scene<AAPLVertex>, parent: Option<NodeId>, description: &NodeDescription, mesh_ids: &[(&str, MeshId)]) {
+    let mesh = description.mesh.as_ref().and_then(|name| {
+        mesh_ids.iter().find(|(mesh_name, _)| mesh_name == name).map(|&(_, id)| id)
+    });
+    let mut node = Node::new();
+    node.mesh = mesh;
+    node.transform = Transform2D {
+        translation: description.translation,
+        rotation: description.rotation.to_radians(),
+        scale: description.scale,
+    };
+    node.tint = description.tint;
+    node.visible = description.visible;
+    node.z_order = description.z_order;
+    node.depth = description.depth;
+    node.blend_mode = description.blend_mode;
+    let node_id = scene.add_node(parent, node).unwrap();
+    for child in &description.children {
+        add_node(scene, Some(node_id), child, mesh_ids);
+    }
+}
+
+// Reading
+
+fn check_keys(value: &Spanned, allowed: &[&str]) -> Result<(), SceneFileError> {
+    match &value.value {
+        Value::Object(members) => {
+            for (name, member) in members {
+                if !allowed.contains(&name.as_str()) {
+                    return invalid(member, format!("unknown key \"{}\" (expected one of {})", name, allowed.join(", ")));
+                }
+            }
+            Ok(())
+        }
+        _ => invalid(value, format!("expected an object but found {}", value.type_name())),
+    }
+}
+
+fn read_array(value: Option<&Spanned>) -> Result<&[Spanned], SceneFileError> {
+    match value {
+        None => Ok(&[]),
+        Some(Spanned { value: Value::Array(elements), .. }) => Ok(elements),
+        Some(other) => invalid(other, format!("expected an array but found {}", other.type_name())),
+    }
+}
+
+fn read_number(value: &Spanned) -> Result<f64, SceneFileError> {
+    match value.value {
+        Value::Number(n) => Ok(n),
+        _ => invalid(value, format!("expected a number but found {}", value.type_name())),
+    }
+}
+
+fn read_integer(value: &Spanned, min: f64, max: f64) -> Result<f64, SceneFileError> {
+    let n = read_number(value)?;
+    if n.fract() != 0. || n < min || n > max {
+        return invalid(value, format!("expected a whole number from {} to {} but found {}", min, max, n));
+    }
+    Ok(n)
+}
+
+fn read_numbers<const N: usize>(value: &Spanned) -> Result<[f64; N], SceneFileError> {
+    match &value.value {
+        Value::Array(elements) if elements.len() == N => {
+            let mut numbers = [0.; N];
+            for (number, element) in numbers.iter_mut().zip(elements) {
+                *number = read_number(element)?;
+            }
+            Ok(numbers)
+        }
+        Value::Array(elements) => invalid(value, format!("expected {} numbers but found {}", N, elements.len())),
+        _ => invalid(value, format!("expected an array of {} numbers but found {}", N, value.type_name())),
+    }
+}
+
+fn read_floats<const N: usize>(value: &Spanned) -> Result<[f32; N], SceneFileError> {
+    let numbers = read_numbers::<N>(value)?;
+    let mut floats = [0.; N];
+    for (float, number) in floats.iter_mut().zip(numbers.iter()) {
+        *float = *number as f32;
+    }
+    Ok(floats)
+}
+
+fn read_string(value: &Spanned) -> Result<String, SceneFileError> {
+    match &value.value {
+        Value::String(s) => Ok(s.clone()),
+        _ => invalid(value, format!("expected a string but found {}", value.type_name())),
+    }
+}
+
+fn read_bool(value: &Spanned) -> Result<bool, SceneFileError> {
+    match value.value {
+        Value::Bool(b) => Ok(b),
+        _ => invalid(value, format!("expected true or false but found {}", value.type_name())),
+    }
+}
+
+fn read_topology(value: &Spanned) -> Result<PrimitiveTopology, SceneFileError> {
+    let name = read_string(value)?;
+    match PrimitiveTopology::from_name(&name) {
+        Some(topology) => Ok(topology),
+        None => invalid(value, format!("unknown topology \"{}\" (expected point, line, line_strip, triangle or triangle_strip)", name)),
+    }
+}
+
+fn read_blend_mode(value: &Spanned) -> Result<BlendMode, SceneFileError> {
+    let name = read_string(value)?;
+    match BlendMode::from_name(&name) {
+        Some(blend_mode) => Ok(blend_mode),
+        None => invalid(value, format!("unknown blend mode \"{}\" (expected opaque, alpha, premultiplied, additive or multiply)", name)),
+    }
+}
+
+fn read_mesh(value: &Spanned) -> Result<MeshDescription, SceneFileError> {
+    check_keys(value, &["name", "topology", "vertices", "indices"])?;
+    let name = match value.get("name") {
+        Some(name) => read_string(name)?,
+        None => return invalid(value, "mesh is missing a \"name\"".to_string()),
+    };
+    let topology = match value.get("topology") {
+        Some(topology) => read_topology(topology)?,
+        None => PrimitiveTopology::Triangle,
+    };
+    let vertices = read_array(value.get("vertices"))?
+        .iter()
+        .map(|vertex| {
+            check_keys(vertex, &["position", "color"])?;
+            let position = match vertex.get("position") {
+                Some(position) => read_floats::<2>(position)?,
+                None => return invalid(vertex, "vertex is missing a \"position\"".to_string()),
+            };
+            let color = match vertex.get("color") {
+                Some(color) => read_floats::<4>(color)?,
+                None => [1., 1., 1., 1.],
+            };
+            Ok(VertexDescription { position, color })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let indices = match value.get("indices") {
+        Some(indices_value) => {
+            let indices = read_array(Some(indices_value))?
+                .iter()
+                .map(|index| read_integer(index, 0., f64::from(u32::MAX)).map(|n| n as u32))
+                .collect::<Result<Vec<_>, _>>()?;
+            Some(indices)
+        }
+        None => None,
+    };
+
+    let description = MeshDescription { name, topology, vertices, indices };
+    if let Err(error) = build_mesh(&description).validate() {
+        return invalid(value, format!("mesh \"{}\" can't be drawn: {}", description.name, error));
+    }
+    Ok(description)
+}
+
+fn read_node(value: &Spanned, meshes: &[MeshDescription]) -> Result<NodeDescription, SceneFileError> {
+    check_keys(value, &["name", "mesh", "translation", "rotation", "scale", "tint", "visible", "z_order", "depth", "blend", "children"])?;
+    let mut node = NodeDescription::default();
+    if let Some(name) = value.get("name") {
+        node.name = Some(read_string(name)?);
+    }
+    if let Some(mesh_value) = value.get("mesh") {
+        let mesh = read_string(mesh_value)?;
+        if !meshes.iter().any(|existing| existing.name == mesh) {
+            return invalid(mesh_value, format!("there is no mesh called \"{}\"", mesh));
+        }
+        node.mesh = Some(mesh);
+    }
+    if let Some(translation) = value.get("translation") {
+        node.translation = read_floats::<2>(translation)?;
+    }
+    if let Some(rotation) = value.get("rotation") {
+        node.rotation = read_number(rotation)? as f32;
+    }
+    if let Some(scale) = value.get("scale") {
+        node.scale = read_floats::<2>(scale)?;
+    }
+    if let Some(tint) = value.get("tint") {
+        node.tint = read_floats::<4>(tint)?;
+    }
+    if let Some(visible) = value.get("visible") {
+        node.visible = read_bool(visible)?;
+    }
+    if let Some(z_order) = value.get("z_order") {
+        node.z_order = read_integer(z_order, f64::from(i32::MIN), f64::from(i32::MAX))? as i32;
+    }
+    if let Some(depth) = value.get("depth") {
+        let n = read_number(depth)?;
+        if !(0. ..=1.).contains(&n) {
+            return invalid(depth, format!("expected a depth from 0 to 1 but found {}", n));
+        }
+        node.depth = n as f32;
+    }
+    if let Some(blend) = value.get("blend") {
+        node.blend_mode = read_blend_mode(blend)?;
+    }
+    node.children = read_array(value.get("children"))?
+        .iter()
+        .map(|child| read_node(child, meshes))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(node)
+}
+
+// Writing
+
+fn object(members: Vec<(&str, Spanned)>) -> Spanned {
+    json::unspanned(Value::Object(members.into_iter().map(|(name, value)| (name.to_string(), value)).collect()))
+}
+
+fn array(elements: Vec<Spanned>) -> Spanned {
+    json::unspanned(Value::Array(elements))
+}
+
+fn number(n: f64) -> Spanned {
+    json::unspanned(Value::Number(n))
+}
+
+fn numbers(ns: &[f64]) -> Spanned {
+    array(ns.iter().map(|&n| number(n)).collect())
+}
+
+fn floats(fs: &[f32]) -> Spanned {
+    array(fs.iter().map(|&f| float(f)).collect())
+}
+
+/// Widens an `f32` via its shortest decimal form,
+/// so the file says `0.1` rather than `0.10000000149011612`.
+fn float(f: f32) -> Spanned {
+    number(f.to_string().parse().unwrap_or_else(|_| f64::from(f)))
+}
+
+fn string(s: &str) -> Spanned {
+    json::unspanned(Value::String(s.to_string()))
+}
+
+fn write_mesh(mesh: &MeshDescription) -> Spanned {
+    let mut members = vec![
+        ("name", string(&mesh.name)),
+        ("topology", string(mesh.topology.name())),
+        ("vertices", array(mesh.vertices.iter()
+            .map(|vertex| object(vec![
+                ("position", floats(&vertex.position)),
+                ("color", floats(&vertex.color)),
+            ]))
+            .collect())),
+    ];
+    if let Some(indices) = &mesh.indices {
+        members.push(("indices", array(indices.iter().map(|&index| number(f64::from(index))).collect())));
+    }
+    object(members)
+}
+
+fn write_node(node: &NodeDescription) -> Spanned {
+    let mut members = Vec::new();
+    if let Some(name) = &node.name {
+        members.push(("name", string(name)));
+    }
+    if let Some(mesh) = &node.mesh {
+        members.push(("mesh", string(mesh)));
+    }
+    members.push(("translation", floats(&node.translation)));
+    members.push(("rotation", float(node.rotation)));
+    members.push(("scale", floats(&node.scale)));
+    members.push(("tint", floats(&node.tint)));
+    members.push(("visible", json::unspanned(Value::Bool(node.visible))));
+    members.push(("z_order", number(f64::from(node.z_order))));
+    members.push(("depth", float(node.depth)));
+    members.push(("blend", string(node.blend_mode.name())));
+    if !node.children.is_empty() {
+        members.push(("children", array(node.children.iter().map(write_node).collect())));
+    }
+    object(members)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nested_description() -> SceneDescription {
+        SceneDescription {
+            clear_color: [0.1, 0.2, 0.3, 1.],
+            meshes: vec![
+                SceneDescription::default().meshes.remove(0),
+                MeshDescription {
+                    name: "strip \"quoted\"".to_string(),
+                    topology: PrimitiveTopology::LineStrip,
+                    vertices: vec![
+                        VertexDescription { position: [-0.5, 1e-3], color: [0.1, 0.2, 0.3, 0.4] },
+                        VertexDescription { position: [100., -7.25], color: [1., 1., 1., 0.] },
+                    ],
+                    indices: None,
+                },
+            ],
+            nodes: vec![
+                NodeDescription {
+                    name: Some("group".to_string()),
+                    translation: [10., -20.],
+                    rotation: 33.3,
+                    scale: [0.5, 2.],
+                    visible: false,
+                    z_order: -3,
+                    children: vec![
+                        NodeDescription { mesh: Some("triangle".to_string()), blend_mode: BlendMode::Additive, ..NodeDescription::default() },
+                        NodeDescription { mesh: Some("strip \"quoted\"".to_string()), tint: [0.9, 0.8, 0.7, 0.6], z_order: 7, depth: 0.25, ..NodeDescription::default() },
+                    ],
+                    ..NodeDescription::default()
+                },
+                NodeDescription { mesh: Some("triangle".to_string()), blend_mode: BlendMode::Multiply, ..NodeDescription::default() },
+            ],
+        }
+    }
+
+    fn invalid_at(text: &str) -> (usize, usize, String) {
+        match SceneDescription::from_json(text) {
+            Err(SceneFileError::Invalid { message, position }) => (position.line, position.column, message),
+            other => panic!("expected an invalid scene, not {:?}", other),
+        }
+    }
+
+    #[test]
+    fn descriptions_read_back_unchanged() {
+        for description in &[SceneDescription::default(), nested_description()] {
+            let text = description.to_json();
+            assert_eq!(&SceneDescription::from_json(&text).unwrap(), description, "{}", text);
+        }
+    }
+
+    #[test]
+    fn saved_files_load_back_unchanged() {
+        let path = std::env::temp_dir().join(format!("hello_triangle_scene_{}.json", std::process::id()));
+        nested_description().save(&path).unwrap();
+        let loaded = SceneDescription::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap(), nested_description());
+    }
+
+    #[test]
+    fn the_example_scene_loads() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes/hello_triangle.json");
+        let description = SceneDescription::load(&path).unwrap();
+        assert!(!description.meshes.is_empty());
+        description.to_scene().validate().unwrap();
+    }
+
+    #[test]
+    fn unknown_keys_are_reported_where_they_are() {
+        let (line, column, message) = invalid_at("{\n  \"version\": 1,\n  \"colour\": [0, 0, 0, 1]\n}");
+        assert_eq!((line, column), (3, 13));
+        assert!(message.starts_with("unknown key \"colour\""), "{}", message);
+
+        let (line, column, message) = invalid_at("{\"version\": 1, \"nodes\": [\n  {\"mesh\": \"triangle\"}\n]}");
+        assert_eq!((line, column, message.as_str()), (2, 12, "there is no mesh called \"triangle\""));
+
+        let (line, column, message) = invalid_at("{\"version\": 1, \"meshes\": [{\"name\": \"m\", \"vertices\": [\n  {\"position\": [0, 0], \"normal\": [0, 1]}]}]}");
+        assert_eq!((line, column), (2, 34));
+        assert!(message.starts_with("unknown key \"normal\""), "{}", message);
+    }
+
+    #[test]
+    fn duplicate_keys_and_names_are_reported_where_they_are() {
+        match SceneDescription::from_json("{\n  \"version\": 1,\n  \"version\": 1\n}") {
+            Err(SceneFileError::Syntax(error)) => {
+                assert_eq!(error.position, Position { line: 3, column: 3 });
+                assert_eq!(error.message, "duplicate key \"version\"");
+            }
+            other => panic!("expected a syntax error, not {:?}", other),
+        }
+
+        let mesh = "{\"name\": \"m\", \"vertices\": [{\"position\": [0, 0]}], \"topology\": \"point\"}";
+        let text = format!("{{\"version\": 1, \"meshes\": [\n  {},\n  {}\n]}}", mesh, mesh);
+        assert_eq!(invalid_at(&text), (3, 3, "there is already a mesh called \"m\"".to_string()));
+    }
+
+    #[test]
+    fn bad_values_are_reported_where_they_are() {
+        assert_eq!(invalid_at("{}"), (1, 1, "missing \"version\"".to_string()));
+        assert_eq!(invalid_at("{\"version\": 2}"), (1, 13, "unsupported version 2 (expected 1)".to_string()));
+        assert_eq!(invalid_at("{\"version\": 1, \"clear_color\": [0, 0, 1]}"),
+                   (1, 31, "expected 4 numbers but found 3".to_string()));
+        assert_eq!(invalid_at("{\"version\": 1, \"nodes\": [{\"z_order\": 1.5}]}"),
+                   (1, 38, "expected a whole number from -2147483648 to 2147483647 but found 1.5".to_string()));
+        assert_eq!(invalid_at("{\"version\": 1, \"nodes\": [{\"depth\": -0.5}]}"),
+                   (1, 36, "expected a depth from 0 to 1 but found -0.5".to_string()));
+        assert_eq!(invalid_at("{\"version\": 1, \"nodes\": [{\"blend\": \"screen\"}]}"),
+                   (1, 36, "unknown blend mode \"screen\" (expected opaque, alpha, premultiplied, additive or multiply)".to_string()));
+        assert!(matches!(SceneDescription::from_json("{\"version\": 01}"), Err(SceneFileError::Syntax(_))));
+    }
+}
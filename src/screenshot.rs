@@ -0,0 +1,278 @@
+//! Saving what's in the view as a PNG
+//!
+//! The renderer copies the drawable's texture into a shared buffer at the end of a frame,
+//! and once the GPU has finished with it, hands the bytes to `CapturedFrame`.
+//! Whatever the view's pixel format, we turn them into 8-bit sRGB RGBA:
+//! the 8-bit formats are just swizzled from BGRA, the 10-bit one is taken from Display P3,
+//! and the half-float one from extended linear sRGB, with colors brighter than white cut off.
+//! Colors outside sRGB are clipped.
+
+use std::fmt::{Display, Formatter};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use crate::image::{Image, encode_channel, srgb_to_linear};
+
+/// The key that saves a screenshot of the next frame.
+pub static SCREENSHOT_KEY: &str = "s";
+
+/// How a drawable's pixels are laid out, for each of the color pixel formats the view can draw to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CapturePixelFormat {
+    Bgra8Unorm,
+    Bgra8UnormSrgb,
+    Bgr10A2Unorm,
+    Rgba16Float,
+}
+
+impl CapturePixelFormat {
+    /// The format with the given name, as `pixel_format::color_pixel_format_name` gives it.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "BGRA8Unorm" => Some(Self::Bgra8Unorm),
+            "BGRA8Unorm_sRGB" => Some(Self::Bgra8UnormSrgb),
+            "BGR10A2Unorm" => Some(Self::Bgr10A2Unorm),
+            "RGBA16Float" => Some(Self::Rgba16Float),
+            _ => None,
+        }
+    }
+
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            Self::Bgra8Unorm | Self::Bgra8UnormSrgb | Self::Bgr10A2Unorm => 4,
+            Self::Rgba16Float => 8,
+        }
+    }
+
+    /// One pixel's bytes as 8-bit sRGB red, green and blue.
+    fn srgb_pixel(self, bytes: &[u8]) -> [u8; 3] {
+        match self {
+            // Unmanaged values are shown as they are, which is as sRGB,
+            // and the GPU has already encoded the sRGB format's.
+            Self::Bgra8Unorm | Self::Bgra8UnormSrgb => [bytes[2], bytes[1], bytes[0]],
+            Self::Bgr10A2Unorm => {
+                // Blue in the lowest bits, then green, then red, with alpha in the top two.
+                let packed = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                let channel = |shift: u32| srgb_to_linear(((packed >> shift) & 0x3ff) as f32 / 1023.);
+                encode_linear(display_p3_to_srgb([channel(20), channel(10), channel(0)]))
+            }
+            Self::Rgba16Float => {
+                let channel = |index: usize| half_to_f32(u16::from_le_bytes([bytes[index * 2], bytes[index * 2 + 1]]));
+                encode_linear([channel(0), channel(1), channel(2)])
+            }
+        }
+    }
+}
+
+/// A copy of a drawable's texture, as the GPU left it in a buffer: rows top first,
+/// each `bytes_per_row` long, which may be more than the pixels need.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CapturedFrame {
+    pub width: usize,
+    pub height: usize,
+    pub bytes_per_row: usize,
+    pub pixel_format: CapturePixelFormat,
+    pub bytes: Vec<u8>,
+}
+
+impl CapturedFrame {
+    /// The frame as 8-bit sRGB, or `None` if it's empty or there aren't enough bytes for it.
+    ///
+    /// It's opaque: the drawable's alpha isn't shown, as the window is behind it,
+    /// but image viewers would show it as transparency.
+    pub fn to_image(&self) -> Option<Image> {
+        let row_len = self.width * self.pixel_format.bytes_per_pixel();
+        if self.height == 0 || self.bytes_per_row < row_len
+            || self.bytes.len() < self.bytes_per_row * (self.height - 1) + row_len {
+            return None;
+        }
+        let mut pixels = Vec::with_capacity(self.width * self.height * 4);
+        for y in 0..self.height {
+            let row = &self.bytes[y * self.bytes_per_row..y * self.bytes_per_row + row_len];
+            for pixel in row.chunks_exact(self.pixel_format.bytes_per_pixel()) {
+                let [red, green, blue] = self.pixel_format.srgb_pixel(pixel);
+                pixels.extend_from_slice(&[red, green, blue, 255]);
+            }
+        }
+        Image::new(self.width, self.height, pixels)
+    }
+}
+
+fn encode_linear(color: [f32; 3]) -> [u8; 3] {
+    [encode_channel(color[0], true), encode_channel(color[1], true), encode_channel(color[2], true)]
+}
+
+/// Converts a linear Display P3 color to linear sRGB. Both have the same white point.
+fn display_p3_to_srgb([red, green, blue]: [f32; 3]) -> [f32; 3] {
+    [
+        1.224_940_2 * red - 0.224_940_2 * green,
+        -0.042_056_955 * red + 1.042_057 * green,
+        -0.019_637_555 * red - 0.078_636_04 * green + 1.098_273_6 * blue,
+    ]
+}
+
+/// A 16-bit float (as Metal's `half`) as an `f32`.
+pub fn half_to_f32(bits: u16) -> f32 {
+    let sign = if bits & 0x8000 != 0 { -1. } else { 1. };
+    let exponent = i32::from((bits >> 10) & 0x1f);
+    let mantissa = f32::from(bits & 0x3ff);
+    match exponent {
+        0 => sign * mantissa * 2f32.powi(-24),
+        0x1f if mantissa == 0. => sign * f32::INFINITY,
+        0x1f => f32::NAN,
+        _ => sign * (1. + mantissa / 1024.) * 2f32.powi(exponent - 15),
+    }
+}
+
+/// Encodes an image as an 8-bit RGBA PNG, marked as sRGB.
+pub fn encode_png(image: &Image) -> Result<Vec<u8>, png::EncodingError> {
+    let mut bytes = Vec::new();
+    let mut encoder = png::Encoder::new(&mut bytes, image.width() as u32, image.height() as u32);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_source_srgb(png::SrgbRenderingIntent::Perceptual);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(image.pixels())?;
+    writer.finish()?;
+    Ok(bytes)
+}
+
+/// Encodes an image as a PNG and writes it to a file.
+pub fn save_png(image: &Image, path: &Path) -> Result<(), ScreenshotError> {
+    let bytes = encode_png(image).map_err(ScreenshotError::Png)?;
+    std::fs::write(path, bytes).map_err(|e| ScreenshotError::Io(path.to_path_buf(), e))
+}
+
+/// Converts a captured frame to sRGB and saves it as a PNG.
+pub fn save_screenshot(frame: &CapturedFrame, path: &Path) -> Result<(), ScreenshotError> {
+    let image = frame.to_image().ok_or(ScreenshotError::WrongSize)?;
+    save_png(&image, path)
+}
+
+/// The name of the screenshot of a frame, taken so many seconds after the Unix epoch.
+pub fn screenshot_file_name(seconds_since_epoch: u64, frame_index: u64) -> String {
+    format!("hello_triangle-{}-{}.png", seconds_since_epoch, frame_index)
+}
+
+#[derive(Debug)]
+pub enum ScreenshotError {
+    UnsupportedPixelFormat(String),
+    WrongSize,
+    Png(png::EncodingError),
+    Io(PathBuf, std::io::Error),
+}
+impl Display for ScreenshotError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnsupportedPixelFormat(name) => write!(f, "Can't read pixel format {} back", name),
+            Self::WrongSize => write!(f, "The captured frame is the wrong size"),
+            Self::Png(_) => write!(f, "Unable to encode the PNG"),
+            Self::Io(path, _) => write!(f, "Unable to write {}", path.display()),
+        }
+    }
+}
+impl Error for ScreenshotError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Png(error) => Some(error),
+            Self::Io(_, error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::decode_png;
+
+    fn frame(pixel_format: CapturePixelFormat, width: usize, height: usize, bytes_per_row: usize, bytes: Vec<u8>) -> CapturedFrame {
+        CapturedFrame { width, height, bytes_per_row, pixel_format, bytes }
+    }
+
+    /// A BGR10A2 pixel's bytes, from 10-bit red, green and blue, and opaque.
+    fn bgr10a2(red: u32, green: u32, blue: u32) -> [u8; 4] {
+        (0b11 << 30 | red << 20 | green << 10 | blue).to_le_bytes()
+    }
+
+    #[test]
+    fn bgra_is_swizzled_to_opaque_rgba() {
+        let bytes = vec![10, 20, 30, 40, 1, 2, 3, 0];
+        for &pixel_format in [CapturePixelFormat::Bgra8Unorm, CapturePixelFormat::Bgra8UnormSrgb].iter() {
+            let image = frame(pixel_format, 2, 1, 8, bytes.clone()).to_image().unwrap();
+            assert_eq!(image.pixels(), [30, 20, 10, 255, 3, 2, 1, 255]);
+        }
+    }
+
+    #[test]
+    fn padding_at_the_ends_of_rows_is_skipped() {
+        // Two rows of one pixel, each padded to eight bytes, except the last, which needn't be.
+        let bytes = vec![1, 2, 3, 4, 99, 99, 99, 99, 5, 6, 7, 8];
+        let image = frame(CapturePixelFormat::Bgra8Unorm, 1, 2, 8, bytes.clone()).to_image().unwrap();
+        assert_eq!((image.width(), image.height()), (1, 2));
+        assert_eq!(image.pixels(), [3, 2, 1, 255, 7, 6, 5, 255]);
+
+        assert_eq!(frame(CapturePixelFormat::Bgra8Unorm, 1, 2, 8, bytes[..11].to_vec()).to_image(), None);
+        assert_eq!(frame(CapturePixelFormat::Bgra8Unorm, 3, 1, 8, bytes.clone()).to_image(), None);
+        assert_eq!(frame(CapturePixelFormat::Bgra8Unorm, 1, 0, 8, bytes).to_image(), None);
+    }
+
+    #[test]
+    fn bgr10a2_is_unpacked_from_display_p3() {
+        let mut bytes = Vec::new();
+        for pixel in [bgr10a2(1023, 1023, 1023), bgr10a2(0, 0, 0), bgr10a2(1023, 0, 0), bgr10a2(0, 0, 1023), bgr10a2(512, 512, 512)].iter() {
+            bytes.extend_from_slice(pixel);
+        }
+        let image = frame(CapturePixelFormat::Bgr10A2Unorm, 5, 1, 20, bytes).to_image().unwrap();
+        let pixels: Vec<&[u8]> = image.pixels().chunks(4).collect();
+        assert_eq!(pixels[0], [255, 255, 255, 255]);
+        assert_eq!(pixels[1], [0, 0, 0, 255]);
+        // Display P3's red and blue are more saturated than sRGB's, so are clipped to them.
+        assert_eq!(pixels[2], [255, 0, 0, 255]);
+        assert_eq!(pixels[3], [0, 0, 255, 255]);
+        // Grays are the same in both.
+        assert_eq!(pixels[4], [128, 128, 128, 255]);
+    }
+
+    #[test]
+    fn half_floats_are_decoded() {
+        assert_eq!(half_to_f32(0x3c00), 1.);
+        assert_eq!(half_to_f32(0xc000), -2.);
+        assert_eq!(half_to_f32(0x3800), 0.5);
+        assert_eq!(half_to_f32(0x7bff), 65504.);
+        assert_eq!(half_to_f32(0x0000), 0.);
+        assert!(half_to_f32(0x8000).is_sign_negative());
+        // Subnormals: the smallest, and the largest.
+        assert_eq!(half_to_f32(0x0001), 2f32.powi(-24));
+        assert_eq!(half_to_f32(0x03ff), 1023. * 2f32.powi(-24));
+        assert_eq!(half_to_f32(0x8001), -(2f32.powi(-24)));
+        assert_eq!(half_to_f32(0x7c00), f32::INFINITY);
+        assert_eq!(half_to_f32(0xfc00), f32::NEG_INFINITY);
+        assert!(half_to_f32(0x7e00).is_nan());
+        assert!(half_to_f32(0xfc01).is_nan());
+    }
+
+    #[test]
+    fn half_float_frames_are_encoded_as_srgb_and_cut_off_at_white() {
+        let halves: [u16; 8] = [0x3800, 0x0000, 0x4000, 0x3c00, 0xbc00, 0x7c00, 0x3c00, 0x0000];
+        let bytes: Vec<u8> = halves.iter().flat_map(|half| half.to_le_bytes().to_vec()).collect();
+        let image = frame(CapturePixelFormat::Rgba16Float, 2, 1, 16, bytes).to_image().unwrap();
+        assert_eq!(image.pixels(), [188, 0, 255, 255, 0, 255, 255, 255]);
+    }
+
+    #[test]
+    fn pngs_round_trip() {
+        let pixels: Vec<u8> = (0..3 * 2 * 4).map(|value| value as u8 * 10).collect();
+        let image = Image::new(3, 2, pixels).unwrap();
+        let bytes = encode_png(&image).unwrap();
+        assert_eq!(decode_png(&bytes).unwrap(), image);
+
+        let path = std::env::temp_dir().join(format!("hello_triangle_screenshot_{}.png", std::process::id()));
+        let captured = frame(CapturePixelFormat::Bgra8Unorm, 1, 1, 4, vec![0, 128, 255, 0]);
+        save_screenshot(&captured, &path).unwrap();
+        let saved = decode_png(&std::fs::read(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(saved.unwrap().pixels(), [255, 128, 0, 255]);
+        assert!(matches!(save_screenshot(&frame(CapturePixelFormat::Bgra8Unorm, 2, 1, 8, vec![0; 4]), &path), Err(ScreenshotError::WrongSize)));
+        assert_eq!(screenshot_file_name(1_700_000_000, 42), "hello_triangle-1700000000-42.png");
+    }
+}
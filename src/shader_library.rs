@@ -0,0 +1,196 @@
+//! Where our shaders come from
+//!
+//! Metal can make a library of shader functions in several ways:
+//! the app's default library, a compiled `.metallib` file,
+//! the bytes of a compiled library, or Metal Shading Language source
+//! compiled while the app runs.
+
+use objc::class;
+use objc::msg_send;
+use objc::sel;
+use objc::sel_impl;
+use cocoa::base::{id, nil};
+use cocoa::foundation::{NSAutoreleasePool, NSString};
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::error::Error;
+use std::io;
+use std::os::raw::c_void;
+use std::path::{Path, PathBuf};
+use crate::display_link::dispatch_queue_t;
+use crate::ns_error::NSErrorDetails;
+
+// From usr/include/dispatch/data.h
+#[allow(non_camel_case_types)]
+type dispatch_data_t = id;
+
+#[link(name="System", kind="framework")]
+extern {
+    // dispatch_data_t dispatch_data_create(const void *buffer, size_t size, dispatch_queue_t _Nullable queue, dispatch_block_t _Nullable destructor);
+    fn dispatch_data_create(buffer: *const c_void, size: usize, queue: dispatch_queue_t, destructor: *const c_void) -> dispatch_data_t;
+    // void dispatch_release(dispatch_object_t object);
+    fn dispatch_release(object: id);
+}
+
+/// The shaders in this repository, for when there's no compiled library to hand.
+static EMBEDDED_SHADER_TYPES: &str = include_str!("../HelloTriangle/HelloTriangle/Renderer/AAPLShaderTypes.h");
+static EMBEDDED_SHADERS: &str = include_str!("../HelloTriangle/HelloTriangle/Renderer/AAPLShaders.metal");
+
+/// Where to get a library of shader functions.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LibrarySource {
+    /// `default.metallib` from the app bundle or next to the executable.
+    Default,
+    /// A compiled library file.
+    Path(PathBuf),
+    /// The bytes of a compiled library, e.g. from `include_bytes!`.
+    // The app embeds its shader source rather than a compiled library (see `embedded`),
+    // so nothing makes one of these, but a build that ships a metallib inside the binary would.
+    #[allow(unused)]
+    Data(&'static [u8]),
+    /// Metal Shading Language source, compiled when loaded.
+    /// `name` says where it came from, for error messages.
+    Source { name: String, text: String },
+}
+
+impl LibrarySource {
+    /// The shader source built into the app.
+    pub fn embedded() -> Self {
+        let mut text = String::new();
+        for line in EMBEDDED_SHADER_TYPES.lines().chain(EMBEDDED_SHADERS.lines()) {
+            // The header is already pasted in above.
+            if quoted_include(line).is_none() {
+                text.push_str(line);
+                text.push('\n');
+            }
+        }
+        LibrarySource::Source { name: "the built-in AAPLShaders.metal".to_string(), text }
+    }
+
+    /// A compiled library or (for `.metal` files) source, depending on the extension.
+    pub fn from_path(path: &Path) -> io::Result<Self> {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("metal") => {
+                let (text, _) = read_shader_source(path)?;
+                Ok(LibrarySource::Source { name: path.display().to_string(), text })
+            }
+            _ => Ok(LibrarySource::Path(path.to_path_buf())),
+        }
+    }
+}
+
+impl Display for LibrarySource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Default => write!(f, "the default library"),
+            Self::Path(path) => write!(f, "the library at {}", path.display()),
+            Self::Data(bytes) => write!(f, "the embedded library ({} bytes)", bytes.len()),
+            Self::Source { name, .. } => write!(f, "the shader source from {}", name),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct LibraryError {
+    /// Which library we were trying to load.
+    pub library: String,
+    /// What Metal said was wrong, if it said.
+    pub cause: Option<NSErrorDetails>,
+}
+impl Display for LibraryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.cause {
+            Some(_) => write!(f, "Unable to load {}", self.library),
+            // newDefaultLibrary doesn't give a reason, but this is the usual one.
+            None => write!(f, "Unable to load {}: no default.metallib was found", self.library),
+        }
+    }
+}
+impl Error for LibraryError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.cause.as_ref().map(|cause| cause as &(dyn Error + 'static))
+    }
+}
+
+/// Creates a library of shader functions.
+///
+/// The caller owns the returned library.
+pub fn new_library(device: id, source: &LibrarySource) -> Result<id, LibraryError> {
+    let pool = unsafe { NSAutoreleasePool::new(nil) };
+    let mut error: id = nil;
+    let library: id = match source {
+        LibrarySource::Default => unsafe { msg_send![device, newDefaultLibrary] },
+        LibrarySource::Path(path) => {
+            // Metal wants a full path.
+            let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.clone());
+            let path_string = unsafe { NSString::alloc(nil).init_str(&path.to_string_lossy()) };
+            let url: id = unsafe { msg_send![class!(NSURL), fileURLWithPath:path_string] };
+            unsafe { msg_send![device, newLibraryWithURL:url error:&mut error] }
+        }
+        LibrarySource::Data(bytes) => unsafe {
+            // With no destructor, dispatch copies the bytes.
+            let data = dispatch_data_create(bytes.as_ptr() as *const c_void, bytes.len(), nil, std::ptr::null());
+            let library: id = msg_send![device, newLibraryWithData:data error:&mut error];
+            dispatch_release(data);
+            library
+        },
+        LibrarySource::Source { text, .. } => {
+            let source_string = unsafe { NSString::alloc(nil).init_str(text) };
+            unsafe { msg_send![device, newLibraryWithSource:source_string options:nil error:&mut error] }
+        }
+    };
+    let result = if library != nil {
+        Ok(library)
+    } else {
+        let cause = match source {
+            LibrarySource::Default => None,
+            _ => Some(NSErrorDetails::from_ns_error_or_unknown(error)),
+        };
+        Err(LibraryError { library: source.to_string(), cause })
+    };
+    unsafe { pool.drain() };
+    result
+}
+
+/// Reads a Metal source file, pasting in the headers it includes with quotes.
+///
+/// Compiling source at run time has no include path,
+/// so `#include "AAPLShaderTypes.h"` would otherwise fail.
+/// Headers are looked for next to the file that includes them, and pasted in only once.
+/// Returns the source and every file that went into it.
+pub fn read_shader_source(path: &Path) -> io::Result<(String, Vec<PathBuf>)> {
+    let mut source = String::new();
+    let mut files = Vec::new();
+    let mut included = HashSet::new();
+    append_shader_source(path, &mut source, &mut files, &mut included)?;
+    Ok((source, files))
+}
+
+fn append_shader_source(path: &Path, source: &mut String, files: &mut Vec<PathBuf>, included: &mut HashSet<PathBuf>) -> io::Result<()> {
+    let text = std::fs::read_to_string(path)?;
+    files.push(path.to_path_buf());
+    included.insert(path.to_path_buf());
+    for line in text.lines() {
+        match quoted_include(line) {
+            Some(header) => {
+                let header_path = path.parent().unwrap_or_else(|| Path::new("")).join(header);
+                if !included.contains(&header_path) {
+                    append_shader_source(&header_path, source, files, included)?;
+                }
+            }
+            None => {
+                source.push_str(line);
+                source.push('\n');
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The file named by an `#include "..."` or `#import "..."` line, if it is one.
+fn quoted_include(line: &str) -> Option<&str> {
+    let directive = line.trim_start().strip_prefix('#')?.trim_start();
+    let rest = directive.strip_prefix("include").or_else(|| directive.strip_prefix("import"))?;
+    let rest = rest.trim().strip_prefix('"')?;
+    Some(&rest[..rest.find('"')?])
+}
@@ -0,0 +1,305 @@
+//! Types and constants shared with the Metal shaders
+//!
+//! These mirror AAPLShaderTypes.h, which the .metal shaders include,
+//! so the layouts here must match the layouts there.
+#![allow(non_upper_case_globals)]
+
+use std::os::raw::c_uint;
+use crate::vector_types::{vector_float2, vector_float4};
+use crate::transform::Matrix3;
+
+// From AAPLShaderTypes.h:
+// Buffer index values shared between shader and C code to ensure Metal shader buffer inputs
+// match Metal API buffer set calls.
+// typedef enum AAPLVertexInputIndex
+// {
+//   AAPLVertexInputIndexVertices     = 0,
+//   AAPLVertexInputIndexViewportSize = 1,
+//   AAPLVertexInputIndexObjectUniforms = 2,
+//   AAPLVertexInputIndexInstances    = 3,
+//   AAPLVertexInputIndexParticleUniforms = 4,
+// } AAPLVertexInputIndex;
+pub static AAPLVertexInputIndexVertices: c_uint     = 0;
+pub static AAPLVertexInputIndexViewportSize: c_uint = 1;
+pub static AAPLVertexInputIndexObjectUniforms: c_uint = 2;
+pub static AAPLVertexInputIndexInstances: c_uint    = 3;
+pub static AAPLVertexInputIndexParticleUniforms: c_uint = 4;
+
+// Buffer index values for the compute kernels.
+// typedef enum AAPLComputeIndex
+// {
+//     AAPLComputeIndexParticles        = 0,
+//     AAPLComputeIndexParticleUniforms = 1,
+// } AAPLComputeIndex;
+pub static AAPLComputeIndexParticles: c_uint        = 0;
+pub static AAPLComputeIndexParticleUniforms: c_uint = 1;
+
+// Buffer index values for the post-processing fragment shaders.
+// typedef enum AAPLFragmentInputIndex
+// {
+//     AAPLFragmentInputIndexPostProcessUniforms = 0,
+// } AAPLFragmentInputIndex;
+pub static AAPLFragmentInputIndexPostProcessUniforms: c_uint = 0;
+
+// Texture and sampler index values shared between shader and C code.
+// typedef enum AAPLTextureIndex
+// {
+//     AAPLTextureIndexBaseColor = 0,
+//     AAPLTextureIndexPostProcessSource = 1,
+//     AAPLTextureIndexBloom = 2,
+//     AAPLTextureIndexColorLut = 3,
+// } AAPLTextureIndex;
+//
+// typedef enum AAPLSamplerIndex
+// {
+//     AAPLSamplerIndexBaseColor = 0,
+// } AAPLSamplerIndex;
+pub static AAPLTextureIndexBaseColor: c_uint = 0;
+pub static AAPLTextureIndexPostProcessSource: c_uint = 1;
+pub static AAPLTextureIndexBloom: c_uint = 2;
+pub static AAPLTextureIndexColorLut: c_uint = 3;
+pub static AAPLSamplerIndexBaseColor: c_uint = 0;
+//
+//  This structure defines the layout of vertices sent to the vertex
+//  shader. This header is shared between the .metal shader and C code, to guarantee that
+//  the layout of the vertex array in the C code matches the layout that the .metal
+//  vertex shader expects.
+// typedef struct
+// {
+//   vector_float2 position;
+//   vector_float4 color;
+// } AAPLVertex;
+#[repr(C, packed(4))]
+#[derive(Copy, Clone)]
+pub struct AAPLVertex {
+    pub position: vector_float2,
+    pub color: vector_float4,
+}
+
+impl AAPLVertex {
+    /// A vertex at the given position (in pixels from the centre of the view)
+    /// with the given RGBA color.
+    pub fn new(position: [f32; 2], color: [f32; 4]) -> Self {
+        AAPLVertex {
+            position: vector_float2::new(position[0], position[1]),
+            color: vector_float4::new(color[0], color[1], color[2], color[3]),
+        }
+    }
+}
+
+//  A vertex with texture coordinates, for texturedVertexShader.
+//  The texture coordinate comes last so it's laid out the same way in Rust.
+// typedef struct
+// {
+//     vector_float2 position;
+//     vector_float4 color;
+//     vector_float2 textureCoordinate;
+// } AAPLTexturedVertex;
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct AAPLTexturedVertex {
+    pub position: vector_float2,
+    pub color: vector_float4,
+    pub texture_coordinate: vector_float2,
+}
+
+impl AAPLTexturedVertex {
+    /// A vertex at the given position (in pixels from the centre of the view)
+    /// with the given RGBA color and texture coordinates (from the texture's top-left corner).
+    pub fn new(position: [f32; 2], color: [f32; 4], texture_coordinate: [f32; 2]) -> Self {
+        AAPLTexturedVertex {
+            position: vector_float2::new(position[0], position[1]),
+            color: vector_float4::new(color[0], color[1], color[2], color[3]),
+            texture_coordinate: vector_float2::new(texture_coordinate[0], texture_coordinate[1]),
+        }
+    }
+}
+
+// typedef struct
+// {
+//     matrix_float3x3 transform;
+//     vector_float4 tint;
+//     float depth;
+// } AAPLObjectUniforms;
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct AAPLObjectUniforms {
+    /// The columns of a `matrix_float3x3`, each padded to four floats.
+    pub transform: [[f32; 4]; 3],
+    pub tint: vector_float4,
+    /// From 0 (nearest) to 1 (farthest).
+    pub depth: f32,
+    /// The struct is padded to a multiple of a `vector_float4`'s sixteen-byte alignment.
+    _padding: [f32; 3],
+}
+
+impl AAPLObjectUniforms {
+    pub fn new(transform: &Matrix3, tint: [f32; 4], depth: f32) -> Self {
+        AAPLObjectUniforms {
+            transform: transform.to_padded_columns(),
+            tint: vector_float4::new(tint[0], tint[1], tint[2], tint[3]),
+            depth,
+            _padding: [0.; 3],
+        }
+    }
+}
+
+// typedef struct
+// {
+//     vector_float4 color;
+//     vector_float2 offset;
+//     vector_float2 scale;
+//     float rotation;
+// } AAPLInstance;
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct AAPLInstance {
+    pub color: vector_float4,
+    /// `vector_float2`s, which are eight bytes in Metal (unlike ours).
+    pub offset: [f32; 2],
+    pub scale: [f32; 2],
+    pub rotation: f32,
+    /// The struct is padded to a multiple of a `vector_float4`'s sixteen-byte alignment.
+    _padding: [f32; 3],
+}
+
+impl AAPLInstance {
+    pub fn new(offset: [f32; 2], scale: [f32; 2], rotation: f32, color: [f32; 4]) -> Self {
+        AAPLInstance {
+            color: vector_float4::new(color[0], color[1], color[2], color[3]),
+            offset,
+            scale,
+            rotation,
+            _padding: [0.; 3],
+        }
+    }
+}
+
+//  One particle, moved by updateParticles and drawn as a point by particleVertexShader.
+//  A particle whose lifetime is zero hasn't been born yet, and isn't drawn.
+// typedef struct
+// {
+//     vector_float4 color;
+//     vector_float2 position;
+//     vector_float2 velocity;
+//     float age;
+//     float lifetime;
+// } AAPLParticle;
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct AAPLParticle {
+    pub color: [f32; 4],
+    pub position: [f32; 2],
+    pub velocity: [f32; 2],
+    pub age: f32,
+    pub lifetime: f32,
+    /// The struct is padded to a multiple of a `vector_float4`'s sixteen-byte alignment.
+    pub _padding: [f32; 2],
+}
+
+//  Everything updateParticles needs for one time step, and the size particleVertexShader draws them.
+//  Positions, speeds and sizes are in pixels, and times in seconds.
+// typedef struct
+// {
+//     vector_float4 startColor;
+//     vector_float4 endColor;
+//     vector_float2 emitterPosition;
+//     vector_float2 gravity;
+//     vector_float2 bounds;
+//     float emitterAngle;
+//     float emitterSpread;
+//     float minSpeed;
+//     float maxSpeed;
+//     float minLifetime;
+//     float maxLifetime;
+//     float restitution;
+//     float timeStep;
+//     float pointSize;
+//     uint32_t particleCount;
+//     uint32_t seed;
+// } AAPLParticleUniforms;
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct AAPLParticleUniforms {
+    pub start_color: [f32; 4],
+    pub end_color: [f32; 4],
+    pub emitter_position: [f32; 2],
+    pub gravity: [f32; 2],
+    /// Half the size of the view: particles bounce when they get this far from the centre.
+    pub bounds: [f32; 2],
+    pub emitter_angle: f32,
+    pub emitter_spread: f32,
+    pub min_speed: f32,
+    pub max_speed: f32,
+    pub min_lifetime: f32,
+    pub max_lifetime: f32,
+    pub restitution: f32,
+    pub time_step: f32,
+    pub point_size: f32,
+    pub particle_count: u32,
+    pub seed: u32,
+    pub _padding: [u32; 3],
+}
+
+//  One direction of a separable gaussian blur, for gaussianBlurFragmentShader:
+//  each pixel becomes the average of the pixels up to radius away along direction (a step of one pixel),
+//  weighted by a gaussian whose standard deviation is sigma pixels.
+// typedef struct
+// {
+//     vector_int2 direction;
+//     float sigma;
+//     uint32_t radius;
+// } AAPLBlurUniforms;
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct AAPLBlurUniforms {
+    pub direction: [i32; 2],
+    pub sigma: f32,
+    pub radius: u32,
+}
+
+//  Bloom: brightPassFragmentShader keeps what's brighter than threshold, easing in over knee,
+//  and bloomCompositeFragmentShader adds the blurred highlights back, scaled by intensity.
+// typedef struct
+// {
+//     float threshold;
+//     float knee;
+//     float intensity;
+// } AAPLBloomUniforms;
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct AAPLBloomUniforms {
+    pub threshold: f32,
+    pub knee: f32,
+    pub intensity: f32,
+}
+
+//  How much colorGradeFragmentShader moves colors towards the color lookup table's,
+//  and how many entries the table has along each axis.
+// typedef struct
+// {
+//     float amount;
+//     uint32_t lutSize;
+// } AAPLColorGradeUniforms;
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct AAPLColorGradeUniforms {
+    pub amount: f32,
+    pub lut_size: u32,
+}
+
+//  vignetteFragmentShader darkens pixels further than radius from the centre (in half-heights of the view),
+//  by up to intensity, easing in over softness.
+// typedef struct
+// {
+//     float intensity;
+//     float radius;
+//     float softness;
+// } AAPLVignetteUniforms;
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct AAPLVignetteUniforms {
+    pub intensity: f32,
+    pub radius: f32,
+    pub softness: f32,
+}
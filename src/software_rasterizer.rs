@@ -0,0 +1,721 @@
+//! Drawing a scene on the CPU, the way our shaders and Metal would
+//!
+//! This lets us check what a scene looks like without a GPU,
+//! e.g. by comparing against a known-good image.
+//! It follows the same steps as the GPU:
+//! `sceneVertexShader` transforms and tints each vertex,
+//! the viewport maps positions to pixels (with y pointing down),
+//! triangles cover the pixels whose centres they contain (using Metal's top-left rule),
+//! colors are interpolated across each primitive,
+//! and the result is blended into the framebuffer.
+//!
+//! A multisampled framebuffer keeps several samples per pixel, at Metal's standard sample positions.
+//! Triangles cover samples rather than pixel centres, the color is worked out once per pixel
+//! (at its centre, as Metal does without sample-rate shading),
+//! and the samples are averaged when the framebuffer is resolved.
+//!
+//! Particles are drawn as `particleFragmentShader` draws them: round points, covering the pixels whose centres they contain.
+//!
+//! Textured meshes are drawn the way `texturedFragmentShader` draws them,
+//! sampling the texture with `SamplerDesc::sample`, and text the way `textFragmentShader` draws it.
+//! Our meshes are flat, so the level of detail is the same across a whole triangle.
+//!
+//! Fragments are depth tested as the default `DepthStencilDesc` tests them:
+//! drawn if they're no farther than what's already there, and then their depth is kept.
+//! Each node is drawn at one depth, so a pixel's samples all get the depth at its centre.
+//!
+//! Post-processing effects are applied pass by pass, as the GPU applies them to the resolved scene.
+
+use crate::batcher::Batch2D;
+use crate::blend::{BlendMode, BlendState};
+use crate::mesh::{Mesh, PrimitiveTopology};
+use crate::scene::Scene;
+use crate::shader_types::{AAPLVertex, AAPLTexturedVertex, AAPLInstance, AAPLParticle};
+use crate::particles::particle_color;
+use crate::transform::{Matrix3, Transform2D};
+use crate::image::TextureData;
+use crate::sampler::SamplerDesc;
+use crate::text::glyph_atlas_sampler;
+use crate::glyph_atlas::DISTANCE_FIELD_SPREAD;
+use crate::post_process::{PostEffect, PostPass, PostShader, PostInput, ColorLut, gaussian_blur, bright_pass, bloom_composite, color_grade, vignette};
+
+/// Where a pixel's samples are, from its top-left corner, for each sample count Metal supports.
+///
+/// These are Metal's standard sample positions.
+static SAMPLE_POSITIONS_1: [[f32; 2]; 1] = [[0.5, 0.5]];
+static SAMPLE_POSITIONS_2: [[f32; 2]; 2] = [[0.75, 0.75], [0.25, 0.25]];
+static SAMPLE_POSITIONS_4: [[f32; 2]; 4] = [
+    [0.375, 0.125], [0.875, 0.375], [0.125, 0.625], [0.625, 0.875],
+];
+static SAMPLE_POSITIONS_8: [[f32; 2]; 8] = [
+    [0.5625, 0.3125], [0.4375, 0.6875], [0.8125, 0.5625], [0.3125, 0.1875],
+    [0.1875, 0.8125], [0.0625, 0.4375], [0.6875, 0.9375], [0.9375, 0.0625],
+];
+
+/// The sample positions for a sample count, or `None` if Metal doesn't support that many.
+pub fn sample_positions(sample_count: usize) -> Option<&'static [[f32; 2]]> {
+    match sample_count {
+        1 => Some(&SAMPLE_POSITIONS_1),
+        2 => Some(&SAMPLE_POSITIONS_2),
+        4 => Some(&SAMPLE_POSITIONS_4),
+        8 => Some(&SAMPLE_POSITIONS_8),
+        _ => None,
+    }
+}
+
+/// An image of RGBA colors, top row first, with one or more samples per pixel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Framebuffer {
+    width: usize,
+    height: usize,
+    sample_positions: &'static [[f32; 2]],
+    /// Each pixel's samples are next to each other.
+    samples: Vec<[f32; 4]>,
+    /// Each sample's depth, from 0 (nearest) to 1 (farthest).
+    depths: Vec<f32>,
+}
+
+impl Framebuffer {
+    /// A framebuffer with one sample per pixel, filled with the clear color.
+    pub fn new(width: usize, height: usize, clear_color: [f32; 4]) -> Self {
+        Self::new_multisample(width, height, 1, clear_color).unwrap()
+    }
+
+    /// A framebuffer with `sample_count` samples per pixel, filled with the clear color
+    /// (and cleared to the farthest depth, as the view's depth attachment is),
+    /// or `None` if Metal doesn't support that many samples.
+    pub fn new_multisample(width: usize, height: usize, sample_count: usize, clear_color: [f32; 4]) -> Option<Self> {
+        Some(Framebuffer {
+            width,
+            height,
+            sample_positions: sample_positions(sample_count)?,
+            samples: vec![clear_color; width * height * sample_count],
+            depths: vec![1.; width * height * sample_count],
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+    pub fn height(&self) -> usize {
+        self.height
+    }
+    pub fn sample_count(&self) -> usize {
+        self.sample_positions.len()
+    }
+
+    /// Where the pixel's samples are, from its top-left corner.
+    pub fn sample_positions(&self) -> &'static [[f32; 2]] {
+        self.sample_positions
+    }
+
+    fn sample_index(&self, x: usize, y: usize, sample: usize) -> usize {
+        (y * self.width + x) * self.sample_count() + sample
+    }
+
+    /// The pixel's color: the average of its samples.
+    pub fn pixel(&self, x: usize, y: usize) -> [f32; 4] {
+        let start = self.sample_index(x, y, 0);
+        let samples = &self.samples[start..start + self.sample_count()];
+        let weight = 1. / samples.len() as f32;
+        interpolate(samples, &vec![weight; samples.len()])
+    }
+    /// Sets every sample of a pixel.
+    pub fn set_pixel(&mut self, x: usize, y: usize, color: [f32; 4]) {
+        for sample in 0..self.sample_count() {
+            self.set_sample(x, y, sample, color);
+        }
+    }
+
+    pub fn sample(&self, x: usize, y: usize, sample: usize) -> [f32; 4] {
+        self.samples[self.sample_index(x, y, sample)]
+    }
+    pub fn set_sample(&mut self, x: usize, y: usize, sample: usize, color: [f32; 4]) {
+        let index = self.sample_index(x, y, sample);
+        self.samples[index] = color;
+    }
+
+    pub fn depth(&self, x: usize, y: usize, sample: usize) -> f32 {
+        self.depths[self.sample_index(x, y, sample)]
+    }
+
+    /// Every sample, top row first, with each pixel's samples next to each other.
+    /// With one sample per pixel, that's every pixel.
+    pub fn samples(&self) -> &[[f32; 4]] {
+        &self.samples
+    }
+
+    /// Fills the framebuffer with a color, and clears its depths to the farthest.
+    pub fn clear(&mut self, clear_color: [f32; 4]) {
+        for sample in self.samples.iter_mut() {
+            *sample = clear_color;
+        }
+        for depth in self.depths.iter_mut() {
+            *depth = 1.;
+        }
+    }
+
+    /// Blends a color into every sample of a pixel.
+    pub fn blend_pixel(&mut self, x: usize, y: usize, color: [f32; 4], blend_state: &BlendState) {
+        for sample in 0..self.sample_count() {
+            self.blend_sample(x, y, sample, color, blend_state);
+        }
+    }
+
+    /// Blends a color into one sample, then clamps it as a unorm color attachment would.
+    pub fn blend_sample(&mut self, x: usize, y: usize, sample: usize, color: [f32; 4], blend_state: &BlendState) {
+        let blended = blend_state.apply(color, self.sample(x, y, sample));
+        self.set_sample(x, y, sample, clamp_color(blended));
+    }
+
+    /// Blends a fragment into one sample if it passes the depth test, keeping its depth.
+    fn blend_fragment(&mut self, x: usize, y: usize, sample: usize, depth: f32, color: [f32; 4], blend_state: &BlendState) {
+        let index = self.sample_index(x, y, sample);
+        if depth <= self.depths[index] {
+            self.depths[index] = depth;
+            self.blend_sample(x, y, sample, color, blend_state);
+        }
+    }
+
+    /// A single-sampled framebuffer with each pixel's samples averaged,
+    /// as `MTLStoreActionMultisampleResolve` does.
+    pub fn resolve(&self) -> Framebuffer {
+        let mut resolved = Framebuffer::new(self.width, self.height, [0.; 4]);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                resolved.set_pixel(x, y, self.pixel(x, y));
+            }
+        }
+        resolved
+    }
+
+    /// The (resolved) pixels as 8-bit RGBA, as they'd be stored in a `RGBA8Unorm` texture.
+    pub fn to_rgba8(&self) -> Vec<u8> {
+        self.resolve().samples.iter()
+            .flat_map(|pixel| pixel.iter().map(|&channel| unorm8(channel)))
+            .collect()
+    }
+}
+
+/// Converts a color channel to 8 bits, rounding to the nearest value as Metal does.
+pub fn unorm8(channel: f32) -> u8 {
+    (channel.clamp(0., 1.) * 255. + 0.5) as u8
+}
+
+fn clamp_color(color: [f32; 4]) -> [f32; 4] {
+    let mut clamped = color;
+    for channel in clamped.iter_mut() {
+        *channel = channel.clamp(0., 1.);
+    }
+    clamped
+}
+
+/// A vertex after the vertex shader and viewport transform:
+/// a position in pixels from the top-left corner, a depth, a color, and texture coordinates
+/// (which are ignored unless drawing with a texture).
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct WindowVertex {
+    pub position: [f32; 2],
+    pub depth: f32,
+    pub color: [f32; 4],
+    pub texture_coordinate: [f32; 2],
+}
+
+/// What colors the fragments: one of our fragment shaders.
+#[derive(Debug, Copy, Clone)]
+pub enum FragmentShader<'a> {
+    /// `fragmentShader`: the interpolated vertex color.
+    VertexColor,
+    /// `texturedFragmentShader`: the texture times the interpolated vertex color.
+    Textured { texture: &'a TextureData, sampler: &'a SamplerDesc },
+    /// `textFragmentShader`: the vertex color, with the glyph atlas's distance field as coverage.
+    Text { atlas: &'a TextureData, sampler: &'a SamplerDesc },
+}
+
+impl<'a> FragmentShader<'a> {
+    fn shade(&self, color: [f32; 4], texture_coordinate: [f32; 2], level_of_detail: f32) -> [f32; 4] {
+        match self {
+            FragmentShader::VertexColor => color,
+            FragmentShader::Textured { texture, sampler } => {
+                let texel = sampler.sample(texture, texture_coordinate, level_of_detail);
+                [texel[0] * color[0], texel[1] * color[1], texel[2] * color[2], texel[3] * color[3]]
+            }
+            FragmentShader::Text { atlas, sampler } => {
+                let distance = sampler.sample(atlas, texture_coordinate, level_of_detail)[3];
+                // What fwidth gives for a straight edge: how much the distance changes from one pixel to the next.
+                let width = level_of_detail.exp2() / (DISTANCE_FIELD_SPREAD as f32 * 2.);
+                let coverage = ((distance - 0.5) / width + 0.5).clamp(0., 1.);
+                [color[0], color[1], color[2], color[3] * coverage]
+            }
+        }
+    }
+
+    /// The level of detail for a triangle: log2 of how many texels
+    /// one pixel step covers, in whichever direction covers the most.
+    fn level_of_detail(&self, vertices: &[WindowVertex; 3]) -> f32 {
+        let texture = match self {
+            FragmentShader::VertexColor => return 0.,
+            FragmentShader::Textured { texture, .. } => texture,
+            FragmentShader::Text { atlas, .. } => atlas,
+        };
+        let [v0, v1, v2] = vertices;
+        let area = edge_function(v0.position, v1.position, v2.position);
+        if area == 0. {
+            return 0.;
+        }
+        // How the texel coordinates change per pixel in x and y, from the plane through the three vertices.
+        let size = [texture.width() as f32, texture.height() as f32];
+        let mut derivatives = [[0.; 2]; 2];
+        for (axis, derivative) in derivatives.iter_mut().enumerate() {
+            let t0 = v0.texture_coordinate[axis] * size[axis];
+            let t1 = v1.texture_coordinate[axis] * size[axis];
+            let t2 = v2.texture_coordinate[axis] * size[axis];
+            let (dx1, dy1) = (v1.position[0] - v0.position[0], v1.position[1] - v0.position[1]);
+            let (dx2, dy2) = (v2.position[0] - v0.position[0], v2.position[1] - v0.position[1]);
+            derivative[0] = ((t1 - t0) * dy2 - (t2 - t0) * dy1) / area;
+            derivative[1] = ((t2 - t0) * dx1 - (t1 - t0) * dx2) / area;
+        }
+        let length_x = (derivatives[0][0].powi(2) + derivatives[1][0].powi(2)).sqrt();
+        let length_y = (derivatives[0][1].powi(2) + derivatives[1][1].powi(2)).sqrt();
+        length_x.max(length_y).log2()
+    }
+}
+
+/// What `sceneVertexShader` and the viewport transform do to a vertex, at depth 0.
+pub fn shade_vertex(vertex: &AAPLVertex, transform: &Matrix3, tint: [f32; 4], width: usize, height: usize) -> WindowVertex {
+    let position = vertex.position;
+    let color = vertex.color;
+    let [x, y] = transform.transform_point([position.x(), position.y()]);
+    let color = [color.x(), color.y(), color.z(), color.w()];
+    WindowVertex {
+        // Positions are in pixels from the centre of the view, with y up.
+        position: [width as f32 / 2. + x, height as f32 / 2. - y],
+        depth: 0.,
+        color: [color[0] * tint[0], color[1] * tint[1], color[2] * tint[2], color[3] * tint[3]],
+        texture_coordinate: [0., 0.],
+    }
+}
+
+/// What `texturedVertexShader` and the viewport transform do to a vertex.
+pub fn shade_textured_vertex(vertex: &AAPLTexturedVertex, transform: &Matrix3, tint: [f32; 4], width: usize, height: usize) -> WindowVertex {
+    let untextured = AAPLVertex { position: vertex.position, color: vertex.color };
+    let texture_coordinate = vertex.texture_coordinate;
+    WindowVertex {
+        texture_coordinate: [texture_coordinate.x(), texture_coordinate.y()],
+        ..shade_vertex(&untextured, transform, tint, width, height)
+    }
+}
+
+/// Draws a scene into a framebuffer, in the same order the renderer would.
+pub fn draw_scene(framebuffer: &mut Framebuffer, scene: &Scene<AAPLVertex>) {
+    for item in scene.draw_list() {
+        // A node whose mesh isn't in the scene has nothing to draw.
+        let mesh = match scene.mesh(item.mesh) {
+            Some(mesh) => mesh,
+            None => continue,
+        };
+        draw_mesh(framebuffer, mesh, &item.world_transform, item.tint, item.depth, &item.blend_mode.blend_state());
+    }
+}
+
+/// Draws a mesh with the given transform, tint and depth.
+pub fn draw_mesh(framebuffer: &mut Framebuffer, mesh: &Mesh<AAPLVertex>, transform: &Matrix3, tint: [f32; 4], depth: f32, blend_state: &BlendState) {
+    let (width, height) = (framebuffer.width(), framebuffer.height());
+    let vertices = assemble_vertices(mesh, |vertex| WindowVertex { depth, ..shade_vertex(vertex, transform, tint, width, height) });
+    draw_primitives(framebuffer, &vertices, mesh.topology(), &FragmentShader::VertexColor, blend_state);
+}
+
+/// Draws a copy of the mesh for each instance, as `instancedVertexShader` does,
+/// in the order of the instances.
+pub fn draw_instanced_mesh(framebuffer: &mut Framebuffer, mesh: &Mesh<AAPLVertex>, instances: &[AAPLInstance], transform: &Matrix3, tint: [f32; 4], depth: f32, blend_state: &BlendState) {
+    for instance in instances {
+        let instance_transform = Transform2D { translation: instance.offset, rotation: instance.rotation, scale: instance.scale };
+        let color = instance.color;
+        let instance_tint = [tint[0] * color.x(), tint[1] * color.y(), tint[2] * color.z(), tint[3] * color.w()];
+        draw_mesh(framebuffer, mesh, &(*transform * instance_transform.to_matrix()), instance_tint, depth, blend_state);
+    }
+}
+
+/// Draws particles the way `particleVertexShader` and `particleFragmentShader` do,
+/// as round points `point_size` pixels across.
+pub fn draw_particles(framebuffer: &mut Framebuffer, particles: &[AAPLParticle], point_size: f32, blend_state: &BlendState) {
+    let (width, height) = (framebuffer.width() as f32, framebuffer.height() as f32);
+    let radius = point_size / 2.;
+    for particle in particles {
+        let color = particle_color(particle);
+        let center = [width / 2. + particle.position[0], height / 2. - particle.position[1]];
+        let left = (center[0] - radius).floor().max(0.) as usize;
+        let right = (center[0] + radius).ceil().min(width).max(0.) as usize;
+        let top = (center[1] - radius).floor().max(0.) as usize;
+        let bottom = (center[1] + radius).ceil().min(height).max(0.) as usize;
+        for y in top..bottom {
+            for x in left..right {
+                let (dx, dy) = (x as f32 + 0.5 - center[0], y as f32 + 0.5 - center[1]);
+                if dx * dx + dy * dy <= radius * radius {
+                    framebuffer.blend_pixel(x, y, color, blend_state);
+                }
+            }
+        }
+    }
+}
+
+/// Applies a chain of post-processing effects to a framebuffer, one after the other, into a new one.
+pub fn apply_post_effects(framebuffer: &Framebuffer, effects: &[PostEffect]) -> Framebuffer {
+    effects.iter().fold(framebuffer.resolve(), |image, effect| apply_post_effect(&image, effect))
+}
+
+/// Applies one post-processing effect to a framebuffer, pass by pass, into a new one.
+pub fn apply_post_effect(framebuffer: &Framebuffer, effect: &PostEffect) -> Framebuffer {
+    let input = framebuffer.resolve();
+    let mut outputs: Vec<Framebuffer> = Vec::new();
+    for pass in effect.passes() {
+        let image = |input_image: PostInput| match input_image {
+            PostInput::EffectInput => &input,
+            PostInput::Pass(index) => &outputs[index],
+        };
+        let output = apply_post_pass(&pass, image(pass.source), pass.bloom.map(image), effect.lut());
+        outputs.push(output);
+    }
+    outputs.pop().unwrap_or(input)
+}
+
+/// Draws one full-screen post-processing pass, as its fragment shader does.
+pub fn apply_post_pass(pass: &PostPass, source: &Framebuffer, bloom: Option<&Framebuffer>, lut: Option<&ColorLut>) -> Framebuffer {
+    let (width, height) = (source.width(), source.height());
+    let read = |x: i32, y: i32| source.pixel(x.clamp(0, width as i32 - 1) as usize, y.clamp(0, height as i32 - 1) as usize);
+    let mut output = Framebuffer::new(width, height, [0.; 4]);
+    for y in 0..height {
+        for x in 0..width {
+            let color = source.pixel(x, y);
+            let shaded = match &pass.shader {
+                PostShader::GaussianBlur(uniforms) => gaussian_blur(read, x as i32, y as i32, uniforms),
+                PostShader::BrightPass(uniforms) => bright_pass(color, uniforms),
+                PostShader::BloomComposite(uniforms) => bloom_composite(color, bloom.expect("bloom composite without the bloom").pixel(x, y), uniforms),
+                PostShader::ColorGrade(uniforms) => color_grade(color, lut.expect("color grade without a lookup table"), uniforms),
+                PostShader::Vignette(uniforms) => vignette(color, x, y, [width, height], uniforms),
+            };
+            output.set_pixel(x, y, clamp_color(shaded));
+        }
+    }
+    output
+}
+
+/// Draws a textured mesh with the given transform and tint, as `TexturedMesh`es are drawn.
+pub fn draw_textured_mesh(framebuffer: &mut Framebuffer, mesh: &Mesh<AAPLTexturedVertex>, transform: &Matrix3, tint: [f32; 4], texture: &TextureData, sampler: &SamplerDesc, blend_state: &BlendState) {
+    let (width, height) = (framebuffer.width(), framebuffer.height());
+    let vertices = assemble_vertices(mesh, |vertex| shade_textured_vertex(vertex, transform, tint, width, height));
+    draw_primitives(framebuffer, &vertices, mesh.topology(), &FragmentShader::Textured { texture, sampler }, blend_state);
+}
+
+/// Draws text made by `text_mesh` with the given transform and tint, from the atlas it was made with.
+pub fn draw_text_mesh(framebuffer: &mut Framebuffer, mesh: &Mesh<AAPLTexturedVertex>, transform: &Matrix3, tint: [f32; 4], atlas: &TextureData, sampler: &SamplerDesc, blend_state: &BlendState) {
+    let (width, height) = (framebuffer.width(), framebuffer.height());
+    let vertices = assemble_vertices(mesh, |vertex| shade_textured_vertex(vertex, transform, tint, width, height));
+    draw_primitives(framebuffer, &vertices, mesh.topology(), &FragmentShader::Text { atlas, sampler }, blend_state);
+}
+
+/// Draws what a `Batcher2D` collected over the framebuffer, as the overlay pass draws it:
+/// shapes, then text from the batcher's atlas, both alpha blended.
+pub fn draw_batch(framebuffer: &mut Framebuffer, batch: &Batch2D, atlas: &TextureData) {
+    let blend_state = BlendMode::Alpha.blend_state();
+    if let Some(shapes) = &batch.shapes {
+        draw_mesh(framebuffer, shapes, &Matrix3::identity(), [1., 1., 1., 1.], 0., &blend_state);
+    }
+    if let Some(text) = &batch.text {
+        draw_text_mesh(framebuffer, text, &Matrix3::identity(), [1., 1., 1., 1.], atlas, &glyph_atlas_sampler(), &blend_state);
+    }
+}
+
+/// The mesh's shaded vertices, in the order the GPU would assemble them.
+fn assemble_vertices<V>(mesh: &Mesh<V>, shade: impl Fn(&V) -> WindowVertex) -> Vec<WindowVertex> {
+    let draw_range = mesh.draw_range();
+    (draw_range.start..draw_range.start + draw_range.count)
+        .map(|position| match mesh.indices() {
+            Some(indices) => indices.get(position).unwrap() as usize,
+            None => position,
+        })
+        .map(|index| shade(&mesh.vertices()[index]))
+        .collect()
+}
+
+fn draw_primitives(framebuffer: &mut Framebuffer, vertices: &[WindowVertex], topology: PrimitiveTopology, shader: &FragmentShader, blend_state: &BlendState) {
+    match topology {
+        PrimitiveTopology::Point => {
+            for vertex in vertices {
+                shade_point(framebuffer, vertex, shader, blend_state);
+            }
+        }
+        PrimitiveTopology::Line => {
+            for line in vertices.chunks_exact(2) {
+                shade_line(framebuffer, &line[0], &line[1], shader, blend_state);
+            }
+        }
+        PrimitiveTopology::LineStrip => {
+            for line in vertices.windows(2) {
+                shade_line(framebuffer, &line[0], &line[1], shader, blend_state);
+            }
+        }
+        PrimitiveTopology::Triangle => {
+            for triangle in vertices.chunks_exact(3) {
+                shade_triangle(framebuffer, [triangle[0], triangle[1], triangle[2]], shader, blend_state);
+            }
+        }
+        PrimitiveTopology::TriangleStrip => {
+            for triangle in vertices.windows(3) {
+                shade_triangle(framebuffer, [triangle[0], triangle[1], triangle[2]], shader, blend_state);
+            }
+        }
+    }
+}
+
+/// How far `point` is to one side of the edge from `a` to `b` (twice the triangle's signed area).
+fn edge_function(a: [f32; 2], b: [f32; 2], point: [f32; 2]) -> f32 {
+    (b[0] - a[0]) * (point[1] - a[1]) - (b[1] - a[1]) * (point[0] - a[0])
+}
+
+/// Whether an edge is a top or left edge of a triangle wound so its area is positive
+/// (with y pointing down): pixel centres exactly on those edges are drawn, on others not,
+/// so triangles that share an edge don't both draw it.
+fn is_top_left(a: [f32; 2], b: [f32; 2]) -> bool {
+    let (dx, dy) = (b[0] - a[0], b[1] - a[1]);
+    (dy == 0. && dx < 0.) || dy > 0.
+}
+
+/// Fills a triangle, interpolating the vertex colors across it.
+///
+/// Each sample the triangle covers gets the color at the pixel's centre.
+pub fn draw_triangle(framebuffer: &mut Framebuffer, vertices: [WindowVertex; 3], blend_state: &BlendState) {
+    shade_triangle(framebuffer, vertices, &FragmentShader::VertexColor, blend_state)
+}
+
+fn shade_triangle(framebuffer: &mut Framebuffer, vertices: [WindowVertex; 3], shader: &FragmentShader, blend_state: &BlendState) {
+    let level_of_detail = shader.level_of_detail(&vertices);
+    let [mut v0, mut v1, v2] = vertices;
+    let mut area = edge_function(v0.position, v1.position, v2.position);
+    if area == 0. {
+        return;
+    }
+    // Metal doesn't cull by default, so draw either winding.
+    if area < 0. {
+        std::mem::swap(&mut v0, &mut v1);
+        area = -area;
+    }
+
+    let min_x = v0.position[0].min(v1.position[0]).min(v2.position[0]).floor().max(0.) as usize;
+    let min_y = v0.position[1].min(v1.position[1]).min(v2.position[1]).floor().max(0.) as usize;
+    let max_x = (v0.position[0].max(v1.position[0]).max(v2.position[0]).ceil().max(0.) as usize).min(framebuffer.width());
+    let max_y = (v0.position[1].max(v1.position[1]).max(v2.position[1]).ceil().max(0.) as usize).min(framebuffer.height());
+
+    let edges = [(v1, v2), (v2, v0), (v0, v1)];
+    let covers = |point: [f32; 2]| edges.iter().all(|(a, b)| {
+        let distance = edge_function(a.position, b.position, point);
+        distance > 0. || (distance == 0. && is_top_left(a.position, b.position))
+    });
+    let sample_positions = framebuffer.sample_positions();
+    for y in min_y..max_y {
+        for x in min_x..max_x {
+            let covered: Vec<usize> = (0..sample_positions.len())
+                .filter(|&sample| {
+                    let [sample_x, sample_y] = sample_positions[sample];
+                    covers([x as f32 + sample_x, y as f32 + sample_y])
+                })
+                .collect();
+            if covered.is_empty() {
+                continue;
+            }
+            let centre = [x as f32 + 0.5, y as f32 + 0.5];
+            let mut weights = [0.; 3];
+            for (weight, (a, b)) in weights.iter_mut().zip(edges.iter()) {
+                *weight = edge_function(a.position, b.position, centre) / area;
+            }
+            let color = interpolate(&[v0.color, v1.color, v2.color], &weights);
+            let texture_coordinate = interpolate_texture_coordinate(&[v0.texture_coordinate, v1.texture_coordinate, v2.texture_coordinate], &weights);
+            let color = shader.shade(color, texture_coordinate, level_of_detail);
+            let depth = v0.depth * weights[0] + v1.depth * weights[1] + v2.depth * weights[2];
+            for sample in covered {
+                framebuffer.blend_fragment(x, y, sample, depth, color, blend_state);
+            }
+        }
+    }
+}
+
+/// Draws a one pixel wide line, stepping along its longer axis.
+///
+/// Lines (and points) aren't anti-aliased: they cover every sample of the pixels they touch.
+pub fn draw_line(framebuffer: &mut Framebuffer, start: &WindowVertex, end: &WindowVertex, blend_state: &BlendState) {
+    shade_line(framebuffer, start, end, &FragmentShader::VertexColor, blend_state)
+}
+
+fn shade_line(framebuffer: &mut Framebuffer, start: &WindowVertex, end: &WindowVertex, shader: &FragmentShader, blend_state: &BlendState) {
+    let (dx, dy) = (end.position[0] - start.position[0], end.position[1] - start.position[1]);
+    let steps = dx.abs().max(dy.abs()).round() as usize;
+    // Like Metal, leave off the last pixel so line strips don't draw their joins twice.
+    for step in 0..steps.max(1) {
+        let t = if steps == 0 { 0. } else { step as f32 / steps as f32 };
+        let point = [start.position[0] + dx * t, start.position[1] + dy * t];
+        let color = interpolate(&[start.color, end.color], &[1. - t, t]);
+        let texture_coordinate = interpolate_texture_coordinate(&[start.texture_coordinate, end.texture_coordinate], &[1. - t, t]);
+        let depth = start.depth * (1. - t) + end.depth * t;
+        blend_point(framebuffer, point, depth, shader.shade(color, texture_coordinate, 0.), blend_state);
+    }
+}
+
+/// Draws a single pixel point.
+pub fn draw_point(framebuffer: &mut Framebuffer, vertex: &WindowVertex, blend_state: &BlendState) {
+    shade_point(framebuffer, vertex, &FragmentShader::VertexColor, blend_state)
+}
+
+fn shade_point(framebuffer: &mut Framebuffer, vertex: &WindowVertex, shader: &FragmentShader, blend_state: &BlendState) {
+    let color = shader.shade(vertex.color, vertex.texture_coordinate, 0.);
+    blend_point(framebuffer, vertex.position, vertex.depth, color, blend_state);
+}
+
+fn blend_point(framebuffer: &mut Framebuffer, point: [f32; 2], depth: f32, color: [f32; 4], blend_state: &BlendState) {
+    let (x, y) = (point[0].floor(), point[1].floor());
+    if x >= 0. && y >= 0. && (x as usize) < framebuffer.width() && (y as usize) < framebuffer.height() {
+        for sample in 0..framebuffer.sample_count() {
+            framebuffer.blend_fragment(x as usize, y as usize, sample, depth, color, blend_state);
+        }
+    }
+}
+
+/// A weighted sum of colors.
+fn interpolate(colors: &[[f32; 4]], weights: &[f32]) -> [f32; 4] {
+    let mut result = [0.; 4];
+    for (color, &weight) in colors.iter().zip(weights) {
+        for (result_channel, &channel) in result.iter_mut().zip(color) {
+            *result_channel += channel * weight;
+        }
+    }
+    result
+}
+
+/// A weighted sum of texture coordinates.
+fn interpolate_texture_coordinate(texture_coordinates: &[[f32; 2]], weights: &[f32]) -> [f32; 2] {
+    let mut result = [0.; 2];
+    for (texture_coordinate, &weight) in texture_coordinates.iter().zip(weights) {
+        result[0] += texture_coordinate[0] * weight;
+        result[1] += texture_coordinate[1] * weight;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::MeshId;
+    use crate::scene_file::SceneDescription;
+    use crate::image::{Image, decode_png};
+    use crate::screenshot::save_png;
+    use std::path::Path;
+
+    fn white_vertex(position: [f32; 2]) -> WindowVertex {
+        WindowVertex { position, depth: 0., color: [1., 1., 1., 1.], texture_coordinate: [0., 0.] }
+    }
+
+    /// A black pixel with a white triangle over the part of it left of `edge`.
+    fn pixel_covered_left_of(edge: f32, sample_count: usize) -> Framebuffer {
+        let mut framebuffer = Framebuffer::new_multisample(1, 1, sample_count, [0., 0., 0., 1.]).unwrap();
+        let triangle = [white_vertex([-10., -10.]), white_vertex([edge, -10.]), white_vertex([edge, 30.])];
+        draw_triangle(&mut framebuffer, triangle, &BlendMode::Opaque.blend_state());
+        framebuffer
+    }
+
+    #[test]
+    fn sample_positions_are_inside_the_pixel() {
+        for sample_count in [1, 2, 4, 8] {
+            let positions = sample_positions(sample_count).unwrap();
+            assert_eq!(positions.len(), sample_count);
+            assert!(positions.iter().flatten().all(|&coordinate| (0. ..1.).contains(&coordinate)));
+        }
+        assert_eq!(sample_positions(3), None);
+        assert_eq!(Framebuffer::new_multisample(1, 1, 16, [0.; 4]), None);
+    }
+
+    #[test]
+    fn edges_cover_the_samples_they_contain() {
+        // One sample, at the centre: all or nothing.
+        assert_eq!(pixel_covered_left_of(0.4, 1).pixel(0, 0), [0., 0., 0., 1.]);
+        assert_eq!(pixel_covered_left_of(0.6, 1).pixel(0, 0), [1., 1., 1., 1.]);
+
+        let four = pixel_covered_left_of(0.4, 4);
+        let covered: Vec<bool> = (0..4).map(|sample| four.sample(0, 0, sample) == [1., 1., 1., 1.]).collect();
+        assert_eq!(covered, [true, false, true, false]);
+        assert_eq!(four.pixel(0, 0), [0.5, 0.5, 0.5, 1.]);
+
+        // Three of the eight samples are left of 0.4.
+        assert_eq!(pixel_covered_left_of(0.4, 8).pixel(0, 0), [0.375, 0.375, 0.375, 1.]);
+    }
+
+    #[test]
+    fn resolving_averages_each_pixels_samples() {
+        let framebuffer = pixel_covered_left_of(0.4, 4);
+        let resolved = framebuffer.resolve();
+        assert_eq!(resolved.sample_count(), 1);
+        assert_eq!(resolved.samples(), &[[0.5, 0.5, 0.5, 1.]]);
+        // 0.5 rounds up to 128, as Metal rounds to the nearest 8-bit value.
+        assert_eq!(framebuffer.to_rgba8(), [128, 128, 128, 255]);
+    }
+
+    #[test]
+    fn the_depth_test_hides_farther_nodes_drawn_later() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes/depth.json");
+        let scene = SceneDescription::load(&path).unwrap().to_scene();
+        let depths: Vec<f32> = scene.draw_list().iter().map(|item| item.depth).collect();
+        assert_eq!(depths, [0.25, 0.75]);
+
+        let mut framebuffer = Framebuffer::new(400, 400, [0., 0., 0., 1.]);
+        draw_scene(&mut framebuffer, &scene);
+        // Where they overlap, the nearer (red) node is drawn even though the farther (blue) one came after it.
+        assert_eq!(framebuffer.pixel(200, 300), [1., 0., 0., 1.]);
+        assert_eq!(framebuffer.depth(200, 300, 0), 0.25);
+        // Elsewhere each is drawn as it would be on its own.
+        assert_eq!(framebuffer.pixel(100, 300), [1., 0., 0., 1.]);
+        assert_eq!(framebuffer.pixel(300, 300), [0., 0., 1., 1.]);
+        assert_eq!(framebuffer.depth(300, 300, 0), 0.75);
+    }
+
+    #[test]
+    fn nodes_drawing_missing_meshes_are_skipped() {
+        let mut scene = SceneDescription::default().to_scene();
+        let node = scene.draw_list()[0].node;
+        scene.node_mut(node).unwrap().mesh = Some(MeshId(7));
+        let mut framebuffer = Framebuffer::new(4, 4, [0., 0., 0., 1.]);
+        draw_scene(&mut framebuffer, &scene);
+        assert!(framebuffer.to_rgba8().chunks(4).all(|pixel| pixel == [0, 0, 0, 255]));
+    }
+
+    /// The example triangle, 35 pixels across, in a 64 by 48 view with 4× multisampling,
+    /// with an effect applied.
+    fn draw_with_effect(effect: &PostEffect) -> Image {
+        let mut description = SceneDescription::default();
+        description.nodes[0].scale = [0.07, 0.07];
+        let [red, green, blue, alpha] = description.clear_color;
+        let mut framebuffer = Framebuffer::new_multisample(64, 48, 4, [red as f32, green as f32, blue as f32, alpha as f32]).unwrap();
+        draw_scene(&mut framebuffer, &description.to_scene());
+        let framebuffer = apply_post_effects(&framebuffer, std::slice::from_ref(effect));
+        Image::new(64, 48, framebuffer.to_rgba8()).unwrap()
+    }
+
+    /// Checks each effect against its known-good image in `goldens`, to within one step per channel.
+    /// Set HELLO_TRIANGLE_UPDATE_GOLDENS to write the images afresh instead.
+    #[test]
+    fn post_effects_match_their_goldens() {
+        let goldens = Path::new(env!("CARGO_MANIFEST_DIR")).join("goldens");
+        for name in ["blur", "bloom", "grade", "vignette"] {
+            let image = draw_with_effect(&PostEffect::from_name(name).unwrap());
+            let path = goldens.join(format!("post_{}.png", name));
+            if std::env::var_os("HELLO_TRIANGLE_UPDATE_GOLDENS").is_some() {
+                save_png(&image, &path).unwrap();
+                continue;
+            }
+            let golden = decode_png(&std::fs::read(&path).unwrap()).unwrap();
+            assert_eq!((golden.width(), golden.height()), (image.width(), image.height()), "{}", name);
+            let worst = image.pixels().iter().zip(golden.pixels())
+                .map(|(&actual, &expected)| actual.abs_diff(expected))
+                .max()
+                .unwrap();
+            assert!(worst <= 1, "{} is off by up to {} from {}", name, worst, path.display());
+        }
+    }
+}
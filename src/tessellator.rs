@@ -0,0 +1,690 @@
+//! Turning 2D shapes into triangles
+//!
+//! Strokes (lines and polylines with a width, joins and caps) and fills
+//! (polygons, circles, arcs and rounded rectangles) become lists of triangles,
+//! which `triangle_list_mesh` turns into a mesh for the colored-vertex pipeline.
+//! Every triangle is anticlockwise with y up, whichever way round the points were given,
+//! and degenerate triangles are left out.
+//!
+//! Curves are split into straight segments no further than `tolerance` pixels from the true curve.
+//! Stroke segments and joins overlap where they meet, so translucent strokes are darker at their corners.
+
+use std::f32::consts::PI;
+use std::fmt::{Display, Formatter};
+use std::error::Error;
+use crate::mesh::{Mesh, PrimitiveTopology};
+use crate::shader_types::AAPLVertex;
+
+/// Three corners, anticlockwise with y up.
+pub type Triangle = [[f32; 2]; 3];
+
+/// How far curves may be from their straight segments, in pixels, unless told otherwise.
+pub static DEFAULT_TOLERANCE: f32 = 0.25;
+
+/// How two segments of a stroke are joined, on the outside of the turn.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LineJoin {
+    /// The outer edges are extended until they meet, unless the miter (from its tip to the inside corner)
+    /// would be more than `miter_limit` times the stroke width, when the join is bevelled instead.
+    Miter,
+    Round,
+    /// The outer corners are joined with a straight line.
+    Bevel,
+}
+
+/// How the ends of an open stroke are drawn.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LineCap {
+    /// The stroke stops at the end point.
+    Butt,
+    /// The stroke goes on half its width past the end point.
+    Square,
+    /// A half circle around the end point.
+    Round,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct StrokeStyle {
+    pub width: f32,
+    pub join: LineJoin,
+    pub cap: LineCap,
+    /// The longest a miter can be, as a multiple of the width.
+    pub miter_limit: f32,
+    /// How far curves (round joins and caps) may be from their segments, in pixels.
+    pub tolerance: f32,
+}
+
+/// The same defaults as SVG and Core Graphics: one pixel wide, mitered and butt-ended.
+impl Default for StrokeStyle {
+    fn default() -> Self {
+        StrokeStyle {
+            width: 1.,
+            join: LineJoin::Miter,
+            cap: LineCap::Butt,
+            miter_limit: 4.,
+            tolerance: DEFAULT_TOLERANCE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TessellationError {
+    /// The polygon's edges cross, so it has no inside to fill.
+    SelfIntersecting { point_count: usize },
+}
+impl Display for TessellationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::SelfIntersecting { point_count } =>
+                write!(f, "the polygon of {} points crosses itself", point_count),
+        }
+    }
+}
+impl Error for TessellationError {}
+
+/// A mesh of the triangles in a single color, with no indices.
+pub fn triangle_list_mesh(triangles: &[Triangle], color: [f32; 4]) -> Mesh<AAPLVertex> {
+    let vertices = triangles.iter()
+        .flat_map(|triangle| triangle.iter().map(|&position| AAPLVertex::new(position, color)))
+        .collect();
+    Mesh::new(vertices, PrimitiveTopology::Triangle)
+}
+
+/// Twice the signed area of the triangle: positive if it's anticlockwise.
+pub fn signed_area_2(triangle: &Triangle) -> f32 {
+    let [a, b, c] = *triangle;
+    cross(sub(b, a), sub(c, a))
+}
+
+/// Adds the triangle anticlockwise, unless it has no area.
+fn push_triangle(triangles: &mut Vec<Triangle>, a: [f32; 2], b: [f32; 2], c: [f32; 2]) {
+    let area = signed_area_2(&[a, b, c]);
+    if area > 0. {
+        triangles.push([a, b, c]);
+    } else if area < 0. {
+        triangles.push([a, c, b]);
+    }
+}
+
+/// How many segments an arc through `angle` radians needs to stay within the tolerance.
+pub fn arc_segment_count(radius: f32, angle: f32, tolerance: f32) -> usize {
+    let angle = angle.abs();
+    if radius <= 0. || angle == 0. {
+        return 0;
+    }
+    // A chord through `step` radians is `radius * (1 - cos(step / 2))` from the arc at its middle.
+    let step = if tolerance >= radius {
+        PI / 2.
+    } else {
+        2. * (1. - tolerance.max(1e-3) / radius).acos()
+    };
+    ((angle / step).ceil() as usize).max(1)
+}
+
+/// Points along an arc, both ends included, from `start_angle` to `end_angle`
+/// (in radians anticlockwise from the x axis; the end may be less than the start, to go clockwise).
+pub fn arc_points(center: [f32; 2], radius: f32, start_angle: f32, end_angle: f32, tolerance: f32) -> Vec<[f32; 2]> {
+    let segments = arc_segment_count(radius, end_angle - start_angle, tolerance).max(1);
+    (0..=segments)
+        .map(|segment| {
+            let angle = start_angle + (end_angle - start_angle) * segment as f32 / segments as f32;
+            [center[0] + radius * angle.cos(), center[1] + radius * angle.sin()]
+        })
+        .collect()
+}
+
+/// Points around a circle, anticlockwise from the x axis, without repeating the first.
+pub fn circle_points(center: [f32; 2], radius: f32, tolerance: f32) -> Vec<[f32; 2]> {
+    let segments = arc_segment_count(radius, 2. * PI, tolerance).max(3);
+    (0..segments)
+        .map(|segment| {
+            let angle = 2. * PI * segment as f32 / segments as f32;
+            [center[0] + radius * angle.cos(), center[1] + radius * angle.sin()]
+        })
+        .collect()
+}
+
+/// Points around a rectangle with rounded corners, anticlockwise.
+///
+/// The corner radius is reduced if it's more than half the width or height.
+pub fn rounded_rect_points(center: [f32; 2], size: [f32; 2], corner_radius: f32, tolerance: f32) -> Vec<[f32; 2]> {
+    let (half_width, half_height) = (size[0].abs() / 2., size[1].abs() / 2.);
+    let radius = corner_radius.max(0.).min(half_width).min(half_height);
+    let inner = [half_width - radius, half_height - radius];
+    // Each corner's centre, and the angle its arc starts at, anticlockwise from the bottom right.
+    let corners = [([1., -1.], -PI / 2.), ([1., 1.], 0.), ([-1., 1.], PI / 2.), ([-1., -1.], PI)];
+    let mut points: Vec<[f32; 2]> = Vec::new();
+    for (corner, &([x, y], start_angle)) in corners.iter().enumerate() {
+        let corner_center = [center[0] + x * inner[0], center[1] + y * inner[1]];
+        if radius == 0. {
+            points.push(corner_center);
+            continue;
+        }
+        let arc = arc_points(corner_center, radius, start_angle, start_angle + PI / 2., tolerance);
+        // Where the straight edge before the corner has no length, the arc starts where the last one ended.
+        let edge_length = inner[corner % 2];
+        let skip = if edge_length == 0. { 1 } else { 0 };
+        points.extend(arc.into_iter().skip(skip));
+    }
+    remove_repeated_points(points, true)
+}
+
+/// Fills a polygon that may be concave, but mustn't cross or touch itself, by cutting off ears.
+///
+/// A polygon of `n` points with no three in a line gives `n - 2` triangles.
+/// Polygons with no area give no triangles.
+pub fn fill_polygon(points: &[[f32; 2]]) -> Result<Vec<Triangle>, TessellationError> {
+    let mut points = remove_collinear_points(remove_repeated_points(points.to_vec(), true));
+    if points.len() < 3 {
+        return Ok(Vec::new());
+    }
+    let point_count = points.len();
+    if crosses_itself(&points) {
+        return Err(TessellationError::SelfIntersecting { point_count });
+    }
+    let area = polygon_signed_area_2(&points);
+    if area == 0. {
+        return Ok(Vec::new());
+    }
+    if area < 0. {
+        points.reverse();
+    }
+
+    let mut remaining: Vec<usize> = (0..point_count).collect();
+    let mut triangles = Vec::with_capacity(point_count - 2);
+    while remaining.len() >= 3 {
+        let count = remaining.len();
+        let mut clipped = false;
+        for i in 0..count {
+            let (previous, current, next) = (remaining[(i + count - 1) % count], remaining[i], remaining[(i + 1) % count]);
+            let (a, b, c) = (points[previous], points[current], points[next]);
+            let turn = cross(sub(b, a), sub(c, b));
+            if turn == 0. {
+                // In a line with its neighbours: it adds nothing.
+                remaining.remove(i);
+                clipped = true;
+                break;
+            }
+            if turn < 0. {
+                continue;
+            }
+            let is_ear = remaining.iter()
+                .filter(|&&index| index != previous && index != current && index != next)
+                .all(|&index| !point_in_triangle(points[index], a, b, c));
+            if is_ear {
+                triangles.push([a, b, c]);
+                remaining.remove(i);
+                clipped = true;
+                break;
+            }
+        }
+        if !clipped {
+            return Err(TessellationError::SelfIntersecting { point_count });
+        }
+    }
+    Ok(triangles)
+}
+
+/// Fills a convex polygon as a fan from its first point: `n - 2` triangles for `n` points.
+pub fn fill_convex_polygon(points: &[[f32; 2]]) -> Vec<Triangle> {
+    let points = remove_repeated_points(points.to_vec(), true);
+    let mut triangles = Vec::new();
+    for i in 1..points.len().saturating_sub(1) {
+        push_triangle(&mut triangles, points[0], points[i], points[i + 1]);
+    }
+    triangles
+}
+
+pub fn fill_circle(center: [f32; 2], radius: f32, tolerance: f32) -> Vec<Triangle> {
+    fill_convex_polygon(&circle_points(center, radius, tolerance))
+}
+
+/// Fills the slice of a circle between two angles, as a fan from the centre.
+pub fn fill_arc(center: [f32; 2], radius: f32, start_angle: f32, end_angle: f32, tolerance: f32) -> Vec<Triangle> {
+    let points = arc_points(center, radius, start_angle, end_angle, tolerance);
+    let mut triangles = Vec::with_capacity(points.len());
+    for pair in points.windows(2) {
+        push_triangle(&mut triangles, center, pair[0], pair[1]);
+    }
+    triangles
+}
+
+pub fn fill_rounded_rect(center: [f32; 2], size: [f32; 2], corner_radius: f32, tolerance: f32) -> Vec<Triangle> {
+    fill_convex_polygon(&rounded_rect_points(center, size, corner_radius, tolerance))
+}
+
+pub fn stroke_line(from: [f32; 2], to: [f32; 2], style: &StrokeStyle) -> Vec<Triangle> {
+    stroke_polyline(&[from, to], false, style)
+}
+
+/// Strokes the outline of a circle.
+pub fn stroke_circle(center: [f32; 2], radius: f32, style: &StrokeStyle) -> Vec<Triangle> {
+    stroke_polyline(&circle_points(center, radius, style.tolerance), true, style)
+}
+
+/// Strokes an arc between two angles, with caps at its ends.
+pub fn stroke_arc(center: [f32; 2], radius: f32, start_angle: f32, end_angle: f32, style: &StrokeStyle) -> Vec<Triangle> {
+    stroke_polyline(&arc_points(center, radius, start_angle, end_angle, style.tolerance), false, style)
+}
+
+pub fn stroke_rounded_rect(center: [f32; 2], size: [f32; 2], corner_radius: f32, style: &StrokeStyle) -> Vec<Triangle> {
+    stroke_polyline(&rounded_rect_points(center, size, corner_radius, style.tolerance), true, style)
+}
+
+/// Strokes the line through the points, back to the first if `closed`.
+///
+/// Each segment is two triangles. Each join adds a triangle for a bevel, two for a miter
+/// and one per arc segment for a round join, except where the line goes straight on.
+/// Open lines add a triangle per arc segment at each round cap; square caps lengthen the end segments.
+pub fn stroke_polyline(points: &[[f32; 2]], closed: bool, style: &StrokeStyle) -> Vec<Triangle> {
+    let mut points = remove_repeated_points(points.to_vec(), closed);
+    let half_width = style.width.abs() / 2.;
+    let mut triangles = Vec::new();
+    if points.is_empty() || half_width == 0. {
+        return triangles;
+    }
+    if points.len() == 1 {
+        // A dot, if the caps reach past it.
+        let point = points[0];
+        match style.cap {
+            LineCap::Butt => {}
+            LineCap::Square => triangles.extend(fill_convex_polygon(&[
+                [point[0] - half_width, point[1] - half_width],
+                [point[0] + half_width, point[1] - half_width],
+                [point[0] + half_width, point[1] + half_width],
+                [point[0] - half_width, point[1] + half_width],
+            ])),
+            LineCap::Round => triangles.extend(fill_circle(point, half_width, style.tolerance)),
+        }
+        return triangles;
+    }
+    let closed = closed && points.len() > 2;
+
+    if !closed && style.cap == LineCap::Square {
+        let last = points.len() - 1;
+        let start_direction = direction(points[1], points[0]);
+        let end_direction = direction(points[last - 1], points[last]);
+        points[0] = add(points[0], scale(start_direction, half_width));
+        points[last] = add(points[last], scale(end_direction, half_width));
+    }
+
+    let segment_count = if closed { points.len() } else { points.len() - 1 };
+    for segment in 0..segment_count {
+        let (start, end) = (points[segment], points[(segment + 1) % points.len()]);
+        let offset = scale(normal(direction(start, end)), half_width);
+        let corners = [add(start, offset), sub(start, offset), sub(end, offset), add(end, offset)];
+        push_triangle(&mut triangles, corners[0], corners[1], corners[2]);
+        push_triangle(&mut triangles, corners[0], corners[2], corners[3]);
+    }
+
+    let joins = if closed { 0..points.len() } else { 1..points.len() - 1 };
+    for join in joins {
+        let previous = points[(join + points.len() - 1) % points.len()];
+        let (point, next) = (points[join], points[(join + 1) % points.len()]);
+        add_join(&mut triangles, previous, point, next, half_width, style);
+    }
+
+    if !closed && style.cap == LineCap::Round {
+        let last = points.len() - 1;
+        add_round_cap(&mut triangles, points[0], direction(points[1], points[0]), half_width, style.tolerance);
+        add_round_cap(&mut triangles, points[last], direction(points[last - 1], points[last]), half_width, style.tolerance);
+    }
+    triangles
+}
+
+/// Fills the gap on the outside of the turn at `point`.
+fn add_join(triangles: &mut Vec<Triangle>, previous: [f32; 2], point: [f32; 2], next: [f32; 2], half_width: f32, style: &StrokeStyle) {
+    let (incoming, outgoing) = (direction(previous, point), direction(point, next));
+    let turn = cross(incoming, outgoing);
+    if turn == 0. && dot(incoming, outgoing) > 0. {
+        // Straight on: the segments already meet.
+        return;
+    }
+    // The outside of the turn is to the right of a left turn, and the left of a right turn.
+    let side = if turn > 0. { -1. } else { 1. };
+    let incoming_offset = scale(normal(incoming), half_width * side);
+    let outgoing_offset = scale(normal(outgoing), half_width * side);
+    let (incoming_corner, outgoing_corner) = (add(point, incoming_offset), add(point, outgoing_offset));
+    match style.join {
+        LineJoin::Bevel => push_triangle(triangles, point, incoming_corner, outgoing_corner),
+        LineJoin::Miter => {
+            // The miter's tip is along the bisector of the two offsets,
+            // 1 / cos(half the turn) half widths from the point.
+            let bisector = add(normal(incoming), normal(outgoing));
+            let bisector_length = length(bisector);
+            let cos_half_turn = bisector_length / 2.;
+            let miter_length = half_width / cos_half_turn.max(1e-6);
+            push_triangle(triangles, point, incoming_corner, outgoing_corner);
+            if bisector_length > 1e-6 && miter_length <= style.miter_limit * half_width {
+                let tip = add(point, scale(bisector, side * miter_length / bisector_length));
+                push_triangle(triangles, incoming_corner, tip, outgoing_corner);
+            }
+        }
+        LineJoin::Round => {
+            let start_angle = incoming_offset[1].atan2(incoming_offset[0]);
+            let mut end_angle = outgoing_offset[1].atan2(outgoing_offset[0]);
+            // The offsets turn the same way as the line: anticlockwise for a left turn.
+            if side < 0. {
+                while end_angle < start_angle {
+                    end_angle += 2. * PI;
+                }
+            } else {
+                while end_angle > start_angle {
+                    end_angle -= 2. * PI;
+                }
+            }
+            triangles.extend(fill_arc(point, half_width, start_angle, end_angle, style.tolerance));
+        }
+    }
+}
+
+/// A half circle past `point`, which the stroke leaves in `direction`.
+fn add_round_cap(triangles: &mut Vec<Triangle>, point: [f32; 2], direction: [f32; 2], half_width: f32, tolerance: f32) {
+    let angle = direction[1].atan2(direction[0]);
+    triangles.extend(fill_arc(point, half_width, angle - PI / 2., angle + PI / 2., tolerance));
+}
+
+/// Drops points that are the same as the one before, and the last if it's the same as the first in a closed shape.
+fn remove_repeated_points(mut points: Vec<[f32; 2]>, closed: bool) -> Vec<[f32; 2]> {
+    points.dedup();
+    if closed && points.len() > 1 && points.first() == points.last() {
+        points.pop();
+    }
+    points
+}
+
+/// Drops the points of a closed shape that are in a line with their neighbours.
+fn remove_collinear_points(mut points: Vec<[f32; 2]>) -> Vec<[f32; 2]> {
+    let mut i = 0;
+    while points.len() >= 3 && i < points.len() {
+        let count = points.len();
+        let (previous, point, next) = (points[(i + count - 1) % count], points[i], points[(i + 1) % count]);
+        if cross(sub(point, previous), sub(next, point)) == 0. {
+            points.remove(i);
+            // The point before might be in a line with its new neighbour.
+            i = i.saturating_sub(1);
+        } else {
+            i += 1;
+        }
+    }
+    points
+}
+
+/// Whether any two edges of the closed shape that aren't next to each other touch.
+fn crosses_itself(points: &[[f32; 2]]) -> bool {
+    let count = points.len();
+    let edge = |i: usize| (points[i], points[(i + 1) % count]);
+    (0..count).any(|i| {
+        // Each edge touches the one before and the one after at their shared point.
+        (i + 2..count).filter(|&j| (j + 1) % count != i).any(|j| {
+            let ((a, b), (c, d)) = (edge(i), edge(j));
+            segments_touch(a, b, c, d)
+        })
+    })
+}
+
+fn segments_touch(a: [f32; 2], b: [f32; 2], c: [f32; 2], d: [f32; 2]) -> bool {
+    let (side_c, side_d) = (cross(sub(b, a), sub(c, a)), cross(sub(b, a), sub(d, a)));
+    let (side_a, side_b) = (cross(sub(d, c), sub(a, c)), cross(sub(d, c), sub(b, c)));
+    if side_c == 0. && side_d == 0. {
+        // In a line: they touch if they overlap.
+        let overlaps = |axis: usize| a[axis].min(b[axis]) <= c[axis].max(d[axis]) && c[axis].min(d[axis]) <= a[axis].max(b[axis]);
+        return overlaps(0) && overlaps(1);
+    }
+    side_c * side_d <= 0. && side_a * side_b <= 0.
+}
+
+/// Twice the polygon's signed area: positive if its points go anticlockwise.
+fn polygon_signed_area_2(points: &[[f32; 2]]) -> f32 {
+    (0..points.len())
+        .map(|i| cross(points[i], points[(i + 1) % points.len()]))
+        .sum()
+}
+
+/// Whether `point` is inside or on the edge of the anticlockwise triangle.
+fn point_in_triangle(point: [f32; 2], a: [f32; 2], b: [f32; 2], c: [f32; 2]) -> bool {
+    cross(sub(b, a), sub(point, a)) >= 0.
+        && cross(sub(c, b), sub(point, b)) >= 0.
+        && cross(sub(a, c), sub(point, c)) >= 0.
+}
+
+fn add(a: [f32; 2], b: [f32; 2]) -> [f32; 2] {
+    [a[0] + b[0], a[1] + b[1]]
+}
+fn sub(a: [f32; 2], b: [f32; 2]) -> [f32; 2] {
+    [a[0] - b[0], a[1] - b[1]]
+}
+fn scale(a: [f32; 2], factor: f32) -> [f32; 2] {
+    [a[0] * factor, a[1] * factor]
+}
+fn dot(a: [f32; 2], b: [f32; 2]) -> f32 {
+    a[0] * b[0] + a[1] * b[1]
+}
+fn cross(a: [f32; 2], b: [f32; 2]) -> f32 {
+    a[0] * b[1] - a[1] * b[0]
+}
+fn length(a: [f32; 2]) -> f32 {
+    dot(a, a).sqrt()
+}
+/// The unit vector from one point towards another.
+fn direction(from: [f32; 2], to: [f32; 2]) -> [f32; 2] {
+    let difference = sub(to, from);
+    scale(difference, 1. / length(difference))
+}
+/// A quarter turn anticlockwise.
+fn normal(a: [f32; 2]) -> [f32; 2] {
+    [-a[1], a[0]]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks every triangle is anticlockwise and has some area.
+    fn assert_anticlockwise(triangles: &[Triangle]) {
+        for triangle in triangles {
+            assert!(signed_area_2(triangle) > 0., "{:?} isn't anticlockwise", triangle);
+        }
+    }
+
+    fn total_area(triangles: &[Triangle]) -> f32 {
+        triangles.iter().map(|triangle| signed_area_2(triangle) / 2.).sum()
+    }
+
+    fn style(width: f32, join: LineJoin, cap: LineCap) -> StrokeStyle {
+        StrokeStyle { width, join, cap, ..StrokeStyle::default() }
+    }
+
+    /// A right-angled turn to the left.
+    static CORNER: [[f32; 2]; 3] = [[0., 0.], [10., 0.], [10., 10.]];
+
+    #[test]
+    fn butt_and_square_caps_are_one_quad() {
+        let butt = stroke_line([0., 0.], [10., 0.], &style(2., LineJoin::Miter, LineCap::Butt));
+        assert_eq!(butt.len(), 2);
+        assert_anticlockwise(&butt);
+        assert_eq!(total_area(&butt), 20.);
+
+        // Square caps make the same quad half the width longer at each end.
+        let square = stroke_line([0., 0.], [10., 0.], &style(2., LineJoin::Miter, LineCap::Square));
+        assert_eq!(square.len(), 2);
+        assert_anticlockwise(&square);
+        assert_eq!(total_area(&square), 24.);
+        let xs = square.iter().flat_map(|triangle| triangle.iter().map(|point| point[0]));
+        let (min_x, max_x) = xs.fold((f32::MAX, f32::MIN), |(min, max), x| (min.min(x), max.max(x)));
+        assert_eq!((min_x, max_x), (-1., 11.));
+    }
+
+    #[test]
+    fn round_caps_add_a_half_circle_at_each_end() {
+        let stroke_style = style(8., LineJoin::Miter, LineCap::Round);
+        let triangles = stroke_line([0., 0.], [20., 0.], &stroke_style);
+        let cap_segments = arc_segment_count(4., PI, stroke_style.tolerance);
+        assert!(cap_segments > 1);
+        assert_eq!(triangles.len(), 2 + 2 * cap_segments);
+        assert_anticlockwise(&triangles);
+        // The quad and a whole circle, less at most the tolerance along the circle's edge.
+        let (area, exact) = (total_area(&triangles), 160. + PI * 16.);
+        assert!(area < exact && area > exact - stroke_style.tolerance * 2. * PI * 4., "area {}", area);
+    }
+
+    #[test]
+    fn each_join_adds_its_triangles() {
+        let segments = 2 * 2;
+        let bevel = stroke_polyline(&CORNER, false, &style(2., LineJoin::Bevel, LineCap::Butt));
+        assert_eq!(bevel.len(), segments + 1);
+        assert_anticlockwise(&bevel);
+
+        // A right angle's miter is √2 half widths, well inside the default limit.
+        let miter = stroke_polyline(&CORNER, false, &style(2., LineJoin::Miter, LineCap::Butt));
+        assert_eq!(miter.len(), segments + 2);
+        assert_anticlockwise(&miter);
+        // The miter fills the outside corner: a unit square, half of it in the bevel.
+        assert!((total_area(&miter) - total_area(&bevel) - 0.5).abs() < 1e-4);
+        let limited = stroke_polyline(&CORNER, false, &StrokeStyle { miter_limit: 1., ..style(2., LineJoin::Miter, LineCap::Butt) });
+        assert_eq!(limited, bevel);
+
+        let round_style = style(2., LineJoin::Round, LineCap::Butt);
+        let round = stroke_polyline(&CORNER, false, &round_style);
+        assert_eq!(round.len(), segments + arc_segment_count(1., PI / 2., round_style.tolerance));
+        assert_anticlockwise(&round);
+
+        // Turning right puts the join on the other side, still anticlockwise.
+        let right_turn = [[0., 0.], [10., 0.], [10., -10.]];
+        for join in [LineJoin::Bevel, LineJoin::Miter, LineJoin::Round].iter() {
+            let triangles = stroke_polyline(&right_turn, false, &style(2., *join, LineCap::Butt));
+            assert_anticlockwise(&triangles);
+        }
+    }
+
+    #[test]
+    fn going_straight_on_needs_no_join() {
+        for join in [LineJoin::Bevel, LineJoin::Miter, LineJoin::Round].iter() {
+            let triangles = stroke_polyline(&[[0., 0.], [5., 0.], [10., 0.]], false, &style(2., *join, LineCap::Butt));
+            assert_eq!(triangles.len(), 4);
+            assert_anticlockwise(&triangles);
+        }
+    }
+
+    #[test]
+    fn closed_strokes_join_every_corner_and_have_no_caps() {
+        let square = [[0., 0.], [10., 0.], [10., 10.], [0., 10.], [0., 0.]];
+        let triangles = stroke_polyline(&square, true, &style(2., LineJoin::Miter, LineCap::Round));
+        assert_eq!(triangles.len(), 4 * 2 + 4 * 2);
+        assert_anticlockwise(&triangles);
+    }
+
+    #[test]
+    fn a_single_point_is_a_dot_unless_the_caps_are_butt() {
+        assert!(stroke_polyline(&[[1., 1.], [1., 1.]], false, &style(2., LineJoin::Miter, LineCap::Butt)).is_empty());
+        let square = stroke_polyline(&[[1., 1.]], false, &style(2., LineJoin::Miter, LineCap::Square));
+        assert_eq!(square.len(), 2);
+        assert_eq!(total_area(&square), 4.);
+        let round_style = style(2., LineJoin::Miter, LineCap::Round);
+        let round = stroke_polyline(&[[1., 1.]], false, &round_style);
+        assert_eq!(round, fill_circle([1., 1.], 1., round_style.tolerance));
+        assert!(stroke_line([0., 0.], [1., 0.], &style(0., LineJoin::Miter, LineCap::Round)).is_empty());
+    }
+
+    #[test]
+    fn concave_polygons_are_cut_into_ears() {
+        // An L, clockwise.
+        let l_shape = [[0., 0.], [0., 20.], [10., 20.], [10., 10.], [20., 10.], [20., 0.]];
+        let triangles = fill_polygon(&l_shape).unwrap();
+        assert_eq!(triangles.len(), l_shape.len() - 2);
+        assert_anticlockwise(&triangles);
+        assert_eq!(total_area(&triangles), 300.);
+        // No triangle covers the notch.
+        assert!(!triangles.iter().any(|&[a, b, c]| point_in_triangle([15., 15.], a, b, c)));
+
+        // Points in a line with their neighbours add no triangles.
+        let square = [[0., 0.], [5., 0.], [10., 0.], [10., 10.], [0., 10.], [0., 0.]];
+        let triangles = fill_polygon(&square).unwrap();
+        assert_eq!(triangles.len(), 2);
+        assert_eq!(total_area(&triangles), 100.);
+
+        assert!(fill_polygon(&[[0., 0.], [1., 1.], [2., 2.]]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn self_crossing_polygons_are_not_filled() {
+        let bow_tie = [[0., 0.], [10., 10.], [10., 0.], [0., 10.]];
+        assert_eq!(fill_polygon(&bow_tie), Err(TessellationError::SelfIntersecting { point_count: 4 }));
+    }
+
+    #[test]
+    fn circles_stay_within_the_tolerance() {
+        let (radius, tolerance) = (50., 0.25);
+        let points = circle_points([0., 0.], radius, tolerance);
+        assert_eq!(points.len(), arc_segment_count(radius, 2. * PI, tolerance));
+        let triangles = fill_circle([0., 0.], radius, tolerance);
+        assert_eq!(triangles.len(), points.len() - 2);
+        assert_anticlockwise(&triangles);
+        // The polygon is inside the circle, but no more than the tolerance in from it.
+        let area = total_area(&triangles);
+        assert!(area < PI * radius * radius && area > PI * (radius - tolerance).powi(2), "area {}", area);
+
+        let stroke_style = style(4., LineJoin::Miter, LineCap::Butt);
+        let outline = stroke_circle([0., 0.], radius, &stroke_style);
+        // Two triangles a segment and two a mitered join.
+        assert_eq!(outline.len(), 4 * circle_points([0., 0.], radius, stroke_style.tolerance).len());
+        assert_anticlockwise(&outline);
+    }
+
+    #[test]
+    fn arcs_go_either_way_round() {
+        let tolerance = 0.25;
+        let anticlockwise = fill_arc([0., 0.], 20., 0., PI / 2., tolerance);
+        assert_eq!(anticlockwise.len(), arc_segment_count(20., PI / 2., tolerance));
+        assert_anticlockwise(&anticlockwise);
+        let area = total_area(&anticlockwise);
+        assert!(area < PI * 100. && area > PI * 100. - tolerance * PI * 10., "area {}", area);
+
+        let clockwise = fill_arc([0., 0.], 20., PI / 2., 0., tolerance);
+        assert_eq!(clockwise.len(), anticlockwise.len());
+        assert_anticlockwise(&clockwise);
+        assert!((total_area(&clockwise) - area).abs() < 1e-3);
+
+        let stroke_style = style(2., LineJoin::Bevel, LineCap::Butt);
+        let stroke = stroke_arc([0., 0.], 20., 0., PI / 2., &stroke_style);
+        let segments = arc_segment_count(20., PI / 2., stroke_style.tolerance);
+        assert_eq!(stroke.len(), 2 * segments + (segments - 1));
+        assert_anticlockwise(&stroke);
+    }
+
+    #[test]
+    fn rounded_rects_have_a_quarter_circle_at_each_corner() {
+        let tolerance = 0.25;
+        let sharp = fill_rounded_rect([5., 5.], [20., 10.], 0., tolerance);
+        assert_eq!(sharp.len(), 2);
+        assert_eq!(total_area(&sharp), 200.);
+
+        let points = rounded_rect_points([0., 0.], [40., 20.], 5., tolerance);
+        assert_eq!(points.len(), 4 * (arc_segment_count(5., PI / 2., tolerance) + 1));
+        let rounded = fill_rounded_rect([0., 0.], [40., 20.], 5., tolerance);
+        assert_eq!(rounded.len(), points.len() - 2);
+        assert_anticlockwise(&rounded);
+        let area = total_area(&rounded);
+        let exact = 800. - (4. - PI) * 25.;
+        assert!(area < exact && area > exact - tolerance * 2. * PI * 5., "area {}", area);
+
+        // Too big a radius makes a circle of the square.
+        let circle = rounded_rect_points([0., 0.], [20., 20.], 100., tolerance);
+        assert!(circle.iter().all(|point| (length(*point) - 10.).abs() < 1e-4));
+        assert_eq!(circle.len(), 4 * arc_segment_count(10., PI / 2., tolerance));
+
+        let stroke_style = style(2., LineJoin::Miter, LineCap::Butt);
+        let outline = stroke_rounded_rect([0., 0.], [40., 20.], 5., &stroke_style);
+        assert_eq!(outline.len(), 4 * points.len());
+        assert_anticlockwise(&outline);
+    }
+
+    #[test]
+    fn triangle_lists_become_meshes_without_indices() {
+        let mesh = triangle_list_mesh(&fill_rounded_rect([0., 0.], [2., 2.], 0., 0.25), [1., 0., 0., 1.]);
+        assert_eq!(mesh.topology(), PrimitiveTopology::Triangle);
+        assert_eq!(mesh.vertices().len(), 6);
+        assert!(mesh.indices().is_none());
+        assert!(mesh.vertices().iter().all(|vertex| (vertex.color.x(), vertex.color.w()) == (1., 1.) && vertex.color.y() == 0.));
+    }
+}
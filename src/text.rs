@@ -0,0 +1,344 @@
+//! Laying out and drawing text
+//!
+//! `layout_text` places each character on a line using the font's advances and kerning,
+//! breaking lines at newlines and (given a width) between words.
+//! `text_mesh` then turns layouts into one mesh of quads textured from a `GlyphAtlas`,
+//! so any amount of text is a single draw with `textFragmentShader`.
+
+use crate::font::Font;
+use crate::glyph_atlas::GlyphAtlas;
+use crate::mesh::{Mesh, IndexData, PrimitiveTopology};
+use crate::scene::Scene;
+use crate::shader_types::{AAPLVertex, AAPLTexturedVertex};
+use crate::sampler::{SamplerDesc, MipFilter};
+
+/// How the lines of a layout line up with each other.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TextAlignment {
+    Left,
+    Center,
+    Right,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct LayoutOptions {
+    /// Lines are broken between words to keep them this wide, if given.
+    pub max_width: Option<f32>,
+    pub alignment: TextAlignment,
+}
+
+impl Default for LayoutOptions {
+    fn default() -> Self {
+        LayoutOptions { max_width: None, alignment: TextAlignment::Left }
+    }
+}
+
+/// A character and where its pen position is.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PositionedGlyph {
+    pub character: char,
+    /// On the baseline, from the top-left corner of the text, with y down.
+    pub position: [f32; 2],
+}
+
+/// Where each character of some text goes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextLayout {
+    /// Every character, spaces included.
+    pub glyphs: Vec<PositionedGlyph>,
+    pub line_count: usize,
+    /// The widest line, or the maximum width if it was given and lines are aligned.
+    pub width: f32,
+    /// From the first line's ascent to the last line's descent.
+    pub height: f32,
+}
+
+/// A line being laid out: the characters and their pen positions.
+#[derive(Default)]
+struct Line {
+    glyphs: Vec<(char, f32)>,
+    /// Where the pen is after the last character.
+    pen: f32,
+    /// Where the pen is after the last character that isn't a space,
+    /// so spaces at the end of a line don't move it when lines are centred or right-aligned.
+    width: f32,
+}
+
+impl Line {
+    fn push(&mut self, font: &Font, character: char) {
+        if let Some(&(previous, _)) = self.glyphs.last() {
+            self.pen += font.kerning(previous, character);
+        }
+        self.glyphs.push((character, self.pen));
+        self.pen += font.advance(character);
+        if !character.is_whitespace() {
+            self.width = self.pen;
+        }
+    }
+
+    /// How far the pen would be after adding the characters.
+    fn pen_after(&self, font: &Font, characters: impl IntoIterator<Item = char>) -> f32 {
+        let mut pen = self.pen;
+        let mut previous = self.glyphs.last().map(|&(character, _)| character);
+        for character in characters {
+            if let Some(previous) = previous {
+                pen += font.kerning(previous, character);
+            }
+            pen += font.advance(character);
+            previous = Some(character);
+        }
+        pen
+    }
+}
+
+/// Lays text out in lines from the top-left corner.
+pub fn layout_text(font: &Font, text: &str, options: &LayoutOptions) -> TextLayout {
+    let mut lines: Vec<Line> = Vec::new();
+    for paragraph in text.split('\n') {
+        let mut line = Line::default();
+        for word in split_words(paragraph) {
+            // Spaces stay on the line they follow, however far they go,
+            // so wrapped lines start with a word.
+            let is_space = word.starts_with(char::is_whitespace);
+            let fits = |line: &Line, characters: &mut dyn Iterator<Item = char>| match options.max_width {
+                Some(max_width) => line.glyphs.is_empty() || line.pen_after(font, characters) <= max_width,
+                None => true,
+            };
+            if !is_space && !fits(&line, &mut word.chars()) {
+                lines.push(std::mem::take(&mut line));
+            }
+            for character in word.chars() {
+                // Words too long for a line of their own are broken between characters.
+                if !is_space && !fits(&line, &mut std::iter::once(character)) {
+                    lines.push(std::mem::take(&mut line));
+                }
+                line.push(font, character);
+            }
+        }
+        lines.push(line);
+    }
+
+    let metrics = font.line_metrics();
+    let widest = lines.iter().map(|line| line.width).fold(0., f32::max);
+    let width = match (options.alignment, options.max_width) {
+        (TextAlignment::Left, _) | (_, None) => widest,
+        (_, Some(max_width)) => max_width,
+    };
+    let mut glyphs = Vec::new();
+    for (line_index, line) in lines.iter().enumerate() {
+        let indent = match options.alignment {
+            TextAlignment::Left => 0.,
+            TextAlignment::Center => (width - line.width) / 2.,
+            TextAlignment::Right => width - line.width,
+        };
+        let baseline = metrics.ascent + line_index as f32 * metrics.line_height();
+        glyphs.extend(line.glyphs.iter().map(|&(character, pen)| PositionedGlyph { character, position: [indent + pen, baseline] }));
+    }
+    let line_count = lines.len();
+    let height = metrics.ascent + metrics.descent + (line_count - 1) as f32 * metrics.line_height();
+    TextLayout { glyphs, line_count, width, height }
+}
+
+/// Splits text into runs of spaces and runs of everything else.
+fn split_words(text: &str) -> impl Iterator<Item = &str> {
+    let mut rest = text;
+    std::iter::from_fn(move || {
+        let first = rest.chars().next()?;
+        let is_space = first.is_whitespace();
+        let end = rest.find(|character: char| character.is_whitespace() != is_space).unwrap_or(rest.len());
+        let (word, remainder) = rest.split_at(end);
+        rest = remainder;
+        Some(word)
+    })
+}
+
+/// How glyph atlases are sampled: smoothly, as distance fields are meant to be, and without mipmaps.
+pub fn glyph_atlas_sampler() -> SamplerDesc {
+    SamplerDesc { mip_filter: MipFilter::NotMipmapped, ..SamplerDesc::default() }
+}
+
+/// Quads for each laid out glyph, with the top-left corner of each layout at the given origin
+/// (in pixels from the centre of the view, with y up).
+///
+/// `None` if there's nothing to draw.
+pub fn text_mesh(atlas: &GlyphAtlas, layouts: &[(&TextLayout, [f32; 2])], color: [f32; 4]) -> Option<Mesh<AAPLTexturedVertex>> {
+    let mut vertices = Vec::new();
+    let mut indices = Vec::new();
+    for (layout, origin) in layouts {
+        append_text_quads(atlas, layout, *origin, color, &mut vertices, &mut indices);
+    }
+    triangle_mesh(vertices, indices)
+}
+
+/// Adds two triangles for each laid out glyph, as `text_mesh` does.
+pub fn append_text_quads(atlas: &GlyphAtlas, layout: &TextLayout, origin: [f32; 2], color: [f32; 4], vertices: &mut Vec<AAPLTexturedVertex>, indices: &mut Vec<u32>) {
+    let (atlas_width, atlas_height) = (atlas.width() as f32, atlas.height() as f32);
+    for positioned_glyph in &layout.glyphs {
+        let glyph = match atlas.glyph(positioned_glyph.character) {
+            Some(glyph) => glyph,
+            None => continue,
+        };
+        let left = origin[0] + positioned_glyph.position[0] + glyph.offset[0];
+        let top = origin[1] - (positioned_glyph.position[1] + glyph.offset[1]);
+        let right = left + glyph.size[0] as f32;
+        let bottom = top - glyph.size[1] as f32;
+        let u0 = glyph.origin[0] as f32 / atlas_width;
+        let v0 = glyph.origin[1] as f32 / atlas_height;
+        let u1 = (glyph.origin[0] + glyph.size[0]) as f32 / atlas_width;
+        let v1 = (glyph.origin[1] + glyph.size[1]) as f32 / atlas_height;
+        let first = vertices.len() as u32;
+        // The same corners as `Mesh::textured_quad`.
+        vertices.push(AAPLTexturedVertex::new([left, bottom], color, [u0, v1]));
+        vertices.push(AAPLTexturedVertex::new([right, bottom], color, [u1, v1]));
+        vertices.push(AAPLTexturedVertex::new([right, top], color, [u1, v0]));
+        vertices.push(AAPLTexturedVertex::new([left, top], color, [u0, v0]));
+        indices.extend([0, 1, 2, 0, 2, 3].iter().map(|index| first + index));
+    }
+}
+
+/// An indexed triangle mesh, with 16-bit indices if they fit, or `None` if there are no vertices.
+pub fn triangle_mesh<V>(vertices: Vec<V>, indices: Vec<u32>) -> Option<Mesh<V>> {
+    if vertices.is_empty() {
+        return None;
+    }
+    Some(Mesh::new(vertices, PrimitiveTopology::Triangle).with_indices(IndexData::from_u32(indices)))
+}
+
+/// A piece of text pinned to a point.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Label {
+    pub text: String,
+    /// In pixels from the centre of the view, with y up.
+    pub anchor: [f32; 2],
+    /// Whether the text goes above the anchor, rather than below it.
+    pub above: bool,
+}
+
+/// The gap between a label and its anchor, in pixels.
+static LABEL_MARGIN: f32 = 6.;
+
+/// A label for each vertex the scene draws, giving its `AAPLVertex` position.
+///
+/// Labels on the lower half of a mesh go below their vertex, and the rest go above.
+pub fn vertex_labels(scene: &Scene<AAPLVertex>) -> Vec<Label> {
+    let mut labels = Vec::new();
+    for item in scene.draw_list() {
+        let vertices = match scene.mesh(item.mesh) {
+            Some(mesh) => mesh.vertices(),
+            None => continue,
+        };
+        let centre_y = vertices.iter().map(|vertex| vertex.position.y()).sum::<f32>() / vertices.len() as f32;
+        labels.extend(vertices.iter().map(|vertex| {
+            let (x, y) = (vertex.position.x(), vertex.position.y());
+            Label {
+                text: format!("({}, {})", x, y),
+                anchor: item.world_transform.transform_point([x, y]),
+                above: y >= centre_y,
+            }
+        }));
+    }
+    labels
+}
+
+/// One mesh for all the labels, each centred on its anchor.
+pub fn label_mesh(font: &Font, atlas: &GlyphAtlas, labels: &[Label], color: [f32; 4]) -> Option<Mesh<AAPLTexturedVertex>> {
+    let options = LayoutOptions { max_width: None, alignment: TextAlignment::Center };
+    let layouts: Vec<TextLayout> = labels.iter().map(|label| layout_text(font, &label.text, &options)).collect();
+    let placed: Vec<(&TextLayout, [f32; 2])> = labels.iter().zip(&layouts)
+        .map(|(label, layout)| {
+            let top = if label.above {
+                label.anchor[1] + LABEL_MARGIN + layout.height
+            } else {
+                label.anchor[1] - LABEL_MARGIN
+            };
+            // Whole pixels, so text drawn at its own size lines up with the pixel grid.
+            (layout, [(label.anchor[0] - layout.width / 2.).round(), top.round()])
+        })
+        .collect();
+    text_mesh(atlas, &placed, color)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::Node;
+    use crate::transform::Transform2D;
+
+    /// Where each character's pen position is.
+    fn positions(layout: &TextLayout) -> Vec<(char, [f32; 2])> {
+        layout.glyphs.iter().map(|glyph| (glyph.character, glyph.position)).collect()
+    }
+
+    // The bitmap font at its own size advances six pixels a character,
+    // with the baseline seven pixels down and nine pixels between baselines.
+
+    #[test]
+    fn lines_break_at_newlines() {
+        let layout = layout_text(&Font::bitmap(1), "ab\nc", &LayoutOptions::default());
+        assert_eq!(positions(&layout), [('a', [0., 7.]), ('b', [6., 7.]), ('c', [0., 16.])]);
+        assert_eq!((layout.line_count, layout.width, layout.height), (2, 12., 17.));
+    }
+
+    #[test]
+    fn lines_wrap_between_words_and_long_words_between_characters() {
+        let options = LayoutOptions { max_width: Some(20.), ..LayoutOptions::default() };
+        let layout = layout_text(&Font::bitmap(1), "aa bb", &options);
+        // The space stays at the end of the first line.
+        assert_eq!(positions(&layout), [('a', [0., 7.]), ('a', [6., 7.]), (' ', [12., 7.]), ('b', [0., 16.]), ('b', [6., 16.])]);
+        assert_eq!(layout.width, 12.);
+
+        let layout = layout_text(&Font::bitmap(1), "abcdef", &options);
+        assert_eq!(layout.line_count, 2);
+        assert_eq!(layout.glyphs[3], PositionedGlyph { character: 'd', position: [0., 16.] });
+    }
+
+    #[test]
+    fn lines_align_within_the_maximum_width() {
+        let right = LayoutOptions { max_width: Some(30.), alignment: TextAlignment::Right };
+        let layout = layout_text(&Font::bitmap(1), "a \nbb", &right);
+        // Trailing spaces don't count.
+        assert_eq!(layout.glyphs[0].position, [24., 7.]);
+        assert_eq!(layout.glyphs[2].position, [18., 16.]);
+        assert_eq!(layout.width, 30.);
+
+        let centre = LayoutOptions { alignment: TextAlignment::Center, ..right };
+        assert_eq!(layout_text(&Font::bitmap(1), "a", &centre).glyphs[0].position, [12., 7.]);
+    }
+
+    #[test]
+    fn text_meshes_have_a_quad_for_each_drawn_glyph() {
+        let font = Font::bitmap(1);
+        let atlas = GlyphAtlas::new(&font, "h i").unwrap();
+        let layout = layout_text(&font, "h i", &LayoutOptions::default());
+        let mesh = text_mesh(&atlas, &[(&layout, [10., 20.])], [1., 1., 1., 1.]).unwrap();
+        // No quad for the space.
+        assert_eq!(mesh.vertices().len(), 8);
+        assert_eq!(mesh.indices().unwrap().len(), 12);
+
+        // The bottom-left corner of the 'h': the glyph is 5×8 plus a four-texel spread on each side,
+        // with its top seven pixels above the baseline.
+        let glyph = atlas.glyph('h').unwrap();
+        let corner = mesh.vertices()[0];
+        assert_eq!([corner.position.x(), corner.position.y()], [10. - 4., 20. - 7. + 11. - 16.]);
+        let v1 = (glyph.origin[1] + glyph.size[1]) as f32 / atlas.height() as f32;
+        assert_eq!([corner.texture_coordinate.x(), corner.texture_coordinate.y()], [glyph.origin[0] as f32 / atlas.width() as f32, v1]);
+
+        assert!(text_mesh(&atlas, &[(&layout_text(&font, "  ", &LayoutOptions::default()), [0., 0.])], [1.; 4]).is_none());
+    }
+
+    #[test]
+    fn each_vertex_is_labelled_with_its_position() {
+        let mut scene = Scene::new();
+        let mesh = scene.add_mesh(Mesh::hello_triangle());
+        let mut node = Node::with_mesh(mesh);
+        node.transform = Transform2D { translation: [10., 0.], ..Transform2D::identity() };
+        scene.add_node(None, node).unwrap();
+        let labels = vertex_labels(&scene);
+        let summary: Vec<(&str, [f32; 2], bool)> = labels.iter().map(|label| (label.text.as_str(), label.anchor, label.above)).collect();
+        assert_eq!(summary, [
+            ("(250, -250)", [260., -250.], false),
+            ("(-250, -250)", [-240., -250.], false),
+            ("(0, 250)", [10., 250.], true),
+        ]);
+    }
+}
@@ -0,0 +1,208 @@
+//! Textures and sampler states on the GPU
+//!
+//! A `Texture` owns an `MTLTexture` made from decoded `TextureData`,
+//! with every mipmap level uploaded.
+//! Sampler states are made from a `SamplerDesc`, once per distinct description.
+
+use objc::class;
+use objc::msg_send;
+use objc::sel;
+use objc::sel_impl;
+use cocoa::base::{id, nil};
+use cocoa::foundation::{NSAutoreleasePool, NSString, NSUInteger};
+use objc::runtime::{objc_release, YES};
+use std::collections::HashMap;
+use std::os::raw::c_void;
+use std::path::Path;
+use crate::pixel_format::MTLPixelFormat;
+use crate::image::{TextureData, ImageError};
+use crate::sampler::{SamplerDesc, MinMagFilter, MipFilter, AddressMode};
+
+// From System/Library/Frameworks/Metal.framework/Versions/A/Headers/MTLPixelFormat.h:
+// MTLPixelFormatRGBA8Unorm      = 70,
+// MTLPixelFormatRGBA8Unorm_sRGB = 71,
+#[allow(non_upper_case_globals)]
+static MTLPixelFormatRGBA8Unorm: MTLPixelFormat = 70;
+#[allow(non_upper_case_globals)]
+static MTLPixelFormatRGBA8Unorm_sRGB: MTLPixelFormat = 71;
+
+// From System/Library/Frameworks/Metal.framework/Versions/A/Headers/MTLTexture.h
+// MTLTextureUsageShaderRead = 0x0001,
+#[allow(non_upper_case_globals)]
+static MTLTextureUsageShaderRead: NSUInteger = 0x0001;
+
+// From System/Library/Frameworks/Metal.framework/Versions/A/Headers/MTLTypes.h
+// typedef struct { NSUInteger x, y, z; } MTLOrigin;
+// typedef struct { NSUInteger width, height, depth; } MTLSize;
+// typedef struct { MTLOrigin origin; MTLSize size; } MTLRegion;
+#[repr(C)]
+struct MTLRegion {
+    x: NSUInteger,
+    y: NSUInteger,
+    z: NSUInteger,
+    width: NSUInteger,
+    height: NSUInteger,
+    depth: NSUInteger,
+}
+
+// From System/Library/Frameworks/Metal.framework/Versions/A/Headers/MTLSampler.h
+// typedef NS_ENUM(NSUInteger, MTLSamplerMinMagFilter) {
+//     MTLSamplerMinMagFilterNearest = 0,
+//     MTLSamplerMinMagFilterLinear = 1,
+// } API_AVAILABLE(macos(10.11), ios(8.0));
+fn mtl_min_mag_filter(filter: MinMagFilter) -> NSUInteger {
+    match filter {
+        MinMagFilter::Nearest => 0,
+        MinMagFilter::Linear => 1,
+    }
+}
+
+// typedef NS_ENUM(NSUInteger, MTLSamplerMipFilter) {
+//     MTLSamplerMipFilterNotMipmapped = 0,
+//     MTLSamplerMipFilterNearest = 1,
+//     MTLSamplerMipFilterLinear = 2,
+// } API_AVAILABLE(macos(10.11), ios(8.0));
+fn mtl_mip_filter(filter: MipFilter) -> NSUInteger {
+    match filter {
+        MipFilter::NotMipmapped => 0,
+        MipFilter::Nearest => 1,
+        MipFilter::Linear => 2,
+    }
+}
+
+// typedef NS_ENUM(NSUInteger, MTLSamplerAddressMode) {
+//     MTLSamplerAddressModeClampToEdge = 0,
+//     MTLSamplerAddressModeMirrorClampToEdge API_AVAILABLE(macos(10.11), ios(14.0)) = 1,
+//     MTLSamplerAddressModeRepeat = 2,
+//     MTLSamplerAddressModeMirrorRepeat = 3,
+//     MTLSamplerAddressModeClampToZero = 4,
+//     ...
+// } API_AVAILABLE(macos(10.11), ios(8.0));
+fn mtl_address_mode(address_mode: AddressMode) -> NSUInteger {
+    match address_mode {
+        AddressMode::ClampToEdge => 0,
+        AddressMode::MirrorClampToEdge => 1,
+        AddressMode::Repeat => 2,
+        AddressMode::MirrorRepeat => 3,
+        AddressMode::ClampToZero => 4,
+    }
+}
+
+/// A texture the GPU can sample, released when dropped.
+#[derive(Debug)]
+pub struct Texture {
+    texture: id,
+    width: usize,
+    height: usize,
+}
+
+impl Texture {
+    /// Uploads every mipmap level of `data` to a new texture.
+    pub fn new(device: id, data: &TextureData, label: &str) -> Self {
+        let pool = unsafe { NSAutoreleasePool::new(nil) };
+        let pixel_format = if data.srgb { MTLPixelFormatRGBA8Unorm_sRGB } else { MTLPixelFormatRGBA8Unorm };
+        let width = data.width() as NSUInteger;
+        let height = data.height() as NSUInteger;
+        let mipmap_level_count = data.levels.len() as NSUInteger;
+        let texture: id = unsafe {
+            let texture_descriptor: id = msg_send![class!(MTLTextureDescriptor),
+                                                   texture2DDescriptorWithPixelFormat:pixel_format
+                                                                                width:width
+                                                                               height:height
+                                                                            mipmapped:YES];
+            let _:() = msg_send![texture_descriptor, setMipmapLevelCount:mipmap_level_count];
+            let _:() = msg_send![texture_descriptor, setUsage:MTLTextureUsageShaderRead];
+            msg_send![device, newTextureWithDescriptor:texture_descriptor]
+        };
+        for (level, image) in data.levels.iter().enumerate() {
+            let region = MTLRegion {
+                x: 0,
+                y: 0,
+                z: 0,
+                width: image.width() as NSUInteger,
+                height: image.height() as NSUInteger,
+                depth: 1,
+            };
+            let level = level as NSUInteger;
+            let bytes = image.pixels().as_ptr() as *const c_void;
+            let bytes_per_row = (image.width() * 4) as NSUInteger;
+            let _:() = unsafe {
+                msg_send![texture, replaceRegion:region mipmapLevel:level withBytes:bytes bytesPerRow:bytes_per_row]
+            };
+        }
+        let label = unsafe { NSString::alloc(nil).init_str(label) };
+        let _:() = unsafe { msg_send![texture, setLabel:label] };
+        unsafe { pool.drain() };
+        Texture { texture, width: data.width(), height: data.height() }
+    }
+
+    /// Reads a PNG, JPEG or KTX file into a new texture.
+    pub fn load(device: id, path: &Path) -> Result<Self, ImageError> {
+        let data = TextureData::load(path)?;
+        Ok(Texture::new(device, &data, &path.display().to_string()))
+    }
+
+    /// The `MTLTexture`, still owned by us.
+    pub fn texture(&self) -> id {
+        self.texture
+    }
+    pub fn width(&self) -> usize {
+        self.width
+    }
+    pub fn height(&self) -> usize {
+        self.height
+    }
+}
+
+impl Drop for Texture {
+    fn drop(&mut self) {
+        // Command buffers still in flight keep their own hold on the texture.
+        unsafe { objc_release(self.texture) };
+    }
+}
+
+/// Sampler states, made once per description.
+pub struct SamplerCache {
+    device: id,
+    sampler_states: HashMap<SamplerDesc, id>,
+}
+
+impl SamplerCache {
+    pub fn new(device: id) -> Self {
+        SamplerCache { device, sampler_states: HashMap::new() }
+    }
+
+    /// The sampler state for a description, made if need be. Still owned by the cache.
+    pub fn get_or_create(&mut self, desc: &SamplerDesc) -> id {
+        let device = self.device;
+        *self.sampler_states.entry(*desc).or_insert_with(|| new_sampler_state(device, desc))
+    }
+}
+
+impl Drop for SamplerCache {
+    fn drop(&mut self) {
+        for (_, sampler_state) in self.sampler_states.drain() {
+            unsafe { objc_release(sampler_state) };
+        }
+    }
+}
+
+/// Creates a sampler state, owned by the caller.
+fn new_sampler_state(device: id, desc: &SamplerDesc) -> id {
+    let min_filter = mtl_min_mag_filter(desc.min_filter);
+    let mag_filter = mtl_min_mag_filter(desc.mag_filter);
+    let mip_filter = mtl_mip_filter(desc.mip_filter);
+    let address_mode_u = mtl_address_mode(desc.address_mode_u);
+    let address_mode_v = mtl_address_mode(desc.address_mode_v);
+    unsafe {
+        let sampler_descriptor: id = msg_send![class!(MTLSamplerDescriptor), new];
+        let _:() = msg_send![sampler_descriptor, setMinFilter:min_filter];
+        let _:() = msg_send![sampler_descriptor, setMagFilter:mag_filter];
+        let _:() = msg_send![sampler_descriptor, setMipFilter:mip_filter];
+        let _:() = msg_send![sampler_descriptor, setSAddressMode:address_mode_u];
+        let _:() = msg_send![sampler_descriptor, setTAddressMode:address_mode_v];
+        let sampler_state: id = msg_send![device, newSamplerStateWithDescriptor:sampler_descriptor];
+        objc_release(sampler_descriptor);
+        sampler_state
+    }
+}
@@ -0,0 +1,72 @@
+//! How to split a compute grid into threadgroups
+//!
+//! A compute kernel runs once for each thread in a grid,
+//! and the threads run in threadgroups whose size the pipeline state limits.
+//! Threadgroups whose width is a multiple of the pipeline's thread execution width
+//! (how many threads the GPU runs in lockstep) keep it busiest.
+//! We dispatch whole threadgroups, which every GPU supports,
+//! so kernels have to ignore the threads past the end of their data.
+
+/// How big a pipeline state's threadgroups can be.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ThreadgroupLimits {
+    /// How many threads run in lockstep.
+    pub thread_execution_width: usize,
+    pub max_total_threads_per_threadgroup: usize,
+}
+
+impl ThreadgroupLimits {
+    /// The most threads a threadgroup of a one-dimensional grid can have
+    /// that's still a whole number of execution widths.
+    pub fn threadgroup_size_1d(&self) -> usize {
+        let width = self.thread_execution_width.max(1);
+        let max_total = self.max_total_threads_per_threadgroup.max(1);
+        if max_total < width {
+            max_total
+        } else {
+            max_total / width * width
+        }
+    }
+}
+
+/// How many threadgroups of the given size it takes to cover a grid.
+pub fn threadgroup_count(grid_size: [usize; 2], threadgroup_size: [usize; 2]) -> [usize; 2] {
+    [
+        grid_size[0].div_ceil(threadgroup_size[0]),
+        grid_size[1].div_ceil(threadgroup_size[1]),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(thread_execution_width: usize, max_total_threads_per_threadgroup: usize) -> ThreadgroupLimits {
+        ThreadgroupLimits { thread_execution_width, max_total_threads_per_threadgroup }
+    }
+
+    #[test]
+    fn threadgroups_are_whole_execution_widths() {
+        assert_eq!(limits(32, 1024).threadgroup_size_1d(), 1024);
+        assert_eq!(limits(32, 1000).threadgroup_size_1d(), 992);
+        assert_eq!(limits(64, 64).threadgroup_size_1d(), 64);
+    }
+
+    #[test]
+    fn threadgroups_stay_within_the_limits() {
+        // Fewer threads allowed than run in lockstep.
+        assert_eq!(limits(32, 20).threadgroup_size_1d(), 20);
+        // Limits no pipeline state reports still give at least one thread.
+        assert_eq!(limits(0, 0).threadgroup_size_1d(), 1);
+        assert_eq!(limits(0, 100).threadgroup_size_1d(), 100);
+    }
+
+    #[test]
+    fn threadgroups_cover_the_grid() {
+        assert_eq!(threadgroup_count([1024, 1], [256, 1]), [4, 1]);
+        assert_eq!(threadgroup_count([1025, 1], [256, 1]), [5, 1]);
+        assert_eq!(threadgroup_count([1, 1], [256, 1]), [1, 1]);
+        assert_eq!(threadgroup_count([0, 1], [256, 1]), [0, 1]);
+        assert_eq!(threadgroup_count([100, 30], [16, 16]), [7, 2]);
+    }
+}
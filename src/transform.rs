@@ -0,0 +1,146 @@
+//! 2D affine transforms for placing things in the view
+//!
+//! Positions are in pixels from the centre of the view, as in the vertex shader.
+
+use std::ops::Mul;
+
+/// A 3x3 matrix in column-major order, as Metal's `matrix_float3x3`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Matrix3 {
+    pub columns: [[f32; 3]; 3],
+}
+
+impl Matrix3 {
+    pub fn identity() -> Self {
+        Matrix3 {
+            columns: [
+                [1., 0., 0.],
+                [0., 1., 0.],
+                [0., 0., 1.],
+            ]
+        }
+    }
+
+    /// Returns the element at the given row and column.
+    pub fn get(&self, row: usize, column: usize) -> f32 {
+        self.columns[column][row]
+    }
+
+    /// Applies the matrix to a point (with an implied third coordinate of 1).
+    pub fn transform_point(&self, point: [f32; 2]) -> [f32; 2] {
+        [
+            self.get(0, 0) * point[0] + self.get(0, 1) * point[1] + self.get(0, 2),
+            self.get(1, 0) * point[0] + self.get(1, 1) * point[1] + self.get(1, 2),
+        ]
+    }
+
+    /// The columns padded out to four floats each,
+    /// which is how Metal lays out a `matrix_float3x3` in memory.
+    pub fn to_padded_columns(self) -> [[f32; 4]; 3] {
+        let mut padded = [[0.; 4]; 3];
+        for (padded_column, column) in padded.iter_mut().zip(self.columns.iter()) {
+            padded_column[..3].copy_from_slice(column);
+        }
+        padded
+    }
+}
+
+impl Default for Matrix3 {
+    fn default() -> Self {
+        Self::identity()
+    }
+}
+
+impl Mul for Matrix3 {
+    type Output = Matrix3;
+
+    fn mul(self, rhs: Matrix3) -> Matrix3 {
+        let mut columns = [[0.; 3]; 3];
+        for (column, result_column) in columns.iter_mut().enumerate() {
+            for (row, result) in result_column.iter_mut().enumerate() {
+                *result = (0..3).map(|k| self.get(row, k) * rhs.get(k, column)).sum();
+            }
+        }
+        Matrix3 { columns }
+    }
+}
+
+/// A translation, rotation and scale, applied in the order scale, rotate, translate.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Transform2D {
+    /// Offset in pixels.
+    pub translation: [f32; 2],
+    /// Anticlockwise rotation in radians.
+    pub rotation: f32,
+    /// Scale along each axis.
+    pub scale: [f32; 2],
+}
+
+impl Transform2D {
+    pub fn identity() -> Self {
+        Transform2D {
+            translation: [0., 0.],
+            rotation: 0.,
+            scale: [1., 1.],
+        }
+    }
+
+    pub fn from_translation(x: f32, y: f32) -> Self {
+        Transform2D { translation: [x, y], ..Self::identity() }
+    }
+
+    pub fn to_matrix(self) -> Matrix3 {
+        let (sin, cos) = self.rotation.sin_cos();
+        let [scale_x, scale_y] = self.scale;
+        let [translate_x, translate_y] = self.translation;
+        Matrix3 {
+            columns: [
+                [cos * scale_x, sin * scale_x, 0.],
+                [-sin * scale_y, cos * scale_y, 0.],
+                [translate_x, translate_y, 1.],
+            ]
+        }
+    }
+}
+
+impl Default for Transform2D {
+    fn default() -> Self {
+        Self::identity()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::FRAC_PI_2;
+
+    fn assert_close(actual: [f32; 2], expected: [f32; 2]) {
+        assert!((actual[0] - expected[0]).abs() < 1e-5 && (actual[1] - expected[1]).abs() < 1e-5,
+                "{:?} isn't {:?}", actual, expected);
+    }
+
+    #[test]
+    fn transform_scales_then_rotates_then_translates() {
+        let transform = Transform2D { translation: [5., -3.], rotation: FRAC_PI_2, scale: [2., 3.] };
+        let matrix = transform.to_matrix();
+        assert_close(matrix.transform_point([1., 0.]), [5., -1.]);
+        assert_close(matrix.transform_point([0., 1.]), [2., -3.]);
+    }
+
+    #[test]
+    fn composed_matrices_apply_the_right_hand_one_first() {
+        let parent = Transform2D { rotation: FRAC_PI_2, ..Transform2D::from_translation(10., 0.) }.to_matrix();
+        let child = Transform2D::from_translation(1., 0.).to_matrix();
+        let point = [0., 1.];
+        assert_close((parent * child).transform_point(point), parent.transform_point(child.transform_point(point)));
+        assert_close((parent * child).transform_point(point), [9., 1.]);
+        assert_eq!(Matrix3::identity() * parent, parent);
+        assert_eq!(parent * Matrix3::identity(), parent);
+    }
+
+    #[test]
+    fn padded_columns_match_metal_layout() {
+        let matrix = Transform2D::from_translation(3., 4.).to_matrix();
+        assert_eq!(matrix.to_padded_columns(), [[1., 0., 0., 0.], [0., 1., 0., 0.], [3., 4., 1., 0.]]);
+    }
+}
@@ -1,6 +1,7 @@
 //! An quick-and-dirty implementation of simd vector types for x68_64
 
 use std::convert::TryInto;
+#[cfg(target_os = "macos")]
 use objc::{Encode, Encoding};
 use std::fmt::{Display, Formatter};
 
@@ -24,6 +25,7 @@ impl vector_uint2 {
     }
 }
 
+#[cfg(target_os = "macos")]
 unsafe impl Encode for vector_uint2 {
     fn encode() -> Encoding {
         unsafe { Encoding::from_str("d") }
@@ -56,6 +58,7 @@ impl vector_float2 {
     }
 }
 
+#[cfg(target_os = "macos")]
 unsafe impl Encode for vector_float2 {
     fn encode() -> Encoding {
         unsafe { Encoding::from_str("ff") }
@@ -97,6 +100,7 @@ impl vector_float4 {
 }
 
 
+#[cfg(target_os = "macos")]
 unsafe impl Encode for vector_float4 {
     fn encode() -> Encoding {
         unsafe { Encoding::from_str("ffff") }
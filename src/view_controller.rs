@@ -6,11 +6,30 @@ use objc::sel_impl;
 use objc::msg_send;
 use cocoa::base::{id, nil};
 use objc::runtime::{Object, Sel};
-use crate::renderer::Renderer;
+use crate::renderer::{Renderer, RendererConfig, RendererInitError, TexturedMesh, TexturedShader};
+use crate::font::Font;
+use crate::frame_stats::log_interval_from_environment;
+use crate::instancing::{InstancedMesh, sunflower_instances};
+use crate::tessellator::{self, LineCap, LineJoin, StrokeStyle, Triangle};
+use crate::particles::ParticleSettings;
+use crate::post_process::post_effects_from_environment;
+use crate::recording::RecordingSettings;
+use crate::command_trace::TraceRecorder;
+use crate::mesh::Mesh;
+use crate::sampler::SamplerDesc;
+use crate::transform::{Matrix3, Transform2D};
+use crate::blend::BlendMode;
+use crate::shader_library::LibrarySource;
+use crate::error_chain::format_error_chain;
 use objc::declare::ClassDecl;
 use std::ffi::c_void;
-use cocoa::foundation::NSAutoreleasePool;
+use cocoa::foundation::{NSAutoreleasePool, NSUInteger};
 use crate::metal_view::{MTLClearColorMake, CGSize, MetalViewDelegate};
+use crate::pixel_format::{MTLPixelFormatDepth32Float_Stencil8, color_pixel_format_from_name};
+use crate::scene_file::SceneDescription;
+use crate::scene::Node;
+use crate::hot_reload::{HotReloader, ShaderSource};
+use std::path::{Path, PathBuf};
 
 #[link(name="Metal", kind="framework")]
 extern {
@@ -19,6 +38,9 @@ extern {
     fn MTLCreateSystemDefaultDevice() -> id;
 }
 
+/// How many samples each pixel gets, unless HELLO_TRIANGLE_SAMPLE_COUNT says otherwise.
+static DEFAULT_SAMPLE_COUNT: NSUInteger = 4;
+
 /// The Rust companion to the Objc ViewController class
 pub struct RSViewController {
     /// The render that will draw in our main view.
@@ -90,17 +112,83 @@ extern "C" fn view_did_load(_self: &mut Object, _sel: Sel) {
         let new_device = MTLCreateSystemDefaultDevice();
         let _: () = msg_send![view, setDevice:new_device];
 
-        let clear_color = MTLClearColorMake(0.0, 0.5, 1.0, 1.0);
+        // The triangle's clear color, unless a scene file says otherwise.
+        let [red, green, blue, alpha] = SceneDescription::default().clear_color;
+        let clear_color = MTLClearColorMake(red, green, blue, alpha);
         let _: () = msg_send![view, setClearColor:clear_color];
 
-        // Create a renderer for our view
-        let renderer_result = Renderer::new_with_metal_kit_view(view);
+        // Set HELLO_TRIANGLE_PIXEL_FORMAT to BGRA8Unorm_sRGB, BGR10A2Unorm (wide gamut)
+        // or RGBA16Float (extended range) to draw in that format instead of BGRA8Unorm.
+        if let Ok(pixel_format_name) = std::env::var("HELLO_TRIANGLE_PIXEL_FORMAT") {
+            match color_pixel_format_from_name(&pixel_format_name) {
+                Some(pixel_format) => {
+                    let _: () = msg_send![view, setColorPixelFormat:pixel_format];
+                }
+                None => println!("Unknown pixel format {}, so drawing in BGRA8Unorm", pixel_format_name),
+            }
+        }
+
+        // Give the view depth and stencil attachments, so overlapping geometry sorts by depth.
+        let _: () = msg_send![view, setDepthStencilPixelFormat:MTLPixelFormatDepth32Float_Stencil8];
+
+        // Smooth the triangle's edges with 4x multisampling, which every Mac GPU supports,
+        // or as many samples as HELLO_TRIANGLE_SAMPLE_COUNT says (1 turns it off).
+        let sample_count = std::env::var("HELLO_TRIANGLE_SAMPLE_COUNT").ok()
+            .and_then(|sample_count| sample_count.parse().ok())
+            .unwrap_or(DEFAULT_SAMPLE_COUNT);
+        // The view falls back to as many as the device supports.
+        let _: () = msg_send![view, setSampleCount:sample_count];
+
+        // Set HELLO_TRIANGLE_PIPELINE_ARCHIVE to the path of a file
+        // to keep the compiled pipelines in between runs.
+        let config = RendererConfig {
+            pipeline_archive: std::env::var_os("HELLO_TRIANGLE_PIPELINE_ARCHIVE").map(PathBuf::from),
+            ..RendererConfig::default()
+        };
+
+        // Create a renderer for our view,
+        // compiling the built-in shader source if there's no default.metallib to hand.
+        let renderer_result = match Renderer::new_with_metal_kit_view_and_config(view, config.clone()) {
+            Err(RendererInitError::MissingLibrary(e)) => {
+                println!("{}, so using the built-in shader source", format_error_chain(&e));
+                let config = RendererConfig { library: LibrarySource::embedded(), ..config };
+                Renderer::new_with_metal_kit_view_and_config(view, config)
+            }
+            renderer_result => renderer_result,
+        };
         match renderer_result {
-            Ok(renderer) => {
+            Ok(mut renderer) => {
+                // Set HELLO_TRIANGLE_FRAME_STATS to a number of seconds
+                // to get the frame statistics printed that often.
+                if let Some(interval) = log_interval_from_environment() {
+                    renderer.set_frame_stats_log_interval(Some(interval));
+                }
+                // Set HELLO_TRIANGLE_HUD to start with the debug HUD showing.
+                renderer.set_hud_visible(std::env::var_os("HELLO_TRIANGLE_HUD").is_some());
+                // Set HELLO_TRIANGLE_SCREENSHOT_DIR to save screenshots there, not in the current directory.
+                if let Some(directory) = std::env::var_os("HELLO_TRIANGLE_SCREENSHOT_DIR") {
+                    renderer.set_screenshot_directory(PathBuf::from(directory));
+                }
+                let hot_reloader = load_watched_files(&mut renderer);
+                renderer.set_hot_reloader(hot_reloader);
+                show_shapes_from_environment(&mut renderer);
+                show_instances_from_environment(&mut renderer);
+                show_particles_from_environment(&mut renderer);
+                post_process_from_environment(&mut renderer);
+                show_texture_from_environment(&mut renderer);
+                label_vertices_from_environment(&mut renderer);
+                // Set HELLO_TRIANGLE_RECORD (see `RecordingSettings::from_environment`) to record the first frames.
+                if let Some(settings) = RecordingSettings::from_environment() {
+                    renderer.start_recording(settings);
+                }
+                // Set HELLO_TRIANGLE_TRACE (see `TraceRecorder::from_environment`) to trace the first frames' commands.
+                if let Some(recorder) = TraceRecorder::from_environment() {
+                    renderer.start_trace(recorder);
+                }
                 _rust_instance_ptr._renderer = Some(Box::new(renderer));
             }
-            _ => {
-                println!("Renderer initialization failed");
+            Err(e) => {
+                println!("Renderer initialization failed: {}", format_error_chain(&e));
                 pool.drain();
                 return;
             }
@@ -116,4 +204,180 @@ extern "C" fn view_did_load(_self: &mut Object, _sel: Sel) {
         let _: () = msg_send![view, setDelegate:_renderer];
         pool.drain();
     }
-}
\ No newline at end of file
+}
+/// Loads the scene and shaders named in the environment,
+/// and returns a reloader that watches them for changes.
+///
+/// Set HELLO_TRIANGLE_SCENE to the path of a scene file
+/// to draw that instead of the triangle.
+/// Set HELLO_TRIANGLE_SHADERS to the path of a `.metal` or `.metallib` file
+/// to draw with that instead of the default library;
+/// otherwise `default.metallib` is watched if it's in the current directory.
+fn load_watched_files(renderer: &mut Renderer) -> Option<HotReloader> {
+    let mut hot_reloader = HotReloader::new();
+
+    if let Ok(scene_path) = std::env::var("HELLO_TRIANGLE_SCENE") {
+        let scene_path = PathBuf::from(scene_path);
+        // If it doesn't load we draw the triangle until it's fixed.
+        if let Err(e) = renderer.load_scene(&scene_path) {
+            println!("Unable to load scene {}: {}", scene_path.display(), e);
+        }
+        hot_reloader.watch_scene(scene_path);
+    }
+
+    match std::env::var("HELLO_TRIANGLE_SHADERS") {
+        Ok(shaders_path) => {
+            let shader_source = ShaderSource::from_path(Path::new(&shaders_path));
+            if let Err(e) = renderer.load_shaders(&shader_source) {
+                println!("Unable to load shaders, using the default library: {}", format_error_chain(&e));
+            }
+            hot_reloader.watch_shaders(shader_source);
+        }
+        Err(_) => {
+            let default_library_path = Path::new("default.metallib");
+            if default_library_path.exists() {
+                hot_reloader.watch_shaders(ShaderSource::LibraryFile(default_library_path.to_path_buf()));
+            }
+        }
+    }
+
+    if hot_reloader.is_watching() {
+        Some(hot_reloader)
+    } else {
+        None
+    }
+}
+
+/// Set HELLO_TRIANGLE_SHAPES to add tessellated shapes to the scene, around the triangle:
+/// a circle, a concave star, a rounded rectangle's outline, a slice of a circle,
+/// and a zigzag stroked with each kind of join and cap.
+fn show_shapes_from_environment(renderer: &mut Renderer) {
+    if std::env::var_os("HELLO_TRIANGLE_SHAPES").is_none() {
+        return;
+    }
+    let tolerance = tessellator::DEFAULT_TOLERANCE;
+    let star: Vec<[f32; 2]> = (0..10)
+        .map(|point| {
+            let radius = if point % 2 == 0 { 60. } else { 25. };
+            let angle = std::f32::consts::PI * (0.5 + point as f32 / 5.);
+            [radius * angle.cos(), radius * angle.sin()]
+        })
+        .collect();
+    let zigzag = [[-60., -15.], [-20., 15.], [20., -15.], [60., 15.]];
+    let stroke = |join, cap| StrokeStyle { width: 10., join, cap, ..StrokeStyle::default() };
+    let mut shapes: Vec<([f32; 2], Vec<Triangle>, [f32; 4])> = vec![
+        ([-250., 150.], tessellator::fill_circle([0., 0.], 50., tolerance), [1., 0.5, 0., 1.]),
+        ([250., 150.], tessellator::stroke_rounded_rect([0., 0.], [120., 80.], 20., &stroke(LineJoin::Miter, LineCap::Butt)), [0., 1., 1., 1.]),
+        ([250., -20.], tessellator::fill_arc([0., 0.], 50., 0., 1.5 * std::f32::consts::PI, tolerance), [1., 1., 0., 1.]),
+    ];
+    match tessellator::fill_polygon(&star) {
+        Ok(triangles) => shapes.push(([-250., -20.], triangles, [1., 0., 1., 1.])),
+        Err(e) => println!("Unable to fill the star: {}", e),
+    }
+    let strokes = [(LineJoin::Miter, LineCap::Butt), (LineJoin::Round, LineCap::Round), (LineJoin::Bevel, LineCap::Square)];
+    for (index, &(join, cap)) in strokes.iter().enumerate() {
+        let triangles = tessellator::stroke_polyline(&zigzag, false, &stroke(join, cap));
+        shapes.push(([-160. + 160. * index as f32, -220.], triangles, [1., 1., 1., 1.]));
+    }
+
+    let scene = renderer.scene_mut();
+    for (translation, triangles, color) in shapes {
+        let mesh = scene.add_mesh(tessellator::triangle_list_mesh(&triangles, color));
+        let mut node = Node::with_mesh(mesh);
+        node.transform = Transform2D::from_translation(translation[0], translation[1]);
+        // The mesh was only just added.
+        scene.add_node(None, node).unwrap();
+    }
+}
+
+/// How far apart the instanced triangles are, in pixels, and how much smaller than the scene's triangle.
+static INSTANCE_SPACING: f32 = 14.;
+static INSTANCE_SCALE: f32 = 0.02;
+
+/// Set HELLO_TRIANGLE_INSTANCES to a number of copies of the triangle
+/// to draw them small, in a spiral out from the centre, with one instanced draw call.
+fn show_instances_from_environment(renderer: &mut Renderer) {
+    let count = match std::env::var("HELLO_TRIANGLE_INSTANCES") {
+        Ok(count) => count,
+        Err(_) => return,
+    };
+    let count: usize = match count.parse() {
+        Ok(count) => count,
+        Err(_) => {
+            println!("HELLO_TRIANGLE_INSTANCES should be a number of instances, not {:?}", count);
+            return;
+        }
+    };
+    let instances = sunflower_instances(count, INSTANCE_SPACING, INSTANCE_SCALE);
+    // The triangle is always a valid mesh.
+    renderer.set_instanced_meshes(vec![InstancedMesh::new(Mesh::hello_triangle(), instances)]).unwrap();
+}
+
+/// Set HELLO_TRIANGLE_PARTICLES to a number of particles
+/// to have a fountain of them simulated on the GPU and drawn over the scene.
+fn show_particles_from_environment(renderer: &mut Renderer) {
+    if let Some(settings) = ParticleSettings::from_environment() {
+        renderer.set_particles(Some(settings));
+    }
+}
+
+/// Set HELLO_TRIANGLE_POST_EFFECTS to a comma-separated list of post-processing effects
+/// (`blur`, `bloom`, `grade` and `vignette`) to apply them to the scene, in that order.
+fn post_process_from_environment(renderer: &mut Renderer) {
+    if let Some(effects) = post_effects_from_environment() {
+        renderer.set_post_effects(effects);
+    }
+}
+
+/// Set HELLO_TRIANGLE_TEXTURE to the path of a PNG, JPEG or KTX file
+/// to draw it (at its own size) in the middle of the view, over the scene.
+fn show_texture_from_environment(renderer: &mut Renderer) {
+    let texture_path = match std::env::var("HELLO_TRIANGLE_TEXTURE") {
+        Ok(texture_path) => PathBuf::from(texture_path),
+        Err(_) => return,
+    };
+    let texture = match renderer.load_texture(&texture_path) {
+        Ok(texture) => texture,
+        Err(e) => {
+            println!("Unable to load texture {}: {}", texture_path.display(), format_error_chain(&e));
+            return;
+        }
+    };
+    let size = [texture.width() as f32, texture.height() as f32];
+    let textured_quad = TexturedMesh {
+        mesh: Mesh::textured_quad([0., 0.], size, [1., 1., 1., 1.]),
+        texture,
+        sampler: SamplerDesc::default(),
+        shader: TexturedShader::Texture,
+        transform: Matrix3::identity(),
+        tint: [1., 1., 1., 1.],
+        blend_mode: BlendMode::Alpha,
+    };
+    // A quad is always a valid mesh.
+    renderer.set_textured_meshes(vec![textured_quad]).unwrap();
+}
+
+/// The size of TrueType label text, from ascent to descent, in pixels.
+static LABEL_FONT_PIXEL_HEIGHT: f32 = 18.;
+
+/// Set HELLO_TRIANGLE_LABEL_VERTICES to label each vertex of the scene with its position
+/// (kept up to date when the scene's reloaded),
+/// and HELLO_TRIANGLE_FONT to the path of a TrueType font to label them with
+/// (otherwise the bundled bitmap font is used).
+fn label_vertices_from_environment(renderer: &mut Renderer) {
+    if std::env::var_os("HELLO_TRIANGLE_LABEL_VERTICES").is_none() {
+        return;
+    }
+    let font = match std::env::var_os("HELLO_TRIANGLE_FONT").map(PathBuf::from) {
+        Some(font_path) => match Font::load_ttf(&font_path, LABEL_FONT_PIXEL_HEIGHT) {
+            Ok(font) => font,
+            Err(e) => {
+                println!("Unable to load font: {}", format_error_chain(&e));
+                return;
+            }
+        },
+        None => Font::bitmap(2),
+    };
+    renderer.label_vertices(font);
+}
+